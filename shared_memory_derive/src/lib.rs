@@ -0,0 +1,218 @@
+//! `#[derive(SharedSafe)]` for `shared_memory`, enable it through that crate's `derive` feature
+//!
+//! On a struct the derive checks at compile time that it is `#[repr(C)]` or `#[repr(transparent)]`, that every
+//! field is `SharedSafe` and that there is no padding between or after the fields, then implements `SharedSafe`
+//! and adds a `FIELD_OFFSET_<FIELD>` constant per field. On a fieldless enum with an explicit integer repr it adds
+//! `to_repr`/`from_repr` conversions instead of implementing the trait, because a discriminant written by another
+//! process may not be one of the variants, so enums are stored as their repr integer.
+//!
+//! `#[shared_struct]` is for control blocks that are used in place rather than copied: it makes the struct
+//! `#[repr(C)]`, accepts `SharedSafe` fields and the std integer atomics, and adds `SIZE`, `ALIGN`, the same
+//! `FIELD_OFFSET_<FIELD>` constants and a checked `attach`. Padding is allowed since the bytes are never copied out.
+
+use proc_macro::TokenStream;
+use proc_macro2::{Span, TokenStream as TokenStream2};
+use quote::{format_ident, quote, quote_spanned};
+use syn::spanned::Spanned;
+use syn::{parse_macro_input, Data, DeriveInput, Error, Fields, Ident};
+
+const INT_REPRS: [&str; 8] = ["u8", "u16", "u32", "u64", "i8", "i16", "i32", "i64"];
+
+#[proc_macro_attribute]
+pub fn shared_struct(attr: TokenStream, item: TokenStream) -> TokenStream {
+    let attr = TokenStream2::from(attr);
+    let mut input = parse_macro_input!(item as DeriveInput);
+    let result = if attr.is_empty() {
+        shared_struct_impl(&mut input)
+    } else {
+        Err(Error::new(attr.span(), "#[shared_struct] takes no arguments"))
+    };
+    result.unwrap_or_else(Error::into_compile_error).into()
+}
+
+#[proc_macro_derive(SharedSafe)]
+pub fn derive_shared_safe(input: TokenStream) -> TokenStream {
+    let input = parse_macro_input!(input as DeriveInput);
+    let result = match &input.data {
+        Data::Struct(_) => derive_struct(&input),
+        Data::Enum(_) => derive_enum(&input),
+        Data::Union(_) => Err(Error::new(Span::call_site(), "SharedSafe can't be derived for unions")),
+    };
+    result.unwrap_or_else(Error::into_compile_error).into()
+}
+
+/// Idents listed in the type's `#[repr(...)]` attributes
+fn reprs(input: &DeriveInput) -> syn::Result<Vec<Ident>> {
+    let mut reprs = Vec::new();
+    for attr in input.attrs.iter().filter(|attr| attr.path().is_ident("repr")) {
+        attr.parse_nested_meta(|meta| {
+            if let Some(ident) = meta.path.get_ident() {
+                reprs.push(ident.clone());
+            }
+            // Skip the arguments of `align(N)` and `packed(N)`
+            if meta.input.peek(syn::token::Paren) {
+                let _content;
+                syn::parenthesized!(_content in meta.input);
+            }
+            Ok(())
+        })?;
+    }
+    Ok(reprs)
+}
+
+/// The `FIELD_OFFSET_<FIELD>` constants for every field of `name`
+fn field_offsets(name: &Ident, fields: &[&syn::Field]) -> Vec<TokenStream2> {
+    fields
+        .iter()
+        .enumerate()
+        .map(|(idx, field)| {
+            let (konst, member) = match &field.ident {
+                Some(ident) => (format_ident!("FIELD_OFFSET_{}", ident.to_string().to_uppercase()), quote!(#ident)),
+                None => {
+                    let idx = syn::Index::from(idx);
+                    (format_ident!("FIELD_OFFSET_{}", idx.index), quote!(#idx))
+                }
+            };
+            let doc = format!("Offset of `{}` from the start of the struct", member);
+            quote! {
+                #[doc = #doc]
+                pub const #konst: usize = ::core::mem::offset_of!(#name, #member);
+            }
+        })
+        .collect()
+}
+
+fn struct_fields(data: &syn::DataStruct) -> Vec<&syn::Field> {
+    match &data.fields {
+        Fields::Named(fields) => fields.named.iter().collect(),
+        Fields::Unnamed(fields) => fields.unnamed.iter().collect(),
+        Fields::Unit => Vec::new(),
+    }
+}
+
+fn shared_struct_impl(input: &mut DeriveInput) -> syn::Result<TokenStream2> {
+    let name = input.ident.clone();
+    let data = match &input.data {
+        Data::Struct(data) => data,
+        _ => return Err(Error::new(name.span(), "#[shared_struct] only goes on structs")),
+    };
+    if !input.generics.params.is_empty() {
+        return Err(Error::new(input.generics.span(), "#[shared_struct] can't be used on generic structs"));
+    }
+    let reprs = reprs(input)?;
+    if let Some(repr) = reprs.iter().find(|repr| *repr != "C" && *repr != "align") {
+        return Err(Error::new(repr.span(), "#[shared_struct] lays the struct out as #[repr(C)], only align(N) can be added"));
+    }
+    let fields = struct_fields(data);
+    let checks = fields.iter().map(|field| {
+        let ty = &field.ty;
+        quote_spanned! {ty.span()=> shared_field::<#ty>(); }
+    });
+    let offsets = field_offsets(&name, &fields);
+    if !reprs.iter().any(|repr| repr == "C") {
+        input.attrs.push(syn::parse_quote!(#[repr(C)]));
+    }
+    Ok(quote! {
+        #input
+
+        const _: () = {
+            fn shared_field<T: ::shared_memory::shared_safe::SharedField>() {}
+            fn check() {
+                #(#checks)*
+            }
+        };
+
+        unsafe impl ::shared_memory::shared_safe::SharedStruct for #name {}
+
+        impl #name {
+            /// Bytes the struct takes up in the segment
+            pub const SIZE: usize = ::core::mem::size_of::<#name>();
+            /// Alignment the struct's offset in the segment has to have
+            pub const ALIGN: usize = ::core::mem::align_of::<#name>();
+
+            #(#offsets)*
+
+            /// The struct at `offset` into `shm`, checked to be inside the mapping and aligned
+            pub fn attach(
+                shm: &::shared_memory::shared_memory::SharedMemory,
+                offset: usize,
+            ) -> ::core::result::Result<&#name, ::shared_memory::shared_memory::Error> {
+                shm.struct_at::<#name>(offset)
+            }
+        }
+    })
+}
+
+fn derive_struct(input: &DeriveInput) -> syn::Result<TokenStream2> {
+    let name = &input.ident;
+    if !input.generics.params.is_empty() {
+        return Err(Error::new(input.generics.span(), "SharedSafe can't be derived for generic structs"));
+    }
+    if !reprs(input)?.iter().any(|repr| repr == "C" || repr == "transparent") {
+        return Err(Error::new(
+            name.span(),
+            "SharedSafe structs need #[repr(C)] or #[repr(transparent)] for a layout that is the same in every process",
+        ));
+    }
+    let Data::Struct(data) = &input.data else { unreachable!() };
+    let fields = struct_fields(data);
+    let checks = fields.iter().map(|field| {
+        let ty = &field.ty;
+        quote_spanned! {ty.span()=> shared_safe::<#ty>(); }
+    });
+    let sizes = fields.iter().map(|field| {
+        let ty = &field.ty;
+        quote! { ::core::mem::size_of::<#ty>() }
+    });
+    let offsets = field_offsets(name, &fields);
+    let padding_error = format!("`{}` has padding, add explicit padding fields so every byte is accounted for", name);
+    Ok(quote! {
+        const _: () = {
+            fn shared_safe<T: ::shared_memory::shared_safe::SharedSafe>() {}
+            fn check() {
+                #(#checks)*
+            }
+            assert!(::core::mem::size_of::<#name>() == 0 #(+ #sizes)*, #padding_error);
+        };
+
+        unsafe impl ::shared_memory::shared_safe::SharedSafe for #name {}
+
+        impl #name {
+            #(#offsets)*
+        }
+    })
+}
+
+fn derive_enum(input: &DeriveInput) -> syn::Result<TokenStream2> {
+    let name = &input.ident;
+    let repr = reprs(input)?
+        .into_iter()
+        .find(|repr| INT_REPRS.iter().any(|int| repr == int))
+        .ok_or_else(|| Error::new(name.span(), "SharedSafe enums need an explicit integer repr like #[repr(u32)]"))?;
+    let Data::Enum(data) = &input.data else { unreachable!() };
+    let mut variants = Vec::new();
+    for variant in &data.variants {
+        if !matches!(variant.fields, Fields::Unit) {
+            return Err(Error::new(variant.span(), "SharedSafe enums can only have variants without data"));
+        }
+        variants.push(&variant.ident);
+    }
+    Ok(quote! {
+        impl #name {
+            /// The value to store in shared memory for this variant
+            pub const fn to_repr(self) -> #repr {
+                self as #repr
+            }
+
+            /// The variant stored as `value`, `None` if no variant has that discriminant
+            pub const fn from_repr(value: #repr) -> ::core::option::Option<Self> {
+                #(
+                    if value == #name::#variants as #repr {
+                        return ::core::option::Option::Some(#name::#variants);
+                    }
+                )*
+                ::core::option::Option::None
+            }
+        }
+    })
+}
@@ -0,0 +1,234 @@
+//! Small command line tool for inspecting and poking at segments from outside the processes using them
+
+use std::io::Write;
+use std::process::ExitCode;
+use std::time::{Duration, SystemTime};
+use std::{env, fs, io, thread};
+
+use shared_memory::ring_buffer::ShmRingBuffer;
+use shared_memory::shared_memory::{Error, SharedMemory};
+
+const USAGE: &str = "usage:
+  shmtool create <name> <size>
+  shmtool read <name> [--size <n>] [--hex] [--offset <n>] [--len <n>] [--follow]
+  shmtool write <name> [--size <n>] (--data <text> | --file <path>)
+  shmtool rm <name>
+  shmtool ls [prefix]
+  shmtool info <name> [--size <n>]";
+
+/// How often `read --follow` checks the segment for changes
+const FOLLOW_INTERVAL: Duration = Duration::from_millis(100);
+
+enum CliError {
+    Usage(String),
+    Shm(Error),
+}
+
+impl From<Error> for CliError {
+    fn from(err: Error) -> Self {
+        CliError::Shm(err)
+    }
+}
+
+impl From<io::Error> for CliError {
+    fn from(err: io::Error) -> Self {
+        CliError::Shm(err.into())
+    }
+}
+
+#[derive(Default)]
+struct Options {
+    positional: Vec<String>,
+    size: Option<String>,
+    offset: Option<String>,
+    len: Option<String>,
+    data: Option<String>,
+    file: Option<String>,
+    hex: bool,
+    follow: bool,
+}
+
+impl Options {
+    fn parse(args: &[String]) -> Result<Self, CliError> {
+        let mut options = Options::default();
+        let mut iter = args.iter();
+        while let Some(arg) = iter.next() {
+            let slot = match arg.as_str() {
+                "--hex" => {
+                    options.hex = true;
+                    continue;
+                }
+                "--follow" => {
+                    options.follow = true;
+                    continue;
+                }
+                "--size" => &mut options.size,
+                "--offset" => &mut options.offset,
+                "--len" => &mut options.len,
+                "--data" => &mut options.data,
+                "--file" => &mut options.file,
+                flag if flag.starts_with("--") => return Err(CliError::Usage(format!("unknown option {}", flag))),
+                _ => {
+                    options.positional.push(arg.clone());
+                    continue;
+                }
+            };
+            match iter.next() {
+                Some(value) => *slot = Some(value.clone()),
+                None => return Err(CliError::Usage(format!("{} needs a value", arg))),
+            }
+        }
+        Ok(options)
+    }
+
+    fn positional(&self, idx: usize, what: &str) -> Result<&str, CliError> {
+        self.positional
+            .get(idx)
+            .map(String::as_str)
+            .ok_or_else(|| CliError::Usage(format!("missing <{}>", what)))
+    }
+}
+
+fn parse_number<T: std::str::FromStr>(value: &str, what: &str) -> Result<T, CliError> {
+    value.parse().map_err(|_| CliError::Usage(format!("invalid {} {:?}", what, value)))
+}
+
+/// Uses `--size` when given, otherwise looks the segment up so the caller doesn't have to know how big it is
+fn segment_size(name: &str, options: &Options) -> Result<i32, CliError> {
+    if let Some(size) = &options.size {
+        return parse_number(size, "size");
+    }
+    let wanted = format!("/{}", name.trim_start_matches('/'));
+    let entries = SharedMemory::list(&wanted)
+        .map_err(|_| CliError::Usage("the segment size can't be looked up on this platform, pass --size".to_string()))?;
+    match entries.into_iter().find(|entry| entry.name == wanted) {
+        Some(entry) => i32::try_from(entry.size).map_err(|_| CliError::Usage(format!("{} is too large to map", name))),
+        None => Err(io::Error::new(io::ErrorKind::NotFound, format!("no segment named {}", name)).into()),
+    }
+}
+
+fn print_range(shm: &SharedMemory, bytes: &[u8], offset: usize, hex: bool) -> Result<(), CliError> {
+    if hex {
+        print!("{}", shm.hexdump(offset..offset + bytes.len()));
+    } else {
+        let mut stdout = io::stdout();
+        stdout.write_all(bytes)?;
+        stdout.flush()?;
+    }
+    Ok(())
+}
+
+fn read(options: &Options) -> Result<(), CliError> {
+    let name = options.positional(1, "name")?;
+    let shm = SharedMemory::open(name, segment_size(name, options)?)?;
+    let size = shm.size() as usize;
+    let offset = match &options.offset {
+        Some(offset) => parse_number(offset, "offset")?,
+        None => 0,
+    };
+    let len = match &options.len {
+        Some(len) => parse_number(len, "length")?,
+        // Without an explicit range the raw output stops at the last non-zero byte, same as read_data
+        None if !options.hex && offset == 0 => shm.read_data().len(),
+        None => size.saturating_sub(offset),
+    };
+    if offset.checked_add(len).is_none_or(|end| end > size) {
+        return Err(Error::OutOfBounds { offset, len, size }.into());
+    }
+    let mut last = shm.snapshot()[offset..offset + len].to_vec();
+    print_range(&shm, &last, offset, options.hex)?;
+    if !options.follow {
+        return Ok(());
+    }
+    loop {
+        thread::sleep(FOLLOW_INTERVAL);
+        let current = &shm.snapshot()[offset..offset + len];
+        if current != last.as_slice() {
+            last = current.to_vec();
+            print_range(&shm, &last, offset, options.hex)?;
+        }
+    }
+}
+
+/// How long before now `time` was, for counters that keep a timestamp
+fn ago(time: Option<SystemTime>) -> String {
+    match time.map(|time| SystemTime::now().duration_since(time)) {
+        None => "never".to_string(),
+        Some(Ok(since)) => format!("{:.3}s ago", since.as_secs_f64()),
+        // Written by a process whose clock is ahead of ours
+        Some(Err(_)) => "just now".to_string(),
+    }
+}
+
+fn run(args: &[String]) -> Result<(), CliError> {
+    let options = Options::parse(args)?;
+    match options.positional(0, "command")? {
+        "create" => {
+            let name = options.positional(1, "name")?;
+            let size = parse_number(options.positional(2, "size")?, "size")?;
+            // Forget the handle so dropping it doesn't unlink the segment we were asked to create.
+            // On Windows the section still goes away when this process exits since nothing else holds it open
+            std::mem::forget(SharedMemory::create(name, size)?);
+        }
+        "read" => read(&options)?,
+        "write" => {
+            let name = options.positional(1, "name")?;
+            let data = match (&options.data, &options.file) {
+                (Some(data), None) => data.clone().into_bytes(),
+                (None, Some(path)) => fs::read(path)?,
+                _ => return Err(CliError::Usage("write needs exactly one of --data or --file".to_string())),
+            };
+            let mut shm = SharedMemory::open(name, segment_size(name, &options)?)?;
+            shm.restore(&data)?;
+        }
+        "rm" => SharedMemory::unlink(options.positional(1, "name")?)?,
+        "ls" => {
+            let prefix = options.positional.get(1).map(String::as_str).unwrap_or("");
+            for entry in SharedMemory::list(prefix)? {
+                println!("{}\t{}", entry.name, entry.size);
+            }
+        }
+        "info" => {
+            let name = options.positional(1, "name")?;
+            let shm = SharedMemory::open(name, segment_size(name, &options)?)?;
+            println!("name: {}", name);
+            println!("size: {} bytes", shm.size());
+            println!("used: {} bytes", shm.read_data().len());
+            drop(shm);
+            // Rings carry counters in their header, anything else just fails to open as one
+            if let Ok(ring) = ShmRingBuffer::open_existing(name) {
+                let stats = ring.stats();
+                println!("ring buffer: {} byte data area, {:?} when full", ring.capacity(), ring.full_policy());
+                println!("  occupancy: {} messages, {} bytes (high water {} bytes)", stats.occupancy_messages, stats.occupancy_bytes, stats.high_water_bytes);
+                println!("  pushed: {}, popped: {}, dropped: {}", stats.pushed, stats.popped, stats.dropped);
+                println!("  last push: {}, last pop: {}", ago(stats.last_push), ago(stats.last_pop));
+            }
+        }
+        other => return Err(CliError::Usage(format!("unknown command {}", other))),
+    }
+    Ok(())
+}
+
+fn exit_code(err: &Error) -> u8 {
+    match err {
+        Error::Io(err) if err.kind() == io::ErrorKind::NotFound => 3,
+        Error::Io(err) if err.kind() == io::ErrorKind::PermissionDenied => 4,
+        Error::Io(_) => 1,
+        _ => 5,
+    }
+}
+
+fn main() -> ExitCode {
+    let args: Vec<String> = env::args().skip(1).collect();
+    match run(&args) {
+        Ok(()) => ExitCode::SUCCESS,
+        Err(CliError::Usage(msg)) => {
+            eprintln!("shmtool: {}\n{}", msg, USAGE);
+            ExitCode::from(2)
+        }
+        Err(CliError::Shm(err)) => {
+            eprintln!("shmtool: {}", err);
+            ExitCode::from(exit_code(&err))
+        }
+    }
+}
@@ -0,0 +1,188 @@
+//! First-fit allocator handing out contiguous runs of fixed-size blocks from the segment it lives in
+//!
+//! The header is followed by a bitmap with one bit per block (set while allocated) and then the blocks themselves,
+//! starting on a 4 KiB boundary. Allocating and freeing both take the lock word in the header, so processes
+//! allocating at the same time simply queue up behind each other, and a first-fit scan holds the lock for as long as
+//! it takes to walk the bitmap. A process that dies holding the lock leaves the allocator locked.
+
+use std::ops::Range;
+use std::sync::atomic::{AtomicU32, Ordering};
+use std::{fmt, io, ptr};
+
+use crate::futex;
+use crate::shared_memory::{Error, SharedMemory};
+
+/// "SHBM"
+const MAGIC: u32 = 0x5348_424d;
+
+const OFF_MAGIC: usize = 0;
+const OFF_LOCK: usize = 4;
+const OFF_BLOCKS: usize = 8;
+const OFF_BLOCK_SIZE: usize = 16;
+const HEADER_SIZE: usize = 64;
+
+const DATA_ALIGNMENT: usize = 4096;
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum BitmapError {
+    /// No run of `count` free blocks exists, even if that many are free in total
+    Fragmented { count: usize },
+    /// Block `block` of the range being freed isn't allocated
+    NotAllocated { block: usize },
+    /// The range is empty or reaches past the last block
+    OutOfRange { range: Range<usize>, blocks: usize },
+}
+
+impl fmt::Display for BitmapError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            BitmapError::Fragmented { count } => write!(f, "no run of {} free blocks", count),
+            BitmapError::NotAllocated { block } => write!(f, "block {} isn't allocated", block),
+            BitmapError::OutOfRange { range, blocks } => {
+                write!(f, "blocks {}..{} are out of range for {} blocks", range.start, range.end, blocks)
+            }
+        }
+    }
+}
+
+impl std::error::Error for BitmapError {}
+
+pub struct ShmBitmapAllocator {
+    shm: SharedMemory,
+    blocks: usize,
+    block_size: usize,
+}
+
+// The bitmap is only touched while holding the lock word
+unsafe impl Send for ShmBitmapAllocator {}
+unsafe impl Sync for ShmBitmapAllocator {}
+
+impl ShmBitmapAllocator {
+    /// Creates the segment with `blocks` free blocks of `block_size` bytes
+    pub fn create(name: &str, blocks: usize, block_size: usize) -> Result<Self, Error> {
+        let total = ShmBitmapAllocator::segment_size(blocks, block_size)?;
+        let alloc = ShmBitmapAllocator { shm: SharedMemory::create(name, total)?, blocks, block_size };
+        unsafe {
+            ptr::write_bytes(alloc.base(), 0, ShmBitmapAllocator::data_offset(blocks));
+        }
+        alloc.shm.write_u64_le(OFF_BLOCKS, blocks as u64)?;
+        alloc.shm.write_u64_le(OFF_BLOCK_SIZE, block_size as u64)?;
+        alloc.word(OFF_MAGIC).store(MAGIC.to_le(), Ordering::Release);
+        Ok(alloc)
+    }
+
+    /// Opens an allocator created with the same geometry by another handle
+    pub fn open(name: &str, blocks: usize, block_size: usize) -> Result<Self, Error> {
+        let total = ShmBitmapAllocator::segment_size(blocks, block_size)?;
+        let alloc = ShmBitmapAllocator { shm: SharedMemory::open(name, total)?, blocks, block_size };
+        let magic = u32::from_le(alloc.word(OFF_MAGIC).load(Ordering::Acquire));
+        if magic != MAGIC {
+            return Err(Error::LayoutMismatch { what: "bitmap magic", expected: MAGIC as u64, found: magic as u64 });
+        }
+        for (what, offset, expected) in [("bitmap blocks", OFF_BLOCKS, blocks), ("bitmap block size", OFF_BLOCK_SIZE, block_size)] {
+            let found = alloc.shm.read_u64_le(offset)?;
+            if found != expected as u64 {
+                return Err(Error::LayoutMismatch { what, expected: expected as u64, found });
+            }
+        }
+        Ok(alloc)
+    }
+
+    fn data_offset(blocks: usize) -> usize {
+        (HEADER_SIZE + blocks.div_ceil(64) * 8).div_ceil(DATA_ALIGNMENT) * DATA_ALIGNMENT
+    }
+
+    fn segment_size(blocks: usize, block_size: usize) -> Result<i32, io::Error> {
+        blocks
+            .checked_mul(block_size)
+            .and_then(|data| data.checked_add(ShmBitmapAllocator::data_offset(blocks)))
+            .and_then(|total| i32::try_from(total).ok())
+            .ok_or_else(|| io::Error::new(io::ErrorKind::InvalidInput, "bitmap allocator doesn't fit in a segment"))
+    }
+
+    pub fn blocks(&self) -> usize {
+        self.blocks
+    }
+
+    pub fn block_size(&self) -> usize {
+        self.block_size
+    }
+
+    /// Offset of block `block` from the start of the segment
+    pub fn block_offset(&self, block: usize) -> usize {
+        ShmBitmapAllocator::data_offset(self.blocks) + block * self.block_size
+    }
+
+    /// The segment the blocks live in
+    pub fn shared_memory(&self) -> &SharedMemory {
+        &self.shm
+    }
+
+    fn base(&self) -> *mut u8 {
+        self.shm.address() as *mut u8
+    }
+
+    fn word(&self, offset: usize) -> &AtomicU32 {
+        unsafe { &*(self.base().add(offset) as *const AtomicU32) }
+    }
+
+    fn bitmap_word(&self, index: usize) -> *mut u64 {
+        unsafe { self.base().add(HEADER_SIZE + index * 8) as *mut u64 }
+    }
+
+    fn is_set(&self, block: usize) -> bool {
+        unsafe { *self.bitmap_word(block / 64) & (1 << (block % 64)) != 0 }
+    }
+
+    fn set_range(&self, range: Range<usize>, allocated: bool) {
+        for block in range {
+            unsafe {
+                let word = self.bitmap_word(block / 64);
+                if allocated {
+                    *word |= 1 << (block % 64);
+                } else {
+                    *word &= !(1 << (block % 64));
+                }
+            }
+        }
+    }
+
+    /// Allocates the first run of `count` contiguous free blocks
+    pub fn alloc_blocks(&self, count: usize) -> Result<Range<usize>, BitmapError> {
+        if count == 0 || count > self.blocks {
+            return Err(BitmapError::OutOfRange { range: 0..count, blocks: self.blocks });
+        }
+        let _lock = futex::lock(self.word(OFF_LOCK));
+        let mut start = 0;
+        let mut block = 0;
+        while block < self.blocks {
+            if block % 64 == 0 && unsafe { *self.bitmap_word(block / 64) } == u64::MAX {
+                // Whole word allocated, skip it in one go
+                block += 64;
+                start = block;
+                continue;
+            }
+            if self.is_set(block) {
+                start = block + 1;
+            } else if block + 1 - start == count {
+                self.set_range(start..block + 1, true);
+                return Ok(start..block + 1);
+            }
+            block += 1;
+        }
+        Err(BitmapError::Fragmented { count })
+    }
+
+    /// Frees every block in `range`, which all have to be allocated, leaving the bitmap untouched otherwise
+    pub fn free_blocks(&self, range: Range<usize>) -> Result<(), BitmapError> {
+        if range.is_empty() || range.end > self.blocks {
+            return Err(BitmapError::OutOfRange { range, blocks: self.blocks });
+        }
+        let _lock = futex::lock(self.word(OFF_LOCK));
+        if let Some(block) = range.clone().find(|&block| !self.is_set(block)) {
+            return Err(BitmapError::NotAllocated { block });
+        }
+        self.set_range(range, false);
+        Ok(())
+    }
+}
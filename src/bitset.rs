@@ -0,0 +1,168 @@
+//! Fixed-size bitset at an offset inside a segment, for occupancy maps and per-id flags shared between processes
+//!
+//! Single-bit operations are one atomic `fetch_or`, `fetch_and` or load on the `u64` word holding the bit. Bulk
+//! setting takes the lock word in the header so two bulk operations don't interleave, and still changes each word
+//! with `fetch_or` so single-bit operations running alongside it are never lost. Counts and searches read the words
+//! one at a time and only reflect a quiet bitset exactly.
+
+use std::fmt;
+use std::ops::Range;
+use std::sync::atomic::{fence, AtomicU32, AtomicU64, Ordering};
+
+use crate::futex;
+use crate::shared_memory::{Error, SharedMemory};
+
+const OFF_LOCK: usize = 0;
+const OFF_BITS: usize = 8;
+const HEADER_SIZE: usize = 16;
+
+/// Alignment the bitset's offset inside the segment has to have
+pub const ALIGNMENT: usize = 8;
+
+/// Bit `bit`, or the end of a range, is past the bitset's `bits` bits
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct OutOfRange {
+    pub bit: usize,
+    pub bits: usize,
+}
+
+impl fmt::Display for OutOfRange {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "bit {} is out of range for {} bits", self.bit, self.bits)
+    }
+}
+
+impl std::error::Error for OutOfRange {}
+
+pub struct ShmBitset<'a> {
+    shm: &'a SharedMemory,
+    offset: usize,
+    bits: usize,
+}
+
+// Only the segment's address and size are read through the reference, the words are only touched atomically
+unsafe impl Send for ShmBitset<'_> {}
+unsafe impl Sync for ShmBitset<'_> {}
+
+impl<'a> ShmBitset<'a> {
+    /// Bytes the bitset takes up with `bits` bits
+    pub fn size_for(bits: usize) -> usize {
+        HEADER_SIZE + bits.div_ceil(64) * 8
+    }
+
+    /// Sets up a bitset of `bits` clear bits at `offset` into `shm`
+    ///
+    /// Nothing else may be using the bitset while it's being initialized
+    pub fn init(shm: &'a SharedMemory, offset: usize, bits: usize) -> Result<Self, Error> {
+        let bitset = ShmBitset::check(shm, offset, bits)?;
+        bitset.lock_word().store(0, Ordering::Relaxed);
+        for word in 0..bitset.words() {
+            bitset.word(word).store(0, Ordering::Relaxed);
+        }
+        shm.write_u64_le(offset + OFF_BITS, bits as u64)?;
+        fence(Ordering::Release);
+        Ok(bitset)
+    }
+
+    /// Attaches to a bitset another handle initialized at `offset` with the same number of bits
+    pub fn attach(shm: &'a SharedMemory, offset: usize, bits: usize) -> Result<Self, Error> {
+        let bitset = ShmBitset::check(shm, offset, bits)?;
+        let found = shm.read_u64_le(offset + OFF_BITS)?;
+        if found != bits as u64 {
+            return Err(Error::LayoutMismatch { what: "bitset size", expected: bits as u64, found });
+        }
+        Ok(bitset)
+    }
+
+    fn check(shm: &'a SharedMemory, offset: usize, bits: usize) -> Result<Self, Error> {
+        if !offset.is_multiple_of(ALIGNMENT) {
+            return Err(Error::UnalignedOffset { offset: offset as u64, alignment: ALIGNMENT as u64 });
+        }
+        let len = ShmBitset::size_for(bits);
+        let size = shm.size() as usize;
+        if offset.checked_add(len).is_none_or(|end| end > size) {
+            return Err(Error::OutOfBounds { offset, len, size });
+        }
+        Ok(ShmBitset { shm, offset, bits })
+    }
+
+    fn lock_word(&self) -> &AtomicU32 {
+        unsafe { &*((self.shm.address() as *mut u8).add(self.offset + OFF_LOCK) as *const AtomicU32) }
+    }
+
+    fn word(&self, index: usize) -> &AtomicU64 {
+        unsafe { &*((self.shm.address() as *mut u8).add(self.offset + HEADER_SIZE + index * 8) as *const AtomicU64) }
+    }
+
+    fn words(&self) -> usize {
+        self.bits.div_ceil(64)
+    }
+
+    /// The word holding `bit` and the bit's mask within it
+    fn locate(&self, bit: usize) -> Result<(&AtomicU64, u64), OutOfRange> {
+        if bit >= self.bits {
+            return Err(OutOfRange { bit, bits: self.bits });
+        }
+        Ok((self.word(bit / 64), 1 << (bit % 64)))
+    }
+
+    /// Bits in this word that lie inside the bitset, the last word can be partly past the end
+    fn valid(&self, index: usize) -> u64 {
+        match self.bits - index * 64 {
+            left if left >= 64 => u64::MAX,
+            left => (1 << left) - 1,
+        }
+    }
+
+    pub fn bits(&self) -> usize {
+        self.bits
+    }
+
+    /// Sets `bit`, returning whether it was already set
+    pub fn set(&self, bit: usize) -> Result<bool, OutOfRange> {
+        let (word, mask) = self.locate(bit)?;
+        Ok(word.fetch_or(mask, Ordering::AcqRel) & mask != 0)
+    }
+
+    /// Clears `bit`, returning whether it was set
+    pub fn clear(&self, bit: usize) -> Result<bool, OutOfRange> {
+        let (word, mask) = self.locate(bit)?;
+        Ok(word.fetch_and(!mask, Ordering::AcqRel) & mask != 0)
+    }
+
+    pub fn test(&self, bit: usize) -> Result<bool, OutOfRange> {
+        let (word, mask) = self.locate(bit)?;
+        Ok(word.load(Ordering::Acquire) & mask != 0)
+    }
+
+    pub fn count_ones(&self) -> usize {
+        (0..self.words()).map(|index| (self.word(index).load(Ordering::Acquire) & self.valid(index)).count_ones() as usize).sum()
+    }
+
+    /// Lowest clear bit, `None` if every bit is set
+    pub fn find_first_zero(&self) -> Option<usize> {
+        (0..self.words()).find_map(|index| {
+            let zeros = !self.word(index).load(Ordering::Acquire) & self.valid(index);
+            (zeros != 0).then(|| index * 64 + zeros.trailing_zeros() as usize)
+        })
+    }
+
+    /// Sets every bit in `range`
+    pub fn set_range(&self, range: Range<usize>) -> Result<(), OutOfRange> {
+        if range.end > self.bits {
+            return Err(OutOfRange { bit: range.end, bits: self.bits });
+        }
+        if range.is_empty() {
+            return Ok(());
+        }
+        let _lock = futex::lock(self.lock_word());
+        let (first, last) = (range.start / 64, (range.end - 1) / 64);
+        for index in first..=last {
+            let low = if index == first { range.start % 64 } else { 0 };
+            let high = if index == last { (range.end - 1) % 64 } else { 63 };
+            let mask = (u64::MAX >> (63 - high)) & (u64::MAX << low);
+            self.word(index).fetch_or(mask, Ordering::AcqRel);
+        }
+        Ok(())
+    }
+}
@@ -0,0 +1,130 @@
+//! Bloom filter at an offset inside a segment, for asking whether an item has definitely never been inserted
+//!
+//! The header holds the number of bits and hashes so openers take them from the segment. Insertions set bits with
+//! `fetch_or` on whole `u64` words, so any number of processes can insert at once and an item is found by
+//! `contains` as soon as its `insert` returns. Bit positions come from double hashing two seeded xxh3 hashes.
+
+use std::io;
+use std::sync::atomic::{fence, AtomicU64, Ordering};
+
+use xxhash_rust::xxh3::xxh3_64_with_seed;
+
+use crate::shared_memory::{Error, SharedMemory};
+
+/// "SHBL"
+const MAGIC: u32 = 0x5348_424c;
+
+const OFF_MAGIC: usize = 0;
+const OFF_HASHES: usize = 4;
+const OFF_BITS: usize = 8;
+const HEADER_SIZE: usize = 16;
+
+const SEED_A: u64 = 0x9e37_79b9_7f4a_7c15;
+const SEED_B: u64 = 0xc2b2_ae3d_27d4_eb4f;
+
+/// Alignment the filter's offset inside the segment has to have
+pub const ALIGNMENT: usize = 8;
+
+/// Bits and hashes that keep the false-positive rate near `false_positive_rate` once `items` items are inserted
+pub fn parameters_for(items: u64, false_positive_rate: f64) -> (u64, u32) {
+    assert!(false_positive_rate > 0.0 && false_positive_rate < 1.0, "the false-positive rate has to be between 0 and 1");
+    let ln2 = std::f64::consts::LN_2;
+    let bits = (-(items.max(1) as f64) * false_positive_rate.ln() / (ln2 * ln2)).ceil().max(64.0);
+    let hashes = (bits / items.max(1) as f64 * ln2).round().clamp(1.0, 32.0);
+    (bits as u64, hashes as u32)
+}
+
+pub struct ShmBloomFilter<'a> {
+    shm: &'a SharedMemory,
+    offset: usize,
+    bits: u64,
+    hashes: u32,
+}
+
+// Only the segment's address and size are read through the reference, the bit array is only touched atomically
+unsafe impl Send for ShmBloomFilter<'_> {}
+unsafe impl Sync for ShmBloomFilter<'_> {}
+
+impl<'a> ShmBloomFilter<'a> {
+    /// Bytes the filter takes up with `bits` bits
+    pub fn size_for(bits: u64) -> usize {
+        HEADER_SIZE + bits.div_ceil(64) as usize * 8
+    }
+
+    /// Sets up an empty filter of `bits` bits checked with `hashes` hashes at `offset` into `shm`
+    ///
+    /// Nothing else may be using the filter while it's being initialized
+    pub fn init(shm: &'a SharedMemory, offset: usize, bits: u64, hashes: u32) -> Result<Self, Error> {
+        if bits == 0 || hashes == 0 {
+            return Err(io::Error::new(io::ErrorKind::InvalidInput, "a bloom filter needs at least one bit and one hash").into());
+        }
+        let filter = ShmBloomFilter::check(shm, offset, bits, hashes)?;
+        for word in 0..bits.div_ceil(64) as usize {
+            filter.word(word).store(0, Ordering::Relaxed);
+        }
+        shm.write_u32_le(offset + OFF_HASHES, hashes)?;
+        shm.write_u64_le(offset + OFF_BITS, bits)?;
+        fence(Ordering::Release);
+        shm.write_u32_le(offset + OFF_MAGIC, MAGIC)?;
+        Ok(filter)
+    }
+
+    /// Attaches to a filter another handle initialized at `offset`, taking its size and hash count from the header
+    pub fn attach(shm: &'a SharedMemory, offset: usize) -> Result<Self, Error> {
+        ShmBloomFilter::check(shm, offset, 0, 0)?;
+        let magic = shm.read_u32_le(offset + OFF_MAGIC)?;
+        if magic != MAGIC {
+            return Err(Error::LayoutMismatch { what: "bloom filter magic", expected: MAGIC as u64, found: magic as u64 });
+        }
+        let hashes = shm.read_u32_le(offset + OFF_HASHES)?;
+        let bits = shm.read_u64_le(offset + OFF_BITS)?;
+        if bits == 0 || hashes == 0 {
+            return Err(io::Error::new(io::ErrorKind::InvalidData, "bloom filter header has no bits or no hashes").into());
+        }
+        ShmBloomFilter::check(shm, offset, bits, hashes)
+    }
+
+    fn check(shm: &'a SharedMemory, offset: usize, bits: u64, hashes: u32) -> Result<Self, Error> {
+        if !offset.is_multiple_of(ALIGNMENT) {
+            return Err(Error::UnalignedOffset { offset: offset as u64, alignment: ALIGNMENT as u64 });
+        }
+        let size = shm.size() as usize;
+        let len = usize::try_from(bits.div_ceil(64)).ok().and_then(|words| words.checked_mul(8)?.checked_add(HEADER_SIZE));
+        if len.and_then(|len| offset.checked_add(len)).is_none_or(|end| end > size) {
+            return Err(Error::OutOfBounds { offset, len: len.unwrap_or(usize::MAX), size });
+        }
+        Ok(ShmBloomFilter { shm, offset, bits, hashes })
+    }
+
+    fn word(&self, index: usize) -> &AtomicU64 {
+        unsafe { &*((self.shm.address() as *mut u8).add(self.offset + HEADER_SIZE + index * 8) as *const AtomicU64) }
+    }
+
+    /// The bits `item` maps to
+    fn positions(&self, item: &[u8]) -> impl Iterator<Item = u64> {
+        let first = xxh3_64_with_seed(item, SEED_A);
+        // Odd so the steps don't collapse onto a few positions when `bits` is even
+        let step = xxh3_64_with_seed(item, SEED_B) | 1;
+        let bits = self.bits;
+        (0..self.hashes as u64).map(move |index| first.wrapping_add(index.wrapping_mul(step)) % bits)
+    }
+
+    pub fn bits(&self) -> u64 {
+        self.bits
+    }
+
+    pub fn hashes(&self) -> u32 {
+        self.hashes
+    }
+
+    pub fn insert(&self, item: &[u8]) {
+        for bit in self.positions(item) {
+            self.word((bit / 64) as usize).fetch_or(1 << (bit % 64), Ordering::Release);
+        }
+    }
+
+    /// False if `item` has definitely never been inserted, true if it probably has
+    pub fn contains(&self, item: &[u8]) -> bool {
+        self.positions(item).all(|bit| self.word((bit / 64) as usize).load(Ordering::Acquire) & (1 << (bit % 64)) != 0)
+    }
+}
@@ -0,0 +1,238 @@
+//! One writer, any number of readers each going at their own pace, cross-process `tokio::sync::broadcast` style
+//!
+//! Messages go into fixed-size slots in sequence order and the writer always overwrites the oldest slot, it never
+//! waits for readers. Each slot carries a version derived from the sequence number of the message in it (odd while
+//! it is being written), so a reader can tell if the slot it just copied was overwritten underneath it. Reader
+//! cursors are local to the reader, nothing about readers is stored in the segment.
+
+use std::sync::atomic::{fence, AtomicU32, AtomicU64, Ordering};
+use std::time::Duration;
+use std::{fmt, io, ptr};
+
+use crate::futex;
+use crate::shared_memory::{Error, SharedMemory};
+
+/// "SHBC"
+const MAGIC: u32 = 0x5348_4243;
+
+const OFF_MAGIC: usize = 0;
+const OFF_SLOTS: usize = 8;
+const OFF_SLOT_SIZE: usize = 16;
+const OFF_WRITE_SEQ: usize = 64;
+const OFF_NOTIFY: usize = 72;
+const HEADER_SIZE: usize = 128;
+
+// Per slot: version (u64), message length (u32), then the message
+const SLOT_HEADER: usize = 16;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum BroadcastRecvError {
+    /// Nothing new yet, or the wait timed out
+    Empty,
+    /// The writer overwrote this many messages before the reader got to them, the cursor has moved past them
+    Lagged(u64),
+}
+
+impl fmt::Display for BroadcastRecvError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            BroadcastRecvError::Empty => write!(f, "no new messages"),
+            BroadcastRecvError::Lagged(skipped) => write!(f, "reader lagged behind and skipped {} messages", skipped),
+        }
+    }
+}
+
+impl std::error::Error for BroadcastRecvError {}
+
+/// What both the writer and readers need to find their way around the segment
+struct Layout {
+    shm: SharedMemory,
+    slots: usize,
+    slot_size: usize,
+}
+
+impl Layout {
+    fn segment_size(slots: usize, slot_size: usize) -> Result<i32, io::Error> {
+        slot_size
+            .div_ceil(8)
+            .checked_mul(8)
+            .and_then(|slot_size| slot_size.checked_add(SLOT_HEADER))
+            .and_then(|stride| stride.checked_mul(slots))
+            .and_then(|total| total.checked_add(HEADER_SIZE))
+            .and_then(|total| i32::try_from(total).ok())
+            .filter(|_| slots > 0)
+            .ok_or_else(|| io::Error::new(io::ErrorKind::InvalidInput, "broadcast ring doesn't fit in a segment"))
+    }
+
+    fn stride(&self) -> usize {
+        SLOT_HEADER + self.slot_size.div_ceil(8) * 8
+    }
+
+    fn base(&self) -> *mut u8 {
+        self.shm.address() as *mut u8
+    }
+
+    fn word64(&self, offset: usize) -> &AtomicU64 {
+        unsafe { &*(self.base().add(offset) as *const AtomicU64) }
+    }
+
+    fn word32(&self, offset: usize) -> &AtomicU32 {
+        unsafe { &*(self.base().add(offset) as *const AtomicU32) }
+    }
+
+    fn slot(&self, seq: u64) -> usize {
+        HEADER_SIZE + (seq % self.slots as u64) as usize * self.stride()
+    }
+
+    fn write_seq(&self) -> &AtomicU64 {
+        self.word64(OFF_WRITE_SEQ)
+    }
+
+    fn notify(&self) -> &AtomicU32 {
+        self.word32(OFF_NOTIFY)
+    }
+}
+
+/// Version a slot holds once message `seq` is completely written into it
+fn version(seq: u64) -> u64 {
+    2 * seq + 2
+}
+
+/// The writing side, create it once and keep it in the single producing process
+pub struct ShmBroadcast {
+    layout: Layout,
+}
+
+// Shared fields only go through atomics, and sending takes `&mut self`
+unsafe impl Send for ShmBroadcast {}
+
+impl ShmBroadcast {
+    /// Creates a ring of `slots` messages of up to `slot_size` bytes each
+    pub fn create(name: &str, slots: usize, slot_size: usize) -> Result<Self, Error> {
+        let total = Layout::segment_size(slots, slot_size)?;
+        let layout = Layout { shm: SharedMemory::create(name, total)?, slots, slot_size };
+        unsafe {
+            ptr::write_bytes(layout.base(), 0, total as usize);
+        }
+        layout.shm.write_u64_le(OFF_SLOTS, slots as u64)?;
+        layout.shm.write_u64_le(OFF_SLOT_SIZE, slot_size as u64)?;
+        layout.word32(OFF_MAGIC).store(MAGIC.to_le(), Ordering::Release);
+        Ok(ShmBroadcast { layout })
+    }
+
+    pub fn max_message_len(&self) -> usize {
+        self.layout.slot_size
+    }
+
+    /// Publishes `msg` over the oldest slot and returns its sequence number
+    pub fn send(&mut self, msg: &[u8]) -> Result<u64, Error> {
+        let layout = &self.layout;
+        if msg.len() > layout.slot_size {
+            return Err(Error::OutOfBounds { offset: 0, len: msg.len(), size: layout.slot_size });
+        }
+        let seq = layout.write_seq().load(Ordering::Relaxed);
+        let slot = layout.slot(seq);
+        let slot_version = layout.word64(slot);
+        // Odd version first so a reader racing with the copy below sees a mismatch when it re-checks
+        slot_version.store(version(seq) - 1, Ordering::Relaxed);
+        fence(Ordering::Release);
+        unsafe {
+            layout.word32(slot + 8).store(msg.len() as u32, Ordering::Relaxed);
+            ptr::copy_nonoverlapping(msg.as_ptr(), layout.base().add(slot + SLOT_HEADER), msg.len());
+        }
+        slot_version.store(version(seq), Ordering::Release);
+        layout.write_seq().store(seq + 1, Ordering::Release);
+        layout.notify().fetch_add(1, Ordering::Release);
+        futex::wake_all(layout.notify());
+        Ok(seq)
+    }
+}
+
+/// A reader with its own cursor, readers never affect the writer or each other
+pub struct ShmBroadcastReceiver {
+    layout: Layout,
+    cursor: u64,
+}
+
+unsafe impl Send for ShmBroadcastReceiver {}
+
+impl ShmBroadcastReceiver {
+    /// Attaches to the ring, only messages sent from now on are received
+    pub fn open(name: &str, slots: usize, slot_size: usize) -> Result<Self, Error> {
+        let layout = Layout { shm: SharedMemory::open(name, Layout::segment_size(slots, slot_size)?)?, slots, slot_size };
+        let magic = u32::from_le(layout.word32(OFF_MAGIC).load(Ordering::Acquire));
+        if magic != MAGIC {
+            return Err(Error::LayoutMismatch { what: "broadcast magic", expected: MAGIC as u64, found: magic as u64 });
+        }
+        for (what, offset, expected) in [("broadcast slots", OFF_SLOTS, slots), ("broadcast slot size", OFF_SLOT_SIZE, slot_size)] {
+            let found = layout.shm.read_u64_le(offset)?;
+            if found != expected as u64 {
+                return Err(Error::LayoutMismatch { what, expected: expected as u64, found });
+            }
+        }
+        let cursor = layout.write_seq().load(Ordering::Acquire);
+        Ok(ShmBroadcastReceiver { layout, cursor })
+    }
+
+    /// Sequence number of the next message this reader will return
+    pub fn cursor(&self) -> u64 {
+        self.cursor
+    }
+
+    /// Moves the cursor past everything that may have been overwritten and reports how far it jumped
+    fn lagged(&mut self) -> BroadcastRecvError {
+        let head = self.layout.write_seq().load(Ordering::Acquire);
+        // The slot for `head` may be mid-write already, so the oldest safe message is one newer than a full lap
+        let oldest = (head + 1).saturating_sub(self.layout.slots as u64).max(self.cursor + 1);
+        let skipped = oldest - self.cursor;
+        self.cursor = oldest;
+        BroadcastRecvError::Lagged(skipped)
+    }
+
+    pub fn try_recv(&mut self) -> Result<Vec<u8>, BroadcastRecvError> {
+        let layout = &self.layout;
+        let head = layout.write_seq().load(Ordering::Acquire);
+        if self.cursor >= head {
+            return Err(BroadcastRecvError::Empty);
+        }
+        if head - self.cursor > layout.slots as u64 {
+            return Err(self.lagged());
+        }
+        let slot = layout.slot(self.cursor);
+        let slot_version = layout.word64(slot);
+        let before = slot_version.load(Ordering::Acquire);
+        if before != version(self.cursor) {
+            return Err(self.lagged());
+        }
+        let len = (layout.word32(slot + 8).load(Ordering::Relaxed) as usize).min(layout.slot_size);
+        let mut msg = vec![0u8; len];
+        unsafe {
+            ptr::copy_nonoverlapping(layout.base().add(slot + SLOT_HEADER), msg.as_mut_ptr(), len);
+        }
+        // Keep the copy above from being reordered after the version re-check
+        fence(Ordering::Acquire);
+        if slot_version.load(Ordering::Relaxed) != before {
+            return Err(self.lagged());
+        }
+        self.cursor += 1;
+        Ok(msg)
+    }
+
+    /// Waits up to `timeout` (or forever with `None`) for the next message, `Empty` means it timed out
+    pub fn recv(&mut self, timeout: Option<Duration>) -> Result<Vec<u8>, BroadcastRecvError> {
+        let deadline = futex::deadline(timeout);
+        loop {
+            let seen = self.layout.notify().load(Ordering::Acquire);
+            match self.try_recv() {
+                Err(BroadcastRecvError::Empty) => {}
+                result => return result,
+            }
+            match futex::remaining(deadline) {
+                Some(left) if left.is_zero() => return Err(BroadcastRecvError::Empty),
+                left => {
+                    futex::wait(self.layout.notify(), seen, left);
+                }
+            }
+        }
+    }
+}
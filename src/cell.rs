@@ -0,0 +1,74 @@
+//! Typed cell at a fixed offset of a mapping that threads of one process can share and write through
+//!
+//! The `SharedMemory` handle itself isn't `Sync`, but a cell is, so a scoped thread can be handed a `&ShmCell`
+//! where it can't be handed the mapping. Every access takes the process-wide locks for the 4 KiB granules the cell's
+//! bytes lie in, so threads going through the same mapping never race on it, and [`ShmCell::update`] is atomic with
+//! respect to them. Cells that overlap share at least one granule and so a lock, even when they start at different
+//! addresses. Another mapping of the same segment (another `SharedMemory` handle, or another process) has a
+//! different address and isn't locked out though: its writes can land in the middle of a `get` or `update` and
+//! leave a torn mix of old and new bytes. `SharedSafe` guarantees such a value is still a valid `T`, just not a
+//! meaningful one, so anything written from several processes needs an atomic or a lock stored in the segment.
+
+use std::marker::PhantomData;
+use std::ptr;
+use std::sync::{Mutex, MutexGuard};
+
+use crate::shared_memory::SharedMemory;
+use crate::shared_safe::SharedSafe;
+
+const LOCK_STRIPES: usize = 64;
+
+/// Bytes of address space covered by each lock, any overlap between two cells is inside some granule of both
+const LOCK_GRANULE: usize = 4096;
+
+static LOCKS: [Mutex<()>; LOCK_STRIPES] = [const { Mutex::new(()) }; LOCK_STRIPES];
+
+/// A `T` inside a mapping, created by [`SharedMemory::cell_at`]
+pub struct ShmCell<'a, T: SharedSafe> {
+    ptr: *mut T,
+    _shm: PhantomData<&'a SharedMemory>,
+}
+
+// Every access goes through the locks for its bytes, and `T: SharedSafe` holds nothing tied to a thread
+unsafe impl<T: SharedSafe> Send for ShmCell<'_, T> {}
+unsafe impl<T: SharedSafe> Sync for ShmCell<'_, T> {}
+
+impl<'a, T: SharedSafe> ShmCell<'a, T> {
+    /// `ptr` has to be in bounds and aligned for `T` for as long as `'a`
+    pub(crate) unsafe fn new(ptr: *mut T) -> Self {
+        ShmCell { ptr, _shm: PhantomData }
+    }
+
+    fn lock(&self) -> Vec<MutexGuard<'static, ()>> {
+        let first = self.ptr as usize / LOCK_GRANULE;
+        let last = (self.ptr as usize + std::mem::size_of::<T>().max(1) - 1) / LOCK_GRANULE;
+        // Past LOCK_STRIPES granules every stripe is taken already
+        let mut stripes: Vec<usize> = (first..=last.min(first + LOCK_STRIPES - 1)).map(|granule| granule % LOCK_STRIPES).collect();
+        // Taken in one global order so two cells sharing granules can't deadlock each other
+        stripes.sort_unstable();
+        // Nothing is written while `update`'s closure runs, so a panic in it leaves the value intact
+        stripes.into_iter().map(|stripe| LOCKS[stripe].lock().unwrap_or_else(|poisoned| poisoned.into_inner())).collect()
+    }
+
+    /// A copy of the current value
+    pub fn get(&self) -> T {
+        let _lock = self.lock();
+        unsafe { ptr::read(self.ptr) }
+    }
+
+    pub fn set(&self, value: T) {
+        let _lock = self.lock();
+        unsafe { ptr::write(self.ptr, value) }
+    }
+
+    /// Replaces the value with `f(old)` and returns the new value, with no other `ShmCell` access to any of the
+    /// same bytes in this process in between
+    ///
+    /// `f` must not touch a cell itself, it could map to the same lock and deadlock
+    pub fn update(&self, f: impl FnOnce(T) -> T) -> T {
+        let _lock = self.lock();
+        let value = f(unsafe { ptr::read(self.ptr) });
+        unsafe { ptr::write(self.ptr, value) };
+        value
+    }
+}
@@ -0,0 +1,188 @@
+//! One payload spread over a primary segment and as many continuation segments as it needs
+//!
+//! `name` holds a small header and the first chunk, `name.1`, `name.2`, … hold the rest, each as large as the
+//! primary. The header records how many segments the current payload uses and its total length, so a reader only
+//! needs the primary's name and size and opens the continuation segments as it goes. Readers open them afresh on
+//! every read because the owner unlinks continuation segments once a shorter payload no longer needs them, and a
+//! cached handle would still see the old ones. There is no synchronisation between a write and a read running at
+//! the same time.
+
+use std::cell::RefCell;
+use std::io;
+use std::ptr;
+
+use crate::shared_memory::{Error, SharedMemory};
+
+/// "SHCH"
+const MAGIC: u32 = 0x5348_4348;
+
+const OFF_MAGIC: usize = 0;
+const OFF_MAX_SEGMENTS: usize = 4;
+const OFF_SEGMENT_SIZE: usize = 8;
+const OFF_SEGMENTS: usize = 16;
+const OFF_LEN: usize = 24;
+pub const HEADER_SIZE: usize = 64;
+
+fn continuation_name(name: &str, index: usize) -> String {
+    format!("{}.{}", name, index)
+}
+
+fn segment_data(shm: &SharedMemory, offset: usize) -> *mut u8 {
+    unsafe { (shm.address() as *mut u8).add(offset) }
+}
+
+/// Handle to a chain, the one that created it writes and unlinks the continuation segments when dropped
+pub struct ShmChain {
+    name: String,
+    primary: SharedMemory,
+    segment_size: usize,
+    max_segments: usize,
+    /// Continuation segments this owner created, `name.1` first, empty for readers
+    owned: RefCell<Vec<SharedMemory>>,
+    is_owner: bool,
+}
+
+impl ShmChain {
+    /// Creates the primary segment, payloads can then use up to `max_segments` segments of `segment_size` bytes,
+    /// the primary included
+    pub fn create(name: &str, segment_size: usize, max_segments: usize) -> Result<Self, Error> {
+        if segment_size <= HEADER_SIZE || max_segments == 0 || max_segments > u32::MAX as usize {
+            return Err(io::Error::new(io::ErrorKind::InvalidInput, "chain segments are too small or too few").into());
+        }
+        let primary = SharedMemory::create(name, ShmChain::segment_len(segment_size)?)?;
+        primary.write_u32_le(OFF_MAX_SEGMENTS, max_segments as u32)?;
+        primary.write_u64_le(OFF_SEGMENT_SIZE, segment_size as u64)?;
+        primary.write_u32_le(OFF_SEGMENTS, 1)?;
+        primary.write_u64_le(OFF_LEN, 0)?;
+        primary.write_u32_le(OFF_MAGIC, MAGIC)?;
+        Ok(ShmChain {
+            name: name.to_string(),
+            primary,
+            segment_size,
+            max_segments,
+            owned: RefCell::new(Vec::new()),
+            is_owner: true,
+        })
+    }
+
+    /// Opens a chain another handle created with the same `segment_size`, for reading
+    pub fn open(name: &str, segment_size: usize) -> Result<Self, Error> {
+        let primary = SharedMemory::open(name, ShmChain::segment_len(segment_size)?)?;
+        let magic = primary.read_u32_le(OFF_MAGIC)?;
+        if magic != MAGIC {
+            return Err(Error::LayoutMismatch { what: "chain magic", expected: MAGIC as u64, found: magic as u64 });
+        }
+        let found = primary.read_u64_le(OFF_SEGMENT_SIZE)?;
+        if found != segment_size as u64 {
+            return Err(Error::LayoutMismatch { what: "chain segment size", expected: segment_size as u64, found });
+        }
+        let max_segments = primary.read_u32_le(OFF_MAX_SEGMENTS)? as usize;
+        Ok(ShmChain {
+            name: name.to_string(),
+            primary,
+            segment_size,
+            max_segments,
+            owned: RefCell::new(Vec::new()),
+            is_owner: false,
+        })
+    }
+
+    fn segment_len(segment_size: usize) -> Result<i32, io::Error> {
+        i32::try_from(segment_size).map_err(|_| io::Error::new(io::ErrorKind::InvalidInput, "chain segment size doesn't fit in a segment"))
+    }
+
+    /// Largest payload the chain can hold
+    pub fn capacity(&self) -> usize {
+        self.max_segments * self.segment_size - HEADER_SIZE
+    }
+
+    /// Segment `index` and the range of the payload it holds
+    fn chunk(&self, index: usize, len: usize) -> (usize, usize) {
+        let primary = self.segment_size - HEADER_SIZE;
+        if index == 0 {
+            (0, primary.min(len))
+        } else {
+            let start = primary + (index - 1) * self.segment_size;
+            (start, (start + self.segment_size).min(len))
+        }
+    }
+
+    fn segments_for(&self, len: usize) -> usize {
+        1 + len.saturating_sub(self.segment_size - HEADER_SIZE).div_ceil(self.segment_size)
+    }
+
+    /// Replaces the payload with `data`, creating continuation segments it needs and unlinking ones it no longer does
+    ///
+    /// # Panics
+    /// Only the handle that created the chain can write to it
+    pub fn write(&self, data: &[u8]) -> Result<(), Error> {
+        assert!(self.is_owner, "only the handle that created a chain can write to it");
+        let capacity = self.capacity();
+        if data.len() > capacity {
+            return Err(Error::OutOfBounds { offset: 0, len: data.len(), size: capacity });
+        }
+        let segments = self.segments_for(data.len());
+        let mut owned = self.owned.borrow_mut();
+        while owned.len() + 1 < segments {
+            let name = continuation_name(&self.name, owned.len() + 1);
+            owned.push(SharedMemory::create(&name, ShmChain::segment_len(self.segment_size)?)?);
+        }
+        for index in 0..segments {
+            let (start, end) = self.chunk(index, data.len());
+            let chunk = &data[start..end];
+            let dest = match index {
+                0 => segment_data(&self.primary, HEADER_SIZE),
+                index => segment_data(&owned[index - 1], 0),
+            };
+            // `chunk` covers at most what is left of the segment after `dest`
+            unsafe { ptr::copy_nonoverlapping(chunk.as_ptr(), dest, chunk.len()) };
+        }
+        self.primary.write_u32_le(OFF_SEGMENTS, segments as u32)?;
+        self.primary.write_u64_le(OFF_LEN, data.len() as u64)?;
+        while owned.len() + 1 > segments {
+            self.unlink_last(&mut owned);
+        }
+        Ok(())
+    }
+
+    fn unlink_last(&self, owned: &mut Vec<SharedMemory>) {
+        let name = continuation_name(&self.name, owned.len());
+        drop(owned.pop());
+        // Dropping unlinks segments this handle created, but not ones `create` found already there and reused
+        let _ = SharedMemory::unlink(&name);
+    }
+
+    /// Reassembles the current payload, opening the continuation segments it lives in
+    pub fn read(&self) -> Result<Vec<u8>, Error> {
+        let segments = self.primary.read_u32_le(OFF_SEGMENTS)? as usize;
+        // A length past usize would truncate into one that passes the checks on 32-bit targets
+        let len = usize::try_from(self.primary.read_u64_le(OFF_LEN)?).unwrap_or(usize::MAX);
+        if segments > self.max_segments || len > self.capacity() || segments != self.segments_for(len) {
+            return Err(io::Error::new(io::ErrorKind::InvalidData, "chain header is inconsistent").into());
+        }
+        let mut data = vec![0u8; len];
+        for index in 0..segments {
+            let (start, end) = self.chunk(index, len);
+            let chunk = &mut data[start..end];
+            if index == 0 {
+                unsafe { ptr::copy_nonoverlapping(segment_data(&self.primary, HEADER_SIZE), chunk.as_mut_ptr(), chunk.len()) };
+            } else {
+                let name = continuation_name(&self.name, index);
+                let segment = SharedMemory::open(&name, ShmChain::segment_len(self.segment_size)?)?;
+                unsafe { ptr::copy_nonoverlapping(segment_data(&segment, 0), chunk.as_mut_ptr(), chunk.len()) };
+            }
+        }
+        Ok(data)
+    }
+}
+
+impl Drop for ShmChain {
+    fn drop(&mut self) {
+        if self.is_owner {
+            let mut owned = std::mem::take(self.owned.get_mut());
+            while !owned.is_empty() {
+                self.unlink_last(&mut owned);
+            }
+        }
+    }
+}
@@ -0,0 +1,237 @@
+//! `std::sync::mpsc`-style channel between processes, built on [`ShmRingBuffer`]
+//!
+//! Any number of [`ShmSender`]s, in any number of processes, can feed one [`ShmReceiver`]. Messages from a single
+//! sender arrive in the order they were sent. The ring header counts live senders and records whether the receiver
+//! was dropped so the other side can report `Disconnected`, though a process that dies without running its
+//! destructors is still counted as connected. The receiver only treats the channel as disconnected once a sender has
+//! connected and the count has dropped back to zero, so senders that should overlap need to connect before the
+//! earlier ones leave.
+
+use std::cell::Cell;
+use std::marker::PhantomData;
+use std::sync::atomic::Ordering;
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+use std::fmt;
+
+use crate::futex;
+use crate::ring_buffer::{PushError, ShmRingBuffer};
+use crate::shared_memory::Error;
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum SendError {
+    /// The receiver has been dropped
+    Disconnected,
+    /// The message is larger than the ring can ever hold
+    TooLarge { len: usize, max: usize },
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum TrySendError {
+    Full,
+    Disconnected,
+    TooLarge { len: usize, max: usize },
+}
+
+/// Every sender has been dropped and there are no messages left
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct RecvError;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum TryRecvError {
+    Empty,
+    Disconnected,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum RecvTimeoutError {
+    Timeout,
+    Disconnected,
+}
+
+impl fmt::Display for SendError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            SendError::Disconnected => write!(f, "sending on a channel whose receiver is gone"),
+            SendError::TooLarge { len, max } => write!(f, "message of {} bytes is larger than the {} byte channel limit", len, max),
+        }
+    }
+}
+
+impl fmt::Display for TrySendError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            TrySendError::Full => write!(f, "sending on a full channel"),
+            TrySendError::Disconnected => write!(f, "sending on a channel whose receiver is gone"),
+            TrySendError::TooLarge { len, max } => write!(f, "message of {} bytes is larger than the {} byte channel limit", len, max),
+        }
+    }
+}
+
+impl fmt::Display for RecvError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "receiving on a channel with no senders left")
+    }
+}
+
+impl fmt::Display for TryRecvError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            TryRecvError::Empty => write!(f, "receiving on an empty channel"),
+            TryRecvError::Disconnected => write!(f, "receiving on a channel with no senders left"),
+        }
+    }
+}
+
+impl fmt::Display for RecvTimeoutError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            RecvTimeoutError::Timeout => write!(f, "timed out waiting on the channel"),
+            RecvTimeoutError::Disconnected => write!(f, "receiving on a channel with no senders left"),
+        }
+    }
+}
+
+impl std::error::Error for SendError {}
+impl std::error::Error for TrySendError {}
+impl std::error::Error for RecvError {}
+impl std::error::Error for TryRecvError {}
+impl std::error::Error for RecvTimeoutError {}
+
+/// Creates the channel segment and returns a connected sender and receiver for it
+///
+/// Other processes join with [`ShmSender::connect`] using the same name and capacity
+pub fn shm_channel(name: &str, capacity_bytes: usize) -> Result<(ShmSender, ShmReceiver), Error> {
+    let receiver = ShmReceiver::create(name, capacity_bytes)?;
+    let sender = ShmSender::connect(name, capacity_bytes)?;
+    Ok((sender, receiver))
+}
+
+/// Sending half of the channel, clone it or `connect` again for more senders
+pub struct ShmSender {
+    ring: Arc<ShmRingBuffer>,
+}
+
+impl ShmSender {
+    /// Joins an existing channel from this or another process
+    pub fn connect(name: &str, capacity_bytes: usize) -> Result<Self, Error> {
+        let ring = ShmRingBuffer::open(name, capacity_bytes)?;
+        ring.senders().fetch_add(1, Ordering::AcqRel);
+        ring.senders_seen().store(1, Ordering::Release);
+        Ok(ShmSender { ring: Arc::new(ring) })
+    }
+
+    /// Sends `msg` without blocking
+    pub fn try_send(&self, msg: &[u8]) -> Result<(), TrySendError> {
+        if self.ring.receiver_dropped().load(Ordering::Acquire) != 0 {
+            return Err(TrySendError::Disconnected);
+        }
+        self.ring.try_push(msg).map_err(|err| match err {
+            PushError::Full => TrySendError::Full,
+            PushError::TooLarge { len, max } => TrySendError::TooLarge { len, max },
+        })
+    }
+
+    /// Sends `msg`, blocking while the channel is full
+    pub fn send(&self, msg: &[u8]) -> Result<(), SendError> {
+        loop {
+            let seq = self.ring.space_seq().load(Ordering::Acquire);
+            match self.try_send(msg) {
+                Ok(()) => return Ok(()),
+                Err(TrySendError::Full) => {}
+                Err(TrySendError::Disconnected) => return Err(SendError::Disconnected),
+                Err(TrySendError::TooLarge { len, max }) => return Err(SendError::TooLarge { len, max }),
+            }
+            futex::wait(self.ring.space_seq(), seq, None);
+        }
+    }
+}
+
+impl Clone for ShmSender {
+    fn clone(&self) -> Self {
+        self.ring.senders().fetch_add(1, Ordering::AcqRel);
+        ShmSender { ring: self.ring.clone() }
+    }
+}
+
+impl Drop for ShmSender {
+    fn drop(&mut self) {
+        if self.ring.senders().fetch_sub(1, Ordering::AcqRel) == 1 {
+            // Last sender gone, wake the receiver so it can notice
+            ShmRingBuffer::notify(self.ring.data_seq());
+        }
+    }
+}
+
+/// Receiving half of the channel, there can only be one per channel
+pub struct ShmReceiver {
+    ring: ShmRingBuffer,
+    // Receiving pops from the ring through `&self`, so the receiver mustn't be shared between threads
+    _not_sync: PhantomData<Cell<()>>,
+}
+
+impl ShmReceiver {
+    /// Creates the channel segment with a `capacity_bytes` data area, senders join with [`ShmSender::connect`]
+    ///
+    /// Until the first sender connects the channel isn't considered disconnected
+    pub fn create(name: &str, capacity_bytes: usize) -> Result<Self, Error> {
+        Ok(ShmReceiver {
+            ring: ShmRingBuffer::create(name, capacity_bytes)?,
+            _not_sync: PhantomData,
+        })
+    }
+
+    fn senders_gone(&self) -> bool {
+        self.ring.senders_seen().load(Ordering::Acquire) != 0 && self.ring.senders().load(Ordering::Acquire) == 0
+    }
+
+    fn pop(&self) -> Option<Vec<u8>> {
+        // Only this receiver ever pops, and it's neither Clone nor Sync
+        unsafe { self.ring.pop_shared() }
+    }
+
+    pub fn try_recv(&self) -> Result<Vec<u8>, TryRecvError> {
+        if let Some(msg) = self.pop() {
+            return Ok(msg);
+        }
+        if self.senders_gone() {
+            // A last message may have landed between the pop and the check
+            return self.pop().ok_or(TryRecvError::Disconnected);
+        }
+        Err(TryRecvError::Empty)
+    }
+
+    /// Waits for the next message, failing once every sender is gone and the channel is drained
+    pub fn recv(&self) -> Result<Vec<u8>, RecvError> {
+        self.recv_deadline(None).map_err(|_| RecvError)
+    }
+
+    pub fn recv_timeout(&self, timeout: Duration) -> Result<Vec<u8>, RecvTimeoutError> {
+        self.recv_deadline(futex::deadline(Some(timeout)))
+    }
+
+    fn recv_deadline(&self, deadline: Option<Instant>) -> Result<Vec<u8>, RecvTimeoutError> {
+        loop {
+            let seq = self.ring.data_seq().load(Ordering::Acquire);
+            match self.try_recv() {
+                Ok(msg) => return Ok(msg),
+                Err(TryRecvError::Disconnected) => return Err(RecvTimeoutError::Disconnected),
+                Err(TryRecvError::Empty) => {}
+            }
+            match futex::remaining(deadline) {
+                Some(left) if left.is_zero() => return Err(RecvTimeoutError::Timeout),
+                left => {
+                    futex::wait(self.ring.data_seq(), seq, left);
+                }
+            }
+        }
+    }
+}
+
+impl Drop for ShmReceiver {
+    fn drop(&mut self) {
+        self.ring.receiver_dropped().store(1, Ordering::Release);
+        // Wake blocked senders so they notice
+        ShmRingBuffer::notify(self.ring.space_seq());
+    }
+}
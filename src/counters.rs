@@ -0,0 +1,305 @@
+//! Named `u64` counters at an offset inside a segment, bumped by workers and scraped by a sidecar
+//!
+//! A header table holds the names, each up to [`MAX_NAME_LEN`] bytes, and every name owns an `AtomicU64` on a cache
+//! line of its own so workers bumping different counters don't contend. New names are appended under the futex lock in
+//! the header and published with a Release store of the count, after which a name and its slot never move, so lookups
+//! and snapshots read the table without the lock.
+//!
+//! Every slot is [`CACHE_LINE`] bytes, so a table spends `CACHE_LINE - 8` bytes of padding per counter on top of its
+//! name entry. [`ShmPerCpuCounter`] pads the same way for one counter split across workers, each bumping a slot of its
+//! own that nobody else writes, with reads summing the slots.
+
+use std::fmt;
+use std::io;
+use std::ptr;
+use std::sync::atomic::{fence, AtomicU32, AtomicU64, Ordering};
+
+use crate::futex;
+use crate::shared_memory::{Error, SharedMemory};
+
+const OFF_LOCK: usize = 0;
+const OFF_CAPACITY: usize = 4;
+const OFF_COUNT: usize = 8;
+const HEADER_SIZE: usize = 64;
+
+/// Longest counter name in bytes
+pub const MAX_NAME_LEN: usize = 32;
+
+/// Length as a `u32` then the name's bytes
+const NAME_ENTRY: usize = 4 + MAX_NAME_LEN;
+
+/// Bytes each counter slot takes up, the line size that false sharing happens at on the target
+///
+/// Apple's arm64 cores and 64-bit PowerPC use 128-byte lines, everything else this builds for is treated as 64
+#[cfg(any(all(target_arch = "aarch64", target_vendor = "apple"), target_arch = "powerpc64"))]
+pub const CACHE_LINE: usize = 128;
+#[cfg(not(any(all(target_arch = "aarch64", target_vendor = "apple"), target_arch = "powerpc64")))]
+pub const CACHE_LINE: usize = 64;
+
+const SLOT_SIZE: usize = CACHE_LINE;
+
+/// Alignment the table's offset inside the segment has to have, a cache line
+pub const ALIGNMENT: usize = CACHE_LINE;
+
+/// Why [`ShmCounters::register`] couldn't hand out a counter
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum RegisterError {
+    /// The name is `len` bytes, more than [`MAX_NAME_LEN`]
+    NameTooLong { len: usize },
+    /// All `capacity` counters are taken by other names
+    Full { capacity: usize },
+}
+
+impl fmt::Display for RegisterError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            RegisterError::NameTooLong { len } => {
+                write!(f, "counter name is {} bytes, the limit is {}", len, MAX_NAME_LEN)
+            }
+            RegisterError::Full { capacity } => write!(f, "all {} counters are registered", capacity),
+        }
+    }
+}
+
+impl std::error::Error for RegisterError {}
+
+/// The slots start on the first cache line after the name table
+fn slots_start(capacity: usize) -> usize {
+    (HEADER_SIZE + capacity * NAME_ENTRY).next_multiple_of(SLOT_SIZE)
+}
+
+fn check_table(shm: &SharedMemory, offset: usize, slots: usize, size_for: fn(usize) -> usize) -> Result<(), Error> {
+    if !offset.is_multiple_of(ALIGNMENT) {
+        return Err(Error::UnalignedOffset { offset: offset as u64, alignment: ALIGNMENT as u64 });
+    }
+    // Keeps `size_for` from overflowing as well
+    if slots > u32::MAX as usize / SLOT_SIZE {
+        return Err(io::Error::new(io::ErrorKind::InvalidInput, "too many counters for one table").into());
+    }
+    let len = size_for(slots);
+    let size = shm.size() as usize;
+    if offset.checked_add(len).is_none_or(|end| end > size) {
+        return Err(Error::OutOfBounds { offset, len, size });
+    }
+    Ok(())
+}
+
+pub struct ShmCounters<'a> {
+    shm: &'a SharedMemory,
+    offset: usize,
+    capacity: usize,
+}
+
+// Only the segment's address and size are read through the reference, registration is serialized by the lock in the
+// segment and the counters themselves are atomic
+unsafe impl Send for ShmCounters<'_> {}
+unsafe impl Sync for ShmCounters<'_> {}
+
+/// One registered counter, cheap to copy into every worker thread
+#[derive(Debug, Clone, Copy)]
+pub struct CounterHandle<'a> {
+    slot: &'a AtomicU64,
+}
+
+impl CounterHandle<'_> {
+    pub fn add(&self, n: u64) {
+        self.slot.fetch_add(n, Ordering::Relaxed);
+    }
+
+    pub fn get(&self) -> u64 {
+        self.slot.load(Ordering::Relaxed)
+    }
+}
+
+impl<'a> ShmCounters<'a> {
+    /// Bytes the table takes up for `capacity` counters
+    pub fn size_for(capacity: usize) -> usize {
+        slots_start(capacity) + capacity * SLOT_SIZE
+    }
+
+    /// Sets up an empty table for `capacity` counters at `offset` into `shm`
+    ///
+    /// Nothing else may be using the table while it's being initialized
+    pub fn init(shm: &'a SharedMemory, offset: usize, capacity: usize) -> Result<Self, Error> {
+        let counters = ShmCounters::check(shm, offset, capacity)?;
+        counters.word(OFF_LOCK).store(0, Ordering::Relaxed);
+        counters.word(OFF_COUNT).store(0, Ordering::Relaxed);
+        shm.write_u32_le(offset + OFF_CAPACITY, capacity as u32)?;
+        fence(Ordering::Release);
+        Ok(counters)
+    }
+
+    /// Attaches to a table another handle initialized at `offset` with the same capacity
+    pub fn attach(shm: &'a SharedMemory, offset: usize, capacity: usize) -> Result<Self, Error> {
+        let counters = ShmCounters::check(shm, offset, capacity)?;
+        let found = shm.read_u32_le(offset + OFF_CAPACITY)? as u64;
+        if found != capacity as u64 {
+            return Err(Error::LayoutMismatch { what: "counter capacity", expected: capacity as u64, found });
+        }
+        Ok(counters)
+    }
+
+    fn check(shm: &'a SharedMemory, offset: usize, capacity: usize) -> Result<Self, Error> {
+        check_table(shm, offset, capacity, ShmCounters::size_for)?;
+        Ok(ShmCounters { shm, offset, capacity })
+    }
+
+    fn base(&self) -> *mut u8 {
+        unsafe { (self.shm.address() as *mut u8).add(self.offset) }
+    }
+
+    fn word(&self, offset: usize) -> &AtomicU32 {
+        unsafe { &*(self.base().add(offset) as *const AtomicU32) }
+    }
+
+    fn slot(&self, index: usize) -> &'a AtomicU64 {
+        unsafe { &*(self.base().add(slots_start(self.capacity) + index * SLOT_SIZE) as *const AtomicU64) }
+    }
+
+    fn count(&self) -> usize {
+        // A corrupt count from another process must not let reads run past the table
+        (self.word(OFF_COUNT).load(Ordering::Acquire) as usize).min(self.capacity)
+    }
+
+    fn name(&self, index: usize) -> Vec<u8> {
+        let entry = unsafe { self.base().add(HEADER_SIZE + index * NAME_ENTRY) };
+        let len = (unsafe { ptr::read_unaligned(entry as *const u32) } as usize).min(MAX_NAME_LEN);
+        let mut name = vec![0u8; len];
+        unsafe { ptr::copy_nonoverlapping(entry.add(4), name.as_mut_ptr(), len) };
+        name
+    }
+
+    fn find(&self, name: &str, from: usize, to: usize) -> Option<usize> {
+        (from..to).find(|&index| self.name(index) == name.as_bytes())
+    }
+
+    pub fn capacity(&self) -> usize {
+        self.capacity
+    }
+
+    /// The counter called `name`, registering it with a value of 0 if no handle has yet
+    pub fn register(&self, name: &str) -> Result<CounterHandle<'a>, RegisterError> {
+        if name.len() > MAX_NAME_LEN {
+            return Err(RegisterError::NameTooLong { len: name.len() });
+        }
+        let seen = self.count();
+        if let Some(index) = self.find(name, 0, seen) {
+            return Ok(CounterHandle { slot: self.slot(index) });
+        }
+        let _lock = futex::lock(self.word(OFF_LOCK));
+        let count = self.count();
+        if let Some(index) = self.find(name, seen, count) {
+            return Ok(CounterHandle { slot: self.slot(index) });
+        }
+        if count == self.capacity {
+            return Err(RegisterError::Full { capacity: self.capacity });
+        }
+        unsafe {
+            let entry = self.base().add(HEADER_SIZE + count * NAME_ENTRY);
+            ptr::write_unaligned(entry as *mut u32, name.len() as u32);
+            ptr::copy_nonoverlapping(name.as_ptr(), entry.add(4), name.len());
+        }
+        let slot = self.slot(count);
+        slot.store(0, Ordering::Relaxed);
+        self.word(OFF_COUNT).store(count as u32 + 1, Ordering::Release);
+        Ok(CounterHandle { slot })
+    }
+
+    /// Every registered name and its current value, in registration order
+    ///
+    /// Each value is read on its own, so counters bumped together can be caught with only some of the bumps
+    pub fn snapshot(&self) -> Vec<(String, u64)> {
+        (0..self.count())
+            .map(|index| {
+                let name = String::from_utf8_lossy(&self.name(index)).into_owned();
+                (name, self.slot(index).load(Ordering::Relaxed))
+            })
+            .collect()
+    }
+}
+
+/// One counter split into a slot per worker, for hot increments that would otherwise fight over a single line
+///
+/// The header takes one cache line and each worker slot another, so `workers` workers cost `(workers + 1) *
+/// CACHE_LINE` bytes
+pub struct ShmPerCpuCounter<'a> {
+    shm: &'a SharedMemory,
+    offset: usize,
+    workers: usize,
+}
+
+// Only the segment's address and size are read through the reference, the slots and the claim count are atomic
+unsafe impl Send for ShmPerCpuCounter<'_> {}
+unsafe impl Sync for ShmPerCpuCounter<'_> {}
+
+impl<'a> ShmPerCpuCounter<'a> {
+    /// Bytes the counter takes up with slots for `workers` workers
+    pub fn size_for(workers: usize) -> usize {
+        (workers + 1) * SLOT_SIZE
+    }
+
+    /// Sets up a counter of 0 with `workers` unclaimed slots at `offset` into `shm`
+    ///
+    /// Nothing else may be using the counter while it's being initialized
+    pub fn init(shm: &'a SharedMemory, offset: usize, workers: usize) -> Result<Self, Error> {
+        let counter = ShmPerCpuCounter::check(shm, offset, workers)?;
+        counter.claimed().store(0, Ordering::Relaxed);
+        for worker in 0..workers {
+            counter.slot(worker).store(0, Ordering::Relaxed);
+        }
+        shm.write_u32_le(offset + OFF_CAPACITY, workers as u32)?;
+        fence(Ordering::Release);
+        Ok(counter)
+    }
+
+    /// Attaches to a counter another handle initialized at `offset` for the same number of workers
+    pub fn attach(shm: &'a SharedMemory, offset: usize, workers: usize) -> Result<Self, Error> {
+        let counter = ShmPerCpuCounter::check(shm, offset, workers)?;
+        let found = shm.read_u32_le(offset + OFF_CAPACITY)? as u64;
+        if found != workers as u64 {
+            return Err(Error::LayoutMismatch { what: "per-worker counter slots", expected: workers as u64, found });
+        }
+        Ok(counter)
+    }
+
+    fn check(shm: &'a SharedMemory, offset: usize, workers: usize) -> Result<Self, Error> {
+        check_table(shm, offset, workers, ShmPerCpuCounter::size_for)?;
+        Ok(ShmPerCpuCounter { shm, offset, workers })
+    }
+
+    fn base(&self) -> *mut u8 {
+        unsafe { (self.shm.address() as *mut u8).add(self.offset) }
+    }
+
+    fn claimed(&self) -> &AtomicU32 {
+        unsafe { &*(self.base().add(OFF_COUNT) as *const AtomicU32) }
+    }
+
+    fn slot(&self, worker: usize) -> &'a AtomicU64 {
+        unsafe { &*(self.base().add((worker + 1) * SLOT_SIZE) as *const AtomicU64) }
+    }
+
+    pub fn workers(&self) -> usize {
+        self.workers
+    }
+
+    /// Claims the next unused slot for the calling worker, slots are never given back
+    pub fn register(&self) -> Result<CounterHandle<'a>, RegisterError> {
+        let claimed = self.claimed();
+        let mut current = claimed.load(Ordering::Relaxed);
+        loop {
+            if current as usize >= self.workers {
+                return Err(RegisterError::Full { capacity: self.workers });
+            }
+            match claimed.compare_exchange_weak(current, current + 1, Ordering::Relaxed, Ordering::Relaxed) {
+                Ok(_) => return Ok(CounterHandle { slot: self.slot(current as usize) }),
+                Err(actual) => current = actual,
+            }
+        }
+    }
+
+    /// The total over every slot, each read on its own like [`ShmCounters::snapshot`]
+    pub fn sum(&self) -> u64 {
+        (0..self.workers).map(|worker| self.slot(worker).load(Ordering::Relaxed)).fold(0, u64::wrapping_add)
+    }
+}
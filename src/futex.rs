@@ -0,0 +1,149 @@
+//! Waiting on a 32-bit word inside a mapping until another thread or process changes it
+//!
+//! On Linux this is a shared (not `FUTEX_PRIVATE_FLAG`) futex so waiters in other processes get woken too.
+//! `WaitOnAddress` on Windows only sees wakes from the same process, so waits there are cut into short slices and
+//! the word is re-checked between them, which bounds the cross-process wake latency to roughly one slice
+
+use std::sync::atomic::{AtomicU32, Ordering};
+use std::time::{Duration, Instant};
+
+#[cfg(target_os = "windows")]
+use winapi::um::synchapi::{WaitOnAddress, WakeByAddressAll, WakeByAddressSingle};
+
+#[cfg(target_os = "windows")]
+use winapi::ctypes::c_void as win_c_void;
+
+/// Longest a single `WaitOnAddress` call blocks before the word is re-checked for cross-process changes
+#[cfg(target_os = "windows")]
+const WINDOWS_WAIT_SLICE: Duration = Duration::from_millis(1);
+
+/// Turns an optional timeout into an optional deadline so repeated waits don't extend the total time
+///
+/// A timeout too long to add to an `Instant`, like `Duration::MAX`, gets no deadline and waits forever
+pub(crate) fn deadline(timeout: Option<Duration>) -> Option<Instant> {
+    timeout.and_then(|timeout| Instant::now().checked_add(timeout))
+}
+
+/// Time left until `deadline`, `Some(ZERO)` once it has passed and `None` for no deadline
+pub(crate) fn remaining(deadline: Option<Instant>) -> Option<Duration> {
+    deadline.map(|deadline| deadline.saturating_duration_since(Instant::now()))
+}
+
+/// Blocks while `word` still holds `expected`, returning false only if `timeout` ran out
+///
+/// Spurious returns are possible so callers always re-check whatever condition they're waiting for. A signal
+/// interrupting the wait isn't one of them, the wait carries on with whatever is left of `timeout`
+#[cfg(target_os = "linux")]
+pub(crate) fn wait(word: &AtomicU32, expected: u32, timeout: Option<Duration>) -> bool {
+    let deadline = deadline(timeout);
+    loop {
+        let timespec = remaining(deadline).map(|left| libc::timespec {
+            tv_sec: left.as_secs() as libc::time_t,
+            tv_nsec: left.subsec_nanos() as libc::c_long,
+        });
+        let timespec_ptr = match &timespec {
+            Some(timespec) => timespec as *const libc::timespec,
+            None => std::ptr::null(),
+        };
+        let result = unsafe {
+            libc::syscall(
+                libc::SYS_futex,
+                word.as_ptr(),
+                libc::FUTEX_WAIT,
+                expected,
+                timespec_ptr,
+            )
+        };
+        if result == 0 {
+            return true;
+        }
+        match std::io::Error::last_os_error().raw_os_error() {
+            Some(libc::ETIMEDOUT) => return false,
+            Some(libc::EINTR) => continue,
+            _ => return true,
+        }
+    }
+}
+
+/// Blocks while `word` still holds `expected`, returning false only if `timeout` ran out
+///
+/// Spurious returns are possible so callers always re-check whatever condition they're waiting for
+#[cfg(target_os = "windows")]
+pub(crate) fn wait(word: &AtomicU32, expected: u32, timeout: Option<Duration>) -> bool {
+    let deadline = deadline(timeout);
+    loop {
+        if word.load(Ordering::Acquire) != expected {
+            return true;
+        }
+        let slice = match remaining(deadline) {
+            Some(left) if left.is_zero() => return false,
+            Some(left) => left.min(WINDOWS_WAIT_SLICE),
+            None => WINDOWS_WAIT_SLICE,
+        };
+        let mut expected = expected;
+        unsafe {
+            WaitOnAddress(
+                word.as_ptr() as *mut win_c_void,
+                &mut expected as *mut u32 as *mut win_c_void,
+                4,
+                slice.as_millis().max(1) as u32,
+            );
+        }
+    }
+}
+
+/// Wakes every thread waiting on `word`
+#[cfg(target_os = "linux")]
+pub(crate) fn wake_all(word: &AtomicU32) {
+    unsafe {
+        libc::syscall(libc::SYS_futex, word.as_ptr(), libc::FUTEX_WAKE, i32::MAX);
+    }
+}
+
+/// Wakes every thread waiting on `word`
+#[cfg(target_os = "windows")]
+pub(crate) fn wake_all(word: &AtomicU32) {
+    unsafe {
+        WakeByAddressAll(word.as_ptr() as *mut win_c_void);
+    }
+}
+
+/// Wakes at most one thread waiting on `word`
+#[cfg(target_os = "linux")]
+pub(crate) fn wake_one(word: &AtomicU32) {
+    unsafe {
+        libc::syscall(libc::SYS_futex, word.as_ptr(), libc::FUTEX_WAKE, 1);
+    }
+}
+
+/// Wakes at most one thread waiting on `word`
+#[cfg(target_os = "windows")]
+pub(crate) fn wake_one(word: &AtomicU32) {
+    unsafe {
+        WakeByAddressSingle(word.as_ptr() as *mut win_c_void);
+    }
+}
+
+/// Held lock on a word inside a mapping, unlocks on drop
+///
+/// The word is 0 when unlocked, 1 when locked and 2 when locked with possible waiters. A process that dies while
+/// holding it leaves it locked for good
+pub(crate) struct WordLock<'a>(&'a AtomicU32);
+
+/// Locks `word`, blocking for as long as another thread or process holds it
+pub(crate) fn lock(word: &AtomicU32) -> WordLock<'_> {
+    if word.compare_exchange(0, 1, Ordering::Acquire, Ordering::Relaxed).is_err() {
+        while word.swap(2, Ordering::Acquire) != 0 {
+            wait(word, 2, None);
+        }
+    }
+    WordLock(word)
+}
+
+impl Drop for WordLock<'_> {
+    fn drop(&mut self) {
+        if self.0.swap(0, Ordering::Release) == 2 {
+            wake_one(self.0);
+        }
+    }
+}
@@ -0,0 +1,177 @@
+//! Latency histogram at an offset inside a segment, recorded into by any number of processes
+//!
+//! The header holds the bucket boundaries along with the total count and sum, so a process attaching to it only needs
+//! the offset. Bucket `i` counts the values above boundary `i - 1` and up to boundary `i`, and one more bucket at the
+//! end counts everything above the last boundary. Every field is an `AtomicU64` updated on its own, so a reader
+//! racing `record` can see a bucket bumped before the count is.
+
+use std::io;
+use std::sync::atomic::{fence, AtomicU64, Ordering};
+
+use crate::shared_memory::{Error, SharedMemory};
+
+/// "SHHI"
+const MAGIC: u32 = 0x5348_4849;
+
+const OFF_MAGIC: usize = 0;
+const OFF_BOUNDS_LEN: usize = 4;
+const OFF_COUNT: usize = 8;
+const OFF_SUM: usize = 16;
+const HEADER_SIZE: usize = 24;
+
+/// Alignment the histogram's offset inside the segment has to have
+pub const ALIGNMENT: usize = 8;
+
+/// `count` boundaries starting at `start` and each `factor` times the one before, stopping early if they'd pass
+/// `u64::MAX`
+pub fn exponential_bounds(start: u64, factor: u64, count: usize) -> Vec<u64> {
+    assert!(start > 0 && factor > 1, "exponential boundaries need to grow");
+    let mut bounds = Vec::with_capacity(count);
+    let mut bound = Some(start);
+    while let Some(next) = bound.filter(|_| bounds.len() < count) {
+        bounds.push(next);
+        bound = next.checked_mul(factor);
+    }
+    bounds
+}
+
+pub struct ShmHistogram<'a> {
+    shm: &'a SharedMemory,
+    offset: usize,
+    /// Copy of the boundaries in the header, which never change once it's initialized
+    bounds: Vec<u64>,
+}
+
+// Only the segment's address and size are read through the reference, every field in the segment is atomic
+unsafe impl Send for ShmHistogram<'_> {}
+unsafe impl Sync for ShmHistogram<'_> {}
+
+impl<'a> ShmHistogram<'a> {
+    /// Bytes the histogram takes up with `bounds` boundaries
+    pub fn size_for(bounds: usize) -> usize {
+        HEADER_SIZE + bounds * 8 + (bounds + 1) * 8
+    }
+
+    /// Sets up an empty histogram at `offset` into `shm` with the given bucket boundaries, which have to be strictly
+    /// increasing
+    ///
+    /// Nothing else may be using the histogram while it's being initialized
+    pub fn init(shm: &'a SharedMemory, offset: usize, bounds: &[u64]) -> Result<Self, Error> {
+        if bounds.is_empty() || bounds.windows(2).any(|pair| pair[0] >= pair[1]) {
+            return Err(io::Error::new(io::ErrorKind::InvalidInput, "histogram boundaries must be strictly increasing").into());
+        }
+        let histogram = ShmHistogram::check(shm, offset, bounds.len())?;
+        shm.write_u32_le(offset + OFF_BOUNDS_LEN, bounds.len() as u32)?;
+        for (index, &bound) in bounds.iter().enumerate() {
+            shm.write_u64_le(offset + HEADER_SIZE + index * 8, bound)?;
+        }
+        histogram.word(OFF_COUNT).store(0, Ordering::Relaxed);
+        histogram.word(OFF_SUM).store(0, Ordering::Relaxed);
+        let histogram = ShmHistogram { bounds: bounds.to_vec(), ..histogram };
+        for bucket in 0..=bounds.len() {
+            histogram.bucket(bucket).store(0, Ordering::Relaxed);
+        }
+        fence(Ordering::Release);
+        shm.write_u32_le(offset + OFF_MAGIC, MAGIC)?;
+        Ok(histogram)
+    }
+
+    /// Attaches to a histogram another handle initialized at `offset`, taking the boundaries from its header
+    pub fn attach(shm: &'a SharedMemory, offset: usize) -> Result<Self, Error> {
+        ShmHistogram::check(shm, offset, 0)?;
+        let magic = shm.read_u32_le(offset + OFF_MAGIC)?;
+        if magic != MAGIC {
+            return Err(Error::LayoutMismatch { what: "histogram magic", expected: MAGIC as u64, found: magic as u64 });
+        }
+        let len = shm.read_u32_le(offset + OFF_BOUNDS_LEN)? as usize;
+        let histogram = ShmHistogram::check(shm, offset, len)?;
+        let bounds = (0..len).map(|index| shm.read_u64_le(offset + HEADER_SIZE + index * 8)).collect::<Result<Vec<_>, _>>()?;
+        if bounds.is_empty() || bounds.windows(2).any(|pair| pair[0] >= pair[1]) {
+            return Err(io::Error::new(io::ErrorKind::InvalidData, "histogram boundaries aren't strictly increasing").into());
+        }
+        Ok(ShmHistogram { bounds, ..histogram })
+    }
+
+    fn check(shm: &'a SharedMemory, offset: usize, bounds: usize) -> Result<Self, Error> {
+        if !offset.is_multiple_of(ALIGNMENT) {
+            return Err(Error::UnalignedOffset { offset: offset as u64, alignment: ALIGNMENT as u64 });
+        }
+        let size = shm.size() as usize;
+        let len = bounds.checked_mul(16).and_then(|len| len.checked_add(HEADER_SIZE + 8));
+        if len.and_then(|len| offset.checked_add(len)).is_none_or(|end| end > size) {
+            return Err(Error::OutOfBounds { offset, len: len.unwrap_or(usize::MAX), size });
+        }
+        Ok(ShmHistogram { shm, offset, bounds: Vec::new() })
+    }
+
+    fn word(&self, offset: usize) -> &AtomicU64 {
+        unsafe { &*((self.shm.address() as *mut u8).add(self.offset + offset) as *const AtomicU64) }
+    }
+
+    fn bucket(&self, index: usize) -> &AtomicU64 {
+        self.word(HEADER_SIZE + (self.bounds.len() + index) * 8)
+    }
+
+    pub fn bounds(&self) -> &[u64] {
+        &self.bounds
+    }
+
+    pub fn record(&self, value: u64) {
+        let bucket = self.bounds.partition_point(|&bound| bound < value);
+        self.bucket(bucket).fetch_add(1, Ordering::Relaxed);
+        self.word(OFF_COUNT).fetch_add(1, Ordering::Relaxed);
+        self.word(OFF_SUM).fetch_add(value, Ordering::Relaxed);
+    }
+
+    /// Values recorded so far
+    pub fn count(&self) -> u64 {
+        self.word(OFF_COUNT).load(Ordering::Relaxed)
+    }
+
+    /// Sum of the values recorded so far, wrapping on overflow
+    pub fn sum(&self) -> u64 {
+        self.word(OFF_SUM).load(Ordering::Relaxed)
+    }
+
+    /// How many values landed in each bucket, the last one being everything above the last boundary
+    pub fn buckets(&self) -> Vec<u64> {
+        (0..=self.bounds.len()).map(|index| self.bucket(index).load(Ordering::Relaxed)).collect()
+    }
+
+    /// Adds everything recorded here to `other`, which has to have the same boundaries
+    pub fn merge_into(&self, other: &ShmHistogram) -> Result<(), Error> {
+        if self.bounds != other.bounds {
+            let found = other.bounds.len() as u64;
+            return Err(Error::LayoutMismatch { what: "histogram boundaries", expected: self.bounds.len() as u64, found });
+        }
+        for (index, count) in self.buckets().into_iter().enumerate() {
+            other.bucket(index).fetch_add(count, Ordering::Relaxed);
+        }
+        other.word(OFF_COUNT).fetch_add(self.count(), Ordering::Relaxed);
+        other.word(OFF_SUM).fetch_add(self.sum(), Ordering::Relaxed);
+        Ok(())
+    }
+
+    /// Upper boundary of the bucket the `q` quantile falls in, `u64::MAX` if that's the bucket above the last
+    /// boundary and 0 if nothing has been recorded
+    ///
+    /// # Panics
+    /// If `q` isn't between 0 and 1
+    pub fn percentile(&self, q: f64) -> u64 {
+        assert!((0.0..=1.0).contains(&q), "quantile {} is outside 0..=1", q);
+        let buckets = self.buckets();
+        let total: u64 = buckets.iter().sum();
+        if total == 0 {
+            return 0;
+        }
+        let rank = ((q * total as f64).ceil() as u64).clamp(1, total);
+        let mut seen = 0;
+        for (index, count) in buckets.into_iter().enumerate() {
+            seen += count;
+            if seen >= rank {
+                return self.bounds.get(index).copied().unwrap_or(u64::MAX);
+            }
+        }
+        u64::MAX
+    }
+}
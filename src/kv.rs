@@ -0,0 +1,198 @@
+//! Tiny key-value table with 16 byte keys and fixed-size values, laid out so C code can read it too
+//!
+//! Lookups are a linear scan, which is the fastest thing for the handful of entries this is meant for. The layout at
+//! the table's offset never changes between versions, every integer is little-endian:
+//!
+//! | Offset | Size | Field |
+//! |--------|------|-------|
+//! | 0 | 4 | Lock word: 0 unlocked, 1 locked, 2 locked with waiters (a Linux futex) |
+//! | 4 | 4 | Capacity, the most entries the table holds |
+//! | 8 | 4 | Value size in bytes |
+//! | 12 | 4 | Number of entries in use |
+//! | 16 | capacity × stride | Entries, where stride is `16 + value size` rounded up to a multiple of 8 |
+//!
+//! Each entry is its 16 byte key followed by the value, and the first "number of entries" of them are the ones in
+//! use, in no particular order. Deleting an entry moves the last one into its place so entries in use stay packed at
+//! the front. A C reader mirroring this takes the lock word the same way before scanning: CAS 0 to 1, or swap in 2
+//! and `FUTEX_WAIT` on 2 until the swap returns 0, and on unlock swap in 0 and `FUTEX_WAKE` one waiter if it was 2.
+
+use std::fmt;
+use std::ptr;
+use std::sync::atomic::{fence, AtomicU32, Ordering};
+
+use crate::futex;
+use crate::shared_memory::{Error, SharedMemory};
+
+const OFF_LOCK: usize = 0;
+const OFF_CAPACITY: usize = 4;
+const OFF_VALUE_SIZE: usize = 8;
+const OFF_LEN: usize = 12;
+pub const HEADER_SIZE: usize = 16;
+
+pub const KEY_SIZE: usize = 16;
+
+/// Alignment the table's offset inside the segment has to have
+pub const ALIGNMENT: usize = 8;
+
+/// `put` found every entry taken by other keys
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Full;
+
+impl fmt::Display for Full {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "key-value table is full")
+    }
+}
+
+impl std::error::Error for Full {}
+
+/// Bytes from the start of one entry to the next for values of `value_size` bytes
+pub fn stride(value_size: usize) -> usize {
+    (KEY_SIZE + value_size).next_multiple_of(8)
+}
+
+pub struct ShmKvStore<'a> {
+    shm: &'a SharedMemory,
+    offset: usize,
+    capacity: usize,
+    value_size: usize,
+}
+
+// Only the segment's address and size are read through the reference, the entries are guarded by the lock in the
+// segment
+unsafe impl Send for ShmKvStore<'_> {}
+unsafe impl Sync for ShmKvStore<'_> {}
+
+impl<'a> ShmKvStore<'a> {
+    /// Bytes the table takes up for `capacity` entries with values of `value_size` bytes
+    pub fn size_for(capacity: usize, value_size: usize) -> usize {
+        HEADER_SIZE + capacity * stride(value_size)
+    }
+
+    /// Sets up an empty table at `offset` into `shm`
+    ///
+    /// Nothing else may be using the table while it's being initialized
+    pub fn init(shm: &'a SharedMemory, offset: usize, capacity: usize, value_size: usize) -> Result<Self, Error> {
+        let store = ShmKvStore::check(shm, offset, capacity, value_size)?;
+        store.word(OFF_LOCK).store(0, Ordering::Relaxed);
+        store.word(OFF_LEN).store(0, Ordering::Relaxed);
+        shm.write_u32_le(offset + OFF_CAPACITY, capacity as u32)?;
+        shm.write_u32_le(offset + OFF_VALUE_SIZE, value_size as u32)?;
+        fence(Ordering::Release);
+        Ok(store)
+    }
+
+    /// Attaches to a table another handle or a C program set up at `offset` with the same geometry
+    pub fn attach(shm: &'a SharedMemory, offset: usize, capacity: usize, value_size: usize) -> Result<Self, Error> {
+        let store = ShmKvStore::check(shm, offset, capacity, value_size)?;
+        let found = shm.read_u32_le(offset + OFF_CAPACITY)? as u64;
+        if found != capacity as u64 {
+            return Err(Error::LayoutMismatch { what: "key-value capacity", expected: capacity as u64, found });
+        }
+        let found = shm.read_u32_le(offset + OFF_VALUE_SIZE)? as u64;
+        if found != value_size as u64 {
+            return Err(Error::LayoutMismatch { what: "key-value value size", expected: value_size as u64, found });
+        }
+        Ok(store)
+    }
+
+    fn check(shm: &'a SharedMemory, offset: usize, capacity: usize, value_size: usize) -> Result<Self, Error> {
+        if !offset.is_multiple_of(ALIGNMENT) {
+            return Err(Error::UnalignedOffset { offset: offset as u64, alignment: ALIGNMENT as u64 });
+        }
+        let size = shm.size() as usize;
+        let fits = u32::try_from(capacity).is_ok() && u32::try_from(value_size).is_ok();
+        let len = fits.then(|| capacity.checked_mul(stride(value_size))?.checked_add(HEADER_SIZE)).flatten();
+        if len.and_then(|len| offset.checked_add(len)).is_none_or(|end| end > size) {
+            return Err(Error::OutOfBounds { offset, len: len.unwrap_or(usize::MAX), size });
+        }
+        Ok(ShmKvStore { shm, offset, capacity, value_size })
+    }
+
+    fn base(&self) -> *mut u8 {
+        unsafe { (self.shm.address() as *mut u8).add(self.offset) }
+    }
+
+    fn word(&self, offset: usize) -> &AtomicU32 {
+        unsafe { &*(self.base().add(offset) as *const AtomicU32) }
+    }
+
+    fn entry(&self, index: usize) -> *mut u8 {
+        unsafe { self.base().add(HEADER_SIZE + index * stride(self.value_size)) }
+    }
+
+    /// Entries in use, only meaningful while holding the lock
+    fn used(&self) -> usize {
+        // A corrupt count from another process must not let the scan run past the table
+        (self.word(OFF_LEN).load(Ordering::Relaxed) as usize).min(self.capacity)
+    }
+
+    fn find(&self, key: &[u8; KEY_SIZE]) -> Option<usize> {
+        (0..self.used()).find(|&index| unsafe { ptr::read_unaligned(self.entry(index) as *const [u8; KEY_SIZE]) } == *key)
+    }
+
+    pub fn capacity(&self) -> usize {
+        self.capacity
+    }
+
+    pub fn value_size(&self) -> usize {
+        self.value_size
+    }
+
+    pub fn len(&self) -> usize {
+        let _lock = futex::lock(self.word(OFF_LOCK));
+        self.used()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    /// Stores `value` under `key`, replacing what was there
+    ///
+    /// # Panics
+    /// If `value` isn't exactly [`value_size`](Self::value_size) bytes
+    pub fn put(&self, key: &[u8; KEY_SIZE], value: &[u8]) -> Result<(), Full> {
+        assert_eq!(value.len(), self.value_size, "values in this table are {} bytes", self.value_size);
+        let _lock = futex::lock(self.word(OFF_LOCK));
+        let index = match self.find(key) {
+            Some(index) => index,
+            None => {
+                let index = self.used();
+                if index == self.capacity {
+                    return Err(Full);
+                }
+                unsafe { ptr::copy_nonoverlapping(key.as_ptr(), self.entry(index), KEY_SIZE) };
+                self.word(OFF_LEN).store(index as u32 + 1, Ordering::Relaxed);
+                index
+            }
+        };
+        unsafe { ptr::copy_nonoverlapping(value.as_ptr(), self.entry(index).add(KEY_SIZE), value.len()) };
+        Ok(())
+    }
+
+    /// The value stored under `key`, borrowed straight from the segment
+    ///
+    /// A `put` or `delete` from another handle can change or move the entry while the slice is alive, with the same
+    /// aliasing caveat as [`SharedMemory::read_data`]. Copy the value out straight away when other writers are active
+    pub fn get(&self, key: &[u8; KEY_SIZE]) -> Option<&[u8]> {
+        let _lock = futex::lock(self.word(OFF_LOCK));
+        let index = self.find(key)?;
+        Some(unsafe { std::slice::from_raw_parts(self.entry(index).add(KEY_SIZE), self.value_size) })
+    }
+
+    /// Removes `key`, returning whether it was there
+    pub fn delete(&self, key: &[u8; KEY_SIZE]) -> bool {
+        let _lock = futex::lock(self.word(OFF_LOCK));
+        let index = match self.find(key) {
+            Some(index) => index,
+            None => return false,
+        };
+        let last = self.used() - 1;
+        if index != last {
+            unsafe { ptr::copy_nonoverlapping(self.entry(last), self.entry(index), stride(self.value_size)) };
+        }
+        self.word(OFF_LEN).store(last as u32, Ordering::Relaxed);
+        true
+    }
+}
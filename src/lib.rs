@@ -1,3 +1,33 @@
+pub mod bitmap;
+pub mod bitset;
+#[cfg(feature = "xxhash")]
+pub mod bloom;
+pub mod broadcast;
+pub mod cell;
+pub mod chain;
+pub mod channel;
+pub mod counters;
+mod futex;
+pub mod histogram;
+pub mod kv;
+pub mod log;
+pub mod name;
+#[cfg(target_os = "windows")]
+pub mod named_mutex;
+#[cfg(any(target_os = "linux", target_vendor = "apple"))]
+pub mod named_semaphore;
+pub mod ptr;
+pub mod ring_buffer;
+pub mod rpc;
+pub mod shared_safe;
+pub mod slab;
+pub mod stack;
+pub mod string;
+pub mod swap;
+#[cfg(feature = "testing")]
+pub mod testing;
+pub mod vec;
+
 pub mod shared_memory {
     #[cfg(target_os = "windows")]
     extern crate winapi;
@@ -5,9 +35,20 @@ pub mod shared_memory {
     #[cfg(target_os = "linux")]
     extern crate libc;
 
+    use std::alloc::{dealloc, Layout};
+    use std::cell::Cell;
     use std::ffi::CString;
-    use std::{io, ptr};
-    use std::io::Error;
+    use std::fs::{self, File};
+    use std::io::Write;
+    use std::path::{Path, PathBuf};
+    use std::ops::Range;
+    use std::sync::atomic::{fence, AtomicU32, AtomicU64, Ordering};
+    use std::time::{Duration, Instant};
+    use std::ptr::NonNull;
+    use std::{fmt, io, ptr, thread};
+
+    #[cfg(target_os = "linux")]
+    use std::ffi::CStr;
 
     #[cfg(target_os = "windows")]
     use winapi::shared::minwindef::*;
@@ -30,299 +71,6792 @@ pub mod shared_memory {
     #[cfg(target_os = "windows")]
     use winapi::shared::basetsd::*;
 
+    #[cfg(target_os = "windows")]
+    use winapi::um::fileapi::*;
+
+    #[cfg(target_os = "windows")]
+    use winapi::um::sysinfoapi::*;
+
+    #[cfg(target_os = "windows")]
+    use winapi::um::errhandlingapi::GetLastError;
+
+    #[cfg(target_os = "windows")]
+    use winapi::um::processthreadsapi::{GetCurrentProcess, GetExitCodeProcess, GetProcessTimes, OpenProcess};
+
+    #[cfg(target_os = "windows")]
+    use winapi::um::psapi::{K32QueryWorkingSetEx, PSAPI_WORKING_SET_EX_INFORMATION};
+
+    #[cfg(target_os = "windows")]
+    use winapi::um::minwinbase::STILL_ACTIVE;
+
+    #[cfg(target_os = "windows")]
+    use winapi::shared::winerror::{ERROR_ALREADY_EXISTS, ERROR_COMMITMENT_LIMIT, ERROR_INVALID_HANDLE, ERROR_INVALID_PARAMETER};
+
     #[cfg(target_os = "linux")]
-    use libc::{off_t, c_int, c_void as lin_c_void, size_t, shm_open, mmap, PROT_READ, PROT_WRITE, MAP_SHARED, O_RDWR, O_CREAT, O_EXCL, close, ftruncate, munmap, shm_unlink};
+    use libc::{off_t, c_int, c_void as lin_c_void, size_t, shm_open, mmap, PROT_READ, PROT_WRITE, MAP_SHARED, O_RDWR, O_CREAT, O_EXCL, ftruncate, munmap, shm_unlink, sysconf, _SC_PAGESIZE, fallocate, madvise, FALLOC_FL_PUNCH_HOLE, FALLOC_FL_KEEP_SIZE, MADV_REMOVE, MADV_WILLNEED, mincore, msync, MS_SYNC, MS_ASYNC};
 
-    use std::os::raw::{c_char};
+    #[cfg(target_os = "linux")]
+    use std::os::unix::ffi::OsStrExt;
 
-    pub struct SharedMemory {
-        size: i32,
-        name: *const c_char,
+    #[cfg(target_os = "linux")]
+    use std::os::fd::{AsFd, AsRawFd, BorrowedFd, FromRawFd, OwnedFd};
 
-        #[cfg(target_os = "windows")]
-        h_map_file: HANDLE,
-        #[cfg(target_os = "windows")]
-        p_buf: *mut win_c_void,
+    #[cfg(target_os = "windows")]
+    use std::os::windows::io::{AsHandle, AsRawHandle, BorrowedHandle, FromRawHandle, OwnedHandle, RawHandle};
 
-        #[cfg(target_os = "linux")]
-        p_buf: *mut lin_c_void,
-        #[cfg(target_os = "linux")]
-        is_create: bool,
+    use crate::cell::ShmCell;
+    use crate::futex;
+    #[cfg(target_os = "linux")]
+    use crate::name::file_name;
+    use crate::name::{normalize_name, unique_name, InvalidName, NamePolicy, Platform};
+    use crate::shared_safe::{SharedSafe, SharedStruct};
+
+    #[derive(Debug)]
+    pub enum Error {
+        Io(io::Error),
+        /// The mapping offset isn't a multiple of [`SharedMemory::offset_alignment`]
+        UnalignedOffset { offset: u64, alignment: u64 },
+        /// The requested window runs past the end of the segment or file backing it
+        WindowOutOfRange { offset: u64, len: usize, available: u64 },
+        /// An access of `len` bytes at `offset` doesn't fit in the `size` byte mapping
+        OutOfBounds { offset: usize, len: usize, size: usize },
+        /// A structure stored in the segment doesn't match what the opener expected, e.g. a different capacity or no magic at all
+        LayoutMismatch { what: &'static str, expected: u64, found: u64 },
+        /// A typed access at `offset` into the mapping isn't aligned for its type
+        Misaligned { offset: usize, alignment: usize },
+        /// A message of `len` bytes still needs `stored` bytes after compression, more than the `available` bytes
+        #[cfg(feature = "lz4")]
+        MessageTooLarge { len: usize, stored: usize, available: usize },
+        /// An access of `len` bytes at `offset` touches a page `protect_range` set to `protection`
+        Protected { offset: usize, len: usize, protection: Protection },
+        /// Opening asked for a window ending `requested` bytes into a segment that is only `actual` bytes long
+        SizeMismatch { requested: u64, actual: u64 },
+        /// A file offset past `max`, the largest the platform's `off_t` holds, which is 32 bits on some 32-bit targets
+        FileOffsetTooLarge { offset: u64, max: u64 },
+        /// Creating a segment that takes `requested` bytes when only `available` are left to back it, see
+        /// [`SharedMemoryBuilder::check_capacity`]
+        InsufficientSpace { requested: u64, available: u64 },
     }
 
-    impl SharedMemory {
-        pub fn size(&self) -> i32 {
-            self.size
+    impl fmt::Display for Error {
+        fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+            match self {
+                Error::Io(err) => err.fmt(f),
+                Error::UnalignedOffset { offset, alignment } => {
+                    write!(f, "offset {} is not a multiple of the {} byte mapping alignment", offset, alignment)
+                }
+                Error::WindowOutOfRange { offset, len, available } => {
+                    write!(f, "window of {} bytes at offset {} runs past the end of the {} byte backing", len, offset, available)
+                }
+                Error::OutOfBounds { offset, len, size } => {
+                    write!(f, "access of {} bytes at offset {} is outside the {} byte mapping", len, offset, size)
+                }
+                Error::LayoutMismatch { what, expected, found } => {
+                    write!(f, "{} mismatch: expected {:#x}, found {:#x}", what, expected, found)
+                }
+                Error::Misaligned { offset, alignment } => {
+                    write!(f, "offset {} is not aligned to {} bytes for this access", offset, alignment)
+                }
+                #[cfg(feature = "lz4")]
+                Error::MessageTooLarge { len, stored, available } => {
+                    write!(f, "message of {} bytes needs {} bytes even compressed, only {} are available", len, stored, available)
+                }
+                Error::Protected { offset, len, protection } => {
+                    write!(f, "access of {} bytes at offset {} touches a page protected as {:?}", len, offset, protection)
+                }
+                Error::SizeMismatch { requested, actual } => {
+                    write!(f, "window ends {} bytes in but the segment is only {} bytes long", requested, actual)
+                }
+                Error::FileOffsetTooLarge { offset, max } => {
+                    write!(f, "file offset {} is past {}, the largest this platform can map at", offset, max)
+                }
+                Error::InsufficientSpace { requested, available } => {
+                    write!(f, "segment needs {} bytes but only {} are left to back it", requested, available)
+                }
+            }
         }
+    }
 
-        pub fn name(&self) -> String {
-            unsafe {
-                let c_str = CString::from_raw(self.name as *mut c_char);
-                let str_slice = c_str.to_str().unwrap();
-                str_slice.to_string()
+    impl std::error::Error for Error {
+        fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+            match self {
+                Error::Io(err) => Some(err),
+                _ => None,
             }
         }
+    }
 
-        #[cfg(target_os = "windows")]
-        pub fn address(&self) -> *mut win_c_void {
-            self.p_buf
+    /// The system call or API an [`OsError`] came out of
+    #[derive(Debug, Clone, Copy, PartialEq, Eq)]
+    pub enum Operation {
+        ShmOpen,
+        Open,
+        Ftruncate,
+        Mmap,
+        Shmget,
+        Shmat,
+        CreateFileMapping,
+        OpenFileMapping,
+        CreateFile,
+        MapViewOfFile,
+        Fstat,
+        Ftok,
+        Shmctl,
+        ShmUnlink,
+        Mprotect,
+        Fallocate,
+        Madvise,
+        Msync,
+    }
+
+    impl fmt::Display for Operation {
+        fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+            f.write_str(match self {
+                Operation::ShmOpen => "shm_open",
+                Operation::Open => "open",
+                Operation::Ftruncate => "ftruncate",
+                Operation::Mmap => "mmap",
+                Operation::Shmget => "shmget",
+                Operation::Shmat => "shmat",
+                Operation::CreateFileMapping => "CreateFileMapping",
+                Operation::OpenFileMapping => "OpenFileMapping",
+                Operation::CreateFile => "CreateFile",
+                Operation::MapViewOfFile => "MapViewOfFile",
+                Operation::Fstat => "fstat",
+                Operation::Ftok => "ftok",
+                Operation::Shmctl => "shmctl",
+                Operation::ShmUnlink => "shm_unlink",
+                Operation::Mprotect => "mprotect",
+                Operation::Fallocate => "fallocate",
+                Operation::Madvise => "madvise",
+                Operation::Msync => "msync",
+            })
         }
+    }
 
-        #[cfg(target_os = "linux")]
-        pub fn address(&self) -> *mut lin_c_void {
-            self.p_buf
+    /// An OS error from creating, opening, mapping or managing a segment, along with what was being done to which segment
+    ///
+    /// It travels inside an `io::Error` of the same kind, so `kind()` checks keep working and
+    /// `err.get_ref().and_then(|inner| inner.downcast_ref::<OsError>())` gets the context back. `source` is the
+    /// error the OS reported, with its `raw_os_error`
+    #[derive(Debug)]
+    pub struct OsError {
+        pub operation: Operation,
+        /// The segment's logical name, or the file's path
+        pub segment: String,
+        /// The size that was asked for, where there was one
+        pub size: Option<usize>,
+        pub source: io::Error,
+    }
+
+    impl OsError {
+        fn wrap(operation: Operation, segment: &str, size: Option<i32>, source: io::Error) -> io::Error {
+            let size = size.map(|size| size.max(0) as usize);
+            io::Error::new(source.kind(), OsError { operation, segment: segment.to_string(), size, source })
         }
 
-        #[cfg(target_os = "windows")]
-        pub fn create(name: &str, size: i32) -> Result<Self, io::Error> {
-            let name_c = CString::new(name).expect("CSTRING::new failed");
-            let h_map_file = unsafe {
-                CreateFileMappingA(
-                    INVALID_HANDLE_VALUE,
-                    ptr::null_mut(),
-                    PAGE_READWRITE | SEC_COMMIT,
-                    0,
-                    size as DWORD,
-                    name_c.as_ptr(),
-                )
-            };
-            if h_map_file.is_null() {
-                return Err(Error::last_os_error());
-            }
-            let p_buf = unsafe {
-                MapViewOfFile(
-                    h_map_file,
-                    FILE_MAP_ALL_ACCESS,
-                    0,
-                    0,
-                    size as SIZE_T
-                )
-            };
-            if p_buf.is_null() {
-                unsafe {
-                    CloseHandle(h_map_file);
-                }
-                return Err(Error::last_os_error());
-            }
-            let shared_memory = SharedMemory {
-                size,
-                name: name_c.into_raw(),
-                h_map_file,
-                p_buf,
-            };
-            Ok(shared_memory)
+        /// Wraps `io::Error::last_os_error`, so it has to be called before anything else that can set it
+        fn last(operation: Operation, segment: &str, size: Option<i32>) -> io::Error {
+            OsError::wrap(operation, segment, size, io::Error::last_os_error())
         }
+    }
 
-        #[cfg(target_os = "linux")]
-        pub fn create(name: &str, size: i32) -> Result<Self, io::Error> {
-            // In linux on program close shared memory areas don't automatically get deleted so if it already exists just pass along arguments to the open command
-            let open_test = SharedMemory::open(name, size);
-            if open_test.is_ok() {
-                // reset the data just in case
-                let t = open_test.unwrap().reset();
-                // return the shared memory
-                return Ok(t);
-            }
-            let name_c = CString::new(name).expect("CString::new failed");
-            let fd = unsafe {
-                shm_open(
-                    name_c.as_ptr(),
-                    O_RDWR | O_CREAT | O_EXCL,
-                    0o600,
-                )
-            };
-            if fd == -1 {
-                return Err(io::Error::last_os_error());
-            }
-            if unsafe { ftruncate(fd, size as off_t) } == -1 {
-                unsafe {
-                    close(fd);
-                }
-                return Err(io::Error::last_os_error());
-            }
-            let p_buf = unsafe {
-                mmap(
-                    ptr::null_mut(),
-                    size as size_t,
-                    PROT_READ | PROT_WRITE,
-                    MAP_SHARED,
-                    fd,
-                    0,
-                )
-            };
-            if p_buf == libc::MAP_FAILED {
-                unsafe {
-                    close(fd);
-                }
-                return Err(io::Error::last_os_error());
+    impl fmt::Display for OsError {
+        fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+            write!(f, "failed to {} segment {:?}", self.operation, self.segment)?;
+            if let Some(size) = self.size {
+                write!(f, " ({} bytes)", size)?;
             }
-            let shared_memory = SharedMemory {
-                size,
-                name: name_c.into_raw(),
-                p_buf,
-                is_create: true,
-            };
-            Ok(shared_memory)
+            write!(f, ": {}", self.source)
         }
+    }
 
-        #[cfg(target_os = "windows")]
-        pub fn open(name: &str, size: i32) -> Result<Self, Error> {
-            let name_c = CString::new(name).expect("CSTRING::new failed");
-            let h_map_file = unsafe {
-                OpenFileMappingA(
-                    FILE_MAP_ALL_ACCESS,
-                    FALSE,
-                    name_c.as_ptr(),
-                )
-            };
-            if h_map_file.is_null() {
-                return Err(Error::last_os_error());
-            }
-            let p_buf = unsafe {
-                MapViewOfFile(
-                    h_map_file,
-                    FILE_MAP_ALL_ACCESS,
-                    0,
-                    0,
-                    size as SIZE_T
-                )
-            };
-            if p_buf.is_null() {
-                unsafe {
-                    CloseHandle(h_map_file);
-                }
-                return Err(Error::last_os_error());
-            }
-            let shared_memory = SharedMemory {
-                size,
-                name: name_c.into_raw(),
-                h_map_file,
-                p_buf,
-            };
-            Ok(shared_memory)
+    impl std::error::Error for OsError {
+        fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+            Some(&self.source)
         }
+    }
 
-        pub fn write_data(&self, data: &[u8]) {
-            // @TODO: Fix this (attempt to subtract with overflow)
-            let data_size = data.len();
-            let offset = self.size() as usize - data_size;
+    impl From<io::Error> for Error {
+        fn from(err: io::Error) -> Self {
+            Error::Io(err)
+        }
+    }
 
-            if offset < data_size {
-                eprintln!("Error: Attempted to write data with a size larger than the shared memory region");
-                return;
-            }
-            if let Some(size) = self.size.checked_sub(data.len() as i32) {
-                let dest = self.address() as *mut u8;
-                unsafe {
-                    ptr::copy_nonoverlapping(data.as_ptr(), dest, data.len());
-                    ptr::write_bytes(dest.add(data.len()), 0, size as usize - data.len());
-                }
-            } else {
-                eprintln!("Error: Attempted to write data with a size larger than the shared memory region");
+    impl From<InvalidName> for Error {
+        fn from(err: InvalidName) -> Self {
+            Error::Io(err.into())
+        }
+    }
+
+    impl From<Error> for io::Error {
+        fn from(err: Error) -> Self {
+            match err {
+                Error::Io(err) => err,
+                other => io::Error::new(io::ErrorKind::InvalidInput, other),
             }
         }
+    }
 
-        pub fn read_data(&self) -> &[u8] {
-            let dest = self.address() as *const u8;
-            let mut size = self.size() as usize;
+    /// Why [`SharedMemory::read_blob_hashed`] couldn't hand back a blob
+    #[cfg(feature = "xxhash")]
+    #[derive(Debug)]
+    pub enum IntegrityError {
+        /// The length prefix or the blob it describes doesn't fit in the mapping
+        Access(Error),
+        /// The blob's bytes don't hash to what was stored with them
+        Mismatch { expected: u64, computed: u64 },
+    }
 
-            // Check for empty bits at the end of the data
-            unsafe {
-                let mut idx = size - 1;
-                while idx >= 0 {
-                    if *dest.add(idx) != 0 {
-                        break;
-                    }
-                    idx -= 1;
+    #[cfg(feature = "xxhash")]
+    impl fmt::Display for IntegrityError {
+        fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+            match self {
+                IntegrityError::Access(err) => err.fmt(f),
+                IntegrityError::Mismatch { expected, computed } => {
+                    write!(f, "blob hash mismatch: stored {:#018x}, computed {:#018x}", expected, computed)
                 }
-                size = idx + 1;
             }
+        }
+    }
 
-            unsafe {
-                std::slice::from_raw_parts(dest, size)
+    #[cfg(feature = "xxhash")]
+    impl std::error::Error for IntegrityError {
+        fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+            match self {
+                IntegrityError::Access(err) => Some(err),
+                IntegrityError::Mismatch { .. } => None,
             }
         }
+    }
 
-        pub fn write_string(&mut self, data: &str) {
-            self.write_data(data.as_bytes());
+    #[cfg(feature = "xxhash")]
+    impl From<Error> for IntegrityError {
+        fn from(err: Error) -> Self {
+            IntegrityError::Access(err)
         }
+    }
 
-        pub fn read_string(&self) -> String {
-            let bytes = self.read_data();
-            String::from_utf8_lossy(&bytes).to_string()
+    /// Bytes in front of a payload written by [`SharedMemory::publish`]: the `u64` sequence word, even once a
+    /// payload is complete and odd while one is being written, then the `u64` payload length
+    pub const PUBLISH_HEADER: usize = 16;
+
+    /// Bytes at the start of the mapping in front of a message written by [`SharedMemory::write_message_notify`]: the
+    /// `u32` generation, odd while a message is being written and futex-waited on by readers, the `u32` pid of the
+    /// last writer and the `u64` message length
+    pub const NOTIFY_HEADER: usize = 16;
+
+    /// Why [`SharedMemory::read_message_blocking`] came back without a message
+    #[derive(Debug)]
+    pub enum WaitError {
+        /// No message newer than the last one this handle read was written in time
+        TimedOut,
+        /// The header or the message it describes doesn't fit in the mapping
+        Access(Error),
+    }
+
+    impl fmt::Display for WaitError {
+        fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+            match self {
+                WaitError::TimedOut => write!(f, "timed out waiting for a new message"),
+                WaitError::Access(err) => err.fmt(f),
+            }
         }
+    }
 
-        pub fn reset(&self) {
-            let empty_bytes = vec![0u8; self.size as usize];
-            self.write_data(empty_bytes.as_slice());
+    impl std::error::Error for WaitError {
+        fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+            match self {
+                WaitError::Access(err) => Some(err),
+                WaitError::TimedOut => None,
+            }
+        }
+    }
+
+    /// How [`SharedMemory::wait_for_change_u32`] came back
+    #[derive(Debug, Clone, Copy, PartialEq, Eq)]
+    pub enum WaitResult {
+        /// The word held this instead of the expected value
+        Changed(u32),
+        /// The word still held the expected value when the timeout ran out
+        TimedOut,
+    }
+
+    impl From<Error> for WaitError {
+        fn from(err: Error) -> Self {
+            WaitError::Access(err)
         }
+    }
 
+    /// Bytes in front of a blob written by [`SharedMemory::write_blob_hashed`], its length and xxh3 hash
+    #[cfg(feature = "xxhash")]
+    pub const BLOB_HEADER: usize = 16;
 
+    /// Bytes in front of a message written by [`SharedMemory::write_message_compressed`]: a little-endian `u32` flag
+    /// (0 stored as is, 1 lz4 block), the `u32` stored length and the `u64` original length
+    #[cfg(feature = "lz4")]
+    pub const MESSAGE_HEADER: usize = 16;
 
-        #[cfg(target_os = "linux")]
-        pub fn open(name: &str, size: i32) -> Result<Self, io::Error> {
-            let name_c = CString::new(name).expect("CString::new failed");
-            let fd = unsafe {
-                shm_open(
-                    name_c.as_ptr(),
-                    O_RDWR,
-                    0o600,
-                )
-            };
-            if fd == -1 {
-                return Err(io::Error::last_os_error());
-            }
-            let p_buf = unsafe {
-                mmap(
-                    ptr::null_mut(),
-                    size as size_t,
-                    PROT_READ | PROT_WRITE,
-                    MAP_SHARED,
-                    fd,
-                    0,
-                )
-            };
-            if p_buf == libc::MAP_FAILED {
-                unsafe {
-                    close(fd);
-                }
-                return Err(io::Error::last_os_error());
+    #[cfg(feature = "lz4")]
+    const MESSAGE_RAW: u32 = 0;
+    #[cfg(feature = "lz4")]
+    const MESSAGE_LZ4: u32 = 1;
+
+    /// A segment found by [`SharedMemory::list`]
+    #[derive(Debug, Clone, PartialEq, Eq)]
+    pub struct SegmentEntry {
+        pub name: String,
+        pub size: u64,
+    }
+
+    /// Bytes at the start of a [`SharedMemory::elect`] segment that identify the leader, user data starts after them
+    ///
+    /// The first 8 bytes are the leader's pid in the low half and the low 32 bits of its start time in the high one,
+    /// zero while unclaimed, so a recycled pid isn't mistaken for a leader that is still running. The next 8 bytes
+    /// identify the process that created the segment the same way
+    pub const ELECTION_HEADER: usize = 64;
+
+    /// How long `elect` gives a creator that hasn't claimed its new segment yet before treating it as dead
+    const ELECTION_CLAIM_GRACE: Duration = Duration::from_secs(1);
+    const ELECTION_POLL: Duration = Duration::from_millis(10);
+
+    /// How many taken names `create_unique` retries past before giving up
+    const UNIQUE_ATTEMPTS: u32 = 8;
+
+    /// How many times `retry_eintr` calls again after a signal interrupted it
+    #[cfg(target_os = "linux")]
+    const EINTR_RETRIES: u32 = 100;
+
+    /// Calls `f` again while it returns -1 with `errno` set to EINTR, for system calls a signal can cut short
+    #[cfg(target_os = "linux")]
+    fn retry_eintr(mut f: impl FnMut() -> c_int) -> c_int {
+        let mut retries = 0;
+        loop {
+            let result = f();
+            if result != -1 || retries == EINTR_RETRIES || io::Error::last_os_error().raw_os_error() != Some(libc::EINTR) {
+                return result;
             }
-            let shared_memory = SharedMemory {
-                size,
-                name: name_c.into_raw(),
-                p_buf,
-                is_create: false,
-            };
-            Ok(shared_memory)
+            retries += 1;
         }
     }
 
-    impl Drop for SharedMemory {
-        fn drop(&mut self) {
-            unsafe {
-                #[cfg(target_os = "windows")]
-                {
-                    UnmapViewOfFile(self.p_buf);
-                    CloseHandle(self.h_map_file);
-                }
-                #[cfg(target_os = "linux")]
-                {
-                    if (!self.p_buf.is_null()) {
-                        munmap(self.p_buf, self.size as size_t);
-                    }
-                    if (self.is_create) {
-                        shm_unlink(self.name);
-                    }
-                }
-                let _ = CString::from_raw(self.name as *mut c_char);
-            }
+    /// Where Linux keeps POSIX shared memory, the tmpfs `available_capacity` reports on
+    #[cfg(target_os = "linux")]
+    const SHM_MOUNT: &str = "/dev/shm";
+
+    /// Free bytes in a `statvfs` result, counting only the blocks an unprivileged process may use
+    #[cfg(target_os = "linux")]
+    // The fields are only 32 bits on some 32-bit targets
+    #[allow(clippy::unnecessary_cast)]
+    pub(crate) fn statvfs_available(stat: &libc::statvfs) -> u64 {
+        (stat.f_bavail as u64).saturating_mul(stat.f_frsize as u64)
+    }
+
+    /// Fails with `InsufficientSpace` inside a `StorageFull` error unless `available` bytes can back a segment of
+    /// `size` bytes, which takes up whole pages of `page` bytes
+    #[cfg(target_os = "linux")]
+    pub(crate) fn check_space(size: i32, available: u64, page: u64) -> io::Result<()> {
+        let requested = (size.max(0) as u64).div_ceil(page) * page;
+        if requested > available {
+            return Err(io::Error::new(io::ErrorKind::StorageFull, Error::InsufficientSpace { requested, available }));
         }
+        Ok(())
     }
 
-}
+    /// Bytes at the start of a [`SharedMemory::create_with_data`] segment: the `u32` flag that is set to
+    /// [`INIT_READY`] once the initial contents are in place, 4 unused bytes and the `u64` initial length
+    pub const INIT_HEADER: usize = 16;
 
-#[cfg(test)]
-mod tests {
-    use super::*;
+    /// "SHIN"
+    pub const INIT_READY: u32 = 0x5348_494e;
+
+    /// Outcome of [`SharedMemory::elect`]
+    pub enum Role {
+        /// This process claimed the segment and is responsible for unlinking it
+        Leader(SharedMemory),
+        /// Another live process leads, this is a plain opened handle
+        Follower(SharedMemory),
+    }
+
+    /// What `shmctl(IPC_STAT)` reports for a segment from [`SharedMemory::create_sysv`] or
+    /// [`SharedMemory::open_sysv`]
+    #[cfg(target_os = "linux")]
+    #[derive(Debug, Clone, Copy, PartialEq, Eq)]
+    pub struct SysvStat {
+        pub size: usize,
+        /// Processes, or handles, currently attached
+        pub attached: u64,
+        pub owner_uid: u32,
+        pub creator_pid: i32,
+    }
+
+    /// What [`SharedMemory::open_or_create`] does with a segment that already exists
+    #[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+    pub enum OpenMode {
+        /// Keep what the segment holds, for a warm restart
+        #[default]
+        PreserveContents,
+        /// Zero the whole mapping before handing it back
+        TruncateIfExisting,
+    }
+
+    /// Options for creating or opening a segment, started with [`SharedMemory::builder`]
+    ///
+    /// The plain constructors are shorthands for a builder, so `SharedMemory::create(name, size)` is
+    /// `SharedMemory::builder(name).size(size).create()`. Options that don't go with the terminal call, like an
+    /// offset on `create`, fail it with `InvalidInput` instead of being ignored
+    ///
+    /// ```no_run
+    /// use shared_memory::name::NamePolicy;
+    /// use shared_memory::shared_memory::SharedMemory;
+    ///
+    /// let shm = SharedMemory::builder("/a-rather-long-segment-name")
+    ///     .size(4096)
+    ///     .name_policy(NamePolicy::HashWhenTooLong)
+    ///     .exclusive(true)
+    ///     .create()?;
+    /// # Ok::<(), shared_memory::shared_memory::Error>(())
+    /// ```
+    #[derive(Debug, Clone)]
+    pub struct SharedMemoryBuilder {
+        name: String,
+        size: Option<i32>,
+        offset: u64,
+        policy: NamePolicy,
+        exclusive: bool,
+        mode: OpenMode,
+        check_capacity: bool,
+        backing_dir: Option<PathBuf>,
+    }
+
+    impl SharedMemoryBuilder {
+        fn new(name: &str) -> Self {
+            SharedMemoryBuilder {
+                name: name.to_string(),
+                size: None,
+                offset: 0,
+                policy: NamePolicy::Reject,
+                exclusive: false,
+                mode: OpenMode::PreserveContents,
+                check_capacity: true,
+                backing_dir: None,
+            }
+        }
+
+        /// Bytes to map, needed by `create` and `open_or_create`, without it `open` maps the whole segment
+        pub fn size(mut self, size: i32) -> Self {
+            self.size = Some(size);
+            self
+        }
+
+        /// Where in an existing segment `open` starts its window, see [`SharedMemory::open_with_offset`]
+        pub fn offset(mut self, offset: u64) -> Self {
+            self.offset = offset;
+            self
+        }
+
+        /// What to do with a name that is too long for the platform, opening has to use the policy creating did
+        pub fn name_policy(mut self, policy: NamePolicy) -> Self {
+            self.policy = policy;
+            self
+        }
+
+        /// Makes `create` fail with `AlreadyExists` rather than hand back a segment that is already there, see
+        /// [`SharedMemory::create_exclusive`]
+        pub fn exclusive(mut self, exclusive: bool) -> Self {
+            self.exclusive = exclusive;
+            self
+        }
+
+        /// What `open_or_create` does with the contents of a segment that already exists
+        pub fn open_mode(mut self, mode: OpenMode) -> Self {
+            self.mode = mode;
+            self
+        }
+
+        /// Whether creating a new segment first checks there is room to back all of it, on by default
+        ///
+        /// Linux sizes the object lazily, so a segment bigger than what is left in `/dev/shm` is created fine and the
+        /// process then gets SIGBUS on the first write past that. With the check that fails up front with
+        /// [`Error::InsufficientSpace`] inside a `StorageFull` error, and reusing an existing segment is never
+        /// checked. Windows commits a section when it's created, so there a section the system can't back always
+        /// fails with `InsufficientSpace` and this changes nothing
+        pub fn check_capacity(mut self, check: bool) -> Self {
+            self.check_capacity = check;
+            self
+        }
+
+        /// Keeps the segment as a file in `dir` instead of under `/dev/shm`, for a tmpfs mounted somewhere else
+        ///
+        /// `create`, `open` and `open_or_create` all look in the directory, so every process has to be given the same
+        /// one. The file is named after the normalized name without its leading `/`, and a handle that owns the
+        /// segment removes it on drop the way it would unlink a shm name. `SharedMemory::unlink` and `list` only
+        /// know about `/dev/shm`. Only Linux has this, elsewhere using the builder fails with `Unsupported`
+        pub fn backing_dir(mut self, dir: impl AsRef<Path>) -> Self {
+            self.backing_dir = Some(dir.as_ref().to_path_buf());
+            self
+        }
+
+        #[cfg(target_os = "linux")]
+        fn dir(&self) -> Result<Option<&Path>, Error> {
+            Ok(self.backing_dir.as_deref())
+        }
+
+        #[cfg(target_os = "windows")]
+        fn dir(&self) -> Result<Option<&Path>, Error> {
+            match &self.backing_dir {
+                Some(_) => Err(io::Error::new(io::ErrorKind::Unsupported, "backing directories are only supported on Linux").into()),
+                None => Ok(None),
+            }
+        }
+
+        fn invalid(message: &str) -> Error {
+            io::Error::new(io::ErrorKind::InvalidInput, message).into()
+        }
+
+        fn size_to_create(&self, call: &str) -> Result<i32, Error> {
+            if self.offset != 0 {
+                return Err(SharedMemoryBuilder::invalid(&format!("{} can't take an offset, only open maps a window into a segment", call)));
+            }
+            self.size.ok_or_else(|| SharedMemoryBuilder::invalid(&format!("{} needs a size", call)))
+        }
+
+        pub fn create(self) -> Result<SharedMemory, Error> {
+            let size = self.size_to_create("create")?;
+            if self.exclusive {
+                Ok(SharedMemory::create_new(&self.name, size, self.policy, self.check_capacity, self.dir()?)?)
+            } else {
+                Ok(SharedMemory::create_or_reuse(&self.name, size, self.policy, self.check_capacity, self.dir()?)?)
+            }
+        }
+
+        /// Opens the segment, failing with [`Error::SizeMismatch`] if the window runs past its end
+        pub fn open(self) -> Result<SharedMemory, Error> {
+            if self.exclusive {
+                return Err(SharedMemoryBuilder::invalid("exclusive only applies to creating a segment"));
+            }
+            match self.size {
+                Some(size) => SharedMemory::open_window(&self.name, self.offset, size, self.policy, true, self.dir()?),
+                None if self.offset != 0 => Err(SharedMemoryBuilder::invalid("opening at an offset needs a size")),
+                None => SharedMemory::open_existing_in(&self.name, self.policy, self.dir()?),
+            }
+        }
+
+        /// Like [`SharedMemory::open_or_create`], the `bool` is whether this call created the segment
+        pub fn open_or_create(self) -> Result<(SharedMemory, bool), Error> {
+            let size = self.size_to_create("open_or_create")?;
+            if self.exclusive {
+                return Err(SharedMemoryBuilder::invalid("open_or_create can't be exclusive, use create to fail on an existing segment"));
+            }
+            match SharedMemory::create_new(&self.name, size, self.policy, self.check_capacity, self.dir()?) {
+                Ok(shm) => return Ok((shm, true)),
+                Err(err) if err.kind() == io::ErrorKind::AlreadyExists => {}
+                Err(err) => return Err(err.into()),
+            }
+            let shm = SharedMemory::open_unsized(&self.name, size, self.policy, self.dir()?)?;
+            #[cfg(target_os = "linux")]
+            shm.grow_backing()?;
+            if self.mode == OpenMode::TruncateIfExisting {
+                let ptr = shm.byte_ptr(0, size.max(0) as usize, Access::Write)?;
+                unsafe { ptr::write_bytes(ptr, 0, size.max(0) as usize) };
+            }
+            Ok((shm, false))
+        }
+    }
+
+    /// `offset` rounded up to the next multiple of `align`, which has to be a power of two
+    ///
+    /// Fails with `InvalidInput` if `align` isn't a power of two or the rounded offset doesn't fit a `usize`
+    pub fn align_offset_up(offset: usize, align: usize) -> Result<usize, Error> {
+        if !align.is_power_of_two() {
+            return Err(io::Error::new(io::ErrorKind::InvalidInput, format!("alignment {} is not a power of two", align)).into());
+        }
+        match offset.checked_add(align - 1) {
+            Some(end) => Ok(end & !(align - 1)),
+            None => Err(io::Error::new(io::ErrorKind::InvalidInput, format!("offset {} overflows when aligned to {}", offset, align)).into()),
+        }
+    }
+
+    /// Page protection for [`SharedMemory::protect_range`]
+    #[derive(Debug, Clone, Copy, PartialEq, Eq)]
+    pub enum Protection {
+        ReadOnly,
+        ReadWrite,
+        NoAccess,
+    }
+
+    /// Whether [`SharedMemory::flush_range`] waits for the write-back
+    #[derive(Debug, Clone, Copy, PartialEq, Eq)]
+    pub enum FlushMode {
+        /// Returns once the pages have been written to the backing file
+        Sync,
+        /// Only schedules the write-back
+        Async,
+    }
+
+    /// A mapping that can only be read, from [`SharedMemory::seal_read_only`] or [`SharedMemory::open_sealed`]
+    ///
+    /// Only the read methods of `SharedMemory` are available, and the pages are mapped read-only underneath too
+    ///
+    /// ```compile_fail
+    /// use shared_memory::shared_memory::SharedMemory;
+    ///
+    /// let shm = SharedMemory::create("/sealed_doc", 4096).unwrap();
+    /// let sealed = shm.seal_read_only().unwrap();
+    /// sealed.write_u32_le(0, 1).unwrap();
+    /// ```
+    pub struct SealedSharedMemory {
+        shm: SharedMemory,
+    }
+
+    /// What a checked accessor is about to do with the bytes it asked for
+    #[derive(Clone, Copy, PartialEq, Eq)]
+    pub(crate) enum Access {
+        Read,
+        Write,
+    }
+
+    pub struct SharedMemory {
+        size: i32,
+        offset: u64,
+        name: CString,
+        /// The name as the caller gave it, `name` is what it was normalized to
+        logical_name: String,
+        /// Start of the view, `size` bytes of it are mapped for as long as the handle lives
+        p_buf: NonNull<u8>,
+
+        #[cfg(target_os = "windows")]
+        h_map_file: Option<OwnedHandle>,
+
+        #[cfg(target_os = "linux")]
+        fd: Option<OwnedFd>,
+        #[cfg(target_os = "linux")]
+        is_create: bool,
+        /// The object is a file in a backing directory rather than a shm name, `name` is its path
+        #[cfg(target_os = "linux")]
+        in_dir: bool,
+        /// Id of the System V segment attached with `shmat` instead of `mmap`, `fd` is -1 then
+        #[cfg(target_os = "linux")]
+        sysv_id: Option<c_int>,
+
+        /// Protection of each page set by `protect_range`, empty while every page is still read-write
+        protection: Vec<Protection>,
+        /// Layout of the heap allocation behind a `create_local` mapping, which has no OS object at all
+        local: Option<Layout>,
+        /// Generation of the last message `read_message_blocking` returned through this handle
+        last_notified: Cell<u32>,
+    }
+
+    impl SharedMemory {
+        pub fn size(&self) -> i32 {
+            self.size
+        }
+
+        /// Where the mapped window starts within the segment or file, this is 0 unless it was opened with an offset
+        pub fn offset(&self) -> u64 {
+            self.offset
+        }
+
+        /// The name this was created or opened with, before it was normalized for the platform
+        pub fn name(&self) -> String {
+            self.logical_name.clone()
+        }
+
+        /// The name actually handed to the OS, which only differs from `name` in normalization unless
+        /// `NamePolicy::HashWhenTooLong` had to shorten it
+        pub fn platform_name(&self) -> String {
+            self.name.to_string_lossy().into_owned()
+        }
+
+        #[cfg(target_os = "windows")]
+        pub fn address(&self) -> *mut win_c_void {
+            self.p_buf.as_ptr() as *mut win_c_void
+        }
+
+        #[cfg(target_os = "linux")]
+        pub fn address(&self) -> *mut lin_c_void {
+            self.p_buf.as_ptr() as *mut lin_c_void
+        }
+
+        /// Starts a [`SharedMemoryBuilder`] for the segment called `name`
+        pub fn builder(name: &str) -> SharedMemoryBuilder {
+            SharedMemoryBuilder::new(name)
+        }
+
+        pub fn create(name: &str, size: i32) -> Result<Self, io::Error> {
+            Ok(SharedMemory::builder(name).size(size).create()?)
+        }
+
+        /// Like `create`, with `policy` deciding what happens to a name that is too long for the platform
+        pub fn create_with_policy(name: &str, size: i32, policy: NamePolicy) -> Result<Self, io::Error> {
+            SharedMemory::create_or_reuse(name, size, policy, true, None)
+        }
+
+        /// Like `create_exclusive`, with `policy` deciding what happens to a name that is too long for the platform
+        pub fn create_exclusive_with_policy(name: &str, size: i32, policy: NamePolicy) -> Result<Self, io::Error> {
+            SharedMemory::create_new(name, size, policy, true, None)
+        }
+
+        /// Bytes left for new segments right now: the free space of the tmpfs at `/dev/shm` on Linux, and on Windows
+        /// how much more the system can commit
+        ///
+        /// Every process allocates from the same pool, so it's a snapshot for sizing segments to what's there
+        #[cfg(target_os = "linux")]
+        pub fn available_capacity() -> io::Result<u64> {
+            let path = CString::new(SHM_MOUNT).expect("the mount path has no NUL");
+            let mut stat: libc::statvfs = unsafe { std::mem::zeroed() };
+            if unsafe { libc::statvfs(path.as_ptr(), &mut stat) } == -1 {
+                return Err(io::Error::last_os_error());
+            }
+            Ok(statvfs_available(&stat))
+        }
+
+        /// Bytes left for new segments right now: the free space of the tmpfs at `/dev/shm` on Linux, and on Windows
+        /// how much more the system can commit
+        ///
+        /// Every process allocates from the same pool, so it's a snapshot for sizing segments to what's there
+        #[cfg(target_os = "windows")]
+        pub fn available_capacity() -> io::Result<u64> {
+            let mut status: MEMORYSTATUSEX = unsafe { std::mem::zeroed() };
+            status.dwLength = std::mem::size_of::<MEMORYSTATUSEX>() as DWORD;
+            if unsafe { GlobalMemoryStatusEx(&mut status) } == 0 {
+                return Err(io::Error::last_os_error());
+            }
+            Ok(status.ullAvailPageFile)
+        }
+
+        /// The error for a `CreateFileMappingA` that just failed, `InsufficientSpace` if the system couldn't commit
+        /// the section
+        #[cfg(target_os = "windows")]
+        fn create_mapping_error(name: &str, size: i32) -> io::Error {
+            let code = unsafe { GetLastError() };
+            let err = OsError::last(Operation::CreateFileMapping, name, Some(size));
+            if code != ERROR_COMMITMENT_LIMIT {
+                return err;
+            }
+            let available = SharedMemory::available_capacity().unwrap_or(0);
+            io::Error::new(io::ErrorKind::StorageFull, Error::InsufficientSpace { requested: size.max(0) as u64, available })
+        }
+
+        #[cfg(target_os = "windows")]
+        fn create_or_reuse(name: &str, size: i32, policy: NamePolicy, check_capacity: bool, dir: Option<&Path>) -> Result<Self, io::Error> {
+            match SharedMemory::create_new(name, size, policy, check_capacity, dir) {
+                Err(err) if err.kind() == io::ErrorKind::AlreadyExists => {}
+                created => return created,
+            }
+            // The section is already there, so as on Linux open it, refusing one smaller than `size`, and reset it
+            let t = SharedMemory::open_window(name, 0, size, policy, true, dir)?;
+            t.reset();
+            Ok(t)
+        }
+
+        #[cfg(target_os = "linux")]
+        fn create_or_reuse(name: &str, size: i32, policy: NamePolicy, check_capacity: bool, dir: Option<&Path>) -> Result<Self, io::Error> {
+            // In linux on program close shared memory areas don't automatically get deleted so if it already exists just pass along arguments to the open command
+            if let Ok(t) = SharedMemory::open_window(name, 0, size, policy, true, dir) {
+                // reset the data just in case
+                t.reset();
+                // return the shared memory
+                return Ok(t);
+            }
+            SharedMemory::create_new(name, size, policy, check_capacity, dir)
+        }
+
+        /// Creates the segment, failing with `AlreadyExists` if one with that name is already there
+        ///
+        /// `create` hands back an existing segment instead, wiped, so two processes can both believe they made it.
+        /// This is the one to use when exactly one of them may, it behaves the same on both platforms
+        pub fn create_exclusive(name: &str, size: i32) -> Result<Self, io::Error> {
+            Ok(SharedMemory::builder(name).size(size).exclusive(true).create()?)
+        }
+
+        /// Creates the segment, or opens it if it's already there and does what `mode` says with its contents
+        ///
+        /// A segment this creates is unlinked when the handle drops, one it opens is left alone like with `open`. On
+        /// Linux an existing segment smaller than `size` is grown to it first, Windows can't grow a section so
+        /// opening a smaller one fails. Zeroing is a plain write over the mapping, so a reader racing it can see it
+        /// half done, use `publish` and `consume` where readers need a coherent view across the restart
+        ///
+        /// The `bool` is whether this call created the segment, at most one of several racing callers gets `true`
+        pub fn open_or_create(name: &str, size: i32, mode: OpenMode) -> Result<(Self, bool), Error> {
+            SharedMemory::builder(name).size(size).open_mode(mode).open_or_create()
+        }
+
+        /// Extends the backing object so the whole mapping is backed, touching the pages past its end would raise
+        /// SIGBUS otherwise
+        #[cfg(target_os = "linux")]
+        fn grow_backing(&self) -> io::Result<()> {
+            let needed = file_offset(self.offset + self.size.max(0) as u64)?;
+            let fd = self.fd()?.as_raw_fd();
+            if !self.backing_covers_window()? && retry_eintr(|| unsafe { ftruncate(fd, needed) }) == -1 {
+                return Err(OsError::last(Operation::Ftruncate, &self.logical_name, Some(self.size)));
+            }
+            Ok(())
+        }
+
+        /// Creates the segment with `initial` already written behind an [`INIT_HEADER`], so no opener going through
+        /// `open_initialized` ever sees it empty or half written
+        ///
+        /// A segment can't be filled before its name exists, so instead the header's flag is set with a Release
+        /// store only once everything else is written, and `open_initialized` treats a segment without it as not
+        /// there yet. Fails with `AlreadyExists` rather than overwriting a segment someone else made. `size` is the
+        /// room after the header, the mapping itself is `INIT_HEADER` bytes bigger
+        pub fn create_with_data(name: &str, size: i32, initial: &[u8]) -> Result<Self, Error> {
+            let total = size
+                .checked_add(INIT_HEADER as i32)
+                .ok_or_else(|| io::Error::new(io::ErrorKind::InvalidInput, "initialized segment size overflows"))?;
+            if initial.len() > size.max(0) as usize {
+                return Err(Error::OutOfBounds { offset: INIT_HEADER, len: initial.len(), size: total as usize });
+            }
+            let shm = SharedMemory::create_exclusive(name, total)?;
+            shm.copy_from_slice_at(INIT_HEADER, initial)?;
+            shm.write_u64_le(8, initial.len() as u64)?;
+            shm.init_flag().store(INIT_READY, Ordering::Release);
+            Ok(shm)
+        }
+
+        /// Opens a segment made by `create_with_data` with the same `size`, failing with `NotFound` until its
+        /// initial contents are completely written
+        pub fn open_initialized(name: &str, size: i32) -> Result<Self, Error> {
+            let total = size
+                .checked_add(INIT_HEADER as i32)
+                .ok_or_else(|| io::Error::new(io::ErrorKind::InvalidInput, "initialized segment size overflows"))?;
+            let shm = SharedMemory::open_unsized(name, total, NamePolicy::Reject, None)?;
+            // The creator may not have sized the object yet, so check that before touching the flag's page
+            if !shm.backing_covers_window()? || shm.init_flag().load(Ordering::Acquire) != INIT_READY {
+                return Err(io::Error::new(io::ErrorKind::NotFound, "segment is still being initialized").into());
+            }
+            Ok(shm)
+        }
+
+        /// A copy of the contents `create_with_data` wrote, as they are now
+        pub fn initial_data(&self) -> Result<Vec<u8>, Error> {
+            let len = self.read_u64_le(8)?;
+            let len = usize::try_from(len).map_err(|_| Error::OutOfBounds { offset: INIT_HEADER, len: usize::MAX, size: self.size as usize })?;
+            self.read_owned_at(INIT_HEADER, len)
+        }
+
+        fn init_flag(&self) -> &AtomicU32 {
+            unsafe { &*(self.address() as *const AtomicU32) }
+        }
+
+        /// A mapping of `size` zeroed bytes on the heap instead of in an OS segment, for testing code that takes a
+        /// `SharedMemory` where shared memory isn't available, e.g. under Miri or on restricted CI machines
+        ///
+        /// Nothing else can open it: it has no name, isn't shared with other processes and is freed on drop. The
+        /// accessors, views and the structures built on a `&SharedMemory` work as usual within the process, their
+        /// futex waits and wakes just never see another process. `discard_range` zeroes instead of releasing, and
+        /// anything that needs the backing object, like `remap_window`, fails with an I/O error
+        #[cfg(feature = "testing")]
+        pub fn create_local(size: i32) -> Self {
+            let page = SharedMemory::page_size();
+            let len = (size.max(0) as usize).div_ceil(page).max(1) * page;
+            // Whole pages so protect_range and resident_pages behave like they do on a real mapping
+            let layout = Layout::from_size_align(len, page).expect("page multiple fits a layout");
+            let p_buf = unsafe { std::alloc::alloc_zeroed(layout) };
+            if p_buf.is_null() {
+                std::alloc::handle_alloc_error(layout);
+            }
+            SharedMemory {
+                size,
+                offset: 0,
+                name: CString::default(),
+                logical_name: String::new(),
+                p_buf: mapped(p_buf),
+                #[cfg(target_os = "windows")]
+                h_map_file: None,
+                #[cfg(target_os = "linux")]
+                fd: None,
+                #[cfg(target_os = "linux")]
+                is_create: false,
+                #[cfg(target_os = "linux")]
+                in_dir: false,
+                #[cfg(target_os = "linux")]
+                sysv_id: None,
+                protection: Vec::new(),
+                local: Some(layout),
+                last_notified: Cell::new(0),
+            }
+        }
+
+        /// Creates a segment under a new name made of `prefix` and a random suffix, returning the name to hand to
+        /// whoever should open it
+        ///
+        /// The prefix is shortened if the platform's length limit needs it, and a name that is already taken is
+        /// retried with a new suffix a few times. Like any created segment it is unlinked when this handle drops
+        pub fn create_unique(prefix: &str, size: i32) -> Result<(Self, String), Error> {
+            let mut attempts = 0;
+            loop {
+                let mut random = [0u8; 8];
+                getrandom::getrandom(&mut random).map_err(io::Error::from)?;
+                let name = unique_name(Platform::current(), prefix, u64::from_le_bytes(random))?;
+                match SharedMemory::create_exclusive(&name, size) {
+                    Ok(shm) => return Ok((shm, name)),
+                    Err(err) if err.kind() == io::ErrorKind::AlreadyExists && attempts < UNIQUE_ATTEMPTS => attempts += 1,
+                    Err(err) => return Err(err.into()),
+                }
+            }
+        }
+
+        #[cfg(target_os = "linux")]
+        fn create_new(name: &str, size: i32, policy: NamePolicy, check_capacity: bool, dir: Option<&Path>) -> Result<Self, io::Error> {
+            let name_c = object_name(name, policy, dir)?;
+            let fd = open_object(&name_c, dir.is_some(), O_RDWR | O_CREAT | O_EXCL);
+            if fd == -1 {
+                return Err(OsError::last(open_operation(dir), name, Some(size)));
+            }
+            let fd = unsafe { OwnedFd::from_raw_fd(fd) };
+            if check_capacity {
+                // Asked of the object's own filesystem, which is wherever the shm mount really is
+                let mut stat: libc::statvfs = unsafe { std::mem::zeroed() };
+                let checked = match unsafe { libc::fstatvfs(fd.as_raw_fd(), &mut stat) } {
+                    -1 => Err(io::Error::last_os_error()),
+                    _ => check_space(size, statvfs_available(&stat), SharedMemory::page_size() as u64),
+                };
+                if let Err(err) = checked {
+                    // The name was only just made, so it's this call's to take back
+                    remove_object(&name_c, dir.is_some());
+                    return Err(err);
+                }
+            }
+            if retry_eintr(|| unsafe { ftruncate(fd.as_raw_fd(), size as off_t) }) == -1 {
+                return Err(OsError::last(Operation::Ftruncate, name, Some(size)));
+            }
+            let p_buf = unsafe {
+                mmap(
+                    ptr::null_mut(),
+                    size as size_t,
+                    PROT_READ | PROT_WRITE,
+                    MAP_SHARED,
+                    fd.as_raw_fd(),
+                    0,
+                )
+            };
+            if p_buf == libc::MAP_FAILED {
+                return Err(OsError::last(Operation::Mmap, name, Some(size)));
+            }
+            let shared_memory = SharedMemory {
+                size,
+                offset: 0,
+                name: name_c,
+                logical_name: name.to_string(),
+                fd: Some(fd),
+                p_buf: mapped(p_buf),
+                protection: Vec::new(),
+                local: None,
+                last_notified: Cell::new(0),
+                is_create: true,
+                in_dir: dir.is_some(),
+                sysv_id: None,
+            };
+            Ok(shared_memory)
+        }
+
+        /// The System V IPC key for `path` and `proj_id`, the same one a C program gets from `ftok`
+        #[cfg(target_os = "linux")]
+        pub fn ftok(path: &Path, proj_id: u8) -> io::Result<i32> {
+            let path_c = CString::new(path.as_os_str().as_bytes()).map_err(|err| io::Error::new(io::ErrorKind::InvalidInput, err))?;
+            match unsafe { libc::ftok(path_c.as_ptr(), proj_id as c_int) } {
+                -1 => Err(OsError::last(Operation::Ftok, &path.to_string_lossy(), None)),
+                key => Ok(key),
+            }
+        }
+
+        /// Creates the System V segment for `key` with `shmget`, or attaches to it if it's already there, for
+        /// talking to programs that use `shmget` and `shmat` rather than POSIX shm
+        ///
+        /// Everything that works on the mapping works the same as on a POSIX segment. If this handle created the
+        /// segment it marks it for removal with `IPC_RMID` when dropped, and the kernel frees it once every process
+        /// has detached, a segment that was already there is left alone like with `open_sysv`. Anything that needs a
+        /// file descriptor, like `remap_window` or sealing, fails with an I/O error
+        #[cfg(target_os = "linux")]
+        pub fn create_sysv(key: i32, size: i32) -> Result<Self, Error> {
+            let id = unsafe { libc::shmget(key, size.max(0) as size_t, libc::IPC_CREAT | libc::IPC_EXCL | 0o600) };
+            if id != -1 {
+                return SharedMemory::attach_sysv(key, id, size, true);
+            }
+            if io::Error::last_os_error().raw_os_error() != Some(libc::EEXIST) {
+                return Err(OsError::last(Operation::Shmget, &format!("sysv:{:#x}", key), Some(size)).into());
+            }
+            let id = unsafe { libc::shmget(key, 0, 0) };
+            if id == -1 {
+                return Err(OsError::last(Operation::Shmget, &format!("sysv:{:#x}", key), Some(size)).into());
+            }
+            // Attaching past the end of the existing segment would fault on the first access there
+            if SharedMemory::sysv_stat_of(id, &format!("sysv:{:#x}", key))?.size < size.max(0) as usize {
+                return Err(io::Error::new(io::ErrorKind::InvalidInput, "existing System V segment is smaller than requested").into());
+            }
+            SharedMemory::attach_sysv(key, id, size, false)
+        }
+
+        /// Attaches to the System V segment for `key` that something else created, taking its size from the segment
+        #[cfg(target_os = "linux")]
+        pub fn open_sysv(key: i32) -> Result<Self, Error> {
+            let id = unsafe { libc::shmget(key, 0, 0) };
+            if id == -1 {
+                return Err(OsError::last(Operation::Shmget, &format!("sysv:{:#x}", key), None).into());
+            }
+            let size = SharedMemory::sysv_stat_of(id, &format!("sysv:{:#x}", key))?.size;
+            let size = i32::try_from(size).map_err(|_| io::Error::new(io::ErrorKind::InvalidData, "System V segment is too large to map"))?;
+            SharedMemory::attach_sysv(key, id, size, false)
+        }
+
+        #[cfg(target_os = "linux")]
+        fn attach_sysv(key: i32, id: c_int, size: i32, is_create: bool) -> Result<Self, Error> {
+            let p_buf = unsafe { libc::shmat(id, ptr::null(), 0) };
+            if p_buf as isize == -1 {
+                return Err(OsError::last(Operation::Shmat, &format!("sysv:{:#x}", key), Some(size)).into());
+            }
+            Ok(SharedMemory {
+                size,
+                offset: 0,
+                name: CString::default(),
+                logical_name: format!("sysv:{:#x}", key),
+                fd: None,
+                p_buf: mapped(p_buf),
+                protection: Vec::new(),
+                local: None,
+                last_notified: Cell::new(0),
+                is_create,
+                in_dir: false,
+                sysv_id: Some(id),
+            })
+        }
+
+        /// Size, attach count and owner of the System V segment this handle is attached to
+        #[cfg(target_os = "linux")]
+        pub fn sysv_stat(&self) -> io::Result<SysvStat> {
+            match self.sysv_id {
+                Some(id) => SharedMemory::sysv_stat_of(id, &self.logical_name),
+                None => Err(io::Error::new(io::ErrorKind::Unsupported, "not a System V segment")),
+            }
+        }
+
+        #[cfg(target_os = "linux")]
+        fn sysv_stat_of(id: c_int, segment: &str) -> io::Result<SysvStat> {
+            let mut ds: libc::shmid_ds = unsafe { std::mem::zeroed() };
+            if unsafe { libc::shmctl(id, libc::IPC_STAT, &mut ds) } == -1 {
+                return Err(OsError::last(Operation::Shmctl, segment, None));
+            }
+            Ok(SysvStat {
+                size: ds.shm_segsz as usize,
+                attached: ds.shm_nattch as u64,
+                owner_uid: ds.shm_perm.uid,
+                creator_pid: ds.shm_cpid,
+            })
+        }
+
+        #[cfg(target_os = "windows")]
+        fn create_new(name: &str, size: i32, policy: NamePolicy, _check_capacity: bool, _dir: Option<&Path>) -> Result<Self, io::Error> {
+            let name_c = normalize_name(name, policy)?.into_c_string();
+            let h_map_file = unsafe {
+                CreateFileMappingA(
+                    INVALID_HANDLE_VALUE,
+                    ptr::null_mut(),
+                    PAGE_READWRITE | SEC_COMMIT,
+                    0,
+                    size as DWORD,
+                    name_c.as_ptr(),
+                )
+            };
+            if h_map_file.is_null() {
+                return Err(SharedMemory::create_mapping_error(name, size));
+            }
+            let h_map_file = unsafe { OwnedHandle::from_raw_handle(h_map_file as RawHandle) };
+            // An existing section is handed back as if it had been created, only the last error tells them apart
+            if unsafe { GetLastError() } == ERROR_ALREADY_EXISTS {
+                return Err(OsError::last(Operation::CreateFileMapping, name, Some(size)));
+            }
+            let p_buf = match SharedMemory::map_view(h_map_file.as_handle(), 0, size, FILE_MAP_ALL_ACCESS) {
+                Ok(p_buf) => p_buf,
+                Err(err) => return Err(OsError::wrap(Operation::MapViewOfFile, name, Some(size), err)),
+            };
+            Ok(SharedMemory {
+                size,
+                offset: 0,
+                name: name_c,
+                logical_name: name.to_string(),
+                h_map_file: Some(h_map_file),
+                p_buf,
+                protection: Vec::new(),
+                local: None,
+                last_notified: Cell::new(0),
+            })
+        }
+
+        /// Opens an existing segment, mapping its first `size` bytes
+        ///
+        /// A `size` past the end of the segment fails with [`Error::SizeMismatch`] (inside the `io::Error`) rather
+        /// than mapping pages that would raise SIGBUS when touched, a smaller one maps just that much of it
+        pub fn open(name: &str, size: i32) -> Result<Self, io::Error> {
+            Ok(SharedMemory::builder(name).size(size).open()?)
+        }
+
+        /// Like `open`, `policy` has to be the one the segment was created with
+        pub fn open_with_policy(name: &str, size: i32, policy: NamePolicy) -> Result<Self, io::Error> {
+            Ok(SharedMemory::builder(name).size(size).name_policy(policy).open()?)
+        }
+
+        /// Opens a window of `size` bytes starting `offset` bytes into an existing segment
+        ///
+        /// `offset` has to be a multiple of [`SharedMemory::offset_alignment`], and `size()` along with every read and write is relative to the window
+        pub fn open_with_offset(name: &str, offset: u64, size: i32) -> Result<Self, Error> {
+            SharedMemory::builder(name).size(size).offset(offset).open()
+        }
+
+        /// Like `open` without the size check, for callers that wait for or fix up a backing that is still short
+        fn open_unsized(name: &str, size: i32, policy: NamePolicy, dir: Option<&Path>) -> Result<Self, Error> {
+            SharedMemory::open_window(name, 0, size, policy, false, dir)
+        }
+
+        /// Opens an existing segment with whatever size it has
+        pub fn open_existing(name: &str) -> Result<Self, Error> {
+            SharedMemory::builder(name).open()
+        }
+
+        /// Like `open_existing`, `policy` has to be the one the segment was created with
+        #[cfg(target_os = "linux")]
+        pub fn open_existing_with_policy(name: &str, policy: NamePolicy) -> Result<Self, Error> {
+            SharedMemory::open_existing_in(name, policy, None)
+        }
+
+        #[cfg(target_os = "linux")]
+        fn open_existing_in(name: &str, policy: NamePolicy, dir: Option<&Path>) -> Result<Self, Error> {
+            let object = object_name(name, policy, dir)?;
+            let fd = open_object(&object, dir.is_some(), libc::O_RDONLY);
+            if fd == -1 {
+                return Err(OsError::last(open_operation(dir), name, None).into());
+            }
+            let fd = unsafe { OwnedFd::from_raw_fd(fd) };
+            let size = SharedMemory::backing_size(fd.as_fd());
+            let size = i32::try_from(size?).map_err(|_| io::Error::new(io::ErrorKind::InvalidData, "segment is too large to map"))?;
+            SharedMemory::open_window(name, 0, size, policy, true, dir)
+        }
+
+        #[cfg(target_os = "windows")]
+        fn open_existing_in(name: &str, policy: NamePolicy, _dir: Option<&Path>) -> Result<Self, Error> {
+            SharedMemory::open_existing_with_policy(name, policy)
+        }
+
+        /// Like `open_existing`, `policy` has to be the one the section was created with. The size is rounded up to
+        /// whole pages, Windows doesn't keep the one it was created with
+        #[cfg(target_os = "windows")]
+        pub fn open_existing_with_policy(name: &str, policy: NamePolicy) -> Result<Self, Error> {
+            let name_c = normalize_name(name, policy)?.into_c_string();
+            let h_map_file = unsafe { OpenFileMappingA(FILE_MAP_ALL_ACCESS, FALSE, name_c.as_ptr()) };
+            if h_map_file.is_null() {
+                return Err(OsError::last(Operation::OpenFileMapping, name, None).into());
+            }
+            let h_map_file = unsafe { OwnedHandle::from_raw_handle(h_map_file as RawHandle) };
+            // A view of size 0 covers the whole section
+            let p_buf = match SharedMemory::map_view(h_map_file.as_handle(), 0, 0, FILE_MAP_ALL_ACCESS) {
+                Ok(p_buf) => p_buf,
+                Err(err) => return Err(OsError::wrap(Operation::MapViewOfFile, name, None, err).into()),
+            };
+            let mut info: MEMORY_BASIC_INFORMATION = unsafe { std::mem::zeroed() };
+            unsafe { VirtualQuery(p_buf.as_ptr() as *const win_c_void, &mut info, std::mem::size_of::<MEMORY_BASIC_INFORMATION>()) };
+            Ok(SharedMemory {
+                size: info.RegionSize.min(i32::MAX as usize) as i32,
+                offset: 0,
+                name: name_c,
+                logical_name: name.to_string(),
+                h_map_file: Some(h_map_file),
+                p_buf,
+                protection: Vec::new(),
+                local: None,
+                last_notified: Cell::new(0),
+            })
+        }
+
+        /// MapViewOfFile already refuses a view past the end of the section, so there is no size to check up front
+        #[cfg(target_os = "windows")]
+        fn open_window(name: &str, offset: u64, size: i32, policy: NamePolicy, _check_size: bool, _dir: Option<&Path>) -> Result<Self, Error> {
+            SharedMemory::check_offset(offset)?;
+            let name_c = normalize_name(name, policy)?.into_c_string();
+            let h_map_file = unsafe {
+                OpenFileMappingA(
+                    FILE_MAP_ALL_ACCESS,
+                    FALSE,
+                    name_c.as_ptr(),
+                )
+            };
+            if h_map_file.is_null() {
+                return Err(OsError::last(Operation::OpenFileMapping, name, Some(size)).into());
+            }
+            let h_map_file = unsafe { OwnedHandle::from_raw_handle(h_map_file as RawHandle) };
+            let p_buf = match SharedMemory::map_view(h_map_file.as_handle(), offset, size, FILE_MAP_ALL_ACCESS) {
+                Ok(p_buf) => p_buf,
+                Err(err) => return Err(OsError::wrap(Operation::MapViewOfFile, name, Some(size), err).into()),
+            };
+            let shared_memory = SharedMemory {
+                size,
+                offset,
+                name: name_c,
+                logical_name: name.to_string(),
+                h_map_file: Some(h_map_file),
+                p_buf,
+                protection: Vec::new(),
+                local: None,
+                last_notified: Cell::new(0),
+            };
+            Ok(shared_memory)
+        }
+
+        /// Maps the whole of an already open `file` shared read-write, for files that can't be opened again by path
+        ///
+        /// With `len` the file is first extended or cut to that many bytes, without it the mapping takes the file's
+        /// current length, and an empty file fails with `InvalidInput` since there would be nothing to map. The
+        /// handle takes over the file and closes it when dropped, and `name()` is empty as there is no path to give
+        pub fn from_file(file: File, len: Option<usize>) -> Result<Self, Error> {
+            let len = match len {
+                // Checked before resizing so a length that can't be mapped leaves the file alone
+                Some(len) if i32::try_from(len).is_err() => {
+                    return Err(io::Error::new(io::ErrorKind::InvalidInput, "the file is too large to map").into());
+                }
+                Some(len) => {
+                    file.set_len(len as u64)?;
+                    len as u64
+                }
+                None => file.metadata()?.len(),
+            };
+            if len == 0 {
+                return Err(io::Error::new(io::ErrorKind::InvalidInput, "the file is empty, set its length first or pass one to from_file").into());
+            }
+            let size = i32::try_from(len).map_err(|_| io::Error::new(io::ErrorKind::InvalidInput, "the file is too large to map"))?;
+            SharedMemory::map_file(file, size)
+        }
+
+        #[cfg(target_os = "windows")]
+        fn map_file(file: File, size: i32) -> Result<Self, Error> {
+            // The mapping object keeps its own reference to the file, as in `open_file`
+            let h_map_file = unsafe { CreateFileMappingA(file.as_raw_handle() as HANDLE, ptr::null_mut(), PAGE_READWRITE, 0, 0, ptr::null()) };
+            let err = OsError::last(Operation::CreateFileMapping, "", Some(size));
+            drop(file);
+            if h_map_file.is_null() {
+                return Err(err.into());
+            }
+            let h_map_file = unsafe { OwnedHandle::from_raw_handle(h_map_file as RawHandle) };
+            let p_buf = match SharedMemory::map_view(h_map_file.as_handle(), 0, size, FILE_MAP_ALL_ACCESS) {
+                Ok(p_buf) => p_buf,
+                Err(err) => return Err(OsError::wrap(Operation::MapViewOfFile, "", Some(size), err).into()),
+            };
+            Ok(SharedMemory {
+                size,
+                offset: 0,
+                name: CString::default(),
+                logical_name: String::new(),
+                h_map_file: Some(h_map_file),
+                p_buf,
+                protection: Vec::new(),
+                local: None,
+                last_notified: Cell::new(0),
+            })
+        }
+
+        #[cfg(target_os = "linux")]
+        fn map_file(file: File, size: i32) -> Result<Self, Error> {
+            let fd = OwnedFd::from(file);
+            let p_buf = match SharedMemory::map_fd(fd.as_fd(), 0, size, PROT_READ | PROT_WRITE) {
+                Ok(p_buf) => p_buf,
+                Err(err) => return Err(OsError::wrap(Operation::Mmap, "", Some(size), err).into()),
+            };
+            Ok(SharedMemory {
+                size,
+                offset: 0,
+                name: CString::default(),
+                logical_name: String::new(),
+                fd: Some(fd),
+                p_buf,
+                protection: Vec::new(),
+                local: None,
+                last_notified: Cell::new(0),
+                is_create: false,
+                in_dir: false,
+                sysv_id: None,
+            })
+        }
+
+        /// Maps a window of `size` bytes starting `offset` bytes into the file at `path`
+        ///
+        /// The file has to already exist and be at least `offset + size` bytes long, it's never created, resized or deleted
+        #[cfg(target_os = "windows")]
+        pub fn open_file(path: &Path, offset: u64, size: i32) -> Result<Self, Error> {
+            SharedMemory::check_offset(offset)?;
+            let name_c = CString::new(path.to_string_lossy().as_bytes()).expect("CSTRING::new failed");
+            let h_file = unsafe {
+                CreateFileA(
+                    name_c.as_ptr(),
+                    GENERIC_READ | GENERIC_WRITE,
+                    FILE_SHARE_READ | FILE_SHARE_WRITE,
+                    ptr::null_mut(),
+                    OPEN_EXISTING,
+                    FILE_ATTRIBUTE_NORMAL,
+                    ptr::null_mut(),
+                )
+            };
+            if h_file == INVALID_HANDLE_VALUE {
+                return Err(OsError::last(Operation::CreateFile, &path.to_string_lossy(), Some(size)).into());
+            }
+            let h_file = unsafe { OwnedHandle::from_raw_handle(h_file as RawHandle) };
+            // The mapping object keeps its own reference to the file, so the file handle can be closed straight away
+            let h_map_file = unsafe {
+                CreateFileMappingA(
+                    h_file.as_raw_handle() as HANDLE,
+                    ptr::null_mut(),
+                    PAGE_READWRITE,
+                    0,
+                    0,
+                    ptr::null(),
+                )
+            };
+            let err = OsError::last(Operation::CreateFileMapping, &path.to_string_lossy(), Some(size));
+            drop(h_file);
+            if h_map_file.is_null() {
+                return Err(err.into());
+            }
+            let h_map_file = unsafe { OwnedHandle::from_raw_handle(h_map_file as RawHandle) };
+            let p_buf = match SharedMemory::map_view(h_map_file.as_handle(), offset, size, FILE_MAP_ALL_ACCESS) {
+                Ok(p_buf) => p_buf,
+                Err(err) => return Err(OsError::wrap(Operation::MapViewOfFile, &path.to_string_lossy(), Some(size), err).into()),
+            };
+            let shared_memory = SharedMemory {
+                size,
+                offset,
+                name: name_c,
+                logical_name: path.to_string_lossy().into_owned(),
+                h_map_file: Some(h_map_file),
+                p_buf,
+                protection: Vec::new(),
+                local: None,
+                last_notified: Cell::new(0),
+            };
+            Ok(shared_memory)
+        }
+
+        #[cfg(target_os = "windows")]
+        fn map_view(h_map_file: BorrowedHandle<'_>, offset: u64, size: i32, access: DWORD) -> Result<NonNull<u8>, io::Error> {
+            let p_buf = unsafe {
+                MapViewOfFile(
+                    h_map_file.as_raw_handle() as HANDLE,
+                    access,
+                    (offset >> 32) as DWORD,
+                    offset as DWORD,
+                    size as SIZE_T
+                )
+            };
+            NonNull::new(p_buf as *mut u8).ok_or_else(io::Error::last_os_error)
+        }
+
+        /// The granularity mapping offsets have to be aligned to, this is the page size on Linux and the allocation granularity on Windows
+        #[cfg(target_os = "windows")]
+        pub fn offset_alignment() -> u64 {
+            let mut info: SYSTEM_INFO = unsafe { std::mem::zeroed() };
+            unsafe {
+                GetSystemInfo(&mut info);
+            }
+            info.dwAllocationGranularity as u64
+        }
+
+        #[cfg(target_os = "linux")]
+        pub fn offset_alignment() -> u64 {
+            unsafe { sysconf(_SC_PAGESIZE) as u64 }
+        }
+
+        fn check_offset(offset: u64) -> Result<(), Error> {
+            let alignment = SharedMemory::offset_alignment();
+            if !offset.is_multiple_of(alignment) {
+                return Err(Error::UnalignedOffset { offset, alignment });
+            }
+            // Also rules out overflow when a window's size is added to it, that's at most i32::MAX
+            #[cfg(target_os = "linux")]
+            file_offset(offset.saturating_add(i32::MAX as u64)).map_err(|_| Error::FileOffsetTooLarge { offset, max: off_t::MAX as u64 - i32::MAX as u64 })?;
+            Ok(())
+        }
+
+        /// Like `try_write_data`, printing any error to stderr instead of returning it, so use `try_write_data` where
+        /// the caller needs to know the write didn't happen
+        pub fn write_data(&self, data: &[u8]) {
+            match self.try_write_data(data) {
+                Ok(()) => {}
+                Err(Error::OutOfBounds { .. }) => eprintln!("Error: Attempted to write data with a size larger than the shared memory region"),
+                Err(err) => eprintln!("Error: Failed to write data to the shared memory region: {}", err),
+            }
+        }
+
+        /// Writes `data` at the start of the mapping and zeroes the rest, so `read_data` ends where `data` does
+        pub fn try_write_data(&self, data: &[u8]) -> Result<(), Error> {
+            self.copy_from_slice_at(0, data)?;
+            let rest = self.size as usize - data.len();
+            let ptr = self.byte_ptr(data.len(), rest, Access::Write)?;
+            unsafe { ptr::write_bytes(ptr, 0, rest) };
+            Ok(())
+        }
+
+        /// The mapping up to its last non-zero byte, borrowed straight from the shared pages
+        ///
+        /// The slice claims to be immutable for as long as it is borrowed, but another process (or another handle in
+        /// this one) can still write to the same memory, which is undefined behaviour for a `&[u8]` and can make two
+        /// reads of the same byte disagree. Only borrow while nothing else can write, and use `read_data_owned` or
+        /// `read_owned_at` whenever a concurrent writer might exist
+        pub fn read_data(&self) -> &[u8] {
+            let bytes = self.bytes();
+            // Check for empty bits at the end of the data
+            let len = bytes.iter().rposition(|&byte| byte != 0).map_or(0, |last| last + 1);
+            &bytes[..len]
+        }
+
+        /// A copy of what `read_data` would return, taken in one pass over the mapping so it can't change underneath
+        /// the caller afterwards
+        ///
+        /// A writer running during the copy can still leave a mix of old and new bytes in it, the copy is only
+        /// guaranteed to be the caller's own
+        pub fn read_data_owned(&self) -> Vec<u8> {
+            let mut data = self.snapshot();
+            let len = data.iter().rposition(|&byte| byte != 0).map_or(0, |last| last + 1);
+            data.truncate(len);
+            data
+        }
+
+        /// A copy of the `len` bytes at `offset`, taken in one pass
+        pub fn read_owned_at(&self, offset: usize, len: usize) -> Result<Vec<u8>, Error> {
+            // Checked before allocating too, `len` often comes out of the segment itself
+            self.byte_ptr(offset, len, Access::Read)?;
+            let mut data = vec![0u8; len];
+            self.copy_to_slice_at(offset, &mut data)?;
+            Ok(data)
+        }
+
+        pub fn write_string(&mut self, data: &str) {
+            self.write_data(data.as_bytes());
+        }
+
+        pub fn read_string(&self) -> String {
+            let bytes = self.read_data();
+            String::from_utf8_lossy(bytes).to_string()
+        }
+
+        pub fn reset(&self) {
+            let empty_bytes = vec![0u8; self.size as usize];
+            self.write_data(empty_bytes.as_slice());
+        }
+
+        /// Whether both mappings hold exactly the same bytes, without copying either of them
+        pub fn content_eq(&self, other: &SharedMemory) -> bool {
+            if self.size != other.size {
+                return false;
+            }
+            // Comparing a mapping against itself (or another view of the same address) is trivially equal
+            if self.address() == other.address() {
+                return true;
+            }
+            self.bytes() == other.bytes()
+        }
+
+        /// Whether the mapping holds exactly `other`, the whole mapping is compared so `other` has to be `size()` bytes long to match
+        pub fn content_eq_slice(&self, other: &[u8]) -> bool {
+            if self.size as usize != other.len() {
+                return false;
+            }
+            self.bytes() == other
+        }
+
+        /// The byte ranges where the two mappings differ with neighbouring differences merged, meant for debugging
+        ///
+        /// If one mapping is longer, the bytes past the end of the shorter one are reported as a single differing range
+        pub fn diff_ranges(&self, other: &SharedMemory) -> Vec<Range<usize>> {
+            let mut ranges: Vec<Range<usize>> = Vec::new();
+            if self.address() == other.address() && self.size == other.size {
+                return ranges;
+            }
+            let ours = self.bytes();
+            let theirs = other.bytes();
+            let common = ours.len().min(theirs.len());
+            for idx in 0..common {
+                if ours[idx] == theirs[idx] {
+                    continue;
+                }
+                match ranges.last_mut() {
+                    Some(last) if last.end == idx => last.end = idx + 1,
+                    _ => ranges.push(idx..idx + 1),
+                }
+            }
+            let longest = ours.len().max(theirs.len());
+            if common < longest {
+                match ranges.last_mut() {
+                    Some(last) if last.end == common => last.end = longest,
+                    _ => ranges.push(common..longest),
+                }
+            }
+            ranges
+        }
+
+        /// Copies the whole mapping out into an owned buffer
+        ///
+        /// Nothing stops another process writing while the copy is taken, so with concurrent writers the snapshot can mix old and new data
+        pub fn snapshot(&self) -> Vec<u8> {
+            self.bytes().to_vec()
+        }
+
+        /// The whole mapping in `chunk_size` byte pieces, the last one shorter if the size isn't a multiple
+        ///
+        /// Like `read_data` the chunks borrow the shared pages, so only iterate while nothing else writes to them.
+        /// Panics if `chunk_size` is 0, as `slice::chunks` does
+        pub fn chunks(&self, chunk_size: usize) -> std::slice::Chunks<'_, u8> {
+            self.bytes().chunks(chunk_size)
+        }
+
+        /// The whole mapping in disjoint writable `chunk_size` byte pieces, which can be handed to separate threads
+        ///
+        /// Panics if `chunk_size` is 0, as `slice::chunks_mut` does
+        pub fn chunks_mut(&mut self, chunk_size: usize) -> std::slice::ChunksMut<'_, u8> {
+            self.bytes_mut().chunks_mut(chunk_size)
+        }
+
+        /// Offset of the first occurrence of `needle` at or after `start`
+        ///
+        /// An empty needle matches at `start` itself, and a `start` past the end or a needle longer than what's left
+        /// finds nothing. Another process can change the bytes during or right after the search, so with concurrent
+        /// writers a result is only a hint to check again. With the `memchr` feature the search uses its SIMD routines
+        pub fn find(&self, needle: &[u8], start: usize) -> Option<usize> {
+            let haystack = self.bytes().get(start..)?;
+            search::find(haystack, needle).map(|found| start + found)
+        }
+
+        /// Offset of the last occurrence of `needle`, an empty needle matching at `size()`
+        pub fn rfind(&self, needle: &[u8]) -> Option<usize> {
+            search::rfind(self.bytes(), needle)
+        }
+
+        /// Offsets of every occurrence of `needle` in increasing order, overlapping ones included
+        ///
+        /// Each next offset is a fresh `find` from one past the previous, so like `find` the offsets are advisory
+        /// when something else is writing
+        pub fn find_all<'a>(&'a self, needle: &'a [u8]) -> impl Iterator<Item = usize> + 'a {
+            std::iter::successors(self.find(needle, 0), move |&found| self.find(needle, found + 1))
+        }
+
+        /// Copies `data` back over the start of the mapping, typically a buffer returned by `snapshot`
+        ///
+        /// Bytes past the end of `data` are left as they are, and data longer than the mapping is rejected without writing anything
+        pub fn restore(&mut self, data: &[u8]) -> Result<(), Error> {
+            self.copy_from_slice_at(0, data)
+        }
+
+        /// Writes the whole mapping to `path`, going through a temporary file next to it and a rename so a crash never leaves a partial dump behind
+        ///
+        /// The temporary file is named after `path`, the process and a counter and created exclusively, so dumps to
+        /// the same path racing from other threads or processes each get their own
+        pub fn dump_to_file(&self, path: &Path) -> io::Result<()> {
+            static DUMPS: AtomicU64 = AtomicU64::new(0);
+            let (tmp_path, mut file) = loop {
+                let mut tmp_path = path.as_os_str().to_owned();
+                tmp_path.push(format!(".{}.{}.tmp", std::process::id(), DUMPS.fetch_add(1, Ordering::Relaxed)));
+                let tmp_path = PathBuf::from(tmp_path);
+                // One left behind by a crashed process that had the same pid is stepped over
+                match File::options().write(true).create_new(true).open(&tmp_path) {
+                    Ok(file) => break (tmp_path, file),
+                    Err(err) if err.kind() == io::ErrorKind::AlreadyExists => continue,
+                    Err(err) => return Err(err),
+                }
+            };
+            let result = (|| {
+                file.write_all(self.bytes())?;
+                file.sync_all()?;
+                fs::rename(&tmp_path, path)
+            })();
+            if result.is_err() {
+                let _ = fs::remove_file(&tmp_path);
+            }
+            result
+        }
+
+        /// Copies the contents of `path` over the start of the mapping and returns how many bytes were loaded
+        ///
+        /// A file larger than the mapping is rejected before anything is written
+        pub fn load_from_file(&mut self, path: &Path) -> io::Result<usize> {
+            let data = fs::read(path)?;
+            self.restore(&data)?;
+            Ok(data.len())
+        }
+
+        /// Formats `range` as classic `offset | hex bytes | ascii` lines, 16 bytes per line
+        ///
+        /// The range is clamped to the mapping instead of failing: bytes past its end are left out, and a range that
+        /// starts past the end of the mapping or past its own end comes back empty, so it's always safe to ask for
+        /// more than there is
+        pub fn hexdump(&self, range: Range<usize>) -> String {
+            let mut out = String::new();
+            self.hexdump_to(&mut out, range).expect("writing to a String can't fail");
+            out
+        }
+
+        /// Same as `hexdump` but streams the lines into `w`, clamping `range` the same way
+        pub fn hexdump_to(&self, w: &mut impl fmt::Write, range: Range<usize>) -> fmt::Result {
+            let bytes = self.bytes();
+            let end = range.end.min(bytes.len());
+            let start = range.start.min(end);
+            for (idx, line) in bytes[start..end].chunks(16).enumerate() {
+                write!(w, "{:08x} |", start + idx * 16)?;
+                for byte in line {
+                    write!(w, " {:02x}", byte)?;
+                }
+                for _ in line.len()..16 {
+                    w.write_str("   ")?;
+                }
+                w.write_str(" | ")?;
+                for &byte in line {
+                    let c = if byte.is_ascii_graphic() || byte == b' ' { byte as char } else { '.' };
+                    w.write_char(c)?;
+                }
+                w.write_char('\n')?;
+            }
+            Ok(())
+        }
+
+        /// Hexdump of the `context` bytes either side of `center`, for "show me what's around offset X", cut short
+        /// where the mapping ends
+        pub fn debug_window(&self, center: usize, context: usize) -> String {
+            self.hexdump(center.saturating_sub(context)..center.saturating_add(context).saturating_add(1))
+        }
+
+        /// Copies the `T` stored at `offset` out of the mapping, `offset` has to be aligned for `T`
+        pub fn read_value<T: SharedSafe>(&self, offset: usize) -> Result<T, Error> {
+            let ptr = self.typed_ptr::<T>(offset, Access::Read)?;
+            Ok(unsafe { ptr::read(ptr) })
+        }
+
+        /// Stores `value` at `offset`, which has to be aligned for `T`
+        pub fn write_value<T: SharedSafe>(&self, offset: usize, value: T) -> Result<(), Error> {
+            let ptr = self.typed_ptr::<T>(offset, Access::Write)?;
+            unsafe { ptr::write(ptr, value) };
+            Ok(())
+        }
+
+        /// Writes `data` behind a [`PUBLISH_HEADER`] at `offset` so that a reader calling `consume` sees either all
+        /// of it or nothing, `offset` has to be 8-aligned and there may only be one publisher per offset
+        ///
+        /// Plain copies into shared memory come with no ordering: on weakly ordered CPUs (ARM, POWER) another core
+        /// can see a flag written after the payload before the payload itself. Here the sequence word is made odd,
+        /// then a Release fence keeps the payload stores from moving above it, and the final Release store of the
+        /// next even value keeps them from moving below it. A reader whose Acquire load sees that even value is
+        /// guaranteed to see every payload byte written before it
+        pub fn publish(&self, offset: usize, data: &[u8]) -> Result<(), Error> {
+            // Check the whole payload fits before touching the sequence word
+            self.byte_ptr(offset, PUBLISH_HEADER.saturating_add(data.len()), Access::Write)?;
+            let (seq, len) = self.publish_words(offset, Access::Write)?;
+            // Only the publisher writes the word, so its own last value can be read without ordering
+            let current = seq.load(Ordering::Relaxed);
+            seq.store(current | 1, Ordering::Relaxed);
+            fence(Ordering::Release);
+            len.store(data.len() as u64, Ordering::Relaxed);
+            self.copy_from_slice_at(offset + PUBLISH_HEADER, data)?;
+            seq.store((current | 1) + 1, Ordering::Release);
+            Ok(())
+        }
+
+        /// Copies the payload last published at `offset` into `buf` and returns its length, `None` if nothing has
+        /// been published yet or a `publish` was in progress during the copy, in which case trying again later works
+        ///
+        /// Only the first `buf.len()` bytes are copied if the payload is longer. The Acquire load of the sequence
+        /// word pairs with the publisher's final Release store, and the Acquire fence after the copy keeps the
+        /// copy's loads from moving below the second check of the word
+        pub fn consume(&self, offset: usize, buf: &mut [u8]) -> Result<Option<usize>, Error> {
+            let (seq, len) = self.publish_words(offset, Access::Read)?;
+            let before = seq.load(Ordering::Acquire);
+            if before == 0 || before & 1 == 1 {
+                return Ok(None);
+            }
+            let payload = usize::try_from(len.load(Ordering::Relaxed))
+                .map_err(|_| Error::OutOfBounds { offset: offset + PUBLISH_HEADER, len: usize::MAX, size: self.size as usize })?;
+            // A torn length can be nonsense, so bound it by the mapping rather than trusting it
+            let available = (self.size as usize).saturating_sub(offset + PUBLISH_HEADER);
+            let copied = payload.min(buf.len()).min(available);
+            self.copy_to_slice_at(offset + PUBLISH_HEADER, &mut buf[..copied])?;
+            fence(Ordering::Acquire);
+            if seq.load(Ordering::Relaxed) != before || payload > available {
+                return Ok(None);
+            }
+            Ok(Some(payload))
+        }
+
+        fn publish_words(&self, offset: usize, access: Access) -> Result<(&AtomicU64, &AtomicU64), Error> {
+            self.byte_ptr(offset, PUBLISH_HEADER, access)?;
+            let seq = self.typed_ptr::<AtomicU64>(offset, access)?;
+            Ok(unsafe { (&*seq, &*seq.add(1)) })
+        }
+
+        /// Writes `data` at the start of the mapping behind a [`NOTIFY_HEADER`] and wakes every handle blocked in
+        /// `read_message_blocking`, there may only be one writer per segment
+        ///
+        /// The generation word works like the sequence word of `publish`, and doubles as the futex readers sleep on
+        pub fn write_message_notify(&self, data: &[u8]) -> Result<(), Error> {
+            // Check the whole message fits before touching the generation
+            self.byte_ptr(0, NOTIFY_HEADER.saturating_add(data.len()), Access::Write)?;
+            let generation = unsafe { &*self.typed_ptr::<AtomicU32>(0, Access::Write)? };
+            let writer = unsafe { &*self.typed_ptr::<AtomicU32>(4, Access::Write)? };
+            let len = unsafe { &*self.typed_ptr::<AtomicU64>(8, Access::Write)? };
+            let current = generation.load(Ordering::Relaxed);
+            generation.store(current | 1, Ordering::Relaxed);
+            fence(Ordering::Release);
+            writer.store(std::process::id(), Ordering::Relaxed);
+            len.store(data.len() as u64, Ordering::Relaxed);
+            self.copy_from_slice_at(NOTIFY_HEADER, data)?;
+            // 0 means nothing was ever written, so wrapping skips it
+            let next = match (current | 1).wrapping_add(1) {
+                0 => 2,
+                next => next,
+            };
+            generation.store(next, Ordering::Release);
+            futex::wake_all(generation);
+            Ok(())
+        }
+
+        /// Pid of the process that last wrote a message with `write_message_notify`, `None` if none was written yet
+        ///
+        /// Only the pid is kept, so a process that has since exited can have handed it on to an unrelated one
+        pub fn last_writer_pid(&self) -> Result<Option<u32>, Error> {
+            let writer = unsafe { &*self.typed_ptr::<AtomicU32>(4, Access::Read)? };
+            match writer.load(Ordering::Relaxed) {
+                0 => Ok(None),
+                pid => Ok(Some(pid)),
+            }
+        }
+
+        /// Waits until a message newer than the last one this handle returned has been written with
+        /// `write_message_notify` and returns a copy of it
+        ///
+        /// Only the latest message is kept, so if several were written since the last call only the newest is
+        /// returned. `None` waits for as long as it takes
+        pub fn read_message_blocking(&self, timeout: Option<Duration>) -> Result<Vec<u8>, WaitError> {
+            let deadline = futex::deadline(timeout);
+            let generation = unsafe { &*self.typed_ptr::<AtomicU32>(0, Access::Read)? };
+            let len = unsafe { &*self.typed_ptr::<AtomicU64>(8, Access::Read)? };
+            loop {
+                let seen = generation.load(Ordering::Acquire);
+                if seen != 0 && seen & 1 == 0 && seen != self.last_notified.get() {
+                    let payload = usize::try_from(len.load(Ordering::Relaxed))
+                        .map_err(|_| Error::OutOfBounds { offset: NOTIFY_HEADER, len: usize::MAX, size: self.size as usize })?;
+                    // A torn length can be nonsense, so bound it by the mapping rather than trusting it
+                    let copied = payload.min((self.size as usize).saturating_sub(NOTIFY_HEADER));
+                    let data = self.read_owned_at(NOTIFY_HEADER, copied)?;
+                    fence(Ordering::Acquire);
+                    if generation.load(Ordering::Relaxed) != seen {
+                        continue;
+                    }
+                    if copied != payload {
+                        return Err(Error::OutOfBounds { offset: NOTIFY_HEADER, len: payload, size: self.size as usize }.into());
+                    }
+                    self.last_notified.set(seen);
+                    return Ok(data);
+                }
+                // Wakes can be spurious or for a message this handle already has, so the loop checks again
+                if !futex::wait(generation, seen, futex::remaining(deadline)) {
+                    return Err(WaitError::TimedOut);
+                }
+            }
+        }
+
+        /// Blocks while the `u32` at `offset` holds `expected`, for up to `timeout` or forever with `None`
+        ///
+        /// This is the futex (`WaitOnAddress` on Windows) that `read_message_blocking` sleeps on, for protocols the
+        /// crate doesn't have. The value in [`WaitResult::Changed`] is what the waiter loaded, the word may hold
+        /// something else again by the time the caller looks, and a change that's undone before the waiter runs can
+        /// go unseen, so treat it as a cue to re-check the protocol's state. Wakes that find the word unchanged go
+        /// back to waiting. Writers store the new value and then call `wake_one_u32` or `wake_all_u32`, on Windows a
+        /// waiter in another process only notices within about a millisecond of the store
+        pub fn wait_for_change_u32(&self, offset: usize, expected: u32, timeout: Option<Duration>) -> Result<WaitResult, Error> {
+            let word = unsafe { &*self.typed_ptr::<AtomicU32>(offset, Access::Read)? };
+            let deadline = futex::deadline(timeout);
+            loop {
+                let value = word.load(Ordering::Acquire);
+                if value != expected {
+                    return Ok(WaitResult::Changed(value));
+                }
+                // Signals are retried inside, so false only ever means the time ran out
+                if !futex::wait(word, expected, futex::remaining(deadline)) {
+                    return Ok(WaitResult::TimedOut);
+                }
+            }
+        }
+
+        /// Wakes one thread blocked in `wait_for_change_u32` on the `u32` at `offset`, in any process
+        pub fn wake_one_u32(&self, offset: usize) -> Result<(), Error> {
+            futex::wake_one(unsafe { &*self.typed_ptr::<AtomicU32>(offset, Access::Read)? });
+            Ok(())
+        }
+
+        /// Wakes every thread blocked in `wait_for_change_u32` on the `u32` at `offset`, in any process
+        pub fn wake_all_u32(&self, offset: usize) -> Result<(), Error> {
+            futex::wake_all(unsafe { &*self.typed_ptr::<AtomicU32>(offset, Access::Read)? });
+            Ok(())
+        }
+
+        /// xxh3 hash of the bytes in `range`, fast enough to check multi-megabyte regions
+        #[cfg(feature = "xxhash")]
+        pub fn hash_range(&self, range: Range<usize>) -> Result<u64, Error> {
+            let len = range.end.saturating_sub(range.start);
+            let ptr = self.byte_ptr(range.start, len, Access::Read)?;
+            Ok(xxhash_rust::xxh3::xxh3_64(unsafe { std::slice::from_raw_parts(ptr, len) }))
+        }
+
+        /// Stores `data` at `offset` behind a [`BLOB_HEADER`] holding its little-endian length and xxh3 hash
+        #[cfg(feature = "xxhash")]
+        pub fn write_blob_hashed(&self, offset: usize, data: &[u8]) -> Result<(), Error> {
+            // Check the whole blob fits before writing any of it
+            self.byte_ptr(offset, BLOB_HEADER.saturating_add(data.len()), Access::Write)?;
+            self.copy_from_slice_at(offset + BLOB_HEADER, data)?;
+            self.write_u64_le(offset, data.len() as u64)?;
+            self.write_u64_le(offset + 8, xxhash_rust::xxh3::xxh3_64(data))
+        }
+
+        /// Copies out a blob written by `write_blob_hashed`, checking it still hashes to what was stored
+        #[cfg(feature = "xxhash")]
+        pub fn read_blob_hashed(&self, offset: usize) -> Result<Vec<u8>, IntegrityError> {
+            let len = self.read_u64_le(offset)?;
+            let expected = self.read_u64_le(offset + 8)?;
+            let len = usize::try_from(len).map_err(|_| Error::OutOfBounds { offset, len: usize::MAX, size: self.size as usize })?;
+            let data = self.read_owned_at(offset + BLOB_HEADER, len)?;
+            let computed = xxhash_rust::xxh3::xxh3_64(&data);
+            if computed != expected {
+                return Err(IntegrityError::Mismatch { expected, computed });
+            }
+            Ok(data)
+        }
+
+        /// Writes `data` at the start of the mapping as a message, lz4 compressed if that makes it smaller
+        ///
+        /// Compressing is what lets a message larger than the mapping still fit, if even the smaller of the two forms
+        /// doesn't fit `Error::MessageTooLarge` says by how much and nothing is written
+        #[cfg(feature = "lz4")]
+        pub fn write_message_compressed(&self, data: &[u8]) -> Result<(), Error> {
+            let compressed = lz4_flex::block::compress(data);
+            let (flag, stored) = if compressed.len() < data.len() { (MESSAGE_LZ4, &compressed[..]) } else { (MESSAGE_RAW, data) };
+            let available = (self.size as usize).saturating_sub(MESSAGE_HEADER);
+            if stored.len() > available {
+                return Err(Error::MessageTooLarge { len: data.len(), stored: stored.len(), available });
+            }
+            self.copy_from_slice_at(MESSAGE_HEADER, stored)?;
+            self.write_u32_le(0, flag)?;
+            self.write_u32_le(4, stored.len() as u32)?;
+            self.write_u64_le(8, data.len() as u64)
+        }
+
+        /// Reads a message written by `write_message_compressed` into `out`, replacing what was in it and
+        /// decompressing if the flag says so
+        #[cfg(feature = "lz4")]
+        pub fn read_message_auto(&self, out: &mut Vec<u8>) -> Result<(), Error> {
+            let flag = self.read_u32_le(0)?;
+            let stored = self.read_u32_le(4)? as usize;
+            let len = self.read_u64_le(8)?;
+            let len = usize::try_from(len).map_err(|_| Error::OutOfBounds { offset: MESSAGE_HEADER, len: usize::MAX, size: self.size as usize })?;
+            let ptr = self.byte_ptr(MESSAGE_HEADER, stored, Access::Read)?;
+            let stored = unsafe { std::slice::from_raw_parts(ptr, stored) };
+            out.clear();
+            match flag {
+                MESSAGE_RAW if stored.len() == len => out.extend_from_slice(stored),
+                // lz4 can't expand more than about 255 to 1, so a bigger length is garbage rather than a huge allocation
+                MESSAGE_LZ4 if len <= stored.len().saturating_mul(255) + 16 => {
+                    out.resize(len, 0);
+                    let written = lz4_flex::block::decompress_into(stored, out)
+                        .map_err(|err| io::Error::new(io::ErrorKind::InvalidData, err))?;
+                    out.truncate(written);
+                }
+                _ => return Err(io::Error::new(io::ErrorKind::InvalidData, "not a message written by write_message_compressed").into()),
+            }
+            Ok(())
+        }
+
+        /// A cell for the `T` at `offset` that threads of this process can share even though the handle can't be, see
+        /// [`ShmCell`] for what it does and doesn't protect against
+        pub fn cell_at<T: SharedSafe>(&self, offset: usize) -> Result<ShmCell<'_, T>, Error> {
+            let ptr = self.typed_ptr::<T>(offset, Access::Write)?;
+            Ok(unsafe { ShmCell::new(ptr) })
+        }
+
+        /// A reference to the `T` laid out at `offset` by `#[shared_struct]`, checked to be inside the mapping, aligned
+        /// and writable
+        ///
+        /// The struct is used where it is, so its atomics are shared with every other mapping of the segment
+        pub fn struct_at<T: SharedStruct>(&self, offset: usize) -> Result<&T, Error> {
+            let ptr = self.typed_ptr::<T>(offset, Access::Write)?;
+            Ok(unsafe { &*ptr })
+        }
+
+        /// Reads a `T` at `offset` with `ptr::read_volatile`, so the read can't be hoisted out of a polling loop or merged with its neighbours
+        ///
+        /// Prefer this over the atomics for flags with a single writer, or device-like registers where every access has to
+        /// actually happen. It gives no ordering with the rest of memory, so anything published alongside the flag needs an atomic
+        pub fn read_volatile_at<T: SharedSafe>(&self, offset: usize) -> Result<T, Error> {
+            let ptr = self.typed_ptr::<T>(offset, Access::Read)?;
+            Ok(unsafe { ptr::read_volatile(ptr) })
+        }
+
+        /// Writes `value` at `offset` with `ptr::write_volatile`, see `read_volatile_at` for when that's the right tool
+        pub fn write_volatile_at<T: SharedSafe>(&self, offset: usize, value: T) -> Result<(), Error> {
+            let ptr = self.typed_ptr::<T>(offset, Access::Write)?;
+            unsafe { ptr::write_volatile(ptr, value) };
+            Ok(())
+        }
+
+        /// The byte at `offset`
+        pub fn get_byte(&self, offset: usize) -> Result<u8, Error> {
+            Ok(self.read_bytes_at::<1>(offset)?[0])
+        }
+
+        pub fn set_byte(&self, offset: usize, value: u8) -> Result<(), Error> {
+            self.copy_from_slice_at(offset, &[value])
+        }
+
+        /// Replaces the byte at `offset` with `f(old)` and returns the new value
+        ///
+        /// This is a plain read followed by a plain write, a change another handle makes in between is lost. Flags
+        /// that more than one process changes belong in an atomic, such as a bit in a `bitset::ShmBitset`
+        pub fn update_byte(&self, offset: usize, f: impl FnOnce(u8) -> u8) -> Result<u8, Error> {
+            let value = f(self.get_byte(offset)?);
+            self.set_byte(offset, value)?;
+            Ok(value)
+        }
+
+        /// Writes `value` at `offset` as little-endian bytes, whatever the host's byte order
+        pub fn write_u16_le(&self, offset: usize, value: u16) -> Result<(), Error> {
+            self.copy_from_slice_at(offset, &value.to_le_bytes())
+        }
+
+        /// Reads a little-endian `u16` at `offset`, whatever the host's byte order
+        pub fn read_u16_le(&self, offset: usize) -> Result<u16, Error> {
+            Ok(u16::from_le_bytes(self.read_bytes_at(offset)?))
+        }
+
+        /// Writes `value` at `offset` as big-endian bytes, whatever the host's byte order
+        pub fn write_u16_be(&self, offset: usize, value: u16) -> Result<(), Error> {
+            self.copy_from_slice_at(offset, &value.to_be_bytes())
+        }
+
+        /// Reads a big-endian `u16` at `offset`, whatever the host's byte order
+        pub fn read_u16_be(&self, offset: usize) -> Result<u16, Error> {
+            Ok(u16::from_be_bytes(self.read_bytes_at(offset)?))
+        }
+
+        /// Writes `value` at `offset` as little-endian bytes, whatever the host's byte order
+        pub fn write_u32_le(&self, offset: usize, value: u32) -> Result<(), Error> {
+            self.copy_from_slice_at(offset, &value.to_le_bytes())
+        }
+
+        /// Reads a little-endian `u32` at `offset`, whatever the host's byte order
+        pub fn read_u32_le(&self, offset: usize) -> Result<u32, Error> {
+            Ok(u32::from_le_bytes(self.read_bytes_at(offset)?))
+        }
+
+        /// Writes `value` at `offset` as big-endian bytes, whatever the host's byte order
+        pub fn write_u32_be(&self, offset: usize, value: u32) -> Result<(), Error> {
+            self.copy_from_slice_at(offset, &value.to_be_bytes())
+        }
+
+        /// Reads a big-endian `u32` at `offset`, whatever the host's byte order
+        pub fn read_u32_be(&self, offset: usize) -> Result<u32, Error> {
+            Ok(u32::from_be_bytes(self.read_bytes_at(offset)?))
+        }
+
+        /// Writes `value` at `offset` as little-endian bytes, whatever the host's byte order
+        pub fn write_u64_le(&self, offset: usize, value: u64) -> Result<(), Error> {
+            self.copy_from_slice_at(offset, &value.to_le_bytes())
+        }
+
+        /// Reads a little-endian `u64` at `offset`, whatever the host's byte order
+        pub fn read_u64_le(&self, offset: usize) -> Result<u64, Error> {
+            Ok(u64::from_le_bytes(self.read_bytes_at(offset)?))
+        }
+
+        /// Writes `value` at `offset` as big-endian bytes, whatever the host's byte order
+        pub fn write_u64_be(&self, offset: usize, value: u64) -> Result<(), Error> {
+            self.copy_from_slice_at(offset, &value.to_be_bytes())
+        }
+
+        /// Reads a big-endian `u64` at `offset`, whatever the host's byte order
+        pub fn read_u64_be(&self, offset: usize) -> Result<u64, Error> {
+            Ok(u64::from_be_bytes(self.read_bytes_at(offset)?))
+        }
+
+        /// Writes `value` at `offset` as little-endian bytes, whatever the host's byte order
+        pub fn write_f64_le(&self, offset: usize, value: f64) -> Result<(), Error> {
+            self.copy_from_slice_at(offset, &value.to_le_bytes())
+        }
+
+        /// Reads a little-endian `f64` at `offset`, whatever the host's byte order
+        pub fn read_f64_le(&self, offset: usize) -> Result<f64, Error> {
+            Ok(f64::from_le_bytes(self.read_bytes_at(offset)?))
+        }
+
+        /// Writes `value` at `offset` as big-endian bytes, whatever the host's byte order
+        pub fn write_f64_be(&self, offset: usize, value: f64) -> Result<(), Error> {
+            self.copy_from_slice_at(offset, &value.to_be_bytes())
+        }
+
+        /// Reads a big-endian `f64` at `offset`, whatever the host's byte order
+        pub fn read_f64_be(&self, offset: usize) -> Result<f64, Error> {
+            Ok(f64::from_be_bytes(self.read_bytes_at(offset)?))
+        }
+
+        /// Copies `src` into the mapping at `offset`, the primitive every other copy into the mapping goes through
+        ///
+        /// `offset + src.len()` is computed without overflowing and has to be within the mapping, otherwise nothing is
+        /// written. Only those `src.len()` bytes are touched, nothing around them is zeroed or padded, and an empty
+        /// `src` copies nothing once its offset has been checked
+        pub fn copy_from_slice_at(&self, offset: usize, src: &[u8]) -> Result<(), Error> {
+            let ptr = self.byte_ptr(offset, src.len(), Access::Write)?;
+            if !src.is_empty() {
+                unsafe { ptr::copy_nonoverlapping(src.as_ptr(), ptr, src.len()) };
+            }
+            Ok(())
+        }
+
+        /// Fills `dst` from the mapping at `offset`, the primitive every other copy out of the mapping goes through
+        ///
+        /// Checked like `copy_from_slice_at`: either all of `dst` comes from inside the mapping or `dst` is left as
+        /// it was
+        pub fn copy_to_slice_at(&self, offset: usize, dst: &mut [u8]) -> Result<(), Error> {
+            let ptr = self.byte_ptr(offset, dst.len(), Access::Read)?;
+            if !dst.is_empty() {
+                unsafe { ptr::copy_nonoverlapping(ptr, dst.as_mut_ptr(), dst.len()) };
+            }
+            Ok(())
+        }
+
+        /// Like `copy_from_slice_at`, splitting the copy across threads for transfers too big for one core to keep up
+        ///
+        /// Each thread copies at least `min_chunk` bytes, and a copy no bigger than that or a machine with one core
+        /// copies on the calling thread. Taking `&mut self` keeps slices from `read_data` and friends from being
+        /// borrowed during the copy
+        pub fn write_at_parallel(&mut self, offset: usize, data: &[u8], min_chunk: usize) -> Result<(), Error> {
+            let ptr = self.byte_ptr(offset, data.len(), Access::Write)?;
+            let chunk = match parallel_chunk(data.len(), min_chunk) {
+                Some(chunk) => chunk,
+                None => return self.copy_from_slice_at(offset, data),
+            };
+            let dst = unsafe { std::slice::from_raw_parts_mut(ptr, data.len()) };
+            thread::scope(|scope| {
+                for (dst, src) in dst.chunks_mut(chunk).zip(data.chunks(chunk)) {
+                    scope.spawn(move || dst.copy_from_slice(src));
+                }
+            });
+            Ok(())
+        }
+
+        /// Like `copy_to_slice_at`, splitting the copy across threads the way `write_at_parallel` does
+        pub fn read_into_parallel(&self, offset: usize, dst: &mut [u8], min_chunk: usize) -> Result<(), Error> {
+            let ptr = self.byte_ptr(offset, dst.len(), Access::Read)?;
+            let chunk = match parallel_chunk(dst.len(), min_chunk) {
+                Some(chunk) => chunk,
+                None => return self.copy_to_slice_at(offset, dst),
+            };
+            let src = unsafe { std::slice::from_raw_parts(ptr, dst.len()) };
+            thread::scope(|scope| {
+                for (dst, src) in dst.chunks_mut(chunk).zip(src.chunks(chunk)) {
+                    scope.spawn(move || dst.copy_from_slice(src));
+                }
+            });
+            Ok(())
+        }
+
+        /// The first offset at or after `offset` whose address is aligned to `align`, checking `size` bytes from there
+        /// still fit in the mapping
+        ///
+        /// Mappings start on a page boundary, so for alignments up to a page the offset is the same in every process
+        /// and can go into a shared layout. A larger alignment depends on where this mapping landed and only holds
+        /// for this handle
+        pub fn aligned_region(&self, offset: usize, size: usize, align: usize) -> Result<usize, Error> {
+            let base = self.address() as usize;
+            let aligned = match base.checked_add(offset) {
+                Some(address) => align_offset_up(address, align)? - base,
+                None => return Err(Error::OutOfBounds { offset, len: size, size: self.size as usize }),
+            };
+            self.byte_ptr(aligned, size, Access::Read)?;
+            Ok(aligned)
+        }
+
+        fn read_bytes_at<const N: usize>(&self, offset: usize) -> Result<[u8; N], Error> {
+            let mut bytes = [0u8; N];
+            self.copy_to_slice_at(offset, &mut bytes)?;
+            Ok(bytes)
+        }
+
+        /// Pointer to `len` bytes at `offset`, checking they lie inside the mapping and that `protect_range` left
+        /// their pages open to `access`
+        fn byte_ptr(&self, offset: usize, len: usize, access: Access) -> Result<*mut u8, Error> {
+            let size = self.size as usize;
+            if offset.checked_add(len).is_none_or(|end| end > size) {
+                return Err(Error::OutOfBounds { offset, len, size });
+            }
+            if !self.protection.is_empty() && len > 0 {
+                let page = SharedMemory::page_size();
+                for &protection in &self.protection[offset / page..=(offset + len - 1) / page] {
+                    let allowed = match protection {
+                        Protection::ReadWrite => true,
+                        Protection::ReadOnly => access == Access::Read,
+                        Protection::NoAccess => false,
+                    };
+                    if !allowed {
+                        return Err(Error::Protected { offset, len, protection });
+                    }
+                }
+            }
+            Ok(unsafe { (self.address() as *mut u8).add(offset) })
+        }
+
+        /// Pointer to a `T` at `offset`, checking it lies inside the mapping, is aligned for `T` and is open to `access`
+        pub(crate) fn typed_ptr<T>(&self, offset: usize, access: Access) -> Result<*mut T, Error> {
+            let ptr = self.byte_ptr(offset, std::mem::size_of::<T>(), access)?;
+            let alignment = std::mem::align_of::<T>();
+            if !(ptr as usize).is_multiple_of(alignment) {
+                return Err(Error::Misaligned { offset, alignment });
+            }
+            Ok(ptr as *mut T)
+        }
+
+        /// The descriptor the segment or file is mapped through, `None` for `create_local` and System V segments
+        ///
+        /// It stays open as long as the handle, so it can be handed to `fstat`, sent to another process or duplicated
+        /// with `try_clone_to_owned`
+        #[cfg(target_os = "linux")]
+        pub fn backing_fd(&self) -> Option<BorrowedFd<'_>> {
+            self.fd.as_ref().map(|fd| fd.as_fd())
+        }
+
+        /// Like `backing_fd`, failing the way a closed descriptor would for mappings that have none
+        #[cfg(target_os = "linux")]
+        fn fd(&self) -> io::Result<BorrowedFd<'_>> {
+            self.backing_fd().ok_or_else(|| io::Error::from_raw_os_error(libc::EBADF))
+        }
+
+        /// The file mapping object the view is mapped from, `None` for `create_local`
+        ///
+        /// It stays open as long as the handle, so it can be duplicated into another process
+        #[cfg(target_os = "windows")]
+        pub fn backing_handle(&self) -> Option<BorrowedHandle<'_>> {
+            self.h_map_file.as_ref().map(|handle| handle.as_handle())
+        }
+
+        /// The whole view as a slice, every safe accessor that lends out the mapping uncopied goes through this or
+        /// `bytes_mut`
+        ///
+        /// `p_buf` is non-null and `size` bytes are mapped behind it until the handle drops, which the borrow
+        /// outlives. What a slice can't promise is that another process leaves the bytes alone, see `read_data`
+        fn bytes(&self) -> &[u8] {
+            unsafe { std::slice::from_raw_parts(self.p_buf.as_ptr(), self.size as usize) }
+        }
+
+        /// Like `bytes`, `&mut self` rules out any other slice from this handle for as long as it lives
+        fn bytes_mut(&mut self) -> &mut [u8] {
+            unsafe { std::slice::from_raw_parts_mut(self.p_buf.as_ptr(), self.size as usize) }
+        }
+
+        /// Keeps the mapping for the rest of the process and hands back all of it, like `Box::leak`
+        ///
+        /// Nothing is unmapped, closed or unlinked, so leaking the handle that created a segment on Linux leaves its
+        /// name in place until something calls [`SharedMemory::unlink`] on it. Pages `protect_range` locked stay
+        /// locked, touching them through the slice faults
+        pub fn leak(self) -> &'static mut [u8] {
+            // Forgetting the handle keeps the view mapped for good, so the slice can borrow it forever
+            let bytes = unsafe { std::slice::from_raw_parts_mut(self.p_buf.as_ptr(), self.size as usize) };
+            std::mem::forget(self);
+            bytes
+        }
+
+        /// Like `leak`, keeping the handle itself so the checked accessors and `name()` stay available
+        pub fn leak_handle(self) -> &'static SharedMemory {
+            Box::leak(Box::new(self))
+        }
+
+
+
+        /// Moves the mapped window to `new_len` bytes starting at `new_offset`, keeping the same segment or file open
+        ///
+        /// The new view is mapped before the old one is released so on error the current window stays usable.
+        /// On Windows the size of the backing isn't known up front, so running past its end is reported by `MapViewOfFile` as an `Error::Io`
+        #[cfg(target_os = "windows")]
+        pub fn remap_window(&mut self, new_offset: u64, new_len: usize) -> Result<(), Error> {
+            SharedMemory::check_offset(new_offset)?;
+            let size = SharedMemory::window_size(new_len)?;
+            let handle = self.backing_handle().ok_or_else(|| io::Error::from_raw_os_error(ERROR_INVALID_HANDLE as i32))?;
+            let p_buf = SharedMemory::map_view(handle, new_offset, size, FILE_MAP_ALL_ACCESS)
+                .map_err(|err| OsError::wrap(Operation::MapViewOfFile, &self.logical_name, Some(size), err))?;
+            unsafe {
+                UnmapViewOfFile(self.address());
+            }
+            self.p_buf = p_buf;
+            self.size = size;
+            self.offset = new_offset;
+            self.protection.clear();
+            Ok(())
+        }
+
+        /// Moves the mapped window to `new_len` bytes starting at `new_offset`, keeping the same segment or file open
+        ///
+        /// The new view is mapped before the old one is released so on error the current window stays usable
+        #[cfg(target_os = "linux")]
+        pub fn remap_window(&mut self, new_offset: u64, new_len: usize) -> Result<(), Error> {
+            SharedMemory::check_offset(new_offset)?;
+            let size = SharedMemory::window_size(new_len)?;
+            let mut stat: libc::stat = unsafe { std::mem::zeroed() };
+            if unsafe { libc::fstat(self.fd()?.as_raw_fd(), &mut stat) } == -1 {
+                return Err(OsError::last(Operation::Fstat, &self.logical_name, Some(size)).into());
+            }
+            let available = stat.st_size as u64;
+            if new_offset.checked_add(new_len as u64).is_none_or(|end| end > available) {
+                return Err(Error::WindowOutOfRange { offset: new_offset, len: new_len, available });
+            }
+            let p_buf = SharedMemory::map_fd(self.fd()?, new_offset, size, PROT_READ | PROT_WRITE)
+                .map_err(|err| OsError::wrap(Operation::Mmap, &self.logical_name, Some(size), err))?;
+            unsafe {
+                munmap(self.address(), self.size as size_t);
+            }
+            self.p_buf = p_buf;
+            self.size = size;
+            self.offset = new_offset;
+            self.protection.clear();
+            Ok(())
+        }
+
+        fn window_size(len: usize) -> Result<i32, io::Error> {
+            i32::try_from(len).map_err(|_| io::Error::new(io::ErrorKind::InvalidInput, "window length doesn't fit in the segment size"))
+        }
+
+        #[cfg(target_os = "windows")]
+        fn page_size() -> usize {
+            let mut info: SYSTEM_INFO = unsafe { std::mem::zeroed() };
+            unsafe {
+                GetSystemInfo(&mut info);
+            }
+            info.dwPageSize as usize
+        }
+
+        #[cfg(target_os = "linux")]
+        fn page_size() -> usize {
+            unsafe { sysconf(_SC_PAGESIZE) as usize }
+        }
+
+        /// The whole pages inside `range` once it is clamped to the mapping, empty if there are none
+        fn page_interior(&self, range: Range<usize>) -> Range<usize> {
+            let page = SharedMemory::page_size();
+            let end = range.end.min(self.size as usize) / page * page;
+            let start = range.start.div_ceil(page) * page;
+            start.min(end)..end
+        }
+
+        /// The pages touching `range` once it is clamped to the mapping, widened out to page boundaries, and none for
+        /// an empty range
+        fn page_cover(&self, range: Range<usize>) -> Range<usize> {
+            if range.is_empty() {
+                return 0..0;
+            }
+            let page = SharedMemory::page_size();
+            let end = range.end.min(self.size as usize).div_ceil(page) * page;
+            let start = range.start / page * page;
+            start.min(end)..end
+        }
+
+        /// Whether each page touching `range` is in memory right now, one entry per page from the one holding
+        /// `range.start`
+        ///
+        /// The range is clamped to the mapping. The answer is a snapshot, pages can be faulted in or reclaimed as soon
+        /// as it is taken
+        #[cfg(target_os = "linux")]
+        pub fn resident_pages(&self, range: Range<usize>) -> io::Result<Vec<bool>> {
+            let pages = self.page_cover(range);
+            let mut residency = vec![0u8; pages.len() / SharedMemory::page_size()];
+            if residency.is_empty() {
+                return Ok(Vec::new());
+            }
+            let addr = unsafe { self.p_buf.as_ptr().add(pages.start) } as *mut lin_c_void;
+            if unsafe { mincore(addr, pages.len(), residency.as_mut_ptr()) } == -1 {
+                return Err(io::Error::last_os_error());
+            }
+            Ok(residency.into_iter().map(|page| page & 1 != 0).collect())
+        }
+
+        /// Whether each page touching `range` is in this process's working set right now, one entry per page from the
+        /// one holding `range.start`
+        ///
+        /// The range is clamped to the mapping. The answer is a snapshot, pages can be faulted in or trimmed as soon
+        /// as it is taken
+        #[cfg(target_os = "windows")]
+        pub fn resident_pages(&self, range: Range<usize>) -> io::Result<Vec<bool>> {
+            let page = SharedMemory::page_size();
+            let pages = self.page_cover(range);
+            let mut info: Vec<PSAPI_WORKING_SET_EX_INFORMATION> = pages
+                .step_by(page)
+                .map(|offset| PSAPI_WORKING_SET_EX_INFORMATION {
+                    VirtualAddress: unsafe { self.p_buf.as_ptr().add(offset) } as *mut win_c_void,
+                    VirtualAttributes: unsafe { std::mem::zeroed() },
+                })
+                .collect();
+            if info.is_empty() {
+                return Ok(Vec::new());
+            }
+            let cb = std::mem::size_of_val(info.as_slice()) as DWORD;
+            if unsafe { K32QueryWorkingSetEx(GetCurrentProcess(), info.as_mut_ptr() as *mut win_c_void, cb) } == 0 {
+                return Err(io::Error::last_os_error());
+            }
+            Ok(info.iter().map(|page| page.VirtualAttributes.Valid() != 0).collect())
+        }
+
+        /// Faults in the pages touching `range` ahead of time, so a latency-sensitive phase that reads them later
+        /// doesn't take the faults itself
+        ///
+        /// The range is clamped to the mapping and widened out to page boundaries. The pages are hinted to the OS as
+        /// needed soon and then one byte of each is read, nothing is written so other processes never see a change.
+        /// Pages `protect_range` made `NoAccess` are skipped rather than faulted on
+        pub fn touch_pages(&self, range: Range<usize>) -> io::Result<()> {
+            let page = SharedMemory::page_size();
+            let pages = self.page_cover(range);
+            if pages.is_empty() {
+                return Ok(());
+            }
+            self.will_need(pages.clone())?;
+            for offset in pages.step_by(page) {
+                if self.protection.get(offset / page) == Some(&Protection::NoAccess) {
+                    continue;
+                }
+                unsafe { ptr::read_volatile(self.p_buf.as_ptr().add(offset)) };
+            }
+            Ok(())
+        }
+
+        #[cfg(target_os = "linux")]
+        fn will_need(&self, pages: Range<usize>) -> io::Result<()> {
+            let addr = unsafe { self.p_buf.as_ptr().add(pages.start) } as *mut lin_c_void;
+            if unsafe { madvise(addr, pages.len(), MADV_WILLNEED) } == -1 {
+                return Err(io::Error::last_os_error());
+            }
+            Ok(())
+        }
+
+        #[cfg(target_os = "windows")]
+        fn will_need(&self, pages: Range<usize>) -> io::Result<()> {
+            let mut entry = WIN32_MEMORY_RANGE_ENTRY {
+                VirtualAddress: unsafe { self.p_buf.as_ptr().add(pages.start) } as *mut win_c_void,
+                NumberOfBytes: pages.len(),
+            };
+            if unsafe { PrefetchVirtualMemory(GetCurrentProcess(), 1, &mut entry, 0) } == 0 {
+                return Err(io::Error::last_os_error());
+            }
+            Ok(())
+        }
+
+        /// How many bytes of the mapping are in memory, counted in whole pages
+        pub fn resident_bytes(&self) -> io::Result<usize> {
+            let resident = self.resident_pages(0..self.size as usize)?;
+            Ok(resident.into_iter().filter(|&page| page).count() * SharedMemory::page_size())
+        }
+
+        /// Changes the protection of the pages touching `range`, e.g. to make an initialised header read-only so a
+        /// stray write faults instead of corrupting it
+        ///
+        /// The range is clamped to the mapping and widened out to page boundaries, so everything else sharing those
+        /// pages gets the same protection. The checked accessors (`read_value`, `write_u32_le` and the like) look at
+        /// the recorded protection and return `Error::Protected` instead of faulting, but `read_data`,
+        /// the comparison helpers and raw pointers from `address` still fault on a page they aren't allowed to touch.
+        /// Only this mapping is affected, other handles keep their own protection, and `remap_window` starts over
+        /// with every page read-write
+        pub fn protect_range(&mut self, range: Range<usize>, protection: Protection) -> io::Result<()> {
+            let page = SharedMemory::page_size();
+            let pages = self.page_cover(range);
+            if pages.is_empty() {
+                return Ok(());
+            }
+            self.set_protection(pages.clone(), protection)?;
+            if self.protection.is_empty() {
+                self.protection = vec![Protection::ReadWrite; (self.size as usize).div_ceil(page)];
+            }
+            self.protection[pages.start / page..pages.end / page].fill(protection);
+            if self.protection.iter().all(|&protection| protection == Protection::ReadWrite) {
+                self.protection.clear();
+            }
+            Ok(())
+        }
+
+        /// Makes the whole mapping read-only for good and returns it as a type that only has read methods, for
+        /// creators that fill a segment once and then publish it
+        ///
+        /// On Linux, if this handle created the segment, the object's mode is also dropped to 0o444 so later opens
+        /// for writing by anyone without `CAP_DAC_OVERRIDE` fail, and consumers should use `open_sealed`. Mappings
+        /// other processes already have are left writable. Windows has no equivalent for an existing mapping object,
+        /// so there only this view becomes read-only
+        pub fn seal_read_only(mut self) -> Result<SealedSharedMemory, Error> {
+            self.protect_range(0..self.size as usize, Protection::ReadOnly)?;
+            self.restrict_opens()?;
+            Ok(SealedSharedMemory { shm: self })
+        }
+
+        #[cfg(target_os = "linux")]
+        fn restrict_opens(&self) -> io::Result<()> {
+            if self.is_create && unsafe { libc::fchmod(self.fd()?.as_raw_fd(), 0o444) } == -1 {
+                return Err(io::Error::last_os_error());
+            }
+            Ok(())
+        }
+
+        #[cfg(target_os = "windows")]
+        fn restrict_opens(&self) -> io::Result<()> {
+            Ok(())
+        }
+
+        /// Maps `size` bytes of an existing segment read-only, which works on segments `seal_read_only` restricted
+        #[cfg(target_os = "linux")]
+        pub fn open_sealed(name: &str, size: i32) -> Result<SealedSharedMemory, Error> {
+            let name_c = normalize_name(name, NamePolicy::Reject)?.into_c_string();
+            let fd = retry_eintr(|| unsafe { shm_open(name_c.as_ptr(), libc::O_RDONLY, 0o600) });
+            if fd == -1 {
+                return Err(OsError::last(Operation::ShmOpen, name, Some(size)).into());
+            }
+            let fd = unsafe { OwnedFd::from_raw_fd(fd) };
+            // Reading past the end of the object would raise SIGBUS instead of failing here
+            let requested = size.max(0) as u64;
+            let actual = SharedMemory::backing_size(fd.as_fd())?;
+            if actual < requested {
+                return Err(Error::SizeMismatch { requested, actual });
+            }
+            let p_buf = match SharedMemory::map_fd(fd.as_fd(), 0, size, PROT_READ) {
+                Ok(p_buf) => p_buf,
+                Err(err) => return Err(OsError::wrap(Operation::Mmap, name, Some(size), err).into()),
+            };
+            let page = SharedMemory::page_size();
+            let shm = SharedMemory {
+                size,
+                offset: 0,
+                name: name_c,
+                logical_name: name.to_string(),
+                fd: Some(fd),
+                p_buf,
+                protection: vec![Protection::ReadOnly; (size as usize).div_ceil(page)],
+                local: None,
+                last_notified: Cell::new(0),
+                is_create: false,
+                in_dir: false,
+                sysv_id: None,
+            };
+            Ok(SealedSharedMemory { shm })
+        }
+
+        /// Maps `size` bytes of an existing segment read-only
+        #[cfg(target_os = "windows")]
+        pub fn open_sealed(name: &str, size: i32) -> Result<SealedSharedMemory, Error> {
+            let name_c = normalize_name(name, NamePolicy::Reject)?.into_c_string();
+            let h_map_file = unsafe { OpenFileMappingA(FILE_MAP_READ, FALSE, name_c.as_ptr()) };
+            if h_map_file.is_null() {
+                return Err(OsError::last(Operation::OpenFileMapping, name, Some(size)).into());
+            }
+            let h_map_file = unsafe { OwnedHandle::from_raw_handle(h_map_file as RawHandle) };
+            let p_buf = match SharedMemory::map_view(h_map_file.as_handle(), 0, size, FILE_MAP_READ) {
+                Ok(p_buf) => p_buf,
+                Err(err) => return Err(OsError::wrap(Operation::MapViewOfFile, name, Some(size), err).into()),
+            };
+            let page = SharedMemory::page_size();
+            let shm = SharedMemory {
+                size,
+                offset: 0,
+                name: name_c,
+                logical_name: name.to_string(),
+                h_map_file: Some(h_map_file),
+                p_buf,
+                protection: vec![Protection::ReadOnly; (size as usize).div_ceil(page)],
+                local: None,
+                last_notified: Cell::new(0),
+            };
+            Ok(SealedSharedMemory { shm })
+        }
+
+        #[cfg(target_os = "linux")]
+        fn set_protection(&self, pages: Range<usize>, protection: Protection) -> io::Result<()> {
+            let prot = match protection {
+                Protection::ReadOnly => PROT_READ,
+                Protection::ReadWrite => PROT_READ | PROT_WRITE,
+                Protection::NoAccess => libc::PROT_NONE,
+            };
+            let addr = unsafe { self.p_buf.as_ptr().add(pages.start) } as *mut lin_c_void;
+            if unsafe { libc::mprotect(addr, pages.len(), prot) } == -1 {
+                return Err(OsError::last(Operation::Mprotect, &self.logical_name, None));
+            }
+            Ok(())
+        }
+
+        #[cfg(target_os = "windows")]
+        fn set_protection(&self, pages: Range<usize>, protection: Protection) -> io::Result<()> {
+            let prot = match protection {
+                Protection::ReadOnly => PAGE_READONLY,
+                Protection::ReadWrite => PAGE_READWRITE,
+                Protection::NoAccess => PAGE_NOACCESS,
+            };
+            let addr = unsafe { self.p_buf.as_ptr().add(pages.start) } as *mut win_c_void;
+            let mut old = 0;
+            if unsafe { VirtualProtect(addr, pages.len(), prot, &mut old) } == 0 {
+                return Err(io::Error::last_os_error());
+            }
+            Ok(())
+        }
+
+        /// Gives the pages in `range` back to the OS, after which they read back as zeros
+        ///
+        /// Only whole pages are released, the range is clamped to the mapping and shrunk to the pages fully inside it,
+        /// so the partial pages at either end keep their contents. On Linux the pages are punched out of the backing
+        /// object, so every process mapping it sees the zeros and tmpfs stops counting them
+        #[cfg(target_os = "linux")]
+        pub fn discard_range(&mut self, range: Range<usize>) -> io::Result<()> {
+            let pages = self.page_interior(range);
+            if pages.is_empty() {
+                return Ok(());
+            }
+            let len = pages.len();
+            if self.local.is_some() {
+                unsafe { ptr::write_bytes(self.p_buf.as_ptr().add(pages.start), 0, len) };
+                return Ok(());
+            }
+            let (offset, punched) = (file_offset(self.offset + pages.start as u64)?, file_offset(len as u64)?);
+            // System V segments have no descriptor to punch holes through, but they're shmem so MADV_REMOVE works
+            if let Some(fd) = &self.fd {
+                if retry_eintr(|| unsafe { fallocate(fd.as_raw_fd(), FALLOC_FL_PUNCH_HOLE | FALLOC_FL_KEEP_SIZE, offset, punched) }) == 0 {
+                    return Ok(());
+                }
+                let err = io::Error::last_os_error();
+                if err.raw_os_error() != Some(libc::EOPNOTSUPP) {
+                    return Err(OsError::wrap(Operation::Fallocate, &self.logical_name, None, err));
+                }
+            }
+            let addr = unsafe { self.p_buf.as_ptr().add(pages.start) } as *mut lin_c_void;
+            if unsafe { madvise(addr, len, MADV_REMOVE) } == -1 {
+                return Err(OsError::last(Operation::Madvise, &self.logical_name, None));
+            }
+            Ok(())
+        }
+
+        /// Gives the pages in `range` back to the OS, after which they read back as zeros
+        ///
+        /// Only whole pages are released, the range is clamped to the mapping and shrunk to the pages fully inside it,
+        /// so the partial pages at either end keep their contents. Windows only promises that reset pages hold
+        /// undefined data, so they are zeroed before being reset and read as zeros whether or not they were dropped
+        #[cfg(target_os = "windows")]
+        pub fn discard_range(&mut self, range: Range<usize>) -> io::Result<()> {
+            let pages = self.page_interior(range);
+            if pages.is_empty() {
+                return Ok(());
+            }
+            let addr = unsafe { self.p_buf.as_ptr().add(pages.start) };
+            unsafe {
+                ptr::write_bytes(addr, 0, pages.len());
+                // Locked pages can't be reset, and most were never locked so failing here is expected
+                VirtualUnlock(addr as *mut win_c_void, pages.len());
+                if VirtualAlloc(addr as *mut win_c_void, pages.len(), MEM_RESET, PAGE_READWRITE).is_null() {
+                    return Err(io::Error::last_os_error());
+                }
+            }
+            Ok(())
+        }
+
+        /// Writes the pages touching `range` back to the file behind the mapping, instead of the whole mapping
+        ///
+        /// The range is widened out to page boundaries and has to lie inside the mapping, an empty one does nothing.
+        /// Shared memory segments have no file to write to, so for them and for `create_local` mappings this only
+        /// checks the range. On Windows `FlushViewOfFile` starts the write-back either way, the mapping doesn't keep
+        /// the file's handle so `FlushMode::Sync` can't also wait for the disk with `FlushFileBuffers`
+        pub fn flush_range(&self, range: Range<usize>, mode: FlushMode) -> io::Result<()> {
+            let size = self.size as usize;
+            if range.start > range.end || range.end > size {
+                return Err(Error::OutOfBounds { offset: range.start, len: range.end.saturating_sub(range.start), size }.into());
+            }
+            let pages = self.page_cover(range);
+            if pages.is_empty() || self.local.is_some() {
+                return Ok(());
+            }
+            let addr = unsafe { self.p_buf.as_ptr().add(pages.start) };
+            SharedMemory::flush_pages(addr, pages.len(), mode, &self.logical_name)
+        }
+
+        #[cfg(target_os = "linux")]
+        fn flush_pages(addr: *mut u8, len: usize, mode: FlushMode, segment: &str) -> io::Result<()> {
+            let flags = match mode {
+                FlushMode::Sync => MS_SYNC,
+                FlushMode::Async => MS_ASYNC,
+            };
+            if unsafe { msync(addr as *mut lin_c_void, len, flags) } == -1 {
+                return Err(OsError::last(Operation::Msync, segment, None));
+            }
+            Ok(())
+        }
+
+        #[cfg(target_os = "windows")]
+        fn flush_pages(addr: *mut u8, len: usize, _mode: FlushMode, _segment: &str) -> io::Result<()> {
+            if unsafe { FlushViewOfFile(addr as *const win_c_void, len) } == 0 {
+                return Err(io::Error::last_os_error());
+            }
+            Ok(())
+        }
+
+        /// Opens `name` like `open`, but keeps retrying every `poll_interval` while the segment doesn't exist yet so
+        /// consumers can start before the producer
+        ///
+        /// On Linux a segment that exists but hasn't been sized by its creator yet (its length is still 0) is waited
+        /// for too, one sized smaller than `size` fails with `SizeMismatch` straight away. Any other error is returned
+        /// straight away as well, and once `timeout` runs out the last `NotFound` is returned. A `timeout` too long to
+        /// add to an `Instant` retries forever
+        pub fn open_with_retry(name: &str, size: i32, timeout: Duration, poll_interval: Duration) -> Result<Self, Error> {
+            let deadline = Instant::now().checked_add(timeout);
+            loop {
+                let err = match SharedMemory::open_unsized(name, size, NamePolicy::Reject, None) {
+                    Ok(shm) if shm.backing_covers_window()? => return Ok(shm),
+                    Ok(shm) => match shm.backing_len()? {
+                        0 => io::Error::new(io::ErrorKind::NotFound, "segment exists but its creator hasn't sized it yet"),
+                        actual => return Err(Error::SizeMismatch { requested: size.max(0) as u64, actual }),
+                    },
+                    Err(Error::Io(err)) if err.kind() == io::ErrorKind::NotFound => err,
+                    Err(err) => return Err(err),
+                };
+                let left = deadline.map_or(poll_interval, |deadline| deadline.saturating_duration_since(Instant::now()));
+                if left.is_zero() {
+                    return Err(err.into());
+                }
+                thread::sleep(poll_interval.min(left));
+            }
+        }
+
+        /// Picks exactly one leader among the processes calling this with the same `name`
+        ///
+        /// Whoever creates the segment exclusively claims it by writing its pid and start time into the first
+        /// [`ELECTION_HEADER`] bytes, everyone else opens it as a follower. If the recorded leader has died (or a creator died before
+        /// claiming), the next caller swaps its own pid in and takes over the existing segment, so followers that
+        /// suspect the leader is gone just call `elect` again. Threads of the leader's own process are followers.
+        /// `size` is the user data after the header, the mapping itself is `ELECTION_HEADER` bytes bigger
+        pub fn elect(name: &str, size: i32) -> Result<Role, Error> {
+            let total = size
+                .checked_add(ELECTION_HEADER as i32)
+                .ok_or_else(|| io::Error::new(io::ErrorKind::InvalidInput, "election segment size overflows"))?;
+            let me = SharedMemory::process_identity(std::process::id())?;
+            let mut unclaimed_since = None;
+            loop {
+                let (mut shm, created) = match SharedMemory::create_exclusive(name, total) {
+                    Ok(shm) => (shm, true),
+                    Err(err) if err.kind() == io::ErrorKind::AlreadyExists => match SharedMemory::open_unsized(name, total, NamePolicy::Reject, None) {
+                        Ok(shm) if shm.backing_covers_window()? => (shm, false),
+                        Ok(_) => {
+                            // The creator hasn't sized it yet
+                            thread::sleep(ELECTION_POLL);
+                            continue;
+                        }
+                        // Unlinked between our create and open, run the election again
+                        Err(Error::Io(err)) if err.kind() == io::ErrorKind::NotFound => continue,
+                        Err(err) => return Err(err),
+                    },
+                    Err(err) => return Err(err.into()),
+                };
+                if created {
+                    // Set before the claim, so any follower that sees a leader sees who created the segment too
+                    shm.creator_word().store(me, Ordering::Relaxed);
+                }
+                loop {
+                    let leader = shm.leader_word();
+                    let current = leader.load(Ordering::Acquire);
+                    let claimable = match current {
+                        0 if created => true,
+                        0 => unclaimed_since.get_or_insert_with(Instant::now).elapsed() >= ELECTION_CLAIM_GRACE,
+                        current if current == me => false,
+                        current => !SharedMemory::identity_alive(current)?,
+                    };
+                    if claimable {
+                        if leader.compare_exchange(current, me, Ordering::AcqRel, Ordering::Acquire).is_ok() {
+                            shm.set_owns_name(true);
+                            return Ok(Role::Leader(shm));
+                        }
+                    } else if current == 0 {
+                        thread::sleep(ELECTION_POLL);
+                    } else {
+                        shm.set_owns_name(false);
+                        return Ok(Role::Follower(shm));
+                    }
+                }
+            }
+        }
+
+        /// Whether the process that leads this [`SharedMemory::elect`] segment is still running
+        ///
+        /// Only meaningful on handles returned by `elect`, an unclaimed segment has no leader and reports false
+        pub fn leader_alive(&self) -> io::Result<bool> {
+            match self.leader_word().load(Ordering::Acquire) {
+                0 => Ok(false),
+                leader => SharedMemory::identity_alive(leader),
+            }
+        }
+
+        /// Whether the process that created this [`SharedMemory::elect`] segment is still running
+        ///
+        /// Unlike `leader_alive` this doesn't change when a new leader takes over from a dead one. Only meaningful on
+        /// handles returned by `elect`
+        pub fn creator_alive(&self) -> io::Result<bool> {
+            match self.creator_word().load(Ordering::Acquire) {
+                0 => Ok(false),
+                creator => SharedMemory::identity_alive(creator),
+            }
+        }
+
+        fn leader_word(&self) -> &AtomicU64 {
+            unsafe { &*(self.address() as *const AtomicU64) }
+        }
+
+        fn creator_word(&self) -> &AtomicU64 {
+            unsafe { &*(self.address() as *const AtomicU64).add(1) }
+        }
+
+        /// What `elect` records for a process, its pid plus the low half of its start time
+        fn process_identity(pid: u32) -> io::Result<u64> {
+            Ok(pid as u64 | (SharedMemory::process_start_time(pid)? as u32 as u64) << 32)
+        }
+
+        /// Whether the process recorded as `identity` is still running, and not just something reusing its pid
+        fn identity_alive(identity: u64) -> io::Result<bool> {
+            let pid = identity as u32;
+            if !SharedMemory::process_alive(pid)? {
+                return Ok(false);
+            }
+            match SharedMemory::process_start_time(pid) {
+                Ok(start) => Ok(start as u32 == (identity >> 32) as u32),
+                // Alive but not ours to inspect, give it the benefit of the doubt
+                Err(_) => Ok(true),
+            }
+        }
+
+        /// Creation time of process `pid` as a `FILETIME`
+        #[cfg(target_os = "windows")]
+        fn process_start_time(pid: u32) -> io::Result<u64> {
+            let handle = unsafe { OpenProcess(PROCESS_QUERY_LIMITED_INFORMATION, FALSE, pid) };
+            if handle.is_null() {
+                return Err(io::Error::last_os_error());
+            }
+            let mut times: [FILETIME; 4] = unsafe { std::mem::zeroed() };
+            let [creation, exit, kernel, user] = &mut times;
+            let ok = unsafe { GetProcessTimes(handle, creation, exit, kernel, user) };
+            let err = io::Error::last_os_error();
+            unsafe {
+                CloseHandle(handle);
+            }
+            if ok == 0 {
+                return Err(err);
+            }
+            Ok((times[0].dwHighDateTime as u64) << 32 | times[0].dwLowDateTime as u64)
+        }
+
+        /// Start time of process `pid` in clock ticks since boot, field 22 of /proc/<pid>/stat
+        #[cfg(target_os = "linux")]
+        fn process_start_time(pid: u32) -> io::Result<u64> {
+            let stat = fs::read_to_string(format!("/proc/{}/stat", pid))?;
+            // The command name in field 2 can contain spaces and parentheses, everything after the last `)` is plain
+            stat.rsplit_once(')')
+                .and_then(|(_, rest)| rest.split_whitespace().nth(19))
+                .and_then(|start| start.parse().ok())
+                .ok_or_else(|| io::Error::new(io::ErrorKind::InvalidData, "couldn't parse /proc/<pid>/stat"))
+        }
+
+        /// Nothing to own on Windows, the section goes away with its last handle
+        #[cfg(target_os = "windows")]
+        fn set_owns_name(&mut self, _owns: bool) {}
+
+        /// Whether dropping this handle unlinks the segment's name
+        #[cfg(target_os = "linux")]
+        fn set_owns_name(&mut self, owns: bool) {
+            self.is_create = owns;
+        }
+
+        /// Whether a process with id `pid` is still running
+        #[cfg(target_os = "windows")]
+        fn process_alive(pid: u32) -> io::Result<bool> {
+            let handle = unsafe { OpenProcess(PROCESS_QUERY_LIMITED_INFORMATION, FALSE, pid) };
+            if handle.is_null() {
+                // Anything but "no such process", e.g. access denied, still means it exists
+                return Ok(unsafe { GetLastError() } != ERROR_INVALID_PARAMETER);
+            }
+            let mut code: DWORD = 0;
+            let ok = unsafe { GetExitCodeProcess(handle, &mut code) };
+            let err = io::Error::last_os_error();
+            unsafe {
+                CloseHandle(handle);
+            }
+            if ok == 0 {
+                return Err(err);
+            }
+            Ok(code == STILL_ACTIVE)
+        }
+
+        /// Whether a process with id `pid` is still running
+        #[cfg(target_os = "linux")]
+        fn process_alive(pid: u32) -> io::Result<bool> {
+            if unsafe { libc::kill(pid as libc::pid_t, 0) } == 0 {
+                return Ok(true);
+            }
+            let err = io::Error::last_os_error();
+            match err.raw_os_error() {
+                Some(libc::ESRCH) => Ok(false),
+                // Exists but belongs to someone we can't signal
+                Some(libc::EPERM) => Ok(true),
+                _ => Err(err),
+            }
+        }
+
+        /// Sections are created at their full size in one call, so a window that mapped is always backed
+        #[cfg(target_os = "windows")]
+        fn backing_covers_window(&self) -> io::Result<bool> {
+            Ok(true)
+        }
+
+        /// A section is never shorter than a view of it
+        #[cfg(target_os = "windows")]
+        fn backing_len(&self) -> io::Result<u64> {
+            Ok(self.offset + self.size as u64)
+        }
+
+        /// Whether the object is long enough for the mapped window, touching a page past its end raises SIGBUS
+        #[cfg(target_os = "linux")]
+        fn backing_covers_window(&self) -> io::Result<bool> {
+            Ok(self.backing_len()? >= self.offset + self.size as u64)
+        }
+
+        /// Length of the object behind the mapping
+        #[cfg(target_os = "linux")]
+        fn backing_len(&self) -> io::Result<u64> {
+            SharedMemory::backing_size(self.fd()?)
+        }
+
+        /// Length of the object behind `fd`
+        #[cfg(target_os = "linux")]
+        fn backing_size(fd: BorrowedFd<'_>) -> io::Result<u64> {
+            let mut stat: libc::stat = unsafe { std::mem::zeroed() };
+            if unsafe { libc::fstat(fd.as_raw_fd(), &mut stat) } == -1 {
+                return Err(io::Error::last_os_error());
+            }
+            Ok(stat.st_size as u64)
+        }
+
+        /// Lists the segments whose names start with `prefix`, sorted by name
+        ///
+        /// Named sections can't be enumerated on Windows so this always fails there
+        #[cfg(target_os = "windows")]
+        pub fn list(_prefix: &str) -> Result<Vec<SegmentEntry>, io::Error> {
+            Err(io::Error::new(io::ErrorKind::Unsupported, "named file mappings can't be listed on Windows"))
+        }
+
+        /// Lists the segments in /dev/shm whose names start with `prefix`, sorted by name
+        ///
+        /// Names are returned with a leading `/` so they can be passed straight back to `open`, and the semaphores glibc keeps in the same directory are skipped
+        #[cfg(target_os = "linux")]
+        pub fn list(prefix: &str) -> Result<Vec<SegmentEntry>, io::Error> {
+            let prefix = prefix.trim_start_matches('/');
+            let mut entries = Vec::new();
+            for entry in fs::read_dir("/dev/shm")? {
+                let entry = entry?;
+                let file_name = entry.file_name();
+                let file_name = file_name.to_string_lossy();
+                if !file_name.starts_with(prefix) || file_name.starts_with("sem.") {
+                    continue;
+                }
+                let metadata = entry.metadata()?;
+                if !metadata.is_file() {
+                    continue;
+                }
+                entries.push(SegmentEntry {
+                    name: format!("/{}", file_name),
+                    size: metadata.len(),
+                });
+            }
+            entries.sort_by(|a, b| a.name.cmp(&b.name));
+            Ok(entries)
+        }
+
+        /// Removes the segment's name so nothing new can open it, existing mappings stay valid until they're dropped
+        ///
+        /// Does nothing on Windows, where a named section is destroyed once the last handle to it is closed
+        pub fn unlink(name: &str) -> Result<(), io::Error> {
+            SharedMemory::unlink_with_policy(name, NamePolicy::Reject)
+        }
+
+        #[cfg(target_os = "windows")]
+        pub fn unlink_with_policy(_name: &str, _policy: NamePolicy) -> Result<(), io::Error> {
+            Ok(())
+        }
+
+        /// Like `unlink`, `policy` has to be the one the segment was created with
+        #[cfg(target_os = "linux")]
+        pub fn unlink_with_policy(name: &str, policy: NamePolicy) -> Result<(), io::Error> {
+            let name_c = normalize_name(name, policy)?.into_c_string();
+            if unsafe { shm_unlink(name_c.as_ptr()) } == -1 {
+                return Err(OsError::last(Operation::ShmUnlink, name, None));
+            }
+            Ok(())
+        }
+
+        /// With `check_size` a window past the end of the object fails with `SizeMismatch` instead of being mapped
+        #[cfg(target_os = "linux")]
+        fn open_window(name: &str, offset: u64, size: i32, policy: NamePolicy, check_size: bool, dir: Option<&Path>) -> Result<Self, Error> {
+            SharedMemory::check_offset(offset)?;
+            let name_c = object_name(name, policy, dir)?;
+            let fd = open_object(&name_c, dir.is_some(), O_RDWR);
+            if fd == -1 {
+                return Err(OsError::last(open_operation(dir), name, Some(size)).into());
+            }
+            let fd = unsafe { OwnedFd::from_raw_fd(fd) };
+            if check_size {
+                let requested = offset + size.max(0) as u64;
+                let checked = SharedMemory::backing_size(fd.as_fd()).map(|actual| (actual >= requested, actual));
+                match checked {
+                    Ok((true, _)) => {}
+                    Ok((false, actual)) => {
+                        return Err(Error::SizeMismatch { requested, actual });
+                    }
+                    Err(err) => return Err(err.into()),
+                }
+            }
+            let p_buf = match SharedMemory::map_fd(fd.as_fd(), offset, size, PROT_READ | PROT_WRITE) {
+                Ok(p_buf) => p_buf,
+                Err(err) => return Err(OsError::wrap(Operation::Mmap, name, Some(size), err).into()),
+            };
+            let shared_memory = SharedMemory {
+                size,
+                offset,
+                name: name_c,
+                logical_name: name.to_string(),
+                fd: Some(fd),
+                p_buf,
+                protection: Vec::new(),
+                local: None,
+                last_notified: Cell::new(0),
+                is_create: false,
+                in_dir: dir.is_some(),
+                sysv_id: None,
+            };
+            Ok(shared_memory)
+        }
+
+        /// Maps a window of `size` bytes starting `offset` bytes into the file at `path`
+        ///
+        /// The file has to already exist and be at least `offset + size` bytes long, it's never created, resized or deleted
+        #[cfg(target_os = "linux")]
+        pub fn open_file(path: &Path, offset: u64, size: i32) -> Result<Self, Error> {
+            SharedMemory::check_offset(offset)?;
+            let name_c = CString::new(path.as_os_str().as_bytes()).expect("CString::new failed");
+            let fd = retry_eintr(|| unsafe { libc::open(name_c.as_ptr(), O_RDWR) });
+            if fd == -1 {
+                return Err(OsError::last(Operation::Open, &path.to_string_lossy(), Some(size)).into());
+            }
+            let fd = unsafe { OwnedFd::from_raw_fd(fd) };
+            let p_buf = match SharedMemory::map_fd(fd.as_fd(), offset, size, PROT_READ | PROT_WRITE) {
+                Ok(p_buf) => p_buf,
+                Err(err) => return Err(OsError::wrap(Operation::Mmap, &path.to_string_lossy(), Some(size), err).into()),
+            };
+            let shared_memory = SharedMemory {
+                size,
+                offset,
+                name: name_c,
+                logical_name: path.to_string_lossy().into_owned(),
+                fd: Some(fd),
+                p_buf,
+                protection: Vec::new(),
+                local: None,
+                last_notified: Cell::new(0),
+                is_create: false,
+                in_dir: false,
+                sysv_id: None,
+            };
+            Ok(shared_memory)
+        }
+
+        #[cfg(target_os = "linux")]
+        fn map_fd(fd: BorrowedFd<'_>, offset: u64, size: i32, prot: c_int) -> Result<NonNull<u8>, io::Error> {
+            let offset = file_offset(offset)?;
+            let p_buf = unsafe {
+                mmap(
+                    ptr::null_mut(),
+                    size as size_t,
+                    prot,
+                    MAP_SHARED,
+                    fd.as_raw_fd(),
+                    offset,
+                )
+            };
+            if p_buf == libc::MAP_FAILED {
+                return Err(io::Error::last_os_error());
+            }
+            Ok(mapped(p_buf))
+        }
+    }
+
+    /// The start of a view that mapping or allocating has already checked for failure, none of which hand back
+    /// address 0 on success
+    #[cfg(any(target_os = "linux", feature = "testing"))]
+    fn mapped<T>(p_buf: *mut T) -> NonNull<u8> {
+        NonNull::new(p_buf as *mut u8).expect("a successful mapping is never at address 0")
+    }
+
+    /// The path to open for the segment `name`: a file in `dir` when there is one, its shm name otherwise
+    #[cfg(target_os = "linux")]
+    fn object_name(name: &str, policy: NamePolicy, dir: Option<&Path>) -> io::Result<CString> {
+        match dir {
+            Some(dir) => {
+                let path = dir.join(file_name(name, policy)?);
+                CString::new(path.as_os_str().as_bytes()).map_err(|err| io::Error::new(io::ErrorKind::InvalidInput, err))
+            }
+            None => Ok(normalize_name(name, policy)?.into_c_string()),
+        }
+    }
+
+    /// Opens what `object_name` gave, with `shm_open` or, for a file in a backing directory, plain `open`
+    #[cfg(target_os = "linux")]
+    fn open_object(object: &CStr, in_dir: bool, flags: c_int) -> c_int {
+        retry_eintr(|| unsafe {
+            if in_dir {
+                // `shm_open` sets close-on-exec by itself
+                libc::open(object.as_ptr(), flags | libc::O_CLOEXEC, 0o600 as libc::c_uint)
+            } else {
+                shm_open(object.as_ptr(), flags, 0o600)
+            }
+        })
+    }
+
+    #[cfg(target_os = "linux")]
+    fn open_operation(dir: Option<&Path>) -> Operation {
+        if dir.is_some() {
+            Operation::Open
+        } else {
+            Operation::ShmOpen
+        }
+    }
+
+    /// Unlinks what `object_name` gave, there is nobody to report a failure to on the drop and cleanup paths
+    #[cfg(target_os = "linux")]
+    fn remove_object(object: &CStr, in_dir: bool) {
+        unsafe {
+            if in_dir {
+                libc::unlink(object.as_ptr());
+            } else {
+                shm_unlink(object.as_ptr());
+            }
+        }
+    }
+
+    /// `offset` as an `off_t`, which only has 32 bits on 32-bit targets built without large file support
+    #[cfg(target_os = "linux")]
+    fn file_offset(offset: u64) -> Result<off_t, Error> {
+        off_t::try_from(offset).map_err(|_| Error::FileOffsetTooLarge { offset, max: off_t::MAX as u64 })
+    }
+
+    /// Bytes per thread for a parallel copy of `len` bytes, `None` when it should stay on the calling thread
+    fn parallel_chunk(len: usize, min_chunk: usize) -> Option<usize> {
+        let threads = thread::available_parallelism().map_or(1, |threads| threads.get());
+        let chunk = len.div_ceil(threads).max(min_chunk).max(1);
+        (threads > 1 && len > chunk).then_some(chunk)
+    }
+
+    mod search {
+        #[cfg(feature = "memchr")]
+        pub fn find(haystack: &[u8], needle: &[u8]) -> Option<usize> {
+            match needle {
+                [byte] => memchr::memchr(*byte, haystack),
+                _ => memchr::memmem::find(haystack, needle),
+            }
+        }
+
+        #[cfg(feature = "memchr")]
+        pub fn rfind(haystack: &[u8], needle: &[u8]) -> Option<usize> {
+            match needle {
+                [byte] => memchr::memrchr(*byte, haystack),
+                _ => memchr::memmem::rfind(haystack, needle),
+            }
+        }
+
+        #[cfg(not(feature = "memchr"))]
+        pub fn find(haystack: &[u8], needle: &[u8]) -> Option<usize> {
+            match needle {
+                [] => Some(0),
+                [byte] => haystack.iter().position(|candidate| candidate == byte),
+                _ => haystack.windows(needle.len()).position(|window| window == needle),
+            }
+        }
+
+        #[cfg(not(feature = "memchr"))]
+        pub fn rfind(haystack: &[u8], needle: &[u8]) -> Option<usize> {
+            match needle {
+                [] => Some(haystack.len()),
+                [byte] => haystack.iter().rposition(|candidate| candidate == byte),
+                _ => haystack.windows(needle.len()).rposition(|window| window == needle),
+            }
+        }
+    }
+
+    macro_rules! sealed_reads {
+        ($($name:ident -> $ty:ty),* $(,)?) => {
+            $(
+                pub fn $name(&self, offset: usize) -> Result<$ty, Error> {
+                    self.shm.$name(offset)
+                }
+            )*
+        };
+    }
+
+    impl SealedSharedMemory {
+        pub fn size(&self) -> i32 {
+            self.shm.size()
+        }
+
+        pub fn name(&self) -> String {
+            self.shm.name()
+        }
+
+        pub fn address(&self) -> *const u8 {
+            self.shm.address() as *const u8
+        }
+
+        pub fn read_data(&self) -> &[u8] {
+            self.shm.read_data()
+        }
+
+        pub fn read_data_owned(&self) -> Vec<u8> {
+            self.shm.read_data_owned()
+        }
+
+        pub fn read_owned_at(&self, offset: usize, len: usize) -> Result<Vec<u8>, Error> {
+            self.shm.read_owned_at(offset, len)
+        }
+
+        pub fn copy_to_slice_at(&self, offset: usize, dst: &mut [u8]) -> Result<(), Error> {
+            self.shm.copy_to_slice_at(offset, dst)
+        }
+
+        pub fn chunks(&self, chunk_size: usize) -> std::slice::Chunks<'_, u8> {
+            self.shm.chunks(chunk_size)
+        }
+
+        pub fn find(&self, needle: &[u8], start: usize) -> Option<usize> {
+            self.shm.find(needle, start)
+        }
+
+        pub fn rfind(&self, needle: &[u8]) -> Option<usize> {
+            self.shm.rfind(needle)
+        }
+
+        pub fn read_string(&self) -> String {
+            self.shm.read_string()
+        }
+
+        pub fn snapshot(&self) -> Vec<u8> {
+            self.shm.snapshot()
+        }
+
+        pub fn content_eq_slice(&self, other: &[u8]) -> bool {
+            self.shm.content_eq_slice(other)
+        }
+
+        pub fn hexdump(&self, range: Range<usize>) -> String {
+            self.shm.hexdump(range)
+        }
+
+        pub fn read_value<T: SharedSafe>(&self, offset: usize) -> Result<T, Error> {
+            self.shm.read_value(offset)
+        }
+
+        pub fn read_volatile_at<T: SharedSafe>(&self, offset: usize) -> Result<T, Error> {
+            self.shm.read_volatile_at(offset)
+        }
+
+        sealed_reads! {
+            get_byte -> u8,
+            read_u16_le -> u16,
+            read_u16_be -> u16,
+            read_u32_le -> u32,
+            read_u32_be -> u32,
+            read_u64_le -> u64,
+            read_u64_be -> u64,
+            read_f64_le -> f64,
+            read_f64_be -> f64,
+        }
+
+        #[cfg(feature = "xxhash")]
+        pub fn hash_range(&self, range: Range<usize>) -> Result<u64, Error> {
+            self.shm.hash_range(range)
+        }
+
+        #[cfg(feature = "xxhash")]
+        pub fn read_blob_hashed(&self, offset: usize) -> Result<Vec<u8>, IntegrityError> {
+            self.shm.read_blob_hashed(offset)
+        }
+
+        #[cfg(feature = "lz4")]
+        pub fn read_message_auto(&self, out: &mut Vec<u8>) -> Result<(), Error> {
+            self.shm.read_message_auto(out)
+        }
+
+        pub fn read_message_blocking(&self, timeout: Option<Duration>) -> Result<Vec<u8>, WaitError> {
+            self.shm.read_message_blocking(timeout)
+        }
+
+        pub fn last_writer_pid(&self) -> Result<Option<u32>, Error> {
+            self.shm.last_writer_pid()
+        }
+
+        pub fn wait_for_change_u32(&self, offset: usize, expected: u32, timeout: Option<Duration>) -> Result<WaitResult, Error> {
+            self.shm.wait_for_change_u32(offset, expected, timeout)
+        }
+
+        pub fn resident_pages(&self, range: Range<usize>) -> io::Result<Vec<bool>> {
+            self.shm.resident_pages(range)
+        }
+
+        pub fn touch_pages(&self, range: Range<usize>) -> io::Result<()> {
+            self.shm.touch_pages(range)
+        }
+    }
+
+    /// The mapped bytes, indexed like a `[u8]` and panicking on out-of-bounds indices the same way
+    ///
+    /// Like `read_data` this borrows the shared pages and knows nothing of `protect_range`, the checked accessors are
+    /// there for access that may fail
+    ///
+    /// ```
+    /// use shared_memory::shared_memory::SharedMemory;
+    ///
+    /// let (mut shm, _name) = SharedMemory::create_unique("index_doc", 4096).unwrap();
+    /// shm[16..20].copy_from_slice(b"SHMK");
+    /// shm[0] = 1;
+    /// assert_eq!(&shm[16..20], b"SHMK");
+    /// assert_eq!(shm[0], 1);
+    /// assert_eq!(shm[..].len(), shm.size() as usize);
+    /// ```
+    impl<I: std::slice::SliceIndex<[u8]>> std::ops::Index<I> for SharedMemory {
+        type Output = I::Output;
+
+        fn index(&self, index: I) -> &I::Output {
+            &self.bytes()[index]
+        }
+    }
+
+    impl<I: std::slice::SliceIndex<[u8]>> std::ops::IndexMut<I> for SharedMemory {
+        fn index_mut(&mut self, index: I) -> &mut I::Output {
+            &mut self.bytes_mut()[index]
+        }
+    }
+
+    impl<I: std::slice::SliceIndex<[u8]>> std::ops::Index<I> for SealedSharedMemory {
+        type Output = I::Output;
+
+        fn index(&self, index: I) -> &I::Output {
+            &self.shm[index]
+        }
+    }
+
+    /// Same as [`SharedMemory::from_file`] using the file's current length
+    impl TryFrom<File> for SharedMemory {
+        type Error = Error;
+
+        fn try_from(file: File) -> Result<Self, Error> {
+            SharedMemory::from_file(file, None)
+        }
+    }
+
+    impl Drop for SharedMemory {
+        fn drop(&mut self) {
+            if let Some(layout) = self.local {
+                if !self.protection.is_empty() {
+                    // The allocator writes to freed memory, so it has to be writable again first
+                    let _ = self.set_protection(0..layout.size(), Protection::ReadWrite);
+                }
+                unsafe {
+                    dealloc(self.p_buf.as_ptr(), layout);
+                }
+                return;
+            }
+            #[cfg(target_os = "linux")]
+            if let Some(id) = self.sysv_id {
+                unsafe {
+                    libc::shmdt(self.address());
+                    if self.is_create {
+                        libc::shmctl(id, libc::IPC_RMID, ptr::null_mut());
+                    }
+                }
+                return;
+            }
+            // The descriptor or mapping handle is closed when its field drops, after the view is gone
+            unsafe {
+                #[cfg(target_os = "windows")]
+                UnmapViewOfFile(self.address());
+                #[cfg(target_os = "linux")]
+                {
+                    munmap(self.address(), self.size as size_t);
+                    if self.is_create {
+                        remove_object(&self.name, self.in_dir);
+                    }
+                }
+            }
+        }
+    }
+
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::name::{normalize_for, unique_name, InvalidName, NamePolicy, Platform};
+    use crate::shared_memory::SharedMemory;
+
+    fn normalized(platform: Platform, name: &str) -> Result<String, InvalidName> {
+        normalize_for(platform, name, NamePolicy::Reject).map(|name| name.into_c_string().into_string().unwrap())
+    }
+
+    fn hashed(platform: Platform, name: &str) -> String {
+        let name = normalize_for(platform, name, NamePolicy::HashWhenTooLong).unwrap();
+        name.into_c_string().into_string().unwrap()
+    }
+
+    /// Writes `data` at `offset` into the mapping through its address
+    fn poke(shm: &SharedMemory, offset: usize, data: &[u8]) {
+        assert!(offset + data.len() <= shm.size() as usize);
+        unsafe { std::ptr::copy_nonoverlapping(data.as_ptr(), (shm.address() as *mut u8).add(offset), data.len()) };
+    }
+
+    /// Copies `len` bytes at `offset` out of the mapping through its address
+    fn peek(shm: &SharedMemory, offset: usize, len: usize) -> Vec<u8> {
+        assert!(offset + len <= shm.size() as usize);
+        unsafe { std::slice::from_raw_parts((shm.address() as *const u8).add(offset), len) }.to_vec()
+    }
+
+    #[test]
+    fn windows_at_an_offset_land_where_the_offset_says() {
+        use crate::shared_memory::Error;
+
+        let path = std::env::temp_dir().join(format!("window.{}", std::process::id()));
+        std::fs::File::create(&path).unwrap().set_len(256 * 1024).unwrap();
+        let window = SharedMemory::open_file(&path, 64 * 1024, 4096).unwrap();
+        assert_eq!(window.size(), 4096);
+        // The window's address is the file's byte at the offset
+        let bytes = window.address() as *mut u8;
+        unsafe {
+            std::ptr::copy_nonoverlapping(b"start of the window".as_ptr(), bytes, 19);
+            std::ptr::copy_nonoverlapping(b"end".as_ptr(), bytes.add(4096 - 3), 3);
+        }
+        let whole = SharedMemory::open_file(&path, 0, 256 * 1024).unwrap();
+        let all = unsafe { std::slice::from_raw_parts(whole.address() as *const u8, 256 * 1024) };
+        assert_eq!(&all[64 * 1024..64 * 1024 + 19], b"start of the window");
+        assert_eq!(&all[68 * 1024 - 3..68 * 1024], b"end");
+        drop((window, whole));
+        let contents = std::fs::read(&path).unwrap();
+        assert_eq!(&contents[64 * 1024..64 * 1024 + 19], b"start of the window");
+        assert!(contents[..64 * 1024].iter().all(|&byte| byte == 0));
+
+        let alignment = SharedMemory::offset_alignment();
+        match SharedMemory::open_file(&path, alignment / 2, 4096) {
+            Err(Error::UnalignedOffset { offset, alignment: found }) => assert_eq!((offset, found), (alignment / 2, alignment)),
+            other => panic!("expected an unaligned offset, got {:?}", other.map(|shm| shm.size())),
+        }
+        std::fs::remove_file(&path).unwrap();
+
+        // Segments open at an offset the same way
+        let name = format!("/window.{}", std::process::id());
+        let shm = SharedMemory::create(&name, 128 * 1024).unwrap();
+        let window = SharedMemory::open_with_offset(&name, 64 * 1024, 4096).unwrap();
+        unsafe { (window.address() as *mut u64).add(1).write(0x5748_4e44) };
+        assert_eq!(unsafe { (shm.address() as *const u64).add(64 * 1024 / 8 + 1).read() }, 0x5748_4e44);
+        assert!(matches!(SharedMemory::open_with_offset(&name, 1, 4096), Err(Error::UnalignedOffset { offset: 1, .. })));
+    }
+
+    #[test]
+    fn sliding_windows_see_their_slice_of_the_file() {
+        use crate::shared_memory::Error;
+
+        let step = SharedMemory::offset_alignment();
+        let path = std::env::temp_dir().join(format!("slide.{}", std::process::id()));
+        let contents: Vec<u8> = (0..4 * step as usize).map(|byte| (byte / 4096 * 31 + byte % 251) as u8).collect();
+        std::fs::write(&path, &contents).unwrap();
+        let mut window = SharedMemory::open_file(&path, 0, 4096).unwrap();
+        let len = step as usize;
+        for offset in (0..4).map(|index| index * step) {
+            window.remap_window(offset, len).unwrap();
+            assert_eq!((window.offset(), window.size() as usize), (offset, len));
+            let seen = unsafe { std::slice::from_raw_parts(window.address() as *const u8, len) };
+            assert_eq!(seen, &contents[offset as usize..offset as usize + len]);
+        }
+        // Writing through the last window reaches the file
+        unsafe { std::ptr::copy_nonoverlapping(b"slid".as_ptr(), (window.address() as *mut u8).add(100), 4) };
+
+        // Neither error moves the window
+        assert!(matches!(window.remap_window(step + 1, 4096), Err(Error::UnalignedOffset { .. })));
+        let past_the_end = window.remap_window(3 * step, 2 * step as usize);
+        #[cfg(target_os = "linux")]
+        assert!(matches!(past_the_end, Err(Error::WindowOutOfRange { offset, available, .. }) if offset == 3 * step && available == 4 * step));
+        #[cfg(target_os = "windows")]
+        assert!(past_the_end.is_err());
+        assert_eq!((window.offset(), window.size() as usize), (3 * step, len));
+        assert_eq!(unsafe { std::slice::from_raw_parts((window.address() as *const u8).add(100), 4) }, b"slid");
+        drop(window);
+        assert_eq!(&std::fs::read(&path).unwrap()[3 * step as usize + 100..][..4], b"slid");
+        std::fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn volatile_polls_see_a_flag_set_through_another_mapping() {
+        use crate::shared_memory::Error;
+
+        let name = format!("/volatile.{}", std::process::id());
+        let shm = SharedMemory::create(&name, 4096).unwrap();
+        let setter = std::thread::spawn(move || {
+            let shm = SharedMemory::open(&name, 4096).unwrap();
+            std::thread::sleep(std::time::Duration::from_millis(20));
+            shm.write_volatile_at(64, 1u8).unwrap();
+        });
+        while shm.read_volatile_at::<u8>(64).unwrap() == 0 {
+            std::hint::spin_loop();
+        }
+        setter.join().unwrap();
+        shm.write_volatile_at(128, [7u16, 9]).unwrap();
+        assert_eq!(shm.read_volatile_at::<[u16; 2]>(128).unwrap(), [7, 9]);
+        assert_eq!(peek(&shm, 128, 4), [7u16.to_ne_bytes(), 9u16.to_ne_bytes()].concat());
+        assert!(matches!(shm.read_volatile_at::<u64>(4090), Err(Error::OutOfBounds { .. })));
+        assert!(matches!(shm.write_volatile_at(4096, 0u8), Err(Error::OutOfBounds { .. })));
+        assert!(matches!(shm.write_volatile_at(usize::MAX, 0u16), Err(Error::OutOfBounds { .. })));
+        assert!(matches!(shm.read_volatile_at::<u32>(130), Err(Error::Misaligned { offset: 130, alignment: 4 })));
+        assert!(matches!(shm.write_volatile_at(65, 0u16), Err(Error::Misaligned { offset: 65, alignment: 2 })));
+    }
+
+    #[test]
+    fn endian_accessors_lay_out_exactly_the_bytes_they_name() {
+        let shm = SharedMemory::create(&format!("/endian.{}", std::process::id()), 4096).unwrap();
+        shm.write_u16_le(0, 0x0102).unwrap();
+        shm.write_u16_be(2, 0x0102).unwrap();
+        shm.write_u32_le(4, 0x0102_0304).unwrap();
+        shm.write_u32_be(8, 0x0102_0304).unwrap();
+        shm.write_u64_le(16, 0x0102_0304_0506_0708).unwrap();
+        shm.write_u64_be(24, 0x0102_0304_0506_0708).unwrap();
+        // 1.0 is 0x3ff0_0000_0000_0000
+        shm.write_f64_le(32, 1.0).unwrap();
+        shm.write_f64_be(40, 1.0).unwrap();
+        let bytes = peek(&shm, 0, 48);
+        assert_eq!(bytes[..12], [2, 1, 1, 2, 4, 3, 2, 1, 1, 2, 3, 4]);
+        assert_eq!(bytes[16..32], [8, 7, 6, 5, 4, 3, 2, 1, 1, 2, 3, 4, 5, 6, 7, 8]);
+        assert_eq!(bytes[32..48], [0, 0, 0, 0, 0, 0, 0xf0, 0x3f, 0x3f, 0xf0, 0, 0, 0, 0, 0, 0]);
+        assert_eq!((shm.read_u16_le(0).unwrap(), shm.read_u16_be(0).unwrap()), (0x0102, 0x0201));
+        assert_eq!((shm.read_u32_le(4).unwrap(), shm.read_u32_be(8).unwrap()), (0x0102_0304, 0x0102_0304));
+        assert_eq!(shm.read_u64_be(16).unwrap(), 0x0807_0605_0403_0201);
+        assert_eq!((shm.read_f64_le(32).unwrap(), shm.read_f64_be(40).unwrap()), (1.0, 1.0));
+        // No alignment needed, these go through byte copies
+        shm.write_u32_be(13, 0xa1b2_c3d4).unwrap();
+        assert_eq!(peek(&shm, 13, 4), [0xa1, 0xb2, 0xc3, 0xd4]);
+        assert!(shm.read_u64_le(4089).is_err());
+        assert!(shm.write_f64_be(usize::MAX - 3, 0.0).is_err());
+
+        // Headers are little-endian on every host, like a log's magic and capacity
+        let name = format!("/endian.log.{}", std::process::id());
+        let _log = crate::log::ShmLog::create(&name, 4096).unwrap();
+        let header = SharedMemory::open(&name, 16).unwrap();
+        assert_eq!(peek(&header, 0, 4), b"GLHS");
+        assert_eq!(peek(&header, 8, 8), [0, 0x10, 0, 0, 0, 0, 0, 0]);
+    }
+
+    #[test]
+    fn shared_safe_types_round_trip_through_the_typed_accessors() {
+        use crate::shared_memory::Error;
+        use crate::shared_safe::SharedSafe;
+
+        #[repr(C)]
+        #[derive(Clone, Copy, Debug, PartialEq)]
+        struct Sample {
+            id: u32,
+            flags: u8,
+            pad: [u8; 3],
+            readings: [f32; 4],
+            total: i64,
+        }
+
+        unsafe impl SharedSafe for Sample {}
+
+        crate::assert_shared_layout!(Sample, size = 32, align = 8);
+        crate::assert_shared_layout!([[u16; 3]; 2], size = 12, align = 2);
+
+        let shm = SharedMemory::create(&format!("/shared_safe.{}", std::process::id()), 4096).unwrap();
+        let sample = Sample { id: 7, flags: 1, pad: [0; 3], readings: [0.5, -1.0, 2.25, 8.0], total: -40 };
+        shm.write_value(64, sample).unwrap();
+        assert_eq!(shm.read_value::<Sample>(64).unwrap(), sample);
+        assert_eq!(shm.read_value::<[Sample; 2]>(64).unwrap()[0], sample);
+        shm.write_value(128, [[1u16, 2, 3], [4, 5, 6]]).unwrap();
+        assert_eq!(shm.read_value::<[u16; 6]>(128).unwrap(), [1, 2, 3, 4, 5, 6]);
+        // Every bit pattern is valid, so reading the bytes as another SharedSafe type is fine
+        shm.write_value(192, -1i8).unwrap();
+        assert_eq!(shm.read_value::<u8>(192).unwrap(), 255);
+        assert!(matches!(shm.read_value::<Sample>(68), Err(Error::Misaligned { offset: 68, alignment: 8 })));
+        assert!(matches!(shm.write_value(4072, sample), Err(Error::OutOfBounds { .. })));
+    }
+
+    #[test]
+    fn cell_updates_from_many_threads_all_land() {
+        use crate::shared_memory::Error;
+
+        let shm = SharedMemory::create(&format!("/cell.{}", std::process::id()), 4096).unwrap();
+        let counter = shm.cell_at::<u64>(64).unwrap();
+        // Another cell for the same address, which has to share the first one's lock
+        let alias = shm.cell_at::<u64>(64).unwrap();
+        let pair = shm.cell_at::<[u32; 2]>(128).unwrap();
+        // Overlaps the second half of `pair` from a different start address, and still has to exclude it
+        let half = shm.cell_at::<u32>(132).unwrap();
+        std::thread::scope(|scope| {
+            for thread in 0..8 {
+                let cell = if thread % 2 == 0 { &counter } else { &alias };
+                let (pair, half) = (&pair, &half);
+                scope.spawn(move || {
+                    for _ in 0..10_000 {
+                        cell.update(|value| value + 1);
+                        // Both halves change in one update, a torn one would lose some of them
+                        let [first, second] = pair.update(|[first, second]| [first + 1, second + 1]);
+                        assert!(first <= second);
+                        if thread < 4 {
+                            half.update(|value| value + 1);
+                        }
+                    }
+                });
+            }
+        });
+        assert_eq!(counter.get(), 80_000);
+        assert_eq!(pair.get(), [80_000, 120_000]);
+        assert_eq!(shm.read_value::<u64>(64).unwrap(), 80_000);
+        alias.set(7);
+        assert_eq!(counter.get(), 7);
+        assert!(matches!(shm.cell_at::<u64>(68), Err(Error::Misaligned { offset: 68, alignment: 8 })));
+        assert!(matches!(shm.cell_at::<u64>(4092), Err(Error::OutOfBounds { .. })));
+    }
+
+    #[test]
+    // Single ranges are the expected diffs here, not a mistaken `vec![0; n]`
+    #[allow(clippy::single_range_in_vec_init)]
+    fn content_comparisons_find_every_differing_byte() {
+        let name = format!("/content_eq.{}", std::process::id());
+        let first = SharedMemory::create(&name, 4096).unwrap();
+        let second = SharedMemory::create(&format!("/content_eq.other.{}", std::process::id()), 4096).unwrap();
+        let same_pages = SharedMemory::open(&name, 4096).unwrap();
+        poke(&first, 0, b"blue config");
+        poke(&second, 0, b"blue config");
+        assert!(first.content_eq(&second) && first.content_eq(&first) && first.content_eq(&same_pages));
+        assert!(first.diff_ranges(&second).is_empty() && first.diff_ranges(&first).is_empty());
+        let mut expected = vec![0u8; 4096];
+        expected[..11].copy_from_slice(b"blue config");
+        assert!(first.content_eq_slice(&expected));
+        assert!(!first.content_eq_slice(&expected[..4095]) && !first.content_eq_slice(&[]));
+
+        // One differing byte, then runs of them
+        poke(&second, 4095, &[1]);
+        assert!(!first.content_eq(&second));
+        assert_eq!(first.diff_ranges(&second), [4095..4096]);
+        poke(&second, 0, b"BLUE");
+        poke(&second, 100, &[1, 1]);
+        assert_eq!(first.diff_ranges(&second), [0..4, 100..102, 4095..4096]);
+        expected[4095] = 1;
+        assert!(!first.content_eq_slice(&expected));
+
+        // Mappings of different lengths are never equal, and the extra bytes count as one difference
+        let shorter = SharedMemory::open(&name, 1024).unwrap();
+        assert!(!first.content_eq(&shorter));
+        assert_eq!(first.diff_ranges(&shorter), [1024..4096]);
+        assert_eq!(shorter.diff_ranges(&first), [1024..4096]);
+    }
+
+    #[test]
+    fn restoring_a_snapshot_brings_back_every_byte() {
+        let mut shm = SharedMemory::create(&format!("/snapshot.{}", std::process::id()), 8192).unwrap();
+        let pattern: Vec<u8> = (0..8192u32).map(|byte| (byte * 7 % 256) as u8).collect();
+        poke(&shm, 0, &pattern);
+        let snapshot = shm.snapshot();
+        assert_eq!(snapshot, pattern);
+        poke(&shm, 100, &[0xff; 1000]);
+        poke(&shm, 8191, &[0]);
+        assert_ne!(shm.snapshot(), snapshot);
+        shm.restore(&snapshot).unwrap();
+        assert!(shm.content_eq_slice(&snapshot));
+
+        // A shorter buffer only covers the start, a longer one is refused before anything is written
+        shm.restore(b"short").unwrap();
+        assert_eq!(shm.snapshot()[..6], [b's', b'h', b'o', b'r', b't', pattern[5]]);
+        shm.restore(&snapshot).unwrap();
+        assert!(shm.restore(&[1; 8193]).is_err());
+        assert!(shm.content_eq_slice(&snapshot));
+    }
+
+    #[test]
+    fn dumps_round_trip_through_a_file() {
+        let dir = std::env::temp_dir().join(format!("dump.{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let path = dir.join("segment.bin");
+        let shm = SharedMemory::create(&format!("/dump.{}", std::process::id()), 4096).unwrap();
+        poke(&shm, 0, b"survives a reboot");
+        poke(&shm, 4095, &[0x7f]);
+        shm.dump_to_file(&path).unwrap();
+        // Only the dump itself is left, the temporary file was renamed over it
+        assert_eq!(std::fs::read_dir(&dir).unwrap().count(), 1);
+        assert_eq!(std::fs::read(&path).unwrap(), shm.snapshot());
+
+        let mut restored = SharedMemory::create(&format!("/dump.restored.{}", std::process::id()), 4096).unwrap();
+        assert_eq!(restored.load_from_file(&path).unwrap(), 4096);
+        assert!(restored.content_eq(&shm));
+
+        // Larger than the segment, nothing of it is copied in
+        std::fs::write(&path, vec![1u8; 4097]).unwrap();
+        let err = restored.load_from_file(&path).unwrap_err();
+        assert_eq!(err.kind(), std::io::ErrorKind::InvalidInput);
+        assert!(restored.content_eq(&shm));
+        assert_eq!(restored.load_from_file(&dir.join("missing")).unwrap_err().kind(), std::io::ErrorKind::NotFound);
+
+        // Dumps racing to the same path don't share a temporary file, the one renamed last wins whole
+        let written: Vec<_> = std::thread::scope(|scope| {
+            let dumps: Vec<_> = (0..4u8)
+                .map(|i| {
+                    let path = &path;
+                    scope.spawn(move || {
+                        let shm = SharedMemory::create(&format!("/dump.{}.{}", std::process::id(), i), 4096).unwrap();
+                        poke(&shm, 0, &[i + 1; 4096]);
+                        shm.dump_to_file(path).unwrap();
+                        shm.snapshot()
+                    })
+                })
+                .collect();
+            dumps.into_iter().map(|dump| dump.join().unwrap()).collect()
+        });
+        assert!(written.contains(&std::fs::read(&path).unwrap()));
+        assert_eq!(std::fs::read_dir(&dir).unwrap().count(), 1);
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn hexdumps_format_partial_lines_and_the_end() {
+        let shm = SharedMemory::create(&format!("/hexdump.{}", std::process::id()), 4096).unwrap();
+        poke(&shm, 0, b"SHMK\x00\x01 header\x7f\xffabcdefghij");
+        assert_eq!(
+            shm.hexdump(0..20),
+            "00000000 | 53 48 4d 4b 00 01 20 68 65 61 64 65 72 7f ff 61 | SHMK.. header..a\n\
+             00000010 | 62 63 64 65                                     | bcde\n"
+        );
+        // Off a line boundary, and clamped to the end of the mapping
+        assert_eq!(shm.hexdump(3..6), "00000003 | 4b 00 01                                        | K..\n");
+        poke(&shm, 4090, b"tail!!");
+        assert_eq!(shm.hexdump(4090..10_000), "00000ffa | 74 61 69 6c 21 21                               | tail!!\n");
+        assert_eq!(shm.hexdump(5000..6000), "");
+        assert_eq!(shm.hexdump(4090..usize::MAX), shm.hexdump(4090..4096));
+        #[allow(clippy::reversed_empty_ranges)]
+        let reversed = shm.hexdump(20..3);
+        assert_eq!(reversed, "");
+        assert_eq!(shm.debug_window(usize::MAX, 8), "");
+        assert_eq!(shm.debug_window(4093, 2), "00000ffb | 61 69 6c 21 21                                  | ail!!\n");
+        assert_eq!(shm.debug_window(1, 4), shm.hexdump(0..6));
+
+        let mut streamed = String::new();
+        shm.hexdump_to(&mut streamed, 0..20).unwrap();
+        assert_eq!(streamed, shm.hexdump(0..20));
+        streamed.clear();
+        shm.hexdump_to(&mut streamed, 4090..10_000).unwrap();
+        assert_eq!(streamed, shm.hexdump(4090..4096));
+    }
+
+    #[test]
+    fn channels_deliver_every_message_in_sender_order() {
+        use crate::channel::{shm_channel, RecvTimeoutError, SendError, ShmSender, TryRecvError, TrySendError};
+        use std::time::Duration;
+
+        const PER_SENDER: u32 = 50_000;
+        let name = format!("/channel.{}", std::process::id());
+        let (first, receiver) = shm_channel(&name, 4096).unwrap();
+        assert_eq!(receiver.try_recv(), Err(TryRecvError::Empty));
+        // A mapping of its own, as a sender in another process would have
+        let second = ShmSender::connect(&name, 4096).unwrap();
+        let sending: Vec<_> = [first, second]
+            .into_iter()
+            .enumerate()
+            .map(|(id, sender)| {
+                std::thread::spawn(move || {
+                    for seq in 0..PER_SENDER {
+                        let mut msg = vec![id as u8];
+                        msg.extend_from_slice(&seq.to_le_bytes());
+                        // The ring holds a few hundred of these, so sends block on a full channel all the time
+                        sender.send(&msg).unwrap();
+                    }
+                })
+            })
+            .collect();
+        let mut next = [0u32; 2];
+        for _ in 0..2 * PER_SENDER {
+            let msg = receiver.recv_timeout(Duration::from_secs(10)).unwrap();
+            let (id, seq) = (msg[0] as usize, u32::from_le_bytes(msg[1..5].try_into().unwrap()));
+            assert_eq!(seq, next[id], "sender {}", id);
+            next[id] += 1;
+        }
+        for sending in sending {
+            sending.join().unwrap();
+        }
+        assert_eq!(next, [PER_SENDER; 2]);
+        // Both senders are gone now
+        assert_eq!(receiver.try_recv(), Err(TryRecvError::Disconnected));
+        assert_eq!(receiver.recv_timeout(Duration::from_secs(1)), Err(RecvTimeoutError::Disconnected));
+        assert!(receiver.recv().is_err());
+
+        let late = ShmSender::connect(&name, 4096).unwrap();
+        assert!(matches!(late.try_send(&[0; 4096]), Err(TrySendError::TooLarge { len: 4096, .. })));
+        late.try_send(b"after a reconnect").unwrap();
+        assert_eq!(receiver.recv_timeout(Duration::from_secs(1)).unwrap(), b"after a reconnect");
+        assert_eq!(receiver.recv_timeout(Duration::from_millis(10)), Err(RecvTimeoutError::Timeout));
+        let sending = std::thread::spawn(move || {
+            std::thread::sleep(Duration::from_millis(20));
+            late.send(b"no deadline").unwrap();
+            late
+        });
+        // Too long to add to an Instant, so it waits without a deadline instead of panicking
+        assert_eq!(receiver.recv_timeout(Duration::MAX).unwrap(), b"no deadline");
+        let late = sending.join().unwrap();
+        drop(receiver);
+        assert_eq!(late.send(b"nobody listens"), Err(SendError::Disconnected));
+        assert_eq!(late.try_send(b"nobody listens"), Err(TrySendError::Disconnected));
+    }
+
+    #[test]
+    fn rpc_calls_get_their_own_responses_out_of_order() {
+        use crate::rpc::{RpcError, ShmRpcClient, ShmRpcServer};
+        use std::time::Duration;
+
+        const CALLS: usize = 500;
+        let name = format!("/rpc.{}", std::process::id());
+        let mut server = ShmRpcServer::bind_with_capacity(&name, 4096).unwrap();
+        let client = ShmRpcClient::connect_with_capacity(&name, 4096).unwrap();
+        let serving = std::thread::spawn(move || {
+            let mut answered = 0;
+            while answered < 2 * CALLS {
+                let (id, request) = server.next_request_timeout(Duration::from_secs(10)).unwrap();
+                // The other thread's request usually follows shortly, answering it first sends responses back out of order
+                if let Some((next, next_request)) = server.next_request_timeout(Duration::from_millis(20)) {
+                    server.respond(next, &next_request.to_ascii_uppercase()).unwrap();
+                    answered += 1;
+                }
+                server.respond(id, &request.to_ascii_uppercase()).unwrap();
+                answered += 1;
+            }
+            server
+        });
+        std::thread::scope(|scope| {
+            for thread in 0..2 {
+                let client = &client;
+                scope.spawn(move || {
+                    for call in 0..CALLS {
+                        let request = format!("thread {} call {}", thread, call);
+                        assert_eq!(client.call(request.as_bytes(), Duration::from_secs(10)).unwrap(), request.to_uppercase().into_bytes());
+                    }
+                });
+            }
+        });
+        let mut server = serving.join().unwrap();
+
+        // A response to a call that already timed out is dropped, the next call only gets its own
+        assert_eq!(client.call(b"too late", Duration::from_millis(50)), Err(RpcError::Timeout));
+        let (late, request) = server.next_request_timeout(Duration::from_secs(1)).unwrap();
+        assert_eq!(request, b"too late");
+        server.respond(late, b"TOO LATE").unwrap();
+        let answering = std::thread::spawn(move || {
+            let (id, request) = server.next_request_timeout(Duration::from_secs(10)).unwrap();
+            server.respond(id, &request.to_ascii_uppercase()).unwrap();
+        });
+        assert_eq!(client.call(b"on time", Duration::from_secs(10)).unwrap(), b"ON TIME");
+        answering.join().unwrap();
+        assert!(matches!(client.call(&[0; 4096], Duration::from_secs(1)), Err(RpcError::TooLarge { len: 4096, .. })));
+    }
+
+    #[test]
+    fn slow_broadcast_readers_lag_by_exactly_what_they_missed() {
+        use crate::broadcast::{BroadcastRecvError, ShmBroadcast, ShmBroadcastReceiver};
+        use std::time::Duration;
+
+        // Sequence number, then its complement, so a torn copy can't pass for a whole message
+        let message = |seq: u64| [seq.to_le_bytes(), (!seq).to_le_bytes()].concat();
+        let name = format!("/broadcast.{}", std::process::id());
+        let mut writer = ShmBroadcast::create(&name, 4, 16).unwrap();
+        let mut reader = ShmBroadcastReceiver::open(&name, 4, 16).unwrap();
+        for seq in 0..10 {
+            assert_eq!(writer.send(&message(seq)).unwrap(), seq);
+        }
+        // Ten sent into four slots, the reader loses the seven that were or could be overwritten
+        assert_eq!(reader.try_recv(), Err(BroadcastRecvError::Lagged(7)));
+        for seq in 7..10 {
+            assert_eq!(reader.try_recv().unwrap(), message(seq));
+        }
+        assert_eq!(reader.try_recv(), Err(BroadcastRecvError::Empty));
+        assert_eq!(reader.recv(Some(Duration::from_millis(10))), Err(BroadcastRecvError::Empty));
+        // A reader that attaches late starts at the writer's position, and one leaving changes nothing
+        let late = ShmBroadcastReceiver::open(&name, 4, 16).unwrap();
+        assert_eq!(late.cursor(), 10);
+        drop(late);
+        assert!(writer.send(&[0; 17]).is_err());
+        assert!(ShmBroadcastReceiver::open(&name, 8, 16).is_err());
+
+        const MESSAGES: u64 = 20_000;
+        let mut slow = ShmBroadcastReceiver::open(&name, 4, 16).unwrap();
+        let start = slow.cursor();
+        let writing = std::thread::spawn(move || {
+            for seq in start..start + MESSAGES {
+                writer.send(&message(seq)).unwrap();
+            }
+        });
+        let (mut expected, mut lagged) = (start, 0);
+        while expected < start + MESSAGES {
+            match slow.recv(Some(Duration::from_secs(10))) {
+                Ok(msg) => {
+                    assert_eq!(msg, message(expected));
+                    expected += 1;
+                }
+                Err(BroadcastRecvError::Lagged(skipped)) => {
+                    lagged += 1;
+                    expected += skipped;
+                }
+                Err(BroadcastRecvError::Empty) => panic!("the writer stalled at {}", expected),
+            }
+            assert_eq!(slow.cursor(), expected);
+            if expected % 64 == 0 {
+                std::thread::sleep(Duration::from_micros(200));
+            }
+        }
+        writing.join().unwrap();
+        assert_eq!(expected, start + MESSAGES);
+        assert!(lagged > 0);
+    }
+
+    #[test]
+    fn logs_replay_every_record_another_handle_appended() {
+        use crate::log::{LogError, ShmLog};
+
+        const RECORDS: usize = 10_000;
+        let record = |index: usize| format!("record {} {}", index, "x".repeat(index % 40)).into_bytes();
+        let name = format!("/log_replay.{}", std::process::id());
+        let log = ShmLog::create(&name, 1 << 20).unwrap();
+        let scribbler = SharedMemory::open(&name, 128 + (1 << 20)).unwrap();
+        let appending = std::thread::spawn(move || {
+            let writer = ShmLog::open(&name, 1 << 20).unwrap();
+            (0..RECORDS).map(|index| writer.append(&record(index)).unwrap()).collect::<Vec<u64>>()
+        });
+        // Replays follow the tail while the other handle is still appending
+        let mut replayed = Vec::new();
+        let mut offset = 0;
+        while replayed.len() < RECORDS {
+            let mut records = log.read_from(offset);
+            for entry in records.by_ref() {
+                replayed.push(entry.unwrap());
+            }
+            offset = records.offset();
+        }
+        let offsets = appending.join().unwrap();
+        assert_eq!(replayed.iter().map(|(offset, _)| *offset).collect::<Vec<_>>(), offsets);
+        assert!(replayed.iter().enumerate().all(|(index, (_, bytes))| *bytes == record(index)));
+        assert_eq!(log.tail(), offset);
+
+        // Full until truncated, and truncated offsets can't be read any more
+        // One byte more than what's left once its record header is counted
+        let big = vec![7u8; (1 << 20) - offset as usize - 7];
+        assert_eq!(log.append(&big), Err(LogError::Full));
+        assert_eq!(log.truncate_before(offsets[1] + 1), Err(LogError::InvalidOffset { offset: offsets[1] + 1 }));
+        log.truncate_before(offset).unwrap();
+        let at = log.append(&big).unwrap();
+        assert_eq!((log.head(), at), (offset, offset));
+        assert_eq!(log.read_from(0).next(), Some(Err(LogError::Truncated { offset: 0, head: offset })));
+        assert_eq!(log.read_from(at).next().unwrap().unwrap(), (at, big));
+        let past = log.tail() + 8;
+        assert_eq!(log.read_from(past).next(), Some(Err(LogError::InvalidOffset { offset: past })));
+        // One flipped payload byte fails the checksum
+        let byte = unsafe { *(scribbler.address() as *const u8).add(128 + 8 + 100) };
+        poke(&scribbler, 128 + 8 + 100, &[byte ^ 1]);
+        assert_eq!(log.read_from(at).next(), Some(Err(LogError::Corrupt { offset: at })));
+    }
+
+    #[test]
+    fn slab_keys_go_stale_and_churn_reuses_pages() {
+        use crate::slab::{ShmSlab, SlabError, SlabKey, PAGE_SIZE};
+
+        let name = format!("/slab.{}", std::process::id());
+        let slab = ShmSlab::create(&name, 8).unwrap();
+        let other = ShmSlab::open(&name, 8).unwrap();
+        let key = slab.insert(b"first").unwrap();
+        assert_eq!(other.get(key).unwrap(), b"first");
+        other.remove(key).unwrap();
+        assert_eq!(slab.get(key), Err(SlabError::InvalidKey));
+        assert_eq!(slab.remove(key), Err(SlabError::InvalidKey));
+        // Same page and slot again, only the generation tells the keys apart
+        let reused = slab.insert(b"second").unwrap();
+        assert_ne!(reused, key);
+        assert_eq!(other.get(key), Err(SlabError::InvalidKey));
+        assert_eq!(other.get(SlabKey::from_u32(reused.as_u32())).unwrap(), b"second");
+        slab.remove(reused).unwrap();
+        assert_eq!(slab.insert(&[0; PAGE_SIZE + 1]), Err(SlabError::TooLarge { len: PAGE_SIZE + 1, max: PAGE_SIZE }));
+        assert_eq!(slab.get(SlabKey::from_u32(u32::MAX)), Err(SlabError::InvalidKey));
+
+        let sizes = [1, 64, 65, 200, 256, 700, 1024, 3000, 4096];
+        let mut live: Vec<(SlabKey, Vec<u8>)> = Vec::new();
+        let mut fills = 0;
+        for round in 0..5000usize {
+            let pick = round * 7919 % 104_729;
+            if pick % 3 == 0 && !live.is_empty() {
+                let (key, bytes) = live.swap_remove(pick % live.len());
+                assert_eq!(other.get(key).unwrap(), &bytes[..]);
+                other.remove(key).unwrap();
+                assert_eq!(slab.get(key), Err(SlabError::InvalidKey));
+                continue;
+            }
+            let bytes = vec![round as u8; sizes[pick % sizes.len()]];
+            match slab.insert(&bytes) {
+                Ok(key) => live.push((key, bytes)),
+                Err(SlabError::Full) => {
+                    // Free every other allocation, leaving partly used pages of every class behind
+                    fills += 1;
+                    let mut index = 0;
+                    live.retain(|&(key, _)| {
+                        index += 1;
+                        index % 2 == 1 || {
+                            slab.remove(key).unwrap();
+                            assert_eq!(other.get(key), Err(SlabError::InvalidKey));
+                            false
+                        }
+                    });
+                }
+                Err(error) => panic!("unexpected {:?}", error),
+            }
+        }
+        assert!(fills > 0, "churn never filled the slab");
+        for (key, bytes) in &live {
+            assert_eq!(slab.get(*key).unwrap(), &bytes[..]);
+        }
+        for (key, _) in live {
+            slab.remove(key).unwrap();
+        }
+        // Every page came back, so the largest class can have all of them
+        let whole: Vec<_> = (0..8).map(|page| slab.insert(&[page; PAGE_SIZE]).unwrap()).collect();
+        assert_eq!(slab.insert(&[0; PAGE_SIZE]), Err(SlabError::Full));
+        assert_eq!(slab.insert(b"small"), Err(SlabError::Full));
+        for (page, key) in whole.into_iter().enumerate() {
+            assert_eq!(other.get(key).unwrap(), &[page as u8; PAGE_SIZE][..]);
+        }
+    }
+
+    #[test]
+    fn stacked_nodes_are_never_owned_twice() {
+        use crate::stack::ShmStack;
+        use std::sync::atomic::{AtomicBool, Ordering};
+
+        const NODES: u32 = 64;
+        let name = format!("/stack.{}", std::process::id());
+        let shm = SharedMemory::create(&name, 4096).unwrap();
+        let other_shm = SharedMemory::open(&name, 4096).unwrap();
+        let stack = ShmStack::init(&shm, 64, NODES).unwrap();
+        let other = ShmStack::attach(&other_shm, 64, NODES).unwrap();
+        for node in 0..NODES {
+            stack.push(node);
+        }
+        let owned: Vec<AtomicBool> = (0..NODES).map(|_| AtomicBool::new(false)).collect();
+        std::thread::scope(|scope| {
+            for handle in [&stack, &other, &stack, &other] {
+                let owned = &owned;
+                scope.spawn(move || {
+                    let mut held = Vec::new();
+                    for round in 0..50_000usize {
+                        // Hold up to a few nodes at once so pushes and pops interleave in every order
+                        if round % 4 != 3 {
+                            if let Some(node) = handle.pop() {
+                                assert!(!owned[node as usize].swap(true, Ordering::Relaxed), "node {} popped twice", node);
+                                held.push(node);
+                            }
+                        }
+                        if held.len() > 2 || round % 4 == 3 {
+                            if let Some(node) = held.pop() {
+                                owned[node as usize].store(false, Ordering::Relaxed);
+                                handle.push(node);
+                            }
+                        }
+                    }
+                    for node in held {
+                        owned[node as usize].store(false, Ordering::Relaxed);
+                        handle.push(node);
+                    }
+                });
+            }
+        });
+        let mut popped: Vec<_> = std::iter::from_fn(|| other.pop()).collect();
+        popped.sort_unstable();
+        assert_eq!(popped, (0..NODES).collect::<Vec<_>>());
+        assert_eq!(stack.pop(), None);
+        assert!(ShmStack::attach(&other_shm, 64, NODES - 1).is_err());
+        assert!(ShmStack::attach(&other_shm, 60, NODES).is_err());
+        assert!(ShmStack::init(&shm, 64, 4096).is_err());
+    }
+
+    #[test]
+    fn bitmap_blocks_are_never_handed_out_twice() {
+        use crate::bitmap::{BitmapError, ShmBitmapAllocator};
+        use std::sync::atomic::{AtomicBool, Ordering};
+
+        const BLOCKS: usize = 256;
+        let name = format!("/bitmap.{}", std::process::id());
+        let first = ShmBitmapAllocator::create(&name, BLOCKS, 64).unwrap();
+        let second = ShmBitmapAllocator::open(&name, BLOCKS, 64).unwrap();
+        let owned: Vec<AtomicBool> = (0..BLOCKS).map(|_| AtomicBool::new(false)).collect();
+        std::thread::scope(|scope| {
+            for (seed, alloc) in [&first, &second].into_iter().enumerate() {
+                let owned = &owned;
+                scope.spawn(move || {
+                    let mut held: Vec<std::ops::Range<usize>> = Vec::new();
+                    for round in 0..20_000usize {
+                        let pick = round * 7919 + seed * 104_729;
+                        if pick % 5 < 2 && !held.is_empty() {
+                            let range = held.swap_remove(pick % held.len());
+                            for block in range.clone() {
+                                let stamp = u64::from_le_bytes(peek(alloc.shared_memory(), alloc.block_offset(block), 8).try_into().unwrap());
+                                assert_eq!(stamp, (seed << 32 | range.start) as u64, "block {} was overwritten", block);
+                                assert!(owned[block].swap(false, Ordering::Relaxed));
+                            }
+                            alloc.free_blocks(range).unwrap();
+                            continue;
+                        }
+                        match alloc.alloc_blocks(1 + pick % 13) {
+                            Ok(range) => {
+                                for block in range.clone() {
+                                    assert!(!owned[block].swap(true, Ordering::Relaxed), "block {} handed out twice", block);
+                                    poke(alloc.shared_memory(), alloc.block_offset(block), &((seed << 32 | range.start) as u64).to_le_bytes());
+                                }
+                                held.push(range);
+                            }
+                            Err(BitmapError::Fragmented { .. }) => {}
+                            Err(error) => panic!("unexpected {:?}", error),
+                        }
+                    }
+                    for range in held {
+                        for block in range.clone() {
+                            owned[block].store(false, Ordering::Relaxed);
+                        }
+                        alloc.free_blocks(range).unwrap();
+                    }
+                });
+            }
+        });
+
+        assert_eq!(second.alloc_blocks(BLOCKS), Ok(0..BLOCKS));
+        for block in (0..BLOCKS).step_by(2) {
+            first.free_blocks(block..block + 1).unwrap();
+        }
+        assert_eq!(second.alloc_blocks(2), Err(BitmapError::Fragmented { count: 2 }));
+        assert_eq!(first.free_blocks(0..1), Err(BitmapError::NotAllocated { block: 0 }));
+        // A range that's only partly allocated is refused without freeing the part that is
+        assert_eq!(first.free_blocks(1..4), Err(BitmapError::NotAllocated { block: 2 }));
+        assert_eq!(second.alloc_blocks(1), Ok(0..1));
+        assert_eq!(second.alloc_blocks(1), Ok(2..3));
+        first.free_blocks(1..4).unwrap();
+        assert_eq!(second.alloc_blocks(3), Ok(1..4));
+        assert_eq!(first.free_blocks(250..257), Err(BitmapError::OutOfRange { range: 250..257, blocks: BLOCKS }));
+        assert_eq!(first.alloc_blocks(0), Err(BitmapError::OutOfRange { range: 0..0, blocks: BLOCKS }));
+        assert!(ShmBitmapAllocator::open(&name, BLOCKS, 128).is_err());
+    }
+
+    #[test]
+    fn open_with_retry_waits_for_a_late_creator() {
+        use crate::shared_memory::Error;
+        use std::time::{Duration, Instant};
+
+        let name = format!("/retry.{}", std::process::id());
+        let (opened, wait_for_open) = std::sync::mpsc::channel();
+        let creator = {
+            let name = name.clone();
+            std::thread::spawn(move || {
+                std::thread::sleep(Duration::from_millis(300));
+                let shm = SharedMemory::create(&name, 4096).unwrap();
+                // Keep the segment around until the opener has written to it
+                wait_for_open.recv().unwrap();
+                shm.read_u32_le(0).unwrap()
+            })
+        };
+        let started = Instant::now();
+        let shm = SharedMemory::open_with_retry(&name, 4096, Duration::from_secs(1), Duration::from_millis(10)).unwrap();
+        assert!(started.elapsed() >= Duration::from_millis(250), "opened after {:?}", started.elapsed());
+        shm.write_u32_le(0, 42).unwrap();
+        opened.send(()).unwrap();
+        assert_eq!(creator.join().unwrap(), 42);
+
+        let started = Instant::now();
+        let missing = format!("/retry_missing.{}", std::process::id());
+        match SharedMemory::open_with_retry(&missing, 4096, Duration::from_millis(100), Duration::from_millis(10)) {
+            Err(Error::Io(err)) => assert_eq!(err.kind(), std::io::ErrorKind::NotFound),
+            other => panic!("expected NotFound, got {:?}", other.err()),
+        }
+        assert!(started.elapsed() >= Duration::from_millis(100));
+        // Anything but NotFound gives up without waiting out the timeout
+        let started = Instant::now();
+        assert!(SharedMemory::open_with_retry("/retry/inner", 4096, Duration::from_secs(10), Duration::from_millis(10)).is_err());
+        assert!(started.elapsed() < Duration::from_secs(5));
+        // So does a segment that is there but smaller than asked for
+        let small = SharedMemory::create(&missing, 4096).unwrap();
+        let started = Instant::now();
+        match SharedMemory::open_with_retry(&missing, 8192, Duration::from_secs(10), Duration::from_millis(10)) {
+            #[cfg(target_os = "linux")]
+            Err(Error::SizeMismatch { requested: 8192, actual: 4096 }) => {}
+            #[cfg(target_os = "windows")]
+            Err(_) => {}
+            other => panic!("expected a size mismatch, got {:?}", other.map(|shm| shm.size())),
+        }
+        assert!(started.elapsed() < Duration::from_secs(5));
+        drop(small);
+
+        // A segment that exists but hasn't been sized yet is waited for
+        #[cfg(target_os = "linux")]
+        {
+            let name_c = std::ffi::CString::new(missing.clone()).unwrap();
+            let fd = unsafe { libc::shm_open(name_c.as_ptr(), libc::O_CREAT | libc::O_EXCL | libc::O_RDWR, 0o600) };
+            assert!(fd >= 0);
+            let sizing = std::thread::spawn(move || {
+                std::thread::sleep(Duration::from_millis(100));
+                assert_eq!(unsafe { libc::ftruncate(fd, 4096) }, 0);
+                unsafe { libc::close(fd) };
+            });
+            let shm = SharedMemory::open_with_retry(&missing, 4096, Duration::MAX, Duration::from_millis(5)).unwrap();
+            assert_eq!(shm.size(), 4096);
+            sizing.join().unwrap();
+            SharedMemory::unlink(&missing).unwrap();
+        }
+    }
+
+    #[test]
+    fn one_of_many_electing_threads_leads() {
+        use crate::shared_memory::Role;
+        use std::sync::Barrier;
+
+        let name = format!("/elect.{}", std::process::id());
+        let barrier = Barrier::new(8);
+        let leaders = std::thread::scope(|scope| {
+            let handles: Vec<_> = (0..8)
+                .map(|_| {
+                    scope.spawn(|| {
+                        barrier.wait();
+                        let role = SharedMemory::elect(&name, 4096).unwrap();
+                        let (Role::Leader(shm) | Role::Follower(shm)) = &role;
+                        assert!(shm.leader_alive().unwrap());
+                        assert!(shm.creator_alive().unwrap());
+                        assert_eq!(shm.read_value::<u64>(0).unwrap() as u32, std::process::id());
+                        // The leader unlinks the segment when dropped, keep it until everyone has elected
+                        barrier.wait();
+                        matches!(role, Role::Leader(_))
+                    })
+                })
+                .collect();
+            handles.into_iter().map(|handle| handle.join().unwrap()).filter(|&leader| leader).count()
+        });
+        assert_eq!(leaders, 1);
+        // Every handle is gone, so the next election starts from scratch
+        assert!(matches!(SharedMemory::elect(&name, 4096).unwrap(), Role::Leader(_)));
+    }
+
+    #[test]
+    #[cfg(feature = "xxhash")]
+    fn flipping_any_blob_byte_fails_its_hash() {
+        use crate::shared_memory::{IntegrityError, BLOB_HEADER};
+
+        const SIZE: usize = 1 << 20;
+        let name = format!("/blob.{}", std::process::id());
+        let shm = SharedMemory::create(&name, SIZE as i32).unwrap();
+        let reader = SharedMemory::open(&name, SIZE as i32).unwrap();
+        let payload: Vec<u8> = (0..600_000u32).map(|i| (i * 31 % 251) as u8).collect();
+        shm.write_blob_hashed(128, &payload).unwrap();
+        assert_eq!(reader.read_blob_hashed(128).unwrap(), payload);
+        let region = 128 + BLOB_HEADER..128 + BLOB_HEADER + payload.len();
+        let stored = reader.hash_range(region.clone()).unwrap();
+        assert_eq!(stored, xxhash_rust::xxh3::xxh3_64(&payload));
+        for at in [0, payload.len() / 2, payload.len() - 1] {
+            let byte = peek(&shm, region.start + at, 1)[0];
+            poke(&shm, region.start + at, &[byte ^ 1]);
+            match reader.read_blob_hashed(128) {
+                Err(IntegrityError::Mismatch { expected, computed }) => {
+                    assert_eq!(expected, stored);
+                    assert_eq!(computed, reader.hash_range(region.clone()).unwrap());
+                    assert_ne!(computed, expected);
+                }
+                other => panic!("flipping byte {} gave {:?}", at, other.map(|data| data.len())),
+            }
+            poke(&shm, region.start + at, &[byte]);
+        }
+        assert_eq!(reader.read_blob_hashed(128).unwrap(), payload);
+        // A length running past the mapping can't be read, which isn't a mismatch
+        shm.write_u64_le(128, 2 * SIZE as u64).unwrap();
+        assert!(matches!(reader.read_blob_hashed(128), Err(IntegrityError::Access(_))));
+        assert!(shm.write_blob_hashed(SIZE - BLOB_HEADER, &[1]).is_err());
+        assert!(shm.hash_range(0..SIZE + 1).is_err());
+        assert_eq!(shm.hash_range(5..5).unwrap(), xxhash_rust::xxh3::xxh3_64(&[]));
+    }
+
+    #[test]
+    #[cfg(feature = "lz4")]
+    fn compressed_messages_round_trip_and_report_what_does_not_fit() {
+        use crate::shared_memory::{Error, MESSAGE_HEADER};
+
+        let name = format!("/lz4.{}", std::process::id());
+        let shm = SharedMemory::create(&name, 4096).unwrap();
+        let reader = SharedMemory::open(&name, 4096).unwrap();
+        let mut out = vec![1, 2, 3];
+        // Only fits compressed
+        let text = b"shared memory ".repeat(1000);
+        shm.write_message_compressed(&text).unwrap();
+        assert_eq!(reader.read_u32_le(0).unwrap(), 1);
+        reader.read_message_auto(&mut out).unwrap();
+        assert_eq!(out, text);
+
+        let zeros = vec![0u8; 2000];
+        shm.write_message_compressed(&zeros).unwrap();
+        assert!((reader.read_u32_le(4).unwrap() as usize) < 100);
+        reader.read_message_auto(&mut out).unwrap();
+        assert_eq!(out, zeros);
+
+        // Random bytes don't compress, so they're stored as they are
+        let mut state = 0x9e37_79b9_7f4a_7c15u64;
+        let mut noise = |len: usize| -> Vec<u8> {
+            (0..len)
+                .map(|_| {
+                    state ^= state << 13;
+                    state ^= state >> 7;
+                    state ^= state << 17;
+                    state as u8
+                })
+                .collect()
+        };
+        let random = noise(3000);
+        shm.write_message_compressed(&random).unwrap();
+        assert_eq!((reader.read_u32_le(0).unwrap(), reader.read_u32_le(4).unwrap()), (0, 3000));
+        reader.read_message_auto(&mut out).unwrap();
+        assert_eq!(out, random);
+
+        let too_large = noise(8192);
+        match shm.write_message_compressed(&too_large) {
+            Err(Error::MessageTooLarge { len, stored, available }) => assert_eq!((len, stored, available), (8192, 8192, 4096 - MESSAGE_HEADER)),
+            other => panic!("expected MessageTooLarge, got {:?}", other),
+        }
+        // Nothing was written over the last message
+        reader.read_message_auto(&mut out).unwrap();
+        assert_eq!(out, random);
+
+        shm.write_message_compressed(&[]).unwrap();
+        reader.read_message_auto(&mut out).unwrap();
+        assert!(out.is_empty());
+        shm.write_u32_le(0, 7).unwrap();
+        assert!(reader.read_message_auto(&mut out).is_err());
+    }
+
+    #[test]
+    fn chains_spill_into_continuation_segments_and_clean_them_up() {
+        use crate::chain::{ShmChain, HEADER_SIZE};
+
+        let name = format!("/chain.{}", std::process::id());
+        let continuation = |index: usize| SharedMemory::open(&format!("{}.{}", name, index), 4096);
+        let chain = ShmChain::create(&name, 4096, 4).unwrap();
+        assert_eq!(chain.capacity(), 4 * 4096 - HEADER_SIZE);
+        let payload: Vec<u8> = (0..10_240u32).map(|i| (i % 253) as u8).collect();
+        chain.write(&payload).unwrap();
+        let reader = ShmChain::open(&name, 4096).unwrap();
+        assert_eq!(reader.read().unwrap(), payload);
+        assert!(continuation(2).is_ok());
+        assert!(continuation(3).is_err());
+
+        // Shrinking unlinks the segments the payload no longer needs
+        chain.write(b"short").unwrap();
+        assert_eq!(reader.read().unwrap(), b"short");
+        assert!(continuation(1).is_err());
+        assert!(chain.write(&vec![0; chain.capacity() + 1]).is_err());
+        assert_eq!(reader.read().unwrap(), b"short");
+
+        let full = vec![7u8; chain.capacity()];
+        chain.write(&full).unwrap();
+        assert_eq!(reader.read().unwrap(), full);
+        assert!(ShmChain::open(&name, 8192).is_err());
+        drop(chain);
+        for index in 1..4 {
+            assert!(continuation(index).is_err());
+        }
+    }
+
+    #[test]
+    #[cfg(target_os = "linux")]
+    fn discarded_pages_read_zero_and_leave_tmpfs() {
+        use std::os::unix::fs::MetadataExt;
+
+        let page = SharedMemory::offset_alignment() as usize;
+        let len = 64 * page;
+        let name = format!("/discard.{}", std::process::id());
+        let mut shm = SharedMemory::create(&name, len as i32).unwrap();
+        let other = SharedMemory::open(&name, len as i32).unwrap();
+        poke(&shm, 0, &vec![0xa5; len]);
+        // What tmpfs charges for the object
+        let stored = |_: &SharedMemory| std::fs::metadata(format!("/dev/shm{}", name)).unwrap().blocks() as usize * 512;
+        let before = stored(&shm);
+        assert!(before >= len);
+        // Unaligned at both ends, so only the whole pages 2 to 61 go
+        shm.discard_range(page + 1..62 * page + 10).unwrap();
+        let after = stored(&shm);
+        assert!(before - after >= 60 * page, "{} bytes stored before discarding, {} after", before, after);
+        let data = peek(&other, 0, len);
+        assert!(data[..2 * page].iter().all(|&byte| byte == 0xa5));
+        assert!(data[2 * page..62 * page].iter().all(|&byte| byte == 0));
+        assert!(data[62 * page..].iter().all(|&byte| byte == 0xa5));
+
+        // Reading the holes through a shared mapping filled them in again
+        let refilled = stored(&shm);
+        // No whole page inside, nothing goes
+        shm.discard_range(10..page - 1).unwrap();
+        shm.discard_range(62 * page + 1..63 * page + 1).unwrap();
+        assert_eq!(stored(&shm), refilled);
+        // Clamped to the mapping, which takes the last page
+        shm.discard_range(63 * page..usize::MAX).unwrap();
+        let data = peek(&other, 0, len);
+        assert!(data[62 * page..63 * page].iter().all(|&byte| byte == 0xa5));
+        assert!(data[63 * page..].iter().all(|&byte| byte == 0));
+        assert_eq!(data[10], 0xa5);
+    }
+
+    #[test]
+    #[cfg(target_os = "linux")]
+    fn written_pages_show_as_resident() {
+        let page = SharedMemory::offset_alignment() as usize;
+        let len = 1024 * page;
+        let shm = SharedMemory::create(&format!("/resident.{}", std::process::id()), len as i32).unwrap();
+        let written = [3, 77, 500, 1023];
+        for &index in &written {
+            poke(&shm, index * page + 17, &[1]);
+        }
+        let resident = shm.resident_pages(0..len).unwrap();
+        assert_eq!(resident.len(), 1024);
+        for &index in &written {
+            assert!(resident[index], "page {} was written but isn't resident", index);
+        }
+        // Nothing else has touched the fresh segment, though the kernel is free to bring in more
+        let count = resident.iter().filter(|&&page| page).count();
+        assert!(count < 64, "{} of 1024 untouched pages are resident", count - written.len());
+        assert_eq!(shm.resident_bytes().unwrap(), count * page);
+
+        // Widened to the pages it touches and clamped to the mapping
+        assert_eq!(shm.resident_pages(3 * page + 5..4 * page).unwrap(), [true]);
+        assert_eq!(shm.resident_pages(77 * page - 1..77 * page + 1).unwrap(), [resident[76], true]);
+        assert_eq!(shm.resident_pages(len - 1..len + 5 * page).unwrap(), [true]);
+        assert!(shm.resident_pages(10..10).unwrap().is_empty());
+        assert!(shm.resident_pages(len..2 * len).unwrap().is_empty());
+    }
+
+    #[test]
+    fn protected_pages_fail_checked_accesses() {
+        use crate::shared_memory::{Error, Protection};
+
+        let page = SharedMemory::offset_alignment() as usize;
+        let name = format!("/protect.{}", std::process::id());
+        let mut shm = SharedMemory::create(&name, 4 * page as i32).unwrap();
+        let other = SharedMemory::open(&name, 4 * page as i32).unwrap();
+        shm.write_u32_le(0, 0x600d).unwrap();
+        shm.protect_range(0..page, Protection::ReadOnly).unwrap();
+        assert!(matches!(shm.write_u32_le(0, 1), Err(Error::Protected { offset: 0, len: 4, protection: Protection::ReadOnly })));
+        assert!(matches!(shm.write_u32_le(page - 2, 1), Err(Error::Protected { .. })));
+        assert_eq!(shm.read_u32_le(0).unwrap(), 0x600d);
+        shm.write_u32_le(page, 2).unwrap();
+        // Only this mapping is protected
+        other.write_u32_le(4, 3).unwrap();
+        assert_eq!(shm.read_u32_le(4).unwrap(), 3);
+
+        shm.protect_range(2 * page..3 * page, Protection::NoAccess).unwrap();
+        assert!(matches!(shm.read_u32_le(2 * page), Err(Error::Protected { protection: Protection::NoAccess, .. })));
+        assert!(matches!(shm.write_u32_le(3 * page - 4, 1), Err(Error::Protected { .. })));
+        assert_eq!(shm.read_u32_le(3 * page).unwrap(), 0);
+
+        shm.protect_range(0..4 * page, Protection::ReadWrite).unwrap();
+        shm.write_u32_le(0, 4).unwrap();
+        shm.write_u32_le(2 * page, 5).unwrap();
+        assert_eq!((other.read_u32_le(0).unwrap(), other.read_u32_le(2 * page).unwrap()), (4, 5));
+    }
+
+    #[test]
+    fn sealed_segments_keep_what_was_written_before_sealing() {
+        let name = format!("/sealed.{}", std::process::id());
+        let shm = SharedMemory::create(&name, 8192).unwrap();
+        poke(&shm, 0, b"published");
+        shm.write_u64_le(4096, 77).unwrap();
+        let sealed = shm.seal_read_only().unwrap();
+        assert_eq!(&sealed.read_data()[..9], b"published");
+        assert_eq!(sealed.read_value::<[u8; 4]>(0).unwrap(), *b"publ");
+        assert_eq!(sealed.size(), 8192);
+        let consumer = SharedMemory::open_sealed(&name, 8192).unwrap();
+        assert_eq!(&consumer.read_data()[..9], b"published");
+        assert_eq!(consumer.read_value::<u64>(4096).unwrap(), 77u64.to_le());
+        assert_eq!(consumer.read_data().windows(4).position(|window| window == b"shed"), Some(5));
+        // Later opens for writing are refused by the object's mode, except to processes that bypass it
+        #[cfg(target_os = "linux")]
+        {
+            use std::os::unix::fs::PermissionsExt;
+            let mode = std::fs::metadata(format!("/dev/shm{}", name)).unwrap().permissions().mode();
+            assert_eq!(mode & 0o777, 0o444);
+        }
+    }
+
+    #[test]
+    fn posix_names_get_one_leading_slash() {
+        for platform in [Platform::Linux, Platform::MacOs] {
+            assert_eq!(normalized(platform, "my_app.frames"), Ok("/my_app.frames".to_string()));
+            assert_eq!(normalized(platform, "/my_app.frames"), Ok("/my_app.frames".to_string()));
+        }
+    }
+
+    #[test]
+    fn posix_names_reject_inner_slashes() {
+        for platform in [Platform::Linux, Platform::MacOs] {
+            assert_eq!(normalized(platform, "a/b"), Err(InvalidName::ContainsSlash { position: 1 }));
+            assert_eq!(normalized(platform, "/a/b"), Err(InvalidName::ContainsSlash { position: 2 }));
+            assert_eq!(normalized(platform, "//a"), Err(InvalidName::ContainsSlash { position: 1 }));
+        }
+    }
+
+    #[test]
+    fn posix_names_allow_backslashes() {
+        assert_eq!(normalized(Platform::Linux, "Global\\a"), Ok("/Global\\a".to_string()));
+    }
+
+    #[test]
+    fn empty_names_are_rejected() {
+        for platform in [Platform::Linux, Platform::MacOs, Platform::Windows] {
+            assert_eq!(normalized(platform, ""), Err(InvalidName::Empty));
+            assert_eq!(normalized(platform, "/"), Err(InvalidName::Empty));
+        }
+        assert_eq!(normalized(Platform::Windows, "Global\\"), Err(InvalidName::Empty));
+        assert_eq!(normalized(Platform::Windows, "/Local\\"), Err(InvalidName::Empty));
+    }
+
+    #[test]
+    fn nul_bytes_are_rejected_everywhere() {
+        for platform in [Platform::Linux, Platform::MacOs, Platform::Windows] {
+            assert_eq!(normalized(platform, "ab\0c"), Err(InvalidName::ContainsNul { position: 2 }));
+        }
+    }
+
+    #[test]
+    fn linux_limit_excludes_the_slash() {
+        let longest = "a".repeat(255);
+        assert_eq!(normalized(Platform::Linux, &longest), Ok(format!("/{}", longest)));
+        assert_eq!(normalized(Platform::Linux, &"a".repeat(256)), Err(InvalidName::TooLong { len: 256, max: 255 }));
+    }
+
+    #[test]
+    fn macos_limit_includes_the_slash() {
+        let longest = "a".repeat(30);
+        assert_eq!(normalized(Platform::MacOs, &longest), Ok(format!("/{}", longest)));
+        assert_eq!(normalized(Platform::MacOs, &format!("/{}", longest)), Ok(format!("/{}", longest)));
+        assert_eq!(normalized(Platform::MacOs, &"a".repeat(31)), Err(InvalidName::TooLong { len: 32, max: 31 }));
+    }
+
+    #[test]
+    fn windows_names_drop_a_leading_slash() {
+        assert_eq!(normalized(Platform::Windows, "my_app.frames"), Ok("my_app.frames".to_string()));
+        assert_eq!(normalized(Platform::Windows, "/my_app.frames"), Ok("my_app.frames".to_string()));
+        assert_eq!(normalized(Platform::Windows, "a/b"), Ok("a/b".to_string()));
+    }
+
+    #[test]
+    fn windows_names_keep_namespace_prefixes() {
+        assert_eq!(normalized(Platform::Windows, "Global\\frames"), Ok("Global\\frames".to_string()));
+        assert_eq!(normalized(Platform::Windows, "Local\\frames"), Ok("Local\\frames".to_string()));
+        assert_eq!(normalized(Platform::Windows, "/Global\\frames"), Ok("Global\\frames".to_string()));
+    }
+
+    #[test]
+    fn windows_names_reject_other_backslashes() {
+        assert_eq!(normalized(Platform::Windows, "a\\b"), Err(InvalidName::ContainsBackslash { position: 1 }));
+        assert_eq!(normalized(Platform::Windows, "Global\\a\\b"), Err(InvalidName::ContainsBackslash { position: 8 }));
+        assert_eq!(normalized(Platform::Windows, "Session\\a"), Err(InvalidName::ContainsBackslash { position: 7 }));
+    }
+
+    #[test]
+    fn windows_limit_counts_the_prefix() {
+        let longest = format!("Global\\{}", "a".repeat(253));
+        assert_eq!(normalized(Platform::Windows, &longest), Ok(longest.clone()));
+        assert_eq!(
+            normalized(Platform::Windows, &format!("{}a", longest)),
+            Err(InvalidName::TooLong { len: 261, max: 260 })
+        );
+    }
+
+    #[test]
+    fn invalid_names_describe_the_rule() {
+        let err = normalize_for(Platform::Linux, "a/b", NamePolicy::Reject).unwrap_err();
+        assert_eq!(err.to_string(), "segment name has a '/' at 1, only a leading one is allowed");
+        let io: std::io::Error = err.into();
+        assert_eq!(io.kind(), std::io::ErrorKind::InvalidInput);
+    }
+
+    const LONG: &str = "appname.tenant.6f1c2a4e-93d7-4b8e-a0f5-1d2c3b4a5e6f.frames";
+
+    #[test]
+    fn hashed_names_fit_the_limit() {
+        let short = hashed(Platform::MacOs, LONG);
+        assert_eq!(short.len(), 31);
+        assert!(short.starts_with("/appname.tenant.6~"), "{}", short);
+        let long = "a".repeat(300);
+        assert_eq!(hashed(Platform::Linux, &long).len(), 256);
+        assert_eq!(hashed(Platform::Windows, &format!("Global\\{}", long)).len(), 260);
+        assert!(hashed(Platform::Windows, &format!("Global\\{}", long)).starts_with("Global\\aaa"));
+    }
+
+    #[test]
+    fn hashed_names_are_deterministic() {
+        assert_eq!(hashed(Platform::MacOs, LONG), hashed(Platform::MacOs, LONG));
+        assert_eq!(hashed(Platform::MacOs, LONG), hashed(Platform::MacOs, &format!("/{}", LONG)));
+    }
+
+    #[test]
+    fn different_long_names_hash_differently() {
+        let other = LONG.replace(".frames", ".frame5");
+        assert_ne!(hashed(Platform::MacOs, LONG), hashed(Platform::MacOs, &other));
+        let names: std::collections::HashSet<_> =
+            (0..1000).map(|idx| hashed(Platform::MacOs, &format!("{}.{}", LONG, idx))).collect();
+        assert_eq!(names.len(), 1000);
+    }
+
+    #[test]
+    fn names_under_the_limit_pass_through() {
+        assert_eq!(hashed(Platform::MacOs, "my_app.frames"), "/my_app.frames");
+        assert_eq!(hashed(Platform::MacOs, &"a".repeat(30)), format!("/{}", "a".repeat(30)));
+        assert_eq!(hashed(Platform::Windows, "Local\\frames"), "Local\\frames");
+    }
+
+    #[test]
+    fn hashing_only_fixes_the_length() {
+        let policy = NamePolicy::HashWhenTooLong;
+        let slashed = format!("{}/{}", LONG, LONG);
+        assert_eq!(normalize_for(Platform::MacOs, &slashed, policy), Err(InvalidName::ContainsSlash { position: LONG.len() }));
+        assert_eq!(normalize_for(Platform::MacOs, "", policy), Err(InvalidName::Empty));
+    }
+
+    #[test]
+    fn unique_names_shorten_the_prefix_to_fit() {
+        assert_eq!(unique_name(Platform::Linux, "/scratch", 0).unwrap(), "scratch.aaaaaaaaaaaaa");
+        assert_eq!(unique_name(Platform::MacOs, "", u64::MAX).unwrap(), "p777777777777");
+        let name = unique_name(Platform::MacOs, LONG, 1).unwrap();
+        assert_eq!(name, "appname.tenant.6.aaaaaaaaaaaab");
+        assert!(normalized(Platform::MacOs, &name).unwrap().len() <= 31);
+        assert_eq!(unique_name(Platform::Linux, "a/b", 0), Err(InvalidName::ContainsSlash { position: 1 }));
+    }
+
+    #[test]
+    fn create_unique_never_collides() {
+        let barrier = std::sync::Arc::new(std::sync::Barrier::new(4));
+        let threads: Vec<_> = (0..4)
+            .map(|_| {
+                let barrier = barrier.clone();
+                std::thread::spawn(move || {
+                    let segments: Vec<_> = (0..25).map(|_| SharedMemory::create_unique("unique_test", 4096).unwrap()).collect();
+                    // Keep every segment alive until all 100 exist, a name is only free again once it is unlinked
+                    barrier.wait();
+                    for (shm, name) in &segments {
+                        assert_eq!(&shm.name(), name);
+                        assert!(name.starts_with("unique_test."));
+                    }
+                    segments.into_iter().map(|(_, name)| name).collect::<Vec<_>>()
+                })
+            })
+            .collect();
+        let names: std::collections::HashSet<_> = threads.into_iter().flat_map(|thread| thread.join().unwrap()).collect();
+        assert_eq!(names.len(), 100);
+    }
+
+    #[cfg(feature = "testing")]
+    #[test]
+    fn local_mappings_read_and_write() {
+        let shm = SharedMemory::create_local(10000);
+        assert_eq!(shm.size(), 10000);
+        assert!(shm.read_data().is_empty());
+        shm.write_u32_le(0, 0xdead_beef).unwrap();
+        shm.write_u64_be(8, 7).unwrap();
+        shm.write_value(16, [1.5f64, -2.0]).unwrap();
+        assert_eq!(shm.read_u32_le(0).unwrap(), 0xdead_beef);
+        assert_eq!(shm.read_u64_le(8).unwrap(), 7u64.swap_bytes());
+        assert_eq!(shm.read_value::<[f64; 2]>(16).unwrap(), [1.5, -2.0]);
+        assert!(matches!(shm.read_u32_le(9998), Err(crate::shared_memory::Error::OutOfBounds { .. })));
+    }
+
+    #[cfg(feature = "testing")]
+    #[test]
+    fn local_mappings_round_trip_strings() {
+        let mut shm = SharedMemory::create_local(64);
+        shm.write_string("hello");
+        assert_eq!(shm.read_string(), "hello");
+        let snapshot = shm.snapshot();
+        assert_eq!(&snapshot[..5], b"hello");
+        assert!(shm.content_eq_slice(&snapshot));
+    }
+
+    #[cfg(feature = "testing")]
+    #[test]
+    #[cfg_attr(miri, ignore = "Miri has no mprotect")]
+    fn local_mappings_support_cells_and_page_operations() {
+        use crate::shared_memory::Protection;
+
+        let mut shm = SharedMemory::create_local(3 * 4096);
+        shm.cell_at::<u64>(4096).unwrap().update(|value| value + 5);
+        assert_eq!(shm.read_u64_le(4096).unwrap(), 5);
+        shm.discard_range(4096..8192).unwrap();
+        assert_eq!(shm.read_u64_le(4096).unwrap(), 0);
+        shm.protect_range(0..1, Protection::ReadOnly).unwrap();
+        assert!(shm.write_u32_le(0, 1).is_err());
+        shm.write_u32_le(4096, 1).unwrap();
+        assert!(shm.remap_window(0, 4096).is_err());
+    }
+
+    #[test]
+    fn owned_reads_are_unaffected_by_later_writes() {
+        let (shm, name) = SharedMemory::create_unique("owned_read", 4096).unwrap();
+        let other = SharedMemory::open(&name, 4096).unwrap();
+        other.write_u32_le(0, 0x0403_0201).unwrap();
+        let data = shm.read_data_owned();
+        let at = shm.read_owned_at(1, 2).unwrap();
+        other.write_u32_le(0, u32::MAX).unwrap();
+        other.write_u32_le(100, 1).unwrap();
+        assert_eq!(data, [1, 2, 3, 4]);
+        assert_eq!(at, [2, 3]);
+        assert_eq!(shm.read_data_owned().len(), 101);
+        assert!(shm.read_owned_at(4095, 2).is_err());
+    }
+
+    #[test]
+    fn consume_sees_whole_payloads_only() {
+        let (shm, name) = SharedMemory::create_unique("publish", 4096).unwrap();
+        let mut buf = [0u8; 256];
+        assert_eq!(shm.consume(64, &mut buf).unwrap(), None);
+        let reader = std::thread::spawn(move || {
+            let shm = SharedMemory::open(&name, 4096).unwrap();
+            let mut buf = [0u8; 256];
+            let mut seen = 0;
+            while seen < 255 {
+                if let Some(len) = shm.consume(64, &mut buf).unwrap() {
+                    // Every payload is `len` copies of `len`, a torn copy would mix two of them
+                    assert!(buf[..len].iter().all(|&byte| byte as usize == len), "torn payload of {} bytes", len);
+                    seen = seen.max(len);
+                }
+            }
+        });
+        for round in 0..2000 {
+            let len = round % 255 + 1;
+            shm.publish(64, &vec![len as u8; len]).unwrap();
+        }
+        shm.publish(64, &[255; 255]).unwrap();
+        reader.join().unwrap();
+        let mut short = [0u8; 4];
+        assert_eq!(shm.consume(64, &mut short).unwrap(), Some(255));
+        assert_eq!(short, [255; 4]);
+        assert!(shm.publish(4096 - 16, &[1]).is_err());
+        assert!(matches!(shm.publish(4, &[1]), Err(crate::shared_memory::Error::Misaligned { .. })));
+    }
+
+    #[repr(C)]
+    #[derive(Clone, Copy)]
+    struct Node {
+        value: u64,
+        next: crate::ptr::ShmPtr<Node>,
+    }
+
+    unsafe impl crate::shared_safe::SharedSafe for Node {}
+
+    #[test]
+    fn offset_pointers_link_across_mappings() {
+        use crate::ptr::ShmPtr;
+
+        let (shm, name) = SharedMemory::create_unique("linked", 4096).unwrap();
+        let mut next = ShmPtr::null();
+        for value in 0..10u64 {
+            let offset = 256 + value as usize * 64;
+            shm.write_value(offset, Node { value, next }).unwrap();
+            next = ShmPtr::from_ref(&shm, next_node(&shm, offset)).unwrap();
+        }
+        shm.write_value(0, next).unwrap();
+
+        let other = SharedMemory::open(&name, 4096).unwrap();
+        assert_ne!(other.address(), shm.address());
+        let mut values = Vec::new();
+        let mut node = other.read_value::<ShmPtr<Node>>(0).unwrap().resolve(&other);
+        while let Some(current) = node {
+            values.push(current.value);
+            node = current.next.resolve(&other);
+        }
+        assert_eq!(values, (0..10).rev().collect::<Vec<_>>());
+    }
+
+    fn next_node(shm: &SharedMemory, offset: usize) -> &Node {
+        crate::ptr::ShmPtr::<Node>::from_offset(offset as u64).resolve(shm).unwrap()
+    }
+
+    #[test]
+    fn offset_pointers_check_bounds_and_alignment() {
+        use crate::ptr::ShmPtr;
+
+        let (mut shm, _) = SharedMemory::create_unique("linked_checks", 4096).unwrap();
+        assert!(ShmPtr::<u64>::null().resolve(&shm).is_none());
+        assert!(ShmPtr::<u64>::from_offset(4090).resolve(&shm).is_none());
+        assert!(ShmPtr::<u64>::from_offset(4).resolve(&shm).is_none());
+        assert!(ShmPtr::<u64>::from_offset(u64::MAX).resolve(&shm).is_none());
+        *ShmPtr::<u64>::from_offset(8).resolve_mut(&mut shm).unwrap() = 5;
+        assert_eq!(shm.read_value::<u64>(8).unwrap(), 5);
+        let outside = 0u64;
+        assert!(ShmPtr::from_ref(&shm, &outside).is_err());
+        assert_eq!(ShmPtr::<u64>::default(), ShmPtr::null());
+    }
+
+    #[test]
+    fn vectors_fill_from_two_handles() {
+        use crate::vec::{Full, ShmVec};
+
+        let (shm, name) = SharedMemory::create_unique("vec", 8192).unwrap();
+        let vec = ShmVec::<u32>::init(&shm, 64, 1000).unwrap();
+        let pusher = std::thread::spawn(move || {
+            let shm = SharedMemory::open(&name, 8192).unwrap();
+            let vec = ShmVec::<u32>::attach(&shm, 64, 1000).unwrap();
+            let mut pushed = Vec::new();
+            for value in (1..).step_by(2) {
+                match vec.push(value) {
+                    Ok(()) => pushed.push(value),
+                    Err(Full(_)) => return pushed,
+                }
+            }
+            unreachable!()
+        });
+        let mut pushed = Vec::new();
+        for value in (0..).step_by(2) {
+            match vec.push(value) {
+                Ok(()) => pushed.push(value),
+                Err(Full(rejected)) => {
+                    assert_eq!(rejected, value);
+                    break;
+                }
+            }
+        }
+        pushed.extend(pusher.join().unwrap());
+        assert_eq!(vec.len(), 1000);
+        let mut stored = vec.as_slice().to_vec();
+        stored.sort_unstable();
+        pushed.sort_unstable();
+        assert_eq!(stored, pushed);
+        assert_eq!(vec.get(1000), None);
+        let last = vec.get(999).unwrap();
+        assert_eq!(vec.pop(), Some(last));
+        vec.clear();
+        assert!(vec.is_empty());
+        assert!(ShmVec::<u64>::attach(&shm, 64, 1000).is_err());
+        assert!(ShmVec::<u32>::attach(&shm, 64, 999).is_err());
+        assert!(ShmVec::<u32>::init(&shm, 64, 2100).is_err());
+    }
+
+    #[test]
+    fn strings_never_tear() {
+        use crate::string::{ShmString, TooLong};
+
+        const SHORT: &str = "idle";
+        const LONG: &str = "current state: REBALANCING partitions 0-511";
+        let (shm, name) = SharedMemory::create_unique("string", 4096).unwrap();
+        let slot = ShmString::init(&shm, 128, 64).unwrap();
+        assert_eq!(slot.get().unwrap(), "");
+        let reads = std::sync::Arc::new(std::sync::atomic::AtomicUsize::new(0));
+        let reader = {
+            let reads = reads.clone();
+            std::thread::spawn(move || {
+                let shm = SharedMemory::open(&name, 4096).unwrap();
+                let slot = ShmString::attach(&shm, 128, 64).unwrap();
+                for _ in 0..50_000 {
+                    let value = slot.get().unwrap();
+                    assert!(value.is_empty() || value == SHORT || value == LONG, "torn read {:?}", value);
+                    reads.fetch_add(1, std::sync::atomic::Ordering::Relaxed);
+                }
+            })
+        };
+        // Keep alternating for as long as the reader is reading
+        let mut round = 0;
+        while reads.load(std::sync::atomic::Ordering::Relaxed) < 50_000 && !reader.is_finished() {
+            slot.set(if round % 2 == 0 { LONG } else { SHORT }).unwrap();
+            round += 1;
+        }
+        reader.join().unwrap();
+        slot.set(SHORT).unwrap();
+        assert_eq!(slot.get().unwrap(), SHORT);
+        assert_eq!(slot.set(&"x".repeat(65)), Err(TooLong { len: 65, capacity: 64 }));
+        assert!(ShmString::attach(&shm, 128, 32).is_err());
+    }
+
+    #[test]
+    fn counter_totals_are_exact() {
+        use crate::counters::{RegisterError, ShmCounters};
+
+        let (shm, name) = SharedMemory::create_unique("counters", 8192).unwrap();
+        let counters = ShmCounters::init(&shm, 128, 4).unwrap();
+        let other = std::thread::spawn(move || {
+            let shm = SharedMemory::open(&name, 8192).unwrap();
+            let counters = ShmCounters::attach(&shm, 128, 4).unwrap();
+            let requests = counters.register("requests").unwrap();
+            let bytes = counters.register("bytes").unwrap();
+            std::thread::scope(|scope| {
+                for _ in 0..4 {
+                    scope.spawn(|| {
+                        for _ in 0..10_000 {
+                            requests.add(1);
+                            bytes.add(512);
+                        }
+                    });
+                }
+            });
+        });
+        let requests = counters.register("requests").unwrap();
+        let errors = counters.register("errors").unwrap();
+        std::thread::scope(|scope| {
+            for _ in 0..4 {
+                scope.spawn(|| {
+                    for _ in 0..10_000 {
+                        requests.add(1);
+                        errors.add(1);
+                    }
+                });
+            }
+        });
+        other.join().unwrap();
+        let mut snapshot = counters.snapshot();
+        snapshot.sort();
+        let expected = [("bytes", 40_000 * 512), ("errors", 40_000), ("requests", 80_000)];
+        assert_eq!(snapshot, expected.map(|(name, value)| (name.to_string(), value)));
+        assert_eq!(counters.register("errors").unwrap().get(), 40_000);
+        counters.register("latency").unwrap();
+        assert_eq!(counters.register("timeouts").unwrap_err(), RegisterError::Full { capacity: 4 });
+        assert_eq!(counters.register(&"x".repeat(33)).unwrap_err(), RegisterError::NameTooLong { len: 33 });
+        assert!(ShmCounters::attach(&shm, 128, 8).is_err());
+    }
+
+    #[test]
+    fn per_worker_slots_sum_to_every_increment() {
+        use crate::counters::{RegisterError, ShmPerCpuCounter, CACHE_LINE};
+
+        let (shm, name) = SharedMemory::create_unique("percpu", 8192).unwrap();
+        let counter = ShmPerCpuCounter::init(&shm, CACHE_LINE, 8).unwrap();
+        let other = std::thread::spawn(move || {
+            let shm = SharedMemory::open(&name, 8192).unwrap();
+            let counter = ShmPerCpuCounter::attach(&shm, CACHE_LINE, 8).unwrap();
+            std::thread::scope(|scope| {
+                for _ in 0..4 {
+                    let slot = counter.register().unwrap();
+                    scope.spawn(move || (0..10_000).for_each(|_| slot.add(1)));
+                }
+            });
+        });
+        std::thread::scope(|scope| {
+            for _ in 0..4 {
+                let slot = counter.register().unwrap();
+                scope.spawn(move || (0..10_000).for_each(|_| slot.add(3)));
+            }
+        });
+        other.join().unwrap();
+        assert_eq!(counter.sum(), 40_000 + 120_000);
+        assert_eq!(counter.register().unwrap_err(), RegisterError::Full { capacity: 8 });
+        assert_eq!(ShmPerCpuCounter::size_for(8), 9 * CACHE_LINE);
+        assert!(ShmPerCpuCounter::attach(&shm, CACHE_LINE, 4).is_err());
+        assert!(ShmPerCpuCounter::init(&shm, CACHE_LINE + 8, 8).is_err());
+    }
+
+    /// Timing comparison rather than a check, run with `cargo test --release -- --ignored --nocapture`
+    #[test]
+    #[ignore]
+    fn padded_slots_against_packed_increments() {
+        use crate::counters::{ShmPerCpuCounter, CACHE_LINE};
+        use std::sync::atomic::{AtomicU64, Ordering};
+
+        const THREADS: usize = 8;
+        const INCREMENTS: u64 = 5_000_000;
+        let (shm, _name) = SharedMemory::create_unique("padding", 8192).unwrap();
+
+        let packed = unsafe { std::slice::from_raw_parts((shm.address() as *const AtomicU64).add(1), THREADS) };
+        let started = std::time::Instant::now();
+        std::thread::scope(|scope| {
+            for slot in packed {
+                scope.spawn(move || (0..INCREMENTS).for_each(|_| { slot.fetch_add(1, Ordering::Relaxed); }));
+            }
+        });
+        let packed_time = started.elapsed();
+
+        let counter = ShmPerCpuCounter::init(&shm, 2 * CACHE_LINE, THREADS).unwrap();
+        let started = std::time::Instant::now();
+        std::thread::scope(|scope| {
+            for _ in 0..THREADS {
+                let slot = counter.register().unwrap();
+                scope.spawn(move || (0..INCREMENTS).for_each(|_| slot.add(1)));
+            }
+        });
+        let padded_time = started.elapsed();
+        assert_eq!(counter.sum(), THREADS as u64 * INCREMENTS);
+        println!("{} threads: packed {:?}, padded {:?}", THREADS, packed_time, padded_time);
+    }
+
+    #[test]
+    fn histogram_percentiles_come_from_both_handles() {
+        use crate::histogram::{exponential_bounds, ShmHistogram};
+
+        let bounds = exponential_bounds(1, 2, 20);
+        assert_eq!(bounds.len(), 20);
+        let (shm, name) = SharedMemory::create_unique("histogram", 4096).unwrap();
+        let histogram = ShmHistogram::init(&shm, 64, &bounds).unwrap();
+        // 980 fast requests of 100us from one process, 20 slow ones of 30ms from the other
+        let other = std::thread::spawn(move || {
+            let shm = SharedMemory::open(&name, 4096).unwrap();
+            let histogram = ShmHistogram::attach(&shm, 64).unwrap();
+            assert_eq!(histogram.bounds(), &exponential_bounds(1, 2, 20)[..]);
+            for _ in 0..20 {
+                histogram.record(30_000);
+            }
+        });
+        for _ in 0..980 {
+            histogram.record(100);
+        }
+        other.join().unwrap();
+        assert_eq!(histogram.count(), 1000);
+        assert_eq!(histogram.sum(), 980 * 100 + 20 * 30_000);
+        assert_eq!(histogram.percentile(0.5), 128);
+        assert_eq!(histogram.percentile(0.98), 128);
+        assert_eq!(histogram.percentile(0.99), 32_768);
+        histogram.record(u64::MAX);
+        assert_eq!(histogram.percentile(1.0), u64::MAX);
+
+        let merged = ShmHistogram::init(&shm, 1024, &bounds).unwrap();
+        assert_eq!(merged.percentile(0.5), 0);
+        histogram.merge_into(&merged).unwrap();
+        assert_eq!(merged.buckets(), histogram.buckets());
+        assert_eq!(merged.count(), 1001);
+        let other_bounds = ShmHistogram::init(&shm, 2048, &[10, 100]).unwrap();
+        assert!(histogram.merge_into(&other_bounds).is_err());
+        assert!(ShmHistogram::init(&shm, 3072, &[10, 10]).is_err());
+        assert!(ShmHistogram::attach(&shm, 3072).is_err());
+    }
+
+    #[test]
+    fn corrupt_ring_and_log_positions_are_refused() {
+        use crate::log::{LogError, ShmLog};
+        use crate::ring_buffer::{PushError, ShmRingBuffer, HEADER_SIZE};
+
+        let name = format!("/corrupt_ring.{}", std::process::id());
+        let mut ring = ShmRingBuffer::create(&name, 256).unwrap();
+        let scribbler = SharedMemory::open(&name, (HEADER_SIZE + 256) as i32).unwrap();
+        // A record header claiming a gigabyte used to be copied out of the data area as is
+        ring.try_push(b"first").unwrap();
+        scribbler.write_u32_le(HEADER_SIZE, 1 << 31 | ((1 << 30) - 1)).unwrap();
+        assert_eq!(ring.try_pop(), None);
+        // A tail off a record boundary
+        scribbler.write_u64_le(128, 3).unwrap();
+        assert_eq!(ring.try_pop(), None);
+        // A tail past the head used to keep producers reloading the head forever
+        scribbler.write_u64_le(128, 64).unwrap();
+        assert_eq!(ring.try_push(b"second"), Err(PushError::Full));
+        assert_eq!(ring.try_pop(), None);
+        // More in flight than the ring holds
+        scribbler.write_u64_le(64, 4096).unwrap();
+        scribbler.write_u64_le(128, 0).unwrap();
+        assert_eq!(ring.try_push(b"second"), Err(PushError::Full));
+        assert_eq!(ring.try_pop(), None);
+        // Put back, the ring works again
+        scribbler.write_u64_le(64, 0).unwrap();
+        scribbler.copy_from_slice_at(HEADER_SIZE, &[0; 256]).unwrap();
+        ring.try_push(b"third").unwrap();
+        assert_eq!(ring.try_pop().unwrap(), b"third");
+
+        let name = format!("/corrupt_log.{}", std::process::id());
+        let log = ShmLog::create(&name, 256).unwrap();
+        let scribbler = SharedMemory::open(&name, 128 + 256).unwrap();
+        log.append(b"record").unwrap();
+        // A tail behind the head used to underflow, and one past the capacity to be read beyond the segment
+        for (head, tail) in [(16, 8), (0, 4096)] {
+            scribbler.write_u64_le(72, head).unwrap();
+            scribbler.write_u64_le(80, tail).unwrap();
+            assert_eq!(log.append(b"more"), Err(LogError::Corrupt { offset: head }));
+            assert_eq!(log.read_from(0).next(), Some(Err(LogError::Corrupt { offset: head })));
+            assert_eq!(log.truncate_before(head + 14), Err(LogError::Corrupt { offset: head }));
+        }
+        // A tail ending inside a record header
+        scribbler.write_u64_le(72, 0).unwrap();
+        scribbler.write_u64_le(80, 14 + 4).unwrap();
+        assert_eq!(log.truncate_before(16), Err(LogError::Corrupt { offset: 14 }));
+    }
+
+    #[test]
+    fn padding_shorter_than_a_record_header_is_refused() {
+        use crate::ring_buffer::{ShmRingBuffer, HEADER_SIZE};
+
+        let name = format!("/short_padding.{}", std::process::id());
+        let mut ring = ShmRingBuffer::create(&name, 256).unwrap();
+        let scribbler = SharedMemory::open(&name, (HEADER_SIZE + 256) as i32).unwrap();
+        ring.try_push(b"first").unwrap();
+        // Committed padding skipping 1 to 7 bytes used to underflow while the record was cleared, 12 stops off a boundary
+        for skipped in [1, 2, 7, 12] {
+            scribbler.write_u32_le(HEADER_SIZE, 1 << 31 | 1 << 30 | skipped).unwrap();
+            assert_eq!(ring.try_pop(), None);
+            assert_eq!(scribbler.read_u64_le(128).unwrap(), 0);
+        }
+        // A whole record's worth is a valid skip, past it the ring is empty
+        scribbler.write_u32_le(HEADER_SIZE, 1 << 31 | 1 << 30 | 16).unwrap();
+        assert_eq!(ring.try_pop(), None);
+        assert_eq!(scribbler.read_u64_le(128).unwrap(), 16);
+    }
+
+    #[test]
+    fn pumps_move_every_message_between_ring_and_channel() {
+        use crate::ring_buffer::ShmRingBuffer;
+        use std::sync::mpsc;
+        use std::time::Duration;
+
+        const MESSAGES: u32 = 10_000;
+        let name = format!("/pump.{}", std::process::id());
+        let ring = ShmRingBuffer::create(&name, 4096).unwrap();
+        let producer = ShmRingBuffer::open(&name, 4096).unwrap();
+        let (sender, receiver) = mpsc::sync_channel(16);
+        let pump = ring.spawn_pump(sender);
+        let pushing = std::thread::spawn(move || {
+            for i in 0..MESSAGES {
+                producer.push(&i.to_le_bytes(), Some(Duration::from_secs(5))).unwrap();
+            }
+        });
+        for i in 0..MESSAGES {
+            assert_eq!(receiver.recv_timeout(Duration::from_secs(5)).unwrap(), i.to_le_bytes());
+        }
+        pushing.join().unwrap();
+        assert!(pump.is_running());
+        let mut ring = pump.stop();
+        assert_eq!(ring.try_pop(), None);
+
+        // Stopped with the channel full and the ring backed up, every message is in one or the other
+        let (sender, receiver) = mpsc::sync_channel(4);
+        for i in 0..100u32 {
+            ring.try_push(&i.to_le_bytes()).unwrap();
+        }
+        let pump = ring.spawn_pump(sender);
+        std::thread::sleep(Duration::from_millis(50));
+        let stopping = std::thread::spawn(move || pump.stop());
+        let delivered: Vec<Vec<u8>> = receiver.iter().collect();
+        let mut ring = stopping.join().unwrap();
+        let left: Vec<Vec<u8>> = std::iter::from_fn(|| ring.try_pop()).collect();
+        assert!(delivered.len() <= 5 && !left.is_empty());
+        let all: Vec<Vec<u8>> = delivered.into_iter().chain(left).collect();
+        assert_eq!(all, (0..100u32).map(|i| i.to_le_bytes().to_vec()).collect::<Vec<_>>());
+
+        // Dropping the receiver ends the pump on its next message
+        let (sender, receiver) = mpsc::sync_channel(1);
+        let producer = ShmRingBuffer::open(&name, 4096).unwrap();
+        let pump = ring.spawn_pump(sender);
+        drop(receiver);
+        producer.try_push(b"one").unwrap();
+        std::thread::sleep(Duration::from_millis(100));
+        assert!(!pump.is_running());
+
+        let mut consumer = pump.stop();
+        let (sender, receiver) = mpsc::sync_channel(16);
+        let feeder = producer.spawn_feeder(receiver);
+        let sending = std::thread::spawn(move || {
+            for i in 0..MESSAGES {
+                sender.send(i.to_le_bytes().to_vec()).unwrap();
+            }
+        });
+        for i in 0..MESSAGES {
+            assert_eq!(consumer.pop(Some(Duration::from_secs(5))).unwrap(), i.to_le_bytes());
+        }
+        sending.join().unwrap();
+        // Every sender is gone and the channel is empty, so the feeder finishes by itself
+        std::thread::sleep(Duration::from_millis(100));
+        assert!(!feeder.is_running());
+        feeder.stop();
+        assert_eq!(consumer.try_pop(), None);
+    }
+
+    #[test]
+    fn async_consumers_share_the_messages_out_exactly_once() {
+        use crate::ring_buffer::ShmRingBuffer;
+        use std::future::Future;
+        use std::sync::{Arc, Mutex};
+        use std::task::{Context, Poll, Wake, Waker};
+        use std::thread::Thread;
+        use std::time::Duration;
+
+        // Just enough of an executor to run a future on the current thread, parking it while the future is pending
+        struct Unpark(Thread);
+        impl Wake for Unpark {
+            fn wake(self: Arc<Self>) {
+                self.0.unpark();
+            }
+        }
+        fn block_on<F: Future>(future: F) -> F::Output {
+            let waker = Waker::from(Arc::new(Unpark(std::thread::current())));
+            let mut cx = Context::from_waker(&waker);
+            let mut future = std::pin::pin!(future);
+            loop {
+                match future.as_mut().poll(&mut cx) {
+                    Poll::Ready(output) => return output,
+                    Poll::Pending => std::thread::park(),
+                }
+            }
+        }
+
+        const MESSAGES: u32 = 5_000;
+        const CONSUMERS: usize = 4;
+        let name = format!("/async_ring.{}", std::process::id());
+        let ring = ShmRingBuffer::create(&name, 4096).unwrap();
+        let producer = ShmRingBuffer::open(&name, 4096).unwrap();
+        let received = Mutex::new(Vec::new());
+        std::thread::scope(|scope| {
+            for _ in 0..CONSUMERS {
+                scope.spawn(|| loop {
+                    let msg = block_on(ring.recv_async());
+                    if msg.is_empty() {
+                        break;
+                    }
+                    received.lock().unwrap().push(u32::from_le_bytes(msg.try_into().unwrap()));
+                });
+            }
+            scope.spawn(|| {
+                for i in 0..MESSAGES {
+                    producer.push(&i.to_le_bytes(), Some(Duration::from_secs(5))).unwrap();
+                    if i % 1000 == 0 {
+                        // Lets every consumer run dry and go back to waiting
+                        std::thread::sleep(Duration::from_millis(20));
+                    }
+                }
+                // An empty message stops one consumer
+                for _ in 0..CONSUMERS {
+                    producer.push(&[], Some(Duration::from_secs(5))).unwrap();
+                }
+            });
+        });
+        let mut received = received.into_inner().unwrap();
+        received.sort_unstable();
+        assert_eq!(received, (0..MESSAGES).collect::<Vec<_>>());
+
+        // A pending future that's dropped takes nothing off the ring and leaves no waker behind
+        let mut cx = Context::from_waker(Waker::noop());
+        let mut pending = Box::pin(ring.recv_async());
+        assert!(pending.as_mut().poll(&mut cx).is_pending());
+        drop(pending);
+        producer.try_push(b"kept").unwrap();
+        assert_eq!(block_on(ring.recv_async()), b"kept");
+    }
+
+    #[test]
+    fn batches_interleave_with_single_pushes_and_pops_across_the_wrap() {
+        use crate::ring_buffer::{PushError, ShmRingBuffer};
+        use std::collections::VecDeque;
+
+        let name = format!("/batch_ring.{}", std::process::id());
+        let mut ring = ShmRingBuffer::create(&name, 512).unwrap();
+        assert_eq!(ring.try_push_batch(&[]), Ok(0));
+        assert_eq!(ring.try_push_batch(&[&[0; 512]]), Err(PushError::TooLarge { len: 512, max: ring.max_message_len() }));
+        let mut model = VecDeque::new();
+        let mut out = Vec::new();
+        let mut state = 0x853c_49e6_748f_ea9bu64;
+        let mut next = move || {
+            state ^= state << 13;
+            state ^= state >> 7;
+            state ^= state << 17;
+            state
+        };
+        let mut serial = 0u32;
+        for round in 0..5000 {
+            let case = format!("round {}", round);
+            if next() % 2 == 0 {
+                // Odd lengths keep the head landing at different distances from the end of the data area
+                let msgs: Vec<Vec<u8>> = (0..next() % 9)
+                    .map(|_| {
+                        serial += 1;
+                        let mut msg = serial.to_le_bytes().to_vec();
+                        msg.resize(4 + (next() % 90) as usize, serial as u8);
+                        msg
+                    })
+                    .collect();
+                let borrowed: Vec<&[u8]> = msgs.iter().map(|msg| &msg[..]).collect();
+                let accepted = match (next() % 4, &borrowed[..]) {
+                    (0, [first, ..]) => ring.try_push(first).map(|_| 1),
+                    _ => ring.try_push_batch(&borrowed),
+                };
+                match accepted {
+                    Ok(accepted) => model.extend(msgs.into_iter().take(accepted)),
+                    Err(PushError::Full) => assert!(!model.is_empty(), "{}", case),
+                    Err(err) => panic!("{}: {:?}", case, err),
+                }
+            } else if next() % 3 == 0 {
+                assert_eq!(ring.try_pop(), model.pop_front(), "{}", case);
+            } else {
+                let max = (next() % 6) as usize;
+                out.clear();
+                let popped = ring.try_pop_batch(&mut out, max);
+                assert_eq!(popped, max.min(model.len()), "{}", case);
+                assert!(out.iter().eq(model.drain(..popped).collect::<Vec<_>>().iter()), "{}", case);
+            }
+        }
+        out.clear();
+        ring.try_pop_batch(&mut out, usize::MAX);
+        assert!(out.iter().eq(model.iter()));
+        assert_eq!(ring.try_pop(), None);
+    }
+
+    /// Timing comparison rather than a check, run with `cargo test --release -- --ignored --nocapture`
+    #[test]
+    #[ignore]
+    fn batched_ring_operations_against_single_for_64_byte_messages() {
+        use crate::ring_buffer::ShmRingBuffer;
+
+        const MESSAGES: usize = 10_000_000;
+        const BATCH: usize = 32;
+        let name = format!("/batch_bench.{}", std::process::id());
+        let mut ring = ShmRingBuffer::create(&name, 1 << 16).unwrap();
+        let msg = [0x5au8; 64];
+        let started = std::time::Instant::now();
+        for _ in 0..MESSAGES / BATCH {
+            for _ in 0..BATCH {
+                ring.try_push(&msg).unwrap();
+            }
+            for _ in 0..BATCH {
+                ring.try_pop().unwrap();
+            }
+        }
+        let single = started.elapsed();
+        let batch = [&msg[..]; BATCH];
+        let mut out = Vec::with_capacity(BATCH);
+        let started = std::time::Instant::now();
+        for _ in 0..MESSAGES / BATCH {
+            assert_eq!(ring.try_push_batch(&batch).unwrap(), BATCH);
+            out.clear();
+            assert_eq!(ring.try_pop_batch(&mut out, BATCH), BATCH);
+        }
+        let batched = started.elapsed();
+        println!("{} messages of 64 bytes: single {:?}, batches of {} {:?}", MESSAGES, single, BATCH, batched);
+    }
+
+    #[test]
+    fn full_policies_reject_block_or_overwrite() {
+        use crate::ring_buffer::{FullPolicy, PushError, ShmRingBuffer};
+        use std::time::{Duration, Instant};
+
+        let name = format!("/policy_reject.{}", std::process::id());
+        let ring = ShmRingBuffer::create(&name, 256).unwrap();
+        assert_eq!(ring.full_policy(), FullPolicy::Reject);
+        while ring.enqueue(&[1; 40]).is_ok() {}
+        assert_eq!(ring.enqueue(&[1; 40]), Err(PushError::Full));
+        assert_eq!(ring.dropped(), 0);
+
+        let name = format!("/policy_block.{}", std::process::id());
+        let policy = FullPolicy::Block { timeout: Duration::from_millis(100) };
+        let ring = ShmRingBuffer::create_with_policy(&name, 256, policy).unwrap();
+        let mut consumer = ShmRingBuffer::open(&name, 256).unwrap();
+        assert_eq!(consumer.full_policy(), policy);
+        while ring.try_push(&[2; 40]).is_ok() {}
+        let started = Instant::now();
+        assert_eq!(ring.enqueue(&[3; 40]), Err(PushError::Full));
+        assert!(started.elapsed() >= Duration::from_millis(100));
+        let popping = std::thread::spawn(move || {
+            std::thread::sleep(Duration::from_millis(30));
+            consumer.try_pop().unwrap();
+            consumer
+        });
+        ring.enqueue(&[3; 40]).unwrap();
+        let mut consumer = popping.join().unwrap();
+        let drained: Vec<Vec<u8>> = std::iter::from_fn(|| consumer.try_pop()).collect();
+        assert_eq!(drained.last().unwrap(), &[3; 40]);
+
+        let name = format!("/policy_overwrite.{}", std::process::id());
+        let ring = ShmRingBuffer::create_with_policy(&name, 256, FullPolicy::OverwriteOldest).unwrap();
+        let mut consumer = ShmRingBuffer::open(&name, 256).unwrap();
+        for i in 0..100u32 {
+            // Mixed lengths so overwriting also has to step past the padding at the end of a lap
+            ring.enqueue(&i.to_le_bytes().repeat(1 + i as usize % 7)).unwrap();
+        }
+        let kept: Vec<Vec<u8>> = std::iter::from_fn(|| consumer.try_pop()).collect();
+        assert_eq!(ring.dropped() + kept.len() as u64, 100);
+        let expected: Vec<Vec<u8>> = (100 - kept.len() as u32..100).map(|i| i.to_le_bytes().repeat(1 + i as usize % 7)).collect();
+        assert_eq!(kept, expected);
+        assert!(ring.enqueue(&[0; 200]).is_err());
+
+        // Producers overwriting while the consumer pops never hand it a torn or repeated message
+        let producer = std::thread::spawn(move || {
+            for i in 0..20_000u32 {
+                ring.enqueue(&i.to_le_bytes().repeat(1 + i as usize % 7)).unwrap();
+            }
+            ring
+        });
+        let mut last = None;
+        let mut received = 0u64;
+        loop {
+            match consumer.try_pop() {
+                Some(msg) => {
+                    let i = u32::from_le_bytes(msg[..4].try_into().unwrap());
+                    assert_eq!(msg, i.to_le_bytes().repeat(1 + i as usize % 7));
+                    assert!(last.is_none_or(|last| i > last), "{} after {:?}", i, last);
+                    last = Some(i);
+                    received += 1;
+                }
+                None if producer.is_finished() => break,
+                None => std::thread::yield_now(),
+            }
+        }
+        let ring = producer.join().unwrap();
+        received += std::iter::from_fn(|| consumer.try_pop()).count() as u64;
+        assert_eq!(ring.dropped() - (100 - kept.len() as u64) + received, 20_000);
+    }
+
+    #[test]
+    fn reservations_publish_only_what_was_committed() {
+        use crate::ring_buffer::{PushError, ShmRingBuffer};
+        use std::io::Write;
+
+        let name = format!("/reserve_ring.{}", std::process::id());
+        let mut ring = ShmRingBuffer::create(&name, 512).unwrap();
+        let mut consumer = ShmRingBuffer::open(&name, 512).unwrap();
+        for round in 0..50u32 {
+            // Serialized straight into the ring, a length-prefixed string and a trailing u64
+            let mut reservation = ring.reserve(100).unwrap();
+            assert_eq!(reservation.len(), 100);
+            let mut cursor = &mut reservation[..];
+            let text = format!("round {}", round);
+            cursor.write_all(&(text.len() as u32).to_le_bytes()).unwrap();
+            cursor.write_all(text.as_bytes()).unwrap();
+            cursor.write_all(&(round as u64 * 3).to_le_bytes()).unwrap();
+            let written = 100 - cursor.len();
+            reservation.commit(written).unwrap();
+
+            // An abandoned reservation ahead of a plain push is stepped over
+            match round % 3 {
+                0 => drop(ring.reserve(40).unwrap()),
+                1 => assert_eq!(ring.reserve(40).unwrap().commit(41), Err(PushError::TooLarge { len: 41, max: 40 })),
+                _ => {}
+            }
+            ring.try_push(b"after").unwrap();
+
+            let mut expected = (text.len() as u32).to_le_bytes().to_vec();
+            expected.extend_from_slice(text.as_bytes());
+            expected.extend_from_slice(&(round as u64 * 3).to_le_bytes());
+            assert_eq!(consumer.try_pop().unwrap(), expected, "round {}", round);
+            assert_eq!(consumer.try_pop().unwrap(), b"after", "round {}", round);
+            assert_eq!(consumer.try_pop(), None, "round {}", round);
+        }
+
+        // The consumer waits at an open reservation, even for messages pushed after it
+        let reservation = ring.reserve(8).unwrap();
+        ring.try_push(b"queued").unwrap();
+        assert_eq!(consumer.try_pop(), None);
+        let mut reservation = reservation;
+        reservation.copy_from_slice(b"reserved");
+        reservation.commit(8).unwrap();
+        assert_eq!(consumer.try_pop().unwrap(), b"reserved");
+        assert_eq!(consumer.try_pop().unwrap(), b"queued");
+        assert!(matches!(ring.reserve(ring.max_message_len() + 1), Err(PushError::TooLarge { .. })));
+        while ring.try_push(&[0; 64]).is_ok() {}
+        assert!(matches!(ring.reserve(64), Err(PushError::Full)));
+        assert!(std::iter::from_fn(|| ring.try_pop()).all(|msg| msg == [0; 64]));
+    }
+
+    #[test]
+    fn ring_stats_count_exactly_what_went_through() {
+        use crate::ring_buffer::{FullPolicy, ShmRingBuffer};
+
+        // Eight byte messages take sixteen bytes with their record header
+        let name = format!("/stats_ring.{}", std::process::id());
+        let mut ring = ShmRingBuffer::create(&name, 1024).unwrap();
+        let stats = ring.stats();
+        assert_eq!((stats.pushed, stats.popped, stats.dropped, stats.occupancy_bytes, stats.high_water_bytes), (0, 0, 0, 0, 0));
+        assert_eq!((stats.last_push, stats.last_pop), (None, None));
+
+        for i in 0..10u64 {
+            ring.try_push(&i.to_le_bytes()).unwrap();
+        }
+        let stats = ring.stats();
+        assert_eq!((stats.pushed, stats.occupancy_messages, stats.occupancy_bytes, stats.high_water_bytes), (10, 10, 160, 160));
+        assert!(stats.last_push.is_some() && stats.last_pop.is_none());
+
+        for _ in 0..4 {
+            ring.try_pop().unwrap();
+        }
+        let stats = ring.stats();
+        assert_eq!((stats.popped, stats.occupancy_messages, stats.occupancy_bytes, stats.high_water_bytes), (4, 6, 96, 160));
+        assert!(stats.last_pop.is_some());
+
+        assert_eq!(ring.try_push_batch(&[&[1; 8], &[2; 8], &[3; 8], &[4; 8], &[5; 8]]).unwrap(), 5);
+        let mut reservation = ring.reserve(8).unwrap();
+        reservation.copy_from_slice(&[6; 8]);
+        reservation.commit(8).unwrap();
+        let stats = ring.stats();
+        assert_eq!((stats.pushed, stats.occupancy_messages, stats.occupancy_bytes, stats.high_water_bytes), (16, 12, 192, 192));
+
+        // Another process sees the same counters without knowing the capacity
+        let other = ShmRingBuffer::open_existing(&name).unwrap();
+        assert_eq!(other.capacity(), 1024);
+        assert_eq!(other.stats(), ring.stats());
+        drop(other);
+        drop(ring);
+
+        let name = format!("/stats_overwrite.{}", std::process::id());
+        let ring = ShmRingBuffer::create_with_policy(&name, 256, FullPolicy::OverwriteOldest).unwrap();
+        for i in 0..20u64 {
+            ring.enqueue(&i.to_le_bytes()).unwrap();
+        }
+        let stats = ring.stats();
+        assert_eq!((stats.pushed, stats.dropped, stats.popped, stats.occupancy_messages), (20, 4, 0, 16));
+        assert_eq!((stats.occupancy_bytes, stats.high_water_bytes), (256, 256));
+    }
+
+    #[test]
+    fn scribbled_headers_never_escape_the_segment() {
+        use crate::kv::ShmKvStore;
+        use crate::log::ShmLog;
+        use crate::ring_buffer::{ShmRingBuffer, HEADER_SIZE};
+        use std::time::Duration;
+
+        let name = format!("/scribbled_ring.{}", std::process::id());
+        let mut ring = ShmRingBuffer::create(&name, 256).unwrap();
+        let ring_bytes = SharedMemory::open(&name, (HEADER_SIZE + 256) as i32).unwrap();
+        let name = format!("/scribbled_log.{}", std::process::id());
+        let log = ShmLog::create(&name, 256).unwrap();
+        let log_bytes = SharedMemory::open(&name, 128 + 256).unwrap();
+        let (shm, _name) = SharedMemory::create_unique("scribbled", 4096).unwrap();
+        let store = ShmKvStore::init(&shm, 64, 8, 24).unwrap();
+        // Seeded xorshift so a failure replays the same sequence
+        let mut state = 0x2545_f491_4f6c_dd1du64;
+        let mut next = move || {
+            state ^= state << 13;
+            state ^= state >> 7;
+            state ^= state << 17;
+            state
+        };
+        for round in 0..2000 {
+            // Positions near the real ones find more edges than uniformly random ones
+            let position = |pick: u64, value: u64, limit: u64| match pick % 4 {
+                0 => value,
+                1 => u64::MAX - value % 64,
+                _ => value % limit,
+            };
+            let (head, tail, record) = (position(next(), next(), 1024), position(next(), next(), 1024), position(next(), next(), 256));
+            ring_bytes.write_u64_le(64, head).unwrap();
+            ring_bytes.write_u64_le(128, tail).unwrap();
+            ring_bytes.write_u32_le(HEADER_SIZE + (record as usize & !7) % 256, next() as u32).unwrap();
+            let _ = ring.try_push(&[round as u8; 13]);
+            let _ = ring.try_pop();
+            let _ = ring.try_pop();
+
+            let (head, tail, record) = (position(next(), next(), 1024), position(next(), next(), 1024), position(next(), next(), 256));
+            log_bytes.write_u64_le(72, head).unwrap();
+            log_bytes.write_u64_le(80, tail).unwrap();
+            log_bytes.write_u32_le(128 + record as usize % 252, next() as u32).unwrap();
+            let _ = log.append(&[round as u8; 5]);
+            let _ = log.read_from(head).take(64).count();
+            let _ = log.truncate_before(position(next(), next(), 1024));
+
+            // The table's length and geometry, then the whole segment for the framed readers
+            shm.write_u32_le(64 + 12, next() as u32).unwrap();
+            let _ = store.get(&[round as u8; 16]);
+            let _ = store.put(&[round as u8; 16], &[1; 24]);
+            let _ = store.delete(&[(round / 2) as u8; 16]);
+            let _ = ShmKvStore::attach(&shm, 64, next() as usize % 64, next() as usize % 64);
+            for word in 0..shm.size() as usize / 8 {
+                shm.write_u64_le(word * 8, next() >> (next() % 64)).unwrap();
+            }
+            let _ = shm.read_message_blocking(Some(Duration::ZERO));
+            #[cfg(feature = "lz4")]
+            let _ = shm.read_message_auto(&mut Vec::new());
+            #[cfg(feature = "xxhash")]
+            let _ = shm.read_blob_hashed(position(next(), next(), 4096) as usize);
+            ShmKvStore::init(&shm, 64, 8, 24).unwrap();
+        }
+    }
+
+    #[test]
+    fn kv_entries_land_where_the_layout_says() {
+        use crate::kv::{Full, ShmKvStore};
+
+        let (shm, name) = SharedMemory::create_unique("kv", 4096).unwrap();
+        let store = ShmKvStore::init(&shm, 64, 3, 6).unwrap();
+        assert_eq!(ShmKvStore::size_for(3, 6), 16 + 3 * 24);
+        let first = *b"0123456789abcdef";
+        let second = [0xee; 16];
+        let third = [0x11; 16];
+        store.put(&first, b"value1").unwrap();
+        store.put(&second, b"value2").unwrap();
+        assert_eq!(shm.read_u32_le(64 + 4).unwrap(), 3);
+        assert_eq!(shm.read_u32_le(64 + 8).unwrap(), 6);
+        assert_eq!(shm.read_u32_le(64 + 12).unwrap(), 2);
+        assert_eq!(shm.read_owned_at(64 + 16, 22).unwrap(), b"0123456789abcdefvalue1");
+        assert_eq!(shm.read_owned_at(64 + 16 + 24, 16).unwrap(), [0xee; 16]);
+        assert_eq!(shm.read_owned_at(64 + 16 + 24 + 16, 6).unwrap(), b"value2");
+
+        std::thread::spawn(move || {
+            let shm = SharedMemory::open(&name, 4096).unwrap();
+            let store = ShmKvStore::attach(&shm, 64, 3, 6).unwrap();
+            assert_eq!(store.get(&first), Some(&b"value1"[..]));
+            store.put(&first, b"VALUE1").unwrap();
+            store.put(&third, b"value3").unwrap();
+        })
+        .join()
+        .unwrap();
+        assert_eq!(store.get(&first), Some(&b"VALUE1"[..]));
+        assert_eq!(store.put(&[0; 16], b"value4"), Err(Full));
+        // Deleting the first entry moves the last one into its place
+        assert!(store.delete(&first));
+        assert!(!store.delete(&first));
+        assert_eq!(store.get(&first), None);
+        assert_eq!(store.len(), 2);
+        assert_eq!(shm.read_owned_at(64 + 16, 22).unwrap(), [&[0x11; 16][..], b"value3"].concat());
+        assert_eq!(store.get(&second), Some(&b"value2"[..]));
+        assert!(ShmKvStore::attach(&shm, 64, 3, 8).is_err());
+        assert!(ShmKvStore::attach(&shm, 64, 4, 6).is_err());
+    }
+
+    #[test]
+    #[cfg(feature = "xxhash")]
+    fn bloom_filter_has_no_false_negatives() {
+        use crate::bloom::{parameters_for, ShmBloomFilter};
+
+        let (bits, hashes) = parameters_for(10_000, 0.01);
+        assert_eq!(hashes, 7);
+        let size = ShmBloomFilter::size_for(bits) + 64;
+        let (shm, name) = SharedMemory::create_unique("bloom", size as i32).unwrap();
+        let filter = ShmBloomFilter::init(&shm, 64, bits, hashes).unwrap();
+        let other = std::thread::spawn(move || {
+            let shm = SharedMemory::open(&name, size as i32).unwrap();
+            let filter = ShmBloomFilter::attach(&shm, 64).unwrap();
+            assert_eq!((filter.bits(), filter.hashes()), (bits, hashes));
+            for id in (1..10_000u32).step_by(2) {
+                filter.insert(format!("id-{}", id).as_bytes());
+            }
+        });
+        for id in (0..10_000u32).step_by(2) {
+            filter.insert(format!("id-{}", id).as_bytes());
+        }
+        other.join().unwrap();
+        assert!((0..10_000u32).all(|id| filter.contains(format!("id-{}", id).as_bytes())));
+        let false_positives = (10_000..20_000u32).filter(|id| filter.contains(format!("id-{}", id).as_bytes())).count();
+        assert!(false_positives < 300, "{} false positives in 10000", false_positives);
+        assert!(ShmBloomFilter::attach(&shm, 0).is_err());
+    }
+
+    #[test]
+    fn bitset_counts_match_the_flips_that_happened() {
+        use crate::bitset::{OutOfRange, ShmBitset};
+
+        fn flip(bitset: &ShmBitset, seed: usize) -> isize {
+            // Each thread's net change is the bits it found clear and set plus the bits it found set and cleared
+            let mut net = 0;
+            for round in 0..20_000 {
+                let bit = (round * 7919 + seed * 104_729) % 1000;
+                if round % 3 == seed % 3 {
+                    net -= bitset.clear(bit).unwrap() as isize;
+                } else {
+                    net += !bitset.set(bit).unwrap() as isize;
+                }
+            }
+            net
+        }
+
+        let (shm, name) = SharedMemory::create_unique("bitset", 4096).unwrap();
+        let bitset = ShmBitset::init(&shm, 64, 1000).unwrap();
+        let other = std::thread::spawn(move || {
+            let shm = SharedMemory::open(&name, 4096).unwrap();
+            let bitset = &ShmBitset::attach(&shm, 64, 1000).unwrap();
+            std::thread::scope(|scope| {
+                let handles: Vec<_> = (0..2).map(|seed| scope.spawn(move || flip(bitset, seed))).collect();
+                handles.into_iter().map(|handle| handle.join().unwrap()).sum::<isize>()
+            })
+        });
+        let shared = &bitset;
+        let here: isize = std::thread::scope(|scope| {
+            let handles: Vec<_> = (2..4).map(|seed| scope.spawn(move || flip(shared, seed))).collect();
+            handles.into_iter().map(|handle| handle.join().unwrap()).sum()
+        });
+        let net = here + other.join().unwrap();
+        assert_eq!(bitset.count_ones() as isize, net);
+
+        for bit in 0..1000 {
+            bitset.clear(bit).unwrap();
+        }
+        assert_eq!(bitset.find_first_zero(), Some(0));
+        bitset.set_range(0..130).unwrap();
+        bitset.set_range(131..1000).unwrap();
+        assert_eq!(bitset.count_ones(), 999);
+        assert_eq!(bitset.find_first_zero(), Some(130));
+        assert!(!bitset.set(130).unwrap());
+        assert_eq!(bitset.find_first_zero(), None);
+        assert_eq!(bitset.test(1000), Err(OutOfRange { bit: 1000, bits: 1000 }));
+        assert_eq!(bitset.set_range(990..1001), Err(OutOfRange { bit: 1001, bits: 1000 }));
+        assert!(ShmBitset::attach(&shm, 64, 999).is_err());
+    }
+
+    #[test]
+    fn blocking_reads_wake_on_notify() {
+        use crate::shared_memory::WaitError;
+        use std::time::{Duration, Instant};
+
+        let (shm, name) = SharedMemory::create_unique("notify", 4096).unwrap();
+        let consumer = std::thread::spawn(move || {
+            let shm = SharedMemory::open(&name, 4096).unwrap();
+            let started = Instant::now();
+            let first = shm.read_message_blocking(Some(Duration::from_secs(5))).unwrap();
+            let waited = started.elapsed();
+            // The producer writes twice before this read, only the latest is kept
+            std::thread::sleep(Duration::from_millis(300));
+            let second = shm.read_message_blocking(Some(Duration::from_secs(5))).unwrap();
+            let timed_out = shm.read_message_blocking(Some(Duration::from_millis(50)));
+            (first, waited, second, matches!(timed_out, Err(WaitError::TimedOut)))
+        });
+        assert_eq!(shm.last_writer_pid().unwrap(), None);
+        std::thread::sleep(Duration::from_millis(200));
+        shm.write_message_notify(b"first").unwrap();
+        std::thread::sleep(Duration::from_millis(50));
+        shm.write_message_notify(b"stale").unwrap();
+        shm.write_message_notify(b"latest").unwrap();
+        let (first, waited, second, timed_out) = consumer.join().unwrap();
+        assert_eq!(first, b"first");
+        assert!(waited >= Duration::from_millis(150) && waited < Duration::from_secs(2), "{:?}", waited);
+        assert_eq!(second, b"latest");
+        assert!(timed_out);
+        assert_eq!(shm.last_writer_pid().unwrap(), Some(std::process::id()));
+        assert!(shm.write_message_notify(&[0; 4096]).is_err());
+    }
+
+    #[test]
+    fn word_waits_see_the_store_and_time_out() {
+        use crate::shared_memory::{Error, WaitResult};
+        use std::time::{Duration, Instant};
+
+        let (shm, name) = SharedMemory::create_unique("word_wait", 4096).unwrap();
+        let waiter = std::thread::spawn(move || {
+            let shm = SharedMemory::open(&name, 4096).unwrap();
+            let started = Instant::now();
+            let result = shm.wait_for_change_u32(64, 0, Some(Duration::from_secs(5))).unwrap();
+            (result, started.elapsed())
+        });
+        std::thread::sleep(Duration::from_millis(200));
+        // A wake without a change sends the waiter back to sleep
+        shm.wake_all_u32(64).unwrap();
+        std::thread::sleep(Duration::from_millis(50));
+        shm.write_u32_le(64, 1).unwrap();
+        shm.wake_one_u32(64).unwrap();
+        let (result, waited) = waiter.join().unwrap();
+        assert_eq!(result, WaitResult::Changed(1));
+        assert!(waited >= Duration::from_millis(200) && waited < Duration::from_secs(2), "{:?}", waited);
+
+        let started = Instant::now();
+        assert_eq!(shm.wait_for_change_u32(64, 1, Some(Duration::from_millis(50))).unwrap(), WaitResult::TimedOut);
+        assert!(started.elapsed() >= Duration::from_millis(50));
+        assert_eq!(shm.wait_for_change_u32(64, 7, None).unwrap(), WaitResult::Changed(1));
+        assert!(matches!(shm.wait_for_change_u32(66, 0, None), Err(Error::Misaligned { offset: 66, alignment: 4 })));
+        assert!(matches!(shm.wake_all_u32(4096), Err(Error::OutOfBounds { .. })));
+    }
+
+    #[cfg(feature = "testing")]
+    #[test]
+    fn another_process_reads_what_this_one_wrote() {
+        use crate::testing;
+
+        let name = format!("/cross_rw.{}", testing::parent_id());
+        testing::run(
+            "tests::another_process_reads_what_this_one_wrote",
+            &name,
+            |child| {
+                let shm = SharedMemory::create_exclusive(&name, 8192).unwrap();
+                shm.copy_from_slice_at(4000, b"from the parent").unwrap();
+                child.wait();
+                // The child writes back before it exits
+                assert_eq!(shm.read_owned_at(0, 14).unwrap(), b"from the child");
+            },
+            |name| {
+                let shm = SharedMemory::open(name, 8192).unwrap();
+                assert_eq!(shm.read_owned_at(4000, 15).unwrap(), b"from the parent");
+                shm.copy_from_slice_at(0, b"from the child").unwrap();
+            },
+        );
+    }
+
+    #[cfg(feature = "testing")]
+    #[test]
+    fn segments_go_away_with_the_process_that_created_them() {
+        use crate::testing;
+
+        let name = format!("/cross_unlink.{}", testing::parent_id());
+        testing::run(
+            "tests::segments_go_away_with_the_process_that_created_them",
+            &name,
+            |child| {
+                child.wait();
+                let err = SharedMemory::open(&name, 4096).err().unwrap();
+                assert_eq!(err.kind(), std::io::ErrorKind::NotFound);
+            },
+            |name| {
+                let shm = SharedMemory::create_exclusive(name, 4096).unwrap();
+                assert_eq!(SharedMemory::open(name, 4096).unwrap().size(), 4096);
+                drop(shm);
+            },
+        );
+    }
+
+    #[cfg(feature = "testing")]
+    #[test]
+    fn notify_wakes_a_reader_in_another_process() {
+        use crate::testing;
+        use std::time::Duration;
+
+        let name = format!("/cross_notify.{}", testing::parent_id());
+        testing::run(
+            "tests::notify_wakes_a_reader_in_another_process",
+            &name,
+            |child| {
+                let shm = SharedMemory::create_exclusive(&name, 4096).unwrap();
+                child.start();
+                // The child reports through the segment once it is attached and about to block
+                while shm.read_u32_le(2048).unwrap() != 1 {
+                    std::thread::sleep(Duration::from_millis(1));
+                }
+                std::thread::sleep(Duration::from_millis(100));
+                shm.write_message_notify(b"ring").unwrap();
+                child.wait();
+            },
+            |name| {
+                let shm = SharedMemory::open(name, 4096).unwrap();
+                shm.write_u32_le(2048, 1).unwrap();
+                assert_eq!(shm.read_message_blocking(Some(Duration::from_secs(10))).unwrap(), b"ring");
+            },
+        );
+    }
+
+    #[cfg(feature = "testing")]
+    #[test]
+    fn a_creator_that_exited_is_reported_dead() {
+        use crate::shared_memory::{Role, ELECTION_HEADER};
+        use crate::testing;
+        use std::time::Duration;
+
+        let name = format!("/cross_alive.{}", testing::parent_id());
+        testing::run(
+            "tests::a_creator_that_exited_is_reported_dead",
+            &name,
+            |child| {
+                let messages = SharedMemory::create_exclusive(&format!("{}.msg", name), 4096).unwrap();
+                assert_eq!(messages.last_writer_pid().unwrap(), None);
+                child.start();
+                let shm = SharedMemory::open_with_retry(&name, 4096 + ELECTION_HEADER as i32, Duration::from_secs(10), Duration::from_millis(1)).unwrap();
+                while !shm.leader_alive().unwrap() {
+                    std::thread::sleep(Duration::from_millis(1));
+                }
+                assert!(shm.creator_alive().unwrap());
+                assert_eq!(messages.read_message_blocking(Some(Duration::from_secs(10))).unwrap(), b"alive");
+                let writer = messages.last_writer_pid().unwrap().unwrap();
+                assert_ne!(writer, std::process::id());
+                // Lets the child exit
+                shm.write_u32_le(ELECTION_HEADER, 1).unwrap();
+                child.wait();
+                assert!(!shm.creator_alive().unwrap());
+                assert!(!shm.leader_alive().unwrap());
+                assert_eq!(messages.last_writer_pid().unwrap(), Some(writer));
+            },
+            |name| {
+                let shm = match SharedMemory::elect(name, 4096).unwrap() {
+                    Role::Leader(shm) => shm,
+                    Role::Follower(_) => panic!("the child should create and lead the segment"),
+                };
+                assert!(shm.creator_alive().unwrap());
+                let messages = SharedMemory::open(&format!("{}.msg", name), 4096).unwrap();
+                messages.write_message_notify(b"alive").unwrap();
+                while shm.read_u32_le(ELECTION_HEADER).unwrap() != 1 {
+                    std::thread::sleep(Duration::from_millis(1));
+                }
+            },
+        );
+    }
+
+    #[test]
+    #[cfg(target_os = "windows")]
+    fn named_mutexes_contend_and_report_abandonment() {
+        use crate::named_mutex::{LockError, NamedMutex};
+        use std::time::Duration;
+
+        let (shm, _) = SharedMemory::create_unique("named_mutex", 4096).unwrap();
+        let mutex = NamedMutex::for_segment(&shm).unwrap();
+        let name = mutex.name().to_string();
+        let counter = std::sync::atomic::AtomicU32::new(0);
+        std::thread::scope(|scope| {
+            for _ in 0..2 {
+                scope.spawn(|| {
+                    let mutex = NamedMutex::open(&name).unwrap();
+                    for _ in 0..1000 {
+                        let _guard = mutex.lock().unwrap();
+                        // A plain load and store would lose updates if both threads held the lock at once
+                        let seen = counter.load(std::sync::atomic::Ordering::Relaxed);
+                        counter.store(seen + 1, std::sync::atomic::Ordering::Relaxed);
+                    }
+                });
+            }
+        });
+        assert_eq!(counter.into_inner(), 2000);
+
+        let held = mutex.lock().unwrap();
+        std::thread::scope(|scope| {
+            scope.spawn(|| assert!(matches!(mutex.lock_timeout(Duration::from_millis(50)), Err(LockError::TimedOut))));
+        });
+        drop(held);
+        std::thread::scope(|scope| {
+            scope.spawn(|| std::mem::forget(mutex.lock().unwrap()));
+        });
+        match mutex.lock() {
+            Err(LockError::Abandoned(guard)) => drop(guard),
+            other => panic!("expected an abandoned mutex, got {:?}", other.map(|_| ())),
+        }
+        drop(mutex.lock().unwrap());
+    }
+
+    #[test]
+    #[cfg(target_os = "linux")]
+    fn named_semaphores_count_posts_across_handles() {
+        use crate::named_semaphore::NamedSemaphore;
+        use std::time::{Duration, Instant};
+
+        let name = format!("/named_sem_test.{}", std::process::id());
+        let items = NamedSemaphore::create(&name, 0).unwrap();
+        assert!(items.is_owner());
+        let started = Instant::now();
+        assert!(!items.wait_timeout(Duration::from_millis(100)).unwrap());
+        assert!(started.elapsed() >= Duration::from_millis(100));
+        let producer = {
+            let name = name.clone();
+            std::thread::spawn(move || {
+                let items = NamedSemaphore::open(&name).unwrap();
+                assert!(!items.is_owner());
+                for _ in 0..3 {
+                    std::thread::sleep(Duration::from_millis(20));
+                    items.post().unwrap();
+                }
+            })
+        };
+        for _ in 0..3 {
+            assert!(items.wait_timeout(Duration::from_secs(5)).unwrap());
+        }
+        producer.join().unwrap();
+        assert!(!items.try_wait().unwrap());
+        items.post().unwrap();
+        items.wait().unwrap();
+        // Creating it again opens the existing semaphore, count and all, without taking the name over
+        items.post().unwrap();
+        let again = NamedSemaphore::create(&name, 5).unwrap();
+        assert!(!again.is_owner());
+        assert!(again.try_wait().unwrap());
+        assert!(!again.try_wait().unwrap());
+        drop(again);
+        let poster = NamedSemaphore::open(&name).unwrap();
+        std::thread::spawn(move || {
+            std::thread::sleep(Duration::from_millis(20));
+            poster.post().unwrap();
+        });
+        assert!(items.wait_timeout(Duration::MAX).unwrap());
+        drop(items);
+        assert!(NamedSemaphore::open(&name).is_err());
+    }
+
+    #[test]
+    #[cfg(target_os = "linux")]
+    fn sysv_segments_exchange_data_by_key() {
+        let path = std::env::temp_dir().join(format!("sysv_key.{}", std::process::id()));
+        std::fs::write(&path, b"").unwrap();
+        let key = SharedMemory::ftok(&path, b'S').unwrap();
+        assert_eq!(SharedMemory::ftok(&path, b'S').unwrap(), key);
+        let created = SharedMemory::create_sysv(key, 8192).unwrap();
+        created.write_u64_le(16, 0xfeed).unwrap();
+        std::thread::spawn(move || {
+            let opened = SharedMemory::open_sysv(key).unwrap();
+            assert_eq!(opened.size(), 8192);
+            assert_eq!(opened.read_u64_le(16).unwrap(), 0xfeed);
+            opened.write_u64_le(24, 0xbeef).unwrap();
+            let stat = opened.sysv_stat().unwrap();
+            assert_eq!((stat.size, stat.attached), (8192, 2));
+            assert_eq!(stat.creator_pid, std::process::id() as i32);
+        })
+        .join()
+        .unwrap();
+        assert_eq!(created.read_u64_le(24).unwrap(), 0xbeef);
+        assert_eq!(created.sysv_stat().unwrap().attached, 1);
+        // A second create finds the segment there and doesn't take it over
+        let again = SharedMemory::create_sysv(key, 4096).unwrap();
+        assert_eq!(again.read_u64_le(24).unwrap(), 0xbeef);
+        assert!(SharedMemory::create_sysv(key, 16384).is_err());
+        drop(again);
+        assert_eq!(created.sysv_stat().unwrap().attached, 1);
+        assert_eq!(SharedMemory::open_sysv(key).unwrap().read_u64_le(16).unwrap(), 0xfeed);
+        drop(created);
+        assert!(SharedMemory::open_sysv(key).is_err());
+        std::fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn open_or_create_preserves_or_zeroes_existing_contents() {
+        use crate::shared_memory::OpenMode;
+
+        let name = format!("/open_or_create.{}", std::process::id());
+        let (created, fresh) = SharedMemory::open_or_create(&name, 4096, OpenMode::TruncateIfExisting).unwrap();
+        assert!(fresh);
+        created.write_u64_le(8, 0x5eed).unwrap();
+        let (warm, fresh) = SharedMemory::open_or_create(&name, 4096, OpenMode::PreserveContents).unwrap();
+        assert!(!fresh);
+        assert_eq!(warm.read_u64_le(8).unwrap(), 0x5eed);
+        drop(warm);
+        let (clean, _) = SharedMemory::open_or_create(&name, 4096, OpenMode::TruncateIfExisting).unwrap();
+        assert_eq!(clean.read_u64_le(8).unwrap(), 0);
+        assert_eq!(created.read_u64_le(8).unwrap(), 0);
+        #[cfg(target_os = "linux")]
+        {
+            let (grown, _) = SharedMemory::open_or_create(&name, 8192, OpenMode::PreserveContents).unwrap();
+            grown.write_u64_le(8000, 1).unwrap();
+            assert_eq!(grown.read_u64_le(8000).unwrap(), 1);
+        }
+        drop(created);
+        assert!(SharedMemory::open(&name, 4096).is_err());
+    }
+
+    #[test]
+    fn exclusive_creation_refuses_an_existing_segment() {
+        let (shm, name) = SharedMemory::create_unique("exclusive", 4096).unwrap();
+        let err = SharedMemory::create_exclusive(&name, 4096).err().unwrap();
+        assert_eq!(err.kind(), std::io::ErrorKind::AlreadyExists);
+        assert!(err.to_string().contains(&name));
+        // Plain `create` hands the existing segment back wiped on both platforms, but not one smaller than asked for
+        shm.write_u64_le(8, 7).unwrap();
+        let again = SharedMemory::create(&name, 4096).unwrap();
+        assert_eq!(shm.read_u64_le(8).unwrap(), 0);
+        assert!(SharedMemory::create(&name, 16384).is_err());
+        drop(again);
+        drop(shm);
+        // Windows destroys the section with its last handle, Linux unlinks it when the creator drops
+        drop(SharedMemory::create_exclusive(&name, 4096).unwrap());
+    }
+
+    #[test]
+    fn leaked_segments_outlive_their_handle() {
+        let (shm, name) = SharedMemory::create_unique("leak", 4096).unwrap();
+        let bytes = shm.leak();
+        let (front, back) = bytes.split_at_mut(2048);
+        std::thread::scope(|scope| {
+            scope.spawn(|| front.fill(1));
+            scope.spawn(|| back.fill(2));
+        });
+        let opened = SharedMemory::open(&name, 4096).unwrap();
+        assert_eq!(opened.read_owned_at(2047, 2).unwrap(), [1, 2]);
+        drop(opened);
+
+        let handle = SharedMemory::open(&name, 4096).unwrap().leak_handle();
+        assert_eq!(handle.name(), name);
+        assert_eq!(handle.get_byte(0).unwrap(), 1);
+        // Nothing unlinks a leaked creator's name
+        SharedMemory::unlink(&name).unwrap();
+    }
+
+    #[test]
+    fn builder_options_reach_the_segment() {
+        use crate::name::NamePolicy;
+        let long = format!("/builder.{}.{}", std::process::id(), "x".repeat(300));
+        SharedMemory::builder(&long).size(4096).create().err().unwrap();
+        let created = SharedMemory::builder(&long).size(4096).name_policy(NamePolicy::HashWhenTooLong).exclusive(true).create().unwrap();
+        created.set_byte(4095, 7).unwrap();
+        let opened = SharedMemory::builder(&long).name_policy(NamePolicy::HashWhenTooLong).open().unwrap();
+        assert_eq!(opened.size(), 4096);
+        assert_eq!(opened.get_byte(4095).unwrap(), 7);
+        let (reopened, was_created) = SharedMemory::builder(&long)
+            .size(4096)
+            .name_policy(NamePolicy::HashWhenTooLong)
+            .open_mode(crate::shared_memory::OpenMode::TruncateIfExisting)
+            .open_or_create()
+            .unwrap();
+        assert!(!was_created);
+        assert_eq!(reopened.get_byte(4095).unwrap(), 0);
+        let error = SharedMemory::builder(&long).size(4096).name_policy(NamePolicy::HashWhenTooLong).exclusive(true).create().err().unwrap();
+        assert_eq!(std::io::Error::from(error).kind(), std::io::ErrorKind::AlreadyExists);
+    }
+
+    #[test]
+    fn boundary_offsets_and_lengths_error_instead_of_wrapping() {
+        use crate::shared_memory::{align_offset_up, Error};
+
+        let (mut shm, _) = SharedMemory::create_unique("bounds", 4096).unwrap();
+        // Past what a 32-bit `i32` size or a 32-bit `usize` sum can hold
+        let past_i32 = i32::MAX as usize + 1;
+        for offset in [usize::MAX, usize::MAX - 3, past_i32, 4093] {
+            assert!(matches!(shm.read_u32_le(offset), Err(Error::OutOfBounds { .. })), "offset {}", offset);
+            assert!(matches!(shm.write_u64_le(offset, 1), Err(Error::OutOfBounds { .. })), "offset {}", offset);
+        }
+        for (offset, len) in [(1, usize::MAX), (usize::MAX, 1), (0, past_i32), (past_i32, 0), (4096, 1)] {
+            assert!(matches!(shm.read_owned_at(offset, len), Err(Error::OutOfBounds { .. })), "{} bytes at {}", len, offset);
+            assert!(shm.aligned_region(offset, len, 8).is_err(), "{} bytes at {}", len, offset);
+        }
+        assert!(align_offset_up(usize::MAX, 8).is_err());
+        assert!(matches!(shm.remap_window(0, past_i32), Err(Error::Io(err)) if err.kind() == std::io::ErrorKind::InvalidInput));
+
+        // File offsets past `off_t`, or too close to it for a window to end inside it, never reach `mmap`
+        #[cfg(target_os = "linux")]
+        {
+            let page = SharedMemory::offset_alignment();
+            for offset in [u64::MAX / page * page, i64::MAX as u64 / page * page] {
+                assert!(matches!(SharedMemory::open_with_offset(&shm.name(), offset, 4096), Err(Error::FileOffsetTooLarge { .. })), "offset {}", offset);
+                assert!(matches!(shm.remap_window(offset, 4096), Err(Error::FileOffsetTooLarge { .. })), "offset {}", offset);
+            }
+            assert_eq!(shm.get_byte(0).unwrap(), 0);
+        }
+
+        let path = std::env::temp_dir().join(format!("bounds.{}", std::process::id()));
+        let file = std::fs::File::create(&path).unwrap();
+        file.set_len(4096).unwrap();
+        assert!(matches!(SharedMemory::from_file(file.try_clone().unwrap(), Some(past_i32)), Err(Error::Io(err)) if err.kind() == std::io::ErrorKind::InvalidInput));
+        assert_eq!(file.metadata().unwrap().len(), 4096);
+        std::fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    #[cfg(target_os = "linux")]
+    fn capacity_preflight_refuses_what_the_mount_cant_back() {
+        use crate::shared_memory::{check_space, statvfs_available, Error};
+        use std::io::ErrorKind;
+
+        // A 64MB container /dev/shm with 4KB blocks, a few of them reserved
+        let mut stat: libc::statvfs = unsafe { std::mem::zeroed() };
+        stat.f_frsize = 4096;
+        stat.f_bfree = 16400;
+        stat.f_bavail = 16384;
+        let available = statvfs_available(&stat);
+        assert_eq!(available, 64 << 20);
+        check_space(64 << 20, available, 4096).unwrap();
+        check_space((64 << 20) - 4095, available, 4096).unwrap();
+        check_space(0, 0, 4096).unwrap();
+        for (size, requested) in [((64 << 20) + 1, (64 << 20) + 4096), (1 << 30, 1 << 30)] {
+            let err = check_space(size, available, 4096).unwrap_err();
+            assert_eq!(err.kind(), ErrorKind::StorageFull);
+            match err.get_ref().and_then(|inner| inner.downcast_ref::<Error>()) {
+                Some(&Error::InsufficientSpace { requested: found, available: left }) => assert_eq!((found, left), (requested, available)),
+                other => panic!("expected InsufficientSpace, got {:?}", other),
+            }
+        }
+
+        let available = SharedMemory::available_capacity().unwrap();
+        assert!(available > 0);
+        let page = SharedMemory::offset_alignment();
+        if available + page <= i32::MAX as u64 {
+            // The mount is smaller than the largest segment, so one bigger than what's left can be asked for
+            let (_shm, name) = SharedMemory::create_unique("preflight", 4096).unwrap();
+            drop(_shm);
+            let size = (available + page) as i32;
+            let err = SharedMemory::builder(&name).size(size).exclusive(true).create().err().unwrap();
+            assert_eq!(std::io::Error::from(err).kind(), ErrorKind::StorageFull);
+            // The refused segment's name was taken back
+            assert!(SharedMemory::open_existing(&name).is_err());
+            let unchecked = SharedMemory::builder(&name).size(size).exclusive(true).check_capacity(false).create().unwrap();
+            assert_eq!(unchecked.size(), size);
+        } else {
+            // Both ways succeed when the mount has the room
+            let (shm, name) = SharedMemory::create_unique("preflight", 1 << 20).unwrap();
+            drop(shm);
+            SharedMemory::builder(&name).size(1 << 20).exclusive(true).check_capacity(false).create().unwrap();
+        }
+    }
+
+    #[test]
+    #[cfg(target_os = "linux")]
+    fn backing_dirs_hold_segments_like_dev_shm() {
+        use crate::shared_memory::{Error, OpenMode};
+        use std::io::ErrorKind;
+
+        // A plain temporary directory stands in for the tmpfs
+        let dir = std::env::temp_dir().join(format!("backing_dir.{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let name = format!("/backed.{}", std::process::id());
+        let file = dir.join(&name[1..]);
+        let in_dir = || SharedMemory::builder(&name).backing_dir(&dir);
+
+        let created = in_dir().size(8192).exclusive(true).create().unwrap();
+        assert_eq!(std::fs::metadata(&file).unwrap().len(), 8192);
+        assert_eq!(created.platform_name(), file.to_string_lossy());
+        created.copy_from_slice_at(4096, b"in the directory").unwrap();
+        // Nothing was made under /dev/shm
+        assert!(SharedMemory::open_existing(&name).is_err());
+
+        let opened = in_dir().size(8192).open().unwrap();
+        assert_eq!(opened.read_owned_at(4096, 16).unwrap(), b"in the directory");
+        let whole = in_dir().open().unwrap();
+        assert_eq!(whole.size(), 8192);
+        assert!(matches!(in_dir().size(4096).offset(4096).open().unwrap().read_owned_at(0, 16), Ok(bytes) if bytes == b"in the directory"));
+        assert!(matches!(in_dir().size(16384).open(), Err(Error::SizeMismatch { requested: 16384, actual: 8192 })));
+        assert_eq!(std::io::Error::from(in_dir().size(8192).exclusive(true).create().err().unwrap()).kind(), ErrorKind::AlreadyExists);
+
+        // Reusing and opening-or-creating an existing file leave it with the creator, as with shm names
+        let (reopened, was_created) = in_dir().size(8192).open_mode(OpenMode::PreserveContents).open_or_create().unwrap();
+        assert!(!was_created);
+        assert_eq!(reopened.read_owned_at(4096, 16).unwrap(), b"in the directory");
+        let reused = in_dir().size(8192).create().unwrap();
+        assert_eq!(reused.read_owned_at(4096, 16).unwrap(), [0; 16]);
+        drop((opened, whole, reopened, reused));
+        assert!(file.exists());
+        drop(created);
+        assert!(!file.exists());
+        let err = std::io::Error::from(in_dir().size(8192).open().err().unwrap());
+        assert_eq!(err.kind(), ErrorKind::NotFound);
+
+        // open_or_create makes the file when it's missing and owns it then
+        let (fresh, was_created) = in_dir().size(4096).open_or_create().unwrap();
+        assert!(was_created && file.exists());
+        drop(fresh);
+        assert!(!file.exists());
+
+        // Names that can't be a file in the directory
+        for bad in ["/..", ".", "/a/b", "/"] {
+            let err = SharedMemory::builder(bad).backing_dir(&dir).size(4096).create().err().unwrap();
+            assert_eq!(std::io::Error::from(err).kind(), ErrorKind::InvalidInput, "{:?}", bad);
+        }
+        assert_eq!(std::fs::read_dir(&dir).unwrap().count(), 0);
+        std::fs::remove_dir(&dir).unwrap();
+    }
+
+    #[test]
+    fn builder_rejects_options_the_call_cant_use() {
+        let (_shm, name) = SharedMemory::create_unique("builder", 4096).unwrap();
+        let rejected = [
+            SharedMemory::builder(&name).create().err(),
+            SharedMemory::builder(&name).size(4096).offset(4096).create().err(),
+            SharedMemory::builder(&name).size(4096).exclusive(true).open().err(),
+            SharedMemory::builder(&name).offset(4096).open().err(),
+            SharedMemory::builder(&name).open_or_create().err(),
+            SharedMemory::builder(&name).size(4096).exclusive(true).open_or_create().map(|(shm, _)| shm).err(),
+        ];
+        for error in rejected {
+            let error = std::io::Error::from(error.expect("the builder should have refused"));
+            assert_eq!(error.kind(), std::io::ErrorKind::InvalidInput, "{}", error);
+        }
+    }
+
+    #[test]
+    fn open_files_map_and_write_through() {
+        use std::convert::TryFrom;
+        let path = std::env::temp_dir().join(format!("from_file.{}", std::process::id()));
+        let open = || std::fs::OpenOptions::new().read(true).write(true).create(true).truncate(false).open(&path).unwrap();
+        std::fs::write(&path, b"").unwrap();
+        let err = std::io::Error::from(SharedMemory::try_from(open()).err().unwrap());
+        assert_eq!(err.kind(), std::io::ErrorKind::InvalidInput);
+
+        let shm = SharedMemory::from_file(open(), Some(8192)).unwrap();
+        assert_eq!(shm.size(), 8192);
+        shm.copy_from_slice_at(4096, b"through the mapping").unwrap();
+        drop(shm);
+        let contents = std::fs::read(&path).unwrap();
+        assert_eq!(contents.len(), 8192);
+        assert_eq!(&contents[4096..4115], b"through the mapping");
+
+        let shm = SharedMemory::try_from(open()).unwrap();
+        assert_eq!(shm.size(), 8192);
+        assert_eq!(shm.read_owned_at(4096, 19).unwrap(), b"through the mapping");
+        drop(shm);
+        std::fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    #[cfg(target_os = "linux")]
+    fn touched_pages_read_without_faulting() {
+        use crate::shared_memory::Protection;
+
+        // Counted for this thread only, the other tests run on threads of their own
+        let minor_faults = || {
+            let mut usage: libc::rusage = unsafe { std::mem::zeroed() };
+            assert_eq!(unsafe { libc::getrusage(libc::RUSAGE_THREAD, &mut usage) }, 0);
+            usage.ru_minflt
+        };
+        let pages = 2048;
+        let page = SharedMemory::offset_alignment() as usize;
+        let len = pages * page;
+        let read_every_page = |shm: &SharedMemory| {
+            let before = minor_faults();
+            let sum = (0..len).step_by(page).map(|offset| shm.get_byte(offset).unwrap() as u64).sum::<u64>();
+            (minor_faults() - before, sum)
+        };
+
+        let (control, _control_name) = SharedMemory::create_unique("untouched", len as i32).unwrap();
+        let (touched, _touched_name) = SharedMemory::create_unique("touched", len as i32).unwrap();
+        touched.touch_pages(0..len).unwrap();
+        let (control_faults, control_sum) = read_every_page(&control);
+        let (touched_faults, touched_sum) = read_every_page(&touched);
+        assert_eq!((control_sum, touched_sum), (0, 0));
+        assert!(touched_faults * 10 < control_faults, "{} faults after touching, {} without", touched_faults, control_faults);
+
+        // Clamped to the mapping, and pages that would fault on any access are left alone
+        let (mut shm, _name) = SharedMemory::create_unique("touch_clamped", 4 * page as i32).unwrap();
+        shm.copy_from_slice_at(page + 1, b"kept").unwrap();
+        shm.protect_range(2 * page..3 * page, Protection::NoAccess).unwrap();
+        shm.touch_pages(page - 1..usize::MAX).unwrap();
+        shm.touch_pages(10..10).unwrap();
+        shm.protect_range(0..4 * page, Protection::ReadWrite).unwrap();
+        assert_eq!(shm.read_owned_at(page + 1, 4).unwrap(), b"kept");
+    }
+
+    #[test]
+    fn flush_range_writes_back_part_of_a_file_mapping() {
+        use crate::shared_memory::{Error, FlushMode};
+
+        let path = std::env::temp_dir().join(format!("flush_range.{}", std::process::id()));
+        let file = std::fs::OpenOptions::new().read(true).write(true).create(true).truncate(true).open(&path).unwrap();
+        let shm = SharedMemory::from_file(file, Some(64 * 4096)).unwrap();
+        shm.copy_from_slice_at(4100, b"near").unwrap();
+        shm.copy_from_slice_at(40 * 4096 + 4000, b"far").unwrap();
+
+        // Unaligned at both ends and straddling a page boundary, widened to whole pages
+        shm.flush_range(4100..4104, FlushMode::Sync).unwrap();
+        shm.flush_range(40 * 4096 + 4000..41 * 4096 + 10, FlushMode::Async).unwrap();
+        shm.flush_range(64 * 4096..64 * 4096, FlushMode::Sync).unwrap();
+        shm.flush_range(0..64 * 4096, FlushMode::Sync).unwrap();
+        let contents = std::fs::read(&path).unwrap();
+        assert_eq!(&contents[4100..4104], b"near");
+        assert_eq!(&contents[40 * 4096 + 4000..40 * 4096 + 4003], b"far");
+
+        // Ranges that don't fit aren't clamped like the page helpers do
+        for range in [0..64 * 4096 + 1, usize::MAX - 1..usize::MAX] {
+            let err = shm.flush_range(range.clone(), FlushMode::Sync).unwrap_err();
+            assert_eq!(err.kind(), std::io::ErrorKind::InvalidInput, "{:?}", range);
+            assert!(matches!(err.get_ref().and_then(|inner| inner.downcast_ref::<Error>()), Some(Error::OutOfBounds { .. })));
+        }
+        #[allow(clippy::reversed_empty_ranges)]
+        let reversed = 10..5;
+        assert!(shm.flush_range(reversed, FlushMode::Async).is_err());
+        drop(shm);
+        std::fs::remove_file(&path).unwrap();
+
+        // Nothing to write back for a segment, but the range is still checked
+        let (shm, _name) = SharedMemory::create_unique("flush_range", 4096).unwrap();
+        shm.flush_range(10..20, FlushMode::Sync).unwrap();
+        assert!(shm.flush_range(0..4097, FlushMode::Sync).is_err());
+    }
+
+    #[test]
+    #[cfg(target_os = "linux")]
+    fn descriptors_close_once_on_drop_and_unwinding() {
+        use std::os::fd::AsRawFd;
+        // Another test can reuse the number as soon as it's closed, so compare what the number points at
+        let identity = |fd: i32| {
+            let mut stat: libc::stat = unsafe { std::mem::zeroed() };
+            (unsafe { libc::fstat(fd, &mut stat) } == 0).then_some((stat.st_dev, stat.st_ino))
+        };
+        let (shm, _name) = SharedMemory::create_unique("owned_fd", 4096).unwrap();
+        let fd = shm.backing_fd().unwrap().as_raw_fd();
+        let before = identity(fd).unwrap();
+        let cloned = shm.backing_fd().unwrap().try_clone_to_owned().unwrap();
+        drop(shm);
+        assert_ne!(identity(fd), Some(before));
+        // The duplicate is its own descriptor and still reaches the object
+        assert_eq!(identity(cloned.as_raw_fd()), Some(before));
+
+        let (shm, _name) = SharedMemory::create_unique("owned_fd", 4096).unwrap();
+        let fd = shm.backing_fd().unwrap().as_raw_fd();
+        let before = identity(fd).unwrap();
+        let unwound = std::panic::catch_unwind(std::panic::AssertUnwindSafe(move || {
+            let _shm = shm;
+            panic!("unwinding with the handle alive");
+        }));
+        assert!(unwound.is_err());
+        assert_ne!(identity(fd), Some(before));
+    }
+
+    #[test]
+    fn opening_past_the_end_of_a_segment_is_refused() {
+        #[cfg(target_os = "linux")]
+        use crate::shared_memory::Error;
+        let (shm, name) = SharedMemory::create_unique("size_check", 4096).unwrap();
+        shm.copy_from_slice_at(0, b"adopted").unwrap();
+        let err = SharedMemory::open(&name, 8192).err().unwrap();
+        #[cfg(target_os = "linux")]
+        match err.get_ref().and_then(|inner| inner.downcast_ref::<Error>()) {
+            Some(Error::SizeMismatch { requested: 8192, actual: 4096 }) => {}
+            other => panic!("expected a size mismatch, got {:?}", other),
+        }
+        #[cfg(target_os = "windows")]
+        drop(err);
+        match SharedMemory::open_with_offset(&name, 0, 4097) {
+            #[cfg(target_os = "linux")]
+            Err(Error::SizeMismatch { requested: 4097, actual: 4096 }) => {}
+            #[cfg(target_os = "windows")]
+            Err(_) => {}
+            other => panic!("expected a size mismatch, got {:?}", other.map(|shm| shm.size())),
+        }
+        // A smaller window is still fine
+        assert_eq!(SharedMemory::open(&name, 1024).unwrap().size(), 1024);
+        let existing = SharedMemory::open_existing(&name).unwrap();
+        assert_eq!(existing.size(), 4096);
+        assert_eq!(existing.read_owned_at(0, 7).unwrap(), b"adopted");
+        // The read-only opener checks the same way
+        match SharedMemory::open_sealed(&name, 8192) {
+            #[cfg(target_os = "linux")]
+            Err(Error::SizeMismatch { requested: 8192, actual: 4096 }) => {}
+            #[cfg(target_os = "windows")]
+            Err(_) => {}
+            other => panic!("expected a size mismatch, got {:?}", other.map(|shm| shm.size())),
+        }
+        assert_eq!(SharedMemory::open_sealed(&name, 4096).unwrap().read_owned_at(0, 7).unwrap(), b"adopted");
+    }
+
+    #[test]
+    fn openers_never_see_a_half_initialized_segment() {
+        let payload: Vec<u8> = (0..60_000u32).map(|byte| (byte % 251) as u8 + 1).collect();
+        for round in 0..20 {
+            let name = format!("/create_with_data.{}.{}", std::process::id(), round);
+            let opener = {
+                let (name, payload) = (name.clone(), payload.clone());
+                std::thread::spawn(move || loop {
+                    match SharedMemory::open_initialized(&name, 65536) {
+                        Ok(shm) => return assert_eq!(shm.initial_data().unwrap(), payload),
+                        Err(crate::shared_memory::Error::Io(err)) if err.kind() == std::io::ErrorKind::NotFound => {}
+                        Err(err) => panic!("{}", err),
+                    }
+                })
+            };
+            let shm = SharedMemory::create_with_data(&name, 65536, &payload).unwrap();
+            opener.join().unwrap();
+            assert!(SharedMemory::create_with_data(&name, 65536, &payload).is_err());
+            drop(shm);
+        }
+    }
+
+    #[test]
+    fn swapped_halves_read_consistently_while_current() {
+        use crate::swap::ShmSwap;
+        use std::sync::atomic::{AtomicUsize, Ordering};
+
+        let (shm, name) = SharedMemory::create_unique("swap", 8192).unwrap();
+        let mut swap = ShmSwap::init(&shm, 64, 1024).unwrap();
+        assert_eq!(swap.active(), (&[0u8; 1024][..], 0));
+        let consistent = std::sync::Arc::new(AtomicUsize::new(0));
+        let reader = {
+            let consistent = consistent.clone();
+            std::thread::spawn(move || {
+                let shm = SharedMemory::open(&name, 8192).unwrap();
+                let swap = ShmSwap::attach(&shm, 64, 1024).unwrap();
+                while consistent.load(Ordering::Relaxed) < 10_000 {
+                    let (half, version) = swap.active();
+                    let copy = half.to_vec();
+                    if swap.is_current(version) {
+                        // Every byte of a committed half is the low byte of its version
+                        assert!(copy.iter().all(|&byte| byte == version as u8), "torn half at version {}", version);
+                        consistent.fetch_add(1, Ordering::Relaxed);
+                    }
+                }
+            })
+        };
+        let mut version = 0;
+        while !reader.is_finished() {
+            swap.stage().fill((version + 1) as u8);
+            version = swap.commit();
+        }
+        reader.join().unwrap();
+        assert_eq!(swap.active().1, version);
+        assert!(swap.active().0.iter().all(|&byte| byte == version as u8));
+        assert!(ShmSwap::attach(&shm, 64, 512).is_err());
+    }
+
+    #[test]
+    fn slice_copies_match_a_model_of_the_mapping() {
+        let (shm, _name) = SharedMemory::create_unique("copies", 4096).unwrap();
+        let size = shm.size() as usize;
+        let mut model = vec![0u8; size];
+        // Seeded xorshift so a failure replays the same sequence
+        let mut state = 0x2545_f491_4f6c_dd1du64;
+        let mut next = move || {
+            state ^= state << 13;
+            state ^= state >> 7;
+            state ^= state << 17;
+            state
+        };
+        for _ in 0..2000 {
+            // Mostly ranges near the end of the mapping, where the bounds checks decide something
+            let offset = match next() % 8 {
+                0 => usize::MAX - (next() % 64) as usize,
+                1 => size + (next() % 64) as usize,
+                _ => (next() % (size as u64 + 1)) as usize,
+            };
+            let len = match next() % 8 {
+                0 => 0,
+                1 => usize::MAX - (next() % 64) as usize,
+                _ => (next() % 256) as usize,
+            };
+            let fits = offset.checked_add(len).is_some_and(|end| end <= size);
+            if next() % 2 == 0 {
+                if len > 4096 {
+                    assert!(!fits);
+                    continue;
+                }
+                let src: Vec<u8> = (0..len).map(|_| next() as u8).collect();
+                assert_eq!(shm.copy_from_slice_at(offset, &src).is_ok(), fits, "write of {} at {}", len, offset);
+                if fits {
+                    model[offset..offset + len].copy_from_slice(&src);
+                }
+            } else if len <= 4096 {
+                let mut dst = vec![0xa5u8; len];
+                assert_eq!(shm.copy_to_slice_at(offset, &mut dst).is_ok(), fits, "read of {} at {}", len, offset);
+                if fits {
+                    assert_eq!(dst, model[offset..offset + len]);
+                } else {
+                    assert!(dst.iter().all(|&byte| byte == 0xa5));
+                }
+            } else {
+                assert!(shm.read_owned_at(offset, len).is_err());
+            }
+            if offset < size || next() % 4 == 0 {
+                let byte = next() as u8;
+                let fits = offset < size;
+                match next() % 3 {
+                    0 => assert_eq!(shm.get_byte(offset).ok(), fits.then(|| model[offset])),
+                    1 => assert_eq!(shm.set_byte(offset, byte).is_ok(), fits),
+                    _ => assert_eq!(shm.update_byte(offset, |old| old ^ byte).ok(), fits.then(|| model[offset] ^ byte)),
+                }
+                if fits {
+                    model[offset] = shm.get_byte(offset).unwrap();
+                }
+            }
+            assert_eq!(shm.snapshot(), model);
+        }
+        assert!(shm.set_byte(size - 1, 9).is_ok());
+        assert_eq!(shm.update_byte(size - 1, |old| old + 1).unwrap(), 10);
+        assert!(matches!(shm.get_byte(size), Err(crate::shared_memory::Error::OutOfBounds { offset, len: 1, .. }) if offset == size));
+        assert!(shm.set_byte(size, 9).is_err());
+
+        let long = vec![7u8; size * 3 / 4];
+        shm.write_data(&long);
+        assert_eq!(shm.read_data(), &long[..]);
+        shm.write_data(&vec![7u8; size + 1]);
+        assert_eq!(shm.read_data(), &long[..]);
+        assert!(matches!(shm.try_write_data(&vec![7u8; size + 1]), Err(crate::shared_memory::Error::OutOfBounds { offset: 0, .. })));
+        shm.try_write_data(b"short").unwrap();
+        assert_eq!(shm.read_data(), b"short");
+    }
+
+    #[test]
+    fn framed_writes_match_a_model_of_the_mapping() {
+        use crate::shared_memory::{NOTIFY_HEADER, WaitError};
+        use std::time::Duration;
+
+        let (mut shm, name) = SharedMemory::create_unique("framing", 4096).unwrap();
+        let reader = SharedMemory::open(&name, 4096).unwrap();
+        let size = shm.size() as usize;
+        let mut model = vec![0u8; size];
+        let mut state = 0x9e37_79b9_7f4a_7c15u64;
+        let mut next = move || {
+            state ^= state << 13;
+            state ^= state >> 7;
+            state ^= state << 17;
+            state
+        };
+        for step in 0..3000 {
+            // Lengths cluster around what is left of the mapping, exactly filling it included
+            let offset = match next() % 8 {
+                0 => usize::MAX - (next() % 16) as usize,
+                1 => size - (next() % 24) as usize,
+                _ => (next() % (size as u64 + 8)) as usize,
+            };
+            let room = size.saturating_sub(offset);
+            let len = match next() % 6 {
+                0 => 0,
+                1 => room,
+                2 => room + 1,
+                _ => (next() % 300) as usize,
+            };
+            let payload: Vec<u8> = (0..len.min(size + 1)).map(|_| next() as u8 | 1).collect();
+            let case = format!("step {} offset {} len {}", step, offset, payload.len());
+            let fits = |len: usize| offset.checked_add(len).is_some_and(|end| end <= size);
+            match next() % 6 {
+                0 => {
+                    let value = next();
+                    assert_eq!(shm.write_u64_le(offset, value).is_ok(), fits(8), "{}", case);
+                    if fits(8) {
+                        model[offset..offset + 8].copy_from_slice(&value.to_le_bytes());
+                    }
+                    let expected = fits(4).then(|| u32::from_le_bytes(model[offset..offset + 4].try_into().unwrap()));
+                    assert_eq!(shm.read_u32_le(offset).ok(), expected, "{}", case);
+                }
+                1 => {
+                    shm.write_data(&payload);
+                    if payload.len() <= size {
+                        model[..payload.len()].copy_from_slice(&payload);
+                        model[payload.len()..].fill(0);
+                    }
+                    let end = model.iter().rposition(|&byte| byte != 0).map_or(0, |last| last + 1);
+                    assert_eq!(reader.read_data_owned(), model[..end], "{}", case);
+                }
+                2 => {
+                    // The other steps scribble over the generation word, which can bring it back to one a
+                    // long-lived reader already returned, so every message gets a reader of its own
+                    let reader = SharedMemory::open(&name, 4096).unwrap();
+                    reader.read_message_blocking(Some(Duration::ZERO)).ok();
+                    let fits = NOTIFY_HEADER + payload.len() <= size;
+                    assert_eq!(shm.write_message_notify(&payload).is_ok(), fits, "{}", case);
+                    if fits {
+                        model[4..8].copy_from_slice(&std::process::id().to_le_bytes());
+                        model[8..16].copy_from_slice(&(payload.len() as u64).to_le_bytes());
+                        model[NOTIFY_HEADER..NOTIFY_HEADER + payload.len()].copy_from_slice(&payload);
+                        assert_eq!(reader.read_message_blocking(Some(Duration::ZERO)).unwrap(), payload, "{}", case);
+                    }
+                    assert!(matches!(reader.read_message_blocking(Some(Duration::ZERO)), Err(WaitError::TimedOut)), "{}", case);
+                    // The generation word is the writer's to keep, take it from the mapping
+                    model[..4].copy_from_slice(&shm.read_owned_at(0, 4).unwrap());
+                }
+                3 => {
+                    let chunk = (next() % 64) as usize + 1;
+                    assert_eq!(shm.write_at_parallel(offset, &payload, chunk).is_ok(), fits(payload.len()), "{}", case);
+                    if fits(payload.len()) {
+                        model[offset..offset + payload.len()].copy_from_slice(&payload);
+                    }
+                    let mut dst = vec![0u8; payload.len()];
+                    assert_eq!(reader.read_into_parallel(offset, &mut dst, chunk).is_ok(), fits(payload.len()), "{}", case);
+                    if fits(payload.len()) {
+                        assert_eq!(dst, model[offset..offset + payload.len()], "{}", case);
+                    }
+                }
+                #[cfg(feature = "xxhash")]
+                4 => {
+                    use crate::shared_memory::BLOB_HEADER;
+                    let fits = fits(BLOB_HEADER + payload.len());
+                    assert_eq!(shm.write_blob_hashed(offset, &payload).is_ok(), fits, "{}", case);
+                    if fits {
+                        model[offset..offset + 8].copy_from_slice(&(payload.len() as u64).to_le_bytes());
+                        model[offset + 8..offset + 16].copy_from_slice(&xxhash_rust::xxh3::xxh3_64(&payload).to_le_bytes());
+                        model[offset + BLOB_HEADER..offset + BLOB_HEADER + payload.len()].copy_from_slice(&payload);
+                        assert_eq!(reader.read_blob_hashed(offset).unwrap(), payload, "{}", case);
+                    }
+                }
+                _ => {
+                    let expected = fits(payload.len()).then(|| model[offset..offset + payload.len()].to_vec());
+                    assert_eq!(reader.read_owned_at(offset, payload.len()).ok(), expected, "{}", case);
+                }
+            }
+            assert!(shm.snapshot() == model, "mapping and model differ after {}", case);
+        }
+    }
+
+    #[test]
+    fn chunks_cover_the_mapping_in_order() {
+        let (mut shm, _name) = SharedMemory::create_unique("chunks", 10_000).unwrap();
+        let size = shm.size() as usize;
+        std::thread::scope(|scope| {
+            for (index, chunk) in shm.chunks_mut(3000).enumerate() {
+                scope.spawn(move || chunk.fill(index as u8 + 1));
+            }
+        });
+        let expected: Vec<u8> = (0..size).map(|offset| (offset / 3000) as u8 + 1).collect();
+        assert_eq!(shm.snapshot(), expected);
+        assert_eq!(shm.chunks(3000).next_back().map(<[u8]>::len), Some(size - size / 3000 * 3000));
+        assert_eq!(shm.chunks(3000).flatten().copied().collect::<Vec<u8>>(), expected);
+        assert_eq!(shm.chunks(size * 2).count(), 1);
+        assert!(std::panic::catch_unwind(std::panic::AssertUnwindSafe(|| shm.chunks(0).count())).is_err());
+    }
+
+    #[test]
+    fn markers_are_found_wherever_they_are_planted() {
+        let (shm, _name) = SharedMemory::create_unique("find", 8192).unwrap();
+        let size = shm.size() as usize;
+        let marker = b"SHMK";
+        for offset in [0, size / 2 + 3, size - marker.len()] {
+            shm.copy_from_slice_at(offset, marker).unwrap();
+        }
+        let expected = vec![0, size / 2 + 3, size - marker.len()];
+        assert_eq!(shm.find_all(marker).collect::<Vec<usize>>(), expected);
+        assert_eq!(shm.find(marker, 1), Some(size / 2 + 3));
+        assert_eq!(shm.find(marker, size - marker.len() + 1), None);
+        assert_eq!(shm.rfind(marker), Some(size - marker.len()));
+        assert_eq!(shm.find(b"K", 0), Some(3));
+        assert_eq!(shm.rfind(b"S"), Some(size - marker.len()));
+        assert_eq!(shm.find(b"SHMKS", 0), None);
+
+        // overlapping matches all count
+        shm.copy_from_slice_at(100, b"aaaa").unwrap();
+        assert_eq!(shm.find_all(b"aa").collect::<Vec<usize>>(), [100, 101, 102]);
+
+        assert_eq!(shm.find(b"", 5), Some(5));
+        assert_eq!(shm.find(b"", size), Some(size));
+        assert_eq!(shm.find(b"", size + 1), None);
+        assert_eq!(shm.rfind(b""), Some(size));
+        assert_eq!(shm.find(&vec![0u8; size + 1], 0), None);
+        assert_eq!(shm.rfind(&vec![0u8; size + 1]), None);
+    }
+
+    #[test]
+    fn aligned_regions_land_on_aligned_addresses() {
+        use crate::shared_memory::align_offset_up;
+
+        assert_eq!(align_offset_up(0, 64).unwrap(), 0);
+        assert_eq!(align_offset_up(1, 64).unwrap(), 64);
+        assert_eq!(align_offset_up(4096, 4096).unwrap(), 4096);
+        assert_eq!(align_offset_up(4097, 4096).unwrap(), 8192);
+        assert!(align_offset_up(8, 48).is_err());
+        assert!(align_offset_up(8, 0).is_err());
+        assert!(align_offset_up(usize::MAX - 2, 64).is_err());
+
+        let (shm, _name) = SharedMemory::create_unique("aligned", 65536).unwrap();
+        let base = shm.address() as usize;
+        for (offset, align) in [(0, 64), (1, 64), (100, 64), (1, 4096), (4097, 4096)] {
+            let aligned = shm.aligned_region(offset, 64, align).unwrap();
+            assert!(aligned >= offset && aligned - offset < align);
+            assert_eq!((base + aligned) % align, 0, "offset {} aligned to {}", offset, align);
+        }
+        assert!(shm.aligned_region(65536 - 64, 64, 64).is_ok());
+        assert!(shm.aligned_region(65536 - 63, 64, 64).is_err());
+        assert!(shm.aligned_region(0, 65537, 1).is_err());
+        assert!(shm.aligned_region(usize::MAX, 1, 64).is_err());
+        assert!(shm.aligned_region(0, 8, 3).is_err());
+    }
+
+    #[test]
+    fn parallel_copies_match_serial_ones() {
+        let (mut shm, _name) = SharedMemory::create_unique("parallel", 1 << 20).unwrap();
+        let data: Vec<u8> = (0..(1 << 20) - 4096).map(|index: usize| (index * 31 % 251) as u8).collect();
+        for min_chunk in [0, 1000, 1 << 19, 1 << 21] {
+            shm.write_data(&[]);
+            shm.write_at_parallel(4096, &data, min_chunk).unwrap();
+            let mut serial = vec![0u8; data.len()];
+            shm.copy_to_slice_at(4096, &mut serial).unwrap();
+            assert_eq!(serial, data);
+            let mut parallel = vec![0u8; data.len()];
+            shm.read_into_parallel(4096, &mut parallel, min_chunk).unwrap();
+            assert_eq!(parallel, data);
+            assert!(shm.chunks(4096).next().unwrap().iter().all(|&byte| byte == 0));
+        }
+        assert!(shm.write_at_parallel(4097, &data, 1000).is_err());
+        assert!(shm.read_into_parallel(usize::MAX, &mut [0u8; 8], 1).is_err());
+    }
+
+    /// Timing comparison rather than a check, run with `cargo test --release -- --ignored --nocapture`
+    #[test]
+    #[ignore]
+    fn parallel_copy_against_serial_for_512_mib() {
+        const LEN: usize = 512 << 20;
+        let (mut shm, _name) = SharedMemory::create_unique("parallel-bench", LEN as i32).unwrap();
+        let data = vec![0x5au8; LEN];
+        let started = std::time::Instant::now();
+        shm.copy_from_slice_at(0, &data).unwrap();
+        let serial = started.elapsed();
+        let started = std::time::Instant::now();
+        shm.write_at_parallel(0, &data, 16 << 20).unwrap();
+        let parallel = started.elapsed();
+        assert!(shm.content_eq_slice(&data));
+        println!("512 MiB: serial {:?}, parallel {:?}", serial, parallel);
+    }
+
+    #[test]
+    fn indexing_matches_the_checked_accessors() {
+        let (mut shm, _name) = SharedMemory::create_unique("index", 4096).unwrap();
+        let size = shm.size() as usize;
+        shm[..].fill(3);
+        assert_eq!(shm.snapshot(), vec![3u8; size]);
+        shm[size - 1] = 9;
+        shm[16..24].copy_from_slice(&7u64.to_le_bytes());
+        assert_eq!(shm.read_u64_le(16).unwrap(), 7);
+        assert_eq!(shm.get_byte(size - 1).unwrap(), 9);
+        assert_eq!(shm[size - 1], 9);
+        assert!(shm[size..].is_empty());
+        assert!(shm[5..5].is_empty());
+        assert_eq!(shm[..].get(size), None);
+    }
+
+    #[test]
+    #[should_panic]
+    fn indexing_past_the_end_panics() {
+        let (shm, _name) = SharedMemory::create_unique("index_oob", 4096).unwrap();
+        let _ = shm[shm.size() as usize];
+    }
+
+    #[test]
+    fn os_errors_name_the_segment_and_operation() {
+        use crate::shared_memory::{Operation, OsError};
+
+        let name = format!("/missing-{}", std::process::id());
+        let err = SharedMemory::open(&name, 65536).err().unwrap();
+        assert_eq!(err.kind(), std::io::ErrorKind::NotFound);
+        #[cfg(target_os = "linux")]
+        assert_eq!(err.to_string(), format!("failed to shm_open segment {:?} (65536 bytes): {}", name, std::io::Error::from_raw_os_error(libc::ENOENT)));
+        let context = err.get_ref().and_then(|inner| inner.downcast_ref::<OsError>()).unwrap();
+        #[cfg(target_os = "linux")]
+        assert_eq!(context.operation, Operation::ShmOpen);
+        #[cfg(target_os = "windows")]
+        assert_eq!(context.operation, Operation::OpenFileMapping);
+        assert_eq!((context.segment.as_str(), context.size), (name.as_str(), Some(65536)));
+        let source = std::error::Error::source(context).unwrap().downcast_ref::<std::io::Error>().unwrap();
+        assert!(source.raw_os_error().is_some());
+
+        let err = SharedMemory::open_with_offset(&name, 0, 4096).err().unwrap();
+        assert!(matches!(&err, crate::shared_memory::Error::Io(io) if io.kind() == std::io::ErrorKind::NotFound));
+        assert!(std::error::Error::source(&err).is_some());
+
+        #[cfg(target_os = "linux")]
+        {
+            let err = SharedMemory::unlink(&name).err().unwrap();
+            let context = err.get_ref().and_then(|inner| inner.downcast_ref::<OsError>()).unwrap();
+            assert_eq!((context.operation, context.segment.as_str()), (Operation::ShmUnlink, name.as_str()));
+            let err = match SharedMemory::open_sealed(&name, 4096).err().unwrap() {
+                crate::shared_memory::Error::Io(io) => io,
+                other => panic!("expected an I/O error, got {:?}", other),
+            };
+            let context = err.get_ref().and_then(|inner| inner.downcast_ref::<OsError>()).unwrap();
+            assert_eq!(context.operation, Operation::ShmOpen);
+            let err = SharedMemory::ftok(std::path::Path::new(&format!("/nonexistent{}", name)), b'S').err().unwrap();
+            assert_eq!(err.get_ref().and_then(|inner| inner.downcast_ref::<OsError>()).unwrap().operation, Operation::Ftok);
+        }
+    }
+
+    #[cfg(target_os = "linux")]
+    #[test]
+    fn futex_waits_run_their_full_timeout_through_signals() {
+        use std::sync::atomic::{AtomicBool, AtomicU32, Ordering};
+        use std::time::{Duration, Instant};
+
+        extern "C" fn ignore(_: libc::c_int) {}
+        unsafe {
+            let mut action: libc::sigaction = std::mem::zeroed();
+            action.sa_sigaction = ignore as *const () as libc::sighandler_t;
+            // No SA_RESTART, so every signal interrupts the wait with EINTR
+            action.sa_flags = 0;
+            libc::sigaction(libc::SIGUSR1, &action, std::ptr::null_mut());
+        }
+        let word = AtomicU32::new(0);
+        let done = AtomicBool::new(false);
+        let (sender, receiver) = std::sync::mpsc::channel();
+        std::thread::scope(|scope| {
+            let waiter = scope.spawn(|| {
+                sender.send(unsafe { libc::pthread_self() }).unwrap();
+                let started = Instant::now();
+                let timed_out = !crate::futex::wait(&word, 0, Some(Duration::from_millis(200)));
+                done.store(true, Ordering::Relaxed);
+                (timed_out, started.elapsed())
+            });
+            let thread = receiver.recv().unwrap();
+            while !done.load(Ordering::Relaxed) {
+                unsafe { libc::pthread_kill(thread, libc::SIGUSR1) };
+                std::thread::sleep(Duration::from_millis(5));
+            }
+            let (timed_out, elapsed) = waiter.join().unwrap();
+            assert!(timed_out);
+            assert!(elapsed >= Duration::from_millis(200), "returned after {:?}", elapsed);
+        });
+    }
+
+    #[test]
+    fn hashing_keeps_multibyte_characters_whole() {
+        let short = hashed(Platform::MacOs, &"é".repeat(40));
+        assert!(short.len() <= 31);
+        assert!(short.starts_with("/éééééééé~"), "{}", short);
+    }
+
+    #[test]
+    fn byte_access_holds_at_the_exact_limits() {
+        use crate::shared_memory::Error;
+
+        let (shm, _name) = SharedMemory::create_unique("byte_limits", 4096).unwrap();
+        let size = shm.size() as usize;
+        // Zero-length accesses are fine anywhere up to the end, one past it is not
+        assert!(shm.copy_from_slice_at(size, &[]).is_ok());
+        assert!(shm.copy_to_slice_at(size, &mut []).is_ok());
+        assert_eq!(shm.read_owned_at(size, 0).unwrap(), b"");
+        assert!(matches!(shm.copy_from_slice_at(size + 1, &[]), Err(Error::OutOfBounds { len: 0, .. })));
+        assert!(shm.copy_to_slice_at(size + 1, &mut []).is_err());
+        // The last byte of the mapping
+        shm.set_byte(size - 1, 0xfe).unwrap();
+        assert_eq!(shm.get_byte(size - 1).unwrap(), 0xfe);
+        assert_eq!(shm.update_byte(size - 1, |old| old + 1).unwrap(), 0xff);
+        assert_eq!(shm.read_owned_at(size - 1, 1).unwrap(), [0xff]);
+        // The end of the mapping and one past it, without reaching the closure
+        for offset in [size, size + 1, usize::MAX] {
+            assert!(matches!(shm.get_byte(offset), Err(Error::OutOfBounds { offset: found, len: 1, size: 4096 }) if found == offset));
+            assert!(matches!(shm.set_byte(offset, 1), Err(Error::OutOfBounds { len: 1, .. })));
+            assert!(shm.update_byte(offset, |_| panic!("called for offset {}", offset)).is_err());
+        }
+        assert_eq!(shm.get_byte(size - 1).unwrap(), 0xff);
+    }
 }
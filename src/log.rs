@@ -0,0 +1,286 @@
+//! Append-only record log inside a segment that any process can replay from a given offset
+//!
+//! Records are addressed by logical offsets that only ever grow, the first byte of the data area sits at the logical
+//! head. Every record is a little-endian `u32` length and CRC-32 of the payload followed by the payload itself.
+//! Appending, reading and truncating all take the lock word in the header, so a process that dies in the middle of
+//! one of those leaves the log locked. A head and tail that don't describe a span of the data area, or a record
+//! length reaching past the tail, come back as [`LogError::Corrupt`] instead of being followed.
+
+use std::sync::atomic::{AtomicU32, AtomicU64, Ordering};
+use std::{fmt, io, ptr};
+
+use crate::futex;
+use crate::shared_memory::{Error, SharedMemory};
+
+/// "SHLG"
+const MAGIC: u32 = 0x5348_4c47;
+
+const OFF_MAGIC: usize = 0;
+const OFF_CAPACITY: usize = 8;
+const OFF_LOCK: usize = 64;
+const OFF_HEAD: usize = 72;
+const OFF_TAIL: usize = 80;
+const HEADER_SIZE: usize = 128;
+
+const RECORD_HEADER: usize = 8;
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum LogError {
+    /// There isn't room for the record until the log is truncated
+    Full,
+    /// The offset was already dropped by [`ShmLog::truncate_before`]
+    Truncated { offset: u64, head: u64 },
+    /// The offset is past the tail or doesn't start a record
+    InvalidOffset { offset: u64 },
+    /// The record at the offset doesn't match its length or checksum
+    Corrupt { offset: u64 },
+}
+
+impl fmt::Display for LogError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            LogError::Full => write!(f, "log is full"),
+            LogError::Truncated { offset, head } => {
+                write!(f, "offset {} was truncated away, the log now starts at {}", offset, head)
+            }
+            LogError::InvalidOffset { offset } => write!(f, "offset {} isn't the start of a record", offset),
+            LogError::Corrupt { offset } => write!(f, "record at offset {} is corrupt", offset),
+        }
+    }
+}
+
+impl std::error::Error for LogError {}
+
+const CRC_TABLE: [u32; 256] = {
+    let mut table = [0u32; 256];
+    let mut i = 0;
+    while i < 256 {
+        let mut crc = i as u32;
+        let mut bit = 0;
+        while bit < 8 {
+            crc = if crc & 1 != 0 { (crc >> 1) ^ 0xedb8_8320 } else { crc >> 1 };
+            bit += 1;
+        }
+        table[i] = crc;
+        i += 1;
+    }
+    table
+};
+
+/// CRC-32 (IEEE), same as zlib's
+fn crc32(data: &[u8]) -> u32 {
+    !data.iter().fold(!0u32, |crc, &b| CRC_TABLE[((crc ^ b as u32) & 0xff) as usize] ^ (crc >> 8))
+}
+
+pub struct ShmLog {
+    shm: SharedMemory,
+    capacity: usize,
+}
+
+// Record bytes are only touched while holding the lock word
+unsafe impl Send for ShmLog {}
+unsafe impl Sync for ShmLog {}
+
+impl ShmLog {
+    /// Creates the segment with room for `capacity` bytes of records, headers included
+    pub fn create(name: &str, capacity: usize) -> Result<Self, Error> {
+        let total = ShmLog::segment_size(capacity)?;
+        let log = ShmLog { shm: SharedMemory::create(name, total)?, capacity };
+        unsafe {
+            ptr::write_bytes(log.base(), 0, total as usize);
+        }
+        log.shm.write_u64_le(OFF_CAPACITY, capacity as u64)?;
+        log.word(OFF_MAGIC).store(MAGIC.to_le(), Ordering::Release);
+        Ok(log)
+    }
+
+    /// Opens a log created with the same `capacity` by another handle
+    pub fn open(name: &str, capacity: usize) -> Result<Self, Error> {
+        let log = ShmLog { shm: SharedMemory::open(name, ShmLog::segment_size(capacity)?)?, capacity };
+        let magic = u32::from_le(log.word(OFF_MAGIC).load(Ordering::Acquire));
+        if magic != MAGIC {
+            return Err(Error::LayoutMismatch { what: "log magic", expected: MAGIC as u64, found: magic as u64 });
+        }
+        let found = log.shm.read_u64_le(OFF_CAPACITY)?;
+        if found != capacity as u64 {
+            return Err(Error::LayoutMismatch { what: "log capacity", expected: capacity as u64, found });
+        }
+        Ok(log)
+    }
+
+    fn segment_size(capacity: usize) -> Result<i32, io::Error> {
+        HEADER_SIZE
+            .checked_add(capacity)
+            .and_then(|total| i32::try_from(total).ok())
+            .ok_or_else(|| io::Error::new(io::ErrorKind::InvalidInput, "log capacity doesn't fit in a segment"))
+    }
+
+    fn base(&self) -> *mut u8 {
+        self.shm.address() as *mut u8
+    }
+
+    fn word(&self, offset: usize) -> &AtomicU32 {
+        unsafe { &*(self.base().add(offset) as *const AtomicU32) }
+    }
+
+    fn position(&self, offset: usize) -> &AtomicU64 {
+        unsafe { &*(self.base().add(offset) as *const AtomicU64) }
+    }
+
+    /// Pointer to logical offset `offset`, which has to be between head and tail
+    fn data(&self, head: u64, offset: u64) -> *mut u8 {
+        unsafe { self.base().add(HEADER_SIZE + (offset - head) as usize) }
+    }
+
+    fn read_u32(&self, head: u64, offset: u64) -> u32 {
+        let mut bytes = [0u8; 4];
+        unsafe { ptr::copy_nonoverlapping(self.data(head, offset), bytes.as_mut_ptr(), 4) };
+        u32::from_le_bytes(bytes)
+    }
+
+    pub fn capacity(&self) -> usize {
+        self.capacity
+    }
+
+    /// Offset of the oldest record still in the log
+    pub fn head(&self) -> u64 {
+        self.position(OFF_HEAD).load(Ordering::Acquire)
+    }
+
+    /// Offset the next record will be appended at
+    pub fn tail(&self) -> u64 {
+        self.position(OFF_TAIL).load(Ordering::Acquire)
+    }
+
+    /// Head and tail as the lock holder sees them, checked to describe a span of the data area
+    fn span(&self) -> Result<(u64, u64), LogError> {
+        let head = self.head();
+        let tail = self.tail();
+        if tail < head || tail - head > self.capacity as u64 {
+            return Err(LogError::Corrupt { offset: head });
+        }
+        Ok((head, tail))
+    }
+
+    /// Appends `record` and returns its offset
+    pub fn append(&self, record: &[u8]) -> Result<u64, LogError> {
+        let need = RECORD_HEADER + record.len();
+        let _lock = futex::lock(self.word(OFF_LOCK));
+        let (head, tail) = self.span()?;
+        if (tail - head) as usize + need > self.capacity || record.len() > u32::MAX as usize || tail.checked_add(need as u64).is_none() {
+            return Err(LogError::Full);
+        }
+        let mut header = [0u8; RECORD_HEADER];
+        header[..4].copy_from_slice(&(record.len() as u32).to_le_bytes());
+        header[4..].copy_from_slice(&crc32(record).to_le_bytes());
+        unsafe {
+            ptr::copy_nonoverlapping(header.as_ptr(), self.data(head, tail), RECORD_HEADER);
+            ptr::copy_nonoverlapping(record.as_ptr(), self.data(head, tail + RECORD_HEADER as u64), record.len());
+        }
+        self.position(OFF_TAIL).store(tail + need as u64, Ordering::Release);
+        Ok(tail)
+    }
+
+    /// Replays records starting at `offset` up to whatever the tail is when the iterator gets there
+    pub fn read_from(&self, offset: u64) -> LogIterator<'_> {
+        LogIterator { log: self, offset, done: false }
+    }
+
+    /// Drops every record before `offset`, moving the rest to the front of the data area to free the space
+    ///
+    /// `offset` has to start a record or be the tail, offsets at or before the head are a no-op
+    pub fn truncate_before(&self, offset: u64) -> Result<(), LogError> {
+        let _lock = futex::lock(self.word(OFF_LOCK));
+        let (head, tail) = self.span()?;
+        if offset <= head {
+            return Ok(());
+        }
+        let mut pos = head;
+        while pos < offset && pos < tail {
+            // A record running past the tail would carry `pos` outside the data area
+            if tail - pos < RECORD_HEADER as u64 || self.read_u32(head, pos) as u64 > tail - pos - RECORD_HEADER as u64 {
+                return Err(LogError::Corrupt { offset: pos });
+            }
+            pos += (RECORD_HEADER + self.read_u32(head, pos) as usize) as u64;
+        }
+        if pos != offset {
+            return Err(LogError::InvalidOffset { offset });
+        }
+        unsafe {
+            ptr::copy(self.data(head, offset), self.data(head, head), (tail - offset) as usize);
+        }
+        self.position(OFF_HEAD).store(offset, Ordering::Release);
+        Ok(())
+    }
+
+    /// Copies the record at `offset` out under the lock, returning it with the offset of the one after it
+    fn read_record(&self, offset: u64) -> Option<Result<(Vec<u8>, u64), LogError>> {
+        let _lock = futex::lock(self.word(OFF_LOCK));
+        let (head, tail) = match self.span() {
+            Ok(span) => span,
+            Err(err) => return Some(Err(err)),
+        };
+        if offset == tail {
+            return None;
+        }
+        if offset < head {
+            return Some(Err(LogError::Truncated { offset, head }));
+        }
+        if offset > tail || tail - offset < RECORD_HEADER as u64 {
+            return Some(Err(LogError::InvalidOffset { offset }));
+        }
+        let len = self.read_u32(head, offset) as usize;
+        let crc = self.read_u32(head, offset + 4);
+        if len as u64 > tail - offset - RECORD_HEADER as u64 {
+            return Some(Err(LogError::Corrupt { offset }));
+        }
+        let next = offset + (RECORD_HEADER + len) as u64;
+        let mut record = vec![0u8; len];
+        unsafe {
+            ptr::copy_nonoverlapping(self.data(head, offset + RECORD_HEADER as u64), record.as_mut_ptr(), len);
+        }
+        if crc32(&record) != crc {
+            return Some(Err(LogError::Corrupt { offset }));
+        }
+        Some(Ok((record, next)))
+    }
+}
+
+/// Yields `(offset, record)` pairs, stopping after the first error
+pub struct LogIterator<'a> {
+    log: &'a ShmLog,
+    offset: u64,
+    done: bool,
+}
+
+impl LogIterator<'_> {
+    /// Offset of the next record the iterator will read
+    pub fn offset(&self) -> u64 {
+        self.offset
+    }
+}
+
+impl Iterator for LogIterator<'_> {
+    type Item = Result<(u64, Vec<u8>), LogError>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        if self.done {
+            return None;
+        }
+        if self.offset > self.log.tail() {
+            self.done = true;
+            return Some(Err(LogError::InvalidOffset { offset: self.offset }));
+        }
+        match self.log.read_record(self.offset)? {
+            Ok((record, next)) => {
+                let offset = self.offset;
+                self.offset = next;
+                Some(Ok((offset, record)))
+            }
+            Err(err) => {
+                self.done = true;
+                Some(Err(err))
+            }
+        }
+    }
+}
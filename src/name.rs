@@ -0,0 +1,231 @@
+//! Turning the logical name a caller passes to a constructor into the name the platform wants
+//!
+//! A logical name like `my_app.frames` works on every platform: POSIX gets `/my_app.frames`, Windows gets it as it
+//! is, and a leading `/` that POSIX callers are used to is accepted and dropped on Windows. The rules for each
+//! platform are plain functions of the name, so all of them can be checked on any host.
+//!
+//! Names over the platform's length limit are rejected unless the constructor is given
+//! [`NamePolicy::HashWhenTooLong`], which swaps them for a shortened prefix and a hash of the whole name. The hash
+//! is fixed, not `std`'s randomly keyed one, so every process and build shortens a name the same way.
+
+use std::ffi::CString;
+use std::{fmt, io};
+
+/// Longest name `shm_open` accepts on Linux, not counting the leading `/`
+const LINUX_MAX: usize = 255;
+/// Longest name `shm_open` accepts on macOS (`PSHMNAMLEN`), counting the leading `/`
+const MACOS_MAX: usize = 31;
+/// Longest name `CreateFileMappingA` accepts (`MAX_PATH`), counting any `Global\` or `Local\` prefix
+const WINDOWS_MAX: usize = 260;
+
+const WINDOWS_PREFIXES: [&str; 2] = ["Global\\", "Local\\"];
+
+/// Characters of the base32 hash a shortened name ends with, 64 bits at 5 per character
+const HASH_CHARS: usize = 13;
+/// `~` then the hash
+const HASH_SUFFIX: usize = 1 + HASH_CHARS;
+
+/// What to do with a name that is too long for the platform
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum NamePolicy {
+    /// Fail with [`InvalidName::TooLong`]
+    #[default]
+    Reject,
+    /// Keep as much of the start of the name as fits and end it with `~` and a base32 hash of the full name
+    HashWhenTooLong,
+}
+
+/// Why a logical name can't be used for a segment
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum InvalidName {
+    /// Nothing is left once the leading `/` or namespace prefix is taken off
+    Empty,
+    /// There is a NUL byte at `position`
+    ContainsNul { position: usize },
+    /// There is a `/` at `position`, POSIX only allows one at the start
+    ContainsSlash { position: usize },
+    /// There is a `\` at `position`, Windows only allows the one ending a `Global\` or `Local\` prefix
+    ContainsBackslash { position: usize },
+    /// The platform name would be `len` bytes, more than the platform's `max`
+    TooLong { len: usize, max: usize },
+    /// The name is `.` or `..`, which as a file in a backing directory would be a directory instead
+    Dots,
+}
+
+impl fmt::Display for InvalidName {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            InvalidName::Empty => write!(f, "segment name is empty"),
+            InvalidName::ContainsNul { position } => write!(f, "segment name has a NUL byte at {}", position),
+            InvalidName::ContainsSlash { position } => {
+                write!(f, "segment name has a '/' at {}, only a leading one is allowed", position)
+            }
+            InvalidName::ContainsBackslash { position } => {
+                write!(f, "segment name has a '\\' at {}, only a Global\\ or Local\\ prefix may have one", position)
+            }
+            InvalidName::TooLong { len, max } => {
+                write!(f, "segment name is {} bytes on this platform, the limit is {}", len, max)
+            }
+            InvalidName::Dots => write!(f, "segment name can't be . or .. when it names a file"),
+        }
+    }
+}
+
+impl std::error::Error for InvalidName {}
+
+impl From<InvalidName> for io::Error {
+    fn from(err: InvalidName) -> Self {
+        io::Error::new(io::ErrorKind::InvalidInput, err)
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) enum Platform {
+    Linux,
+    /// Every Apple target, iOS, tvOS and watchOS share macOS's `PSHMNAMLEN`
+    MacOs,
+    Windows,
+}
+
+impl Platform {
+    pub(crate) fn current() -> Platform {
+        if cfg!(target_os = "windows") {
+            Platform::Windows
+        } else if cfg!(target_vendor = "apple") {
+            Platform::MacOs
+        } else {
+            Platform::Linux
+        }
+    }
+}
+
+/// A name ready to hand to `shm_open` or `CreateFileMappingA`
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub(crate) struct PlatformName(CString);
+
+impl PlatformName {
+    pub(crate) fn into_c_string(self) -> CString {
+        self.0
+    }
+}
+
+/// Normalizes `name` for the platform this was compiled for
+pub(crate) fn normalize_name(name: &str, policy: NamePolicy) -> Result<PlatformName, InvalidName> {
+    normalize_for(Platform::current(), name, policy)
+}
+
+/// Normalizes `name` into the name of a file in a backing directory: the Linux shm name without its leading `/`
+#[cfg(target_os = "linux")]
+pub(crate) fn file_name(name: &str, policy: NamePolicy) -> Result<String, InvalidName> {
+    let normalized = normalize_for(Platform::Linux, name, policy)?.into_c_string().into_string().expect("names start out as a str");
+    match &normalized[1..] {
+        "." | ".." => Err(InvalidName::Dots),
+        file => Ok(file.to_string()),
+    }
+}
+
+pub(crate) fn normalize_for(platform: Platform, name: &str, policy: NamePolicy) -> Result<PlatformName, InvalidName> {
+    if let Some(position) = name.find('\0') {
+        return Err(InvalidName::ContainsNul { position });
+    }
+    let normalized = match platform {
+        Platform::Linux | Platform::MacOs => posix_name(platform, name),
+        Platform::Windows => windows_name(name),
+    };
+    let normalized = match (normalized, policy) {
+        (Err(InvalidName::TooLong { .. }), NamePolicy::HashWhenTooLong) => shortened(platform, name),
+        (normalized, _) => normalized?,
+    };
+    Ok(PlatformName(CString::new(normalized).expect("NUL bytes were checked for")))
+}
+
+/// `name`, already known to be valid apart from its length, cut down to fit with a hash of all of it at the end
+fn shortened(platform: Platform, name: &str) -> String {
+    let unslashed = name.strip_prefix('/').unwrap_or(name);
+    let (lead, body, budget) = match platform {
+        Platform::Linux => ("/", unslashed, LINUX_MAX),
+        Platform::MacOs => ("/", unslashed, MACOS_MAX - 1),
+        Platform::Windows => {
+            let prefix = WINDOWS_PREFIXES.iter().find(|prefix| unslashed.starts_with(*prefix)).map_or("", |prefix| *prefix);
+            (prefix, &unslashed[prefix.len()..], WINDOWS_MAX - prefix.len())
+        }
+    };
+    let mut keep = budget - HASH_SUFFIX;
+    while !body.is_char_boundary(keep) {
+        keep -= 1;
+    }
+    format!("{}{}~{}", lead, &body[..keep], base32(fnv1a(unslashed.as_bytes())))
+}
+
+/// `prefix` followed by `.` and a base32 rendering of `random`, with the prefix cut short if the whole name would
+/// otherwise be too long for the platform
+pub(crate) fn unique_name(platform: Platform, prefix: &str, random: u64) -> Result<String, InvalidName> {
+    let prefix = prefix.strip_prefix('/').unwrap_or(prefix);
+    let limit = match platform {
+        Platform::Linux => LINUX_MAX,
+        Platform::MacOs => MACOS_MAX - 1,
+        Platform::Windows => WINDOWS_MAX,
+    };
+    let mut keep = prefix.len().min(limit - HASH_SUFFIX);
+    while !prefix.is_char_boundary(keep) {
+        keep -= 1;
+    }
+    let name = match &prefix[..keep] {
+        "" => base32(random),
+        prefix => format!("{}.{}", prefix, base32(random)),
+    };
+    normalize_for(platform, &name, NamePolicy::Reject)?;
+    Ok(name)
+}
+
+fn fnv1a(bytes: &[u8]) -> u64 {
+    bytes.iter().fold(0xcbf2_9ce4_8422_2325, |hash, &byte| (hash ^ byte as u64).wrapping_mul(0x0100_0000_01b3))
+}
+
+/// Lowercase RFC 4648 alphabet, case matters on POSIX but not for Windows object names
+fn base32(mut value: u64) -> String {
+    const ALPHABET: &[u8; 32] = b"abcdefghijklmnopqrstuvwxyz234567";
+    let mut out = [0u8; HASH_CHARS];
+    for slot in out.iter_mut().rev() {
+        *slot = ALPHABET[(value & 31) as usize];
+        value >>= 5;
+    }
+    String::from_utf8(out.to_vec()).expect("the alphabet is ASCII")
+}
+
+fn posix_name(platform: Platform, name: &str) -> Result<String, InvalidName> {
+    let body = name.strip_prefix('/').unwrap_or(name);
+    let skipped = name.len() - body.len();
+    if body.is_empty() {
+        return Err(InvalidName::Empty);
+    }
+    if let Some(position) = body.find('/') {
+        return Err(InvalidName::ContainsSlash { position: skipped + position });
+    }
+    let normalized = format!("/{}", body);
+    let (len, max) = match platform {
+        Platform::MacOs => (normalized.len(), MACOS_MAX),
+        _ => (body.len(), LINUX_MAX),
+    };
+    if len > max {
+        return Err(InvalidName::TooLong { len, max });
+    }
+    Ok(normalized)
+}
+
+fn windows_name(name: &str) -> Result<String, InvalidName> {
+    let unslashed = name.strip_prefix('/').unwrap_or(name);
+    let prefix = WINDOWS_PREFIXES.iter().find(|prefix| unslashed.starts_with(*prefix)).map_or("", |prefix| *prefix);
+    let body = &unslashed[prefix.len()..];
+    let skipped = name.len() - body.len();
+    if body.is_empty() {
+        return Err(InvalidName::Empty);
+    }
+    if let Some(position) = body.find('\\') {
+        return Err(InvalidName::ContainsBackslash { position: skipped + position });
+    }
+    if unslashed.len() > WINDOWS_MAX {
+        return Err(InvalidName::TooLong { len: unslashed.len(), max: WINDOWS_MAX });
+    }
+    Ok(unslashed.to_string())
+}
@@ -0,0 +1,141 @@
+//! Windows kernel mutexes made with `CreateMutexA`, for sharing a lock with code that isn't Rust
+//!
+//! Ownership of a kernel mutex belongs to a thread, so the guard can't be sent to another thread and the mutex is
+//! released by the thread that locked it. Windows releases a mutex whose owning thread exits without releasing it,
+//! and hands it to the next waiter as abandoned, which [`LockError::Abandoned`] passes on so whatever the lock
+//! protects can be checked and repaired.
+
+use std::marker::PhantomData;
+use std::ptr;
+use std::time::Duration;
+use std::{fmt, io};
+
+use winapi::shared::minwindef::FALSE;
+use winapi::shared::winerror::WAIT_TIMEOUT;
+use winapi::um::handleapi::CloseHandle;
+use winapi::um::synchapi::{CreateMutexA, ReleaseMutex, WaitForSingleObject};
+use winapi::um::winbase::{OpenMutexA, INFINITE, WAIT_ABANDONED, WAIT_OBJECT_0};
+use winapi::um::winnt::{HANDLE, MUTANT_QUERY_STATE, SYNCHRONIZE};
+
+use crate::name::{normalize_name, NamePolicy};
+use crate::shared_memory::{Error, SharedMemory};
+
+/// Appended to a segment's name by [`NamedMutex::for_segment`], mutexes and file mappings share one namespace so
+/// they can't have the same name
+pub const SEGMENT_SUFFIX: &str = ".lock";
+
+/// Why a lock attempt didn't hand back a plain guard
+pub enum LockError<'a> {
+    /// The previous owner exited while holding the mutex, it's locked now but what it protects may be half updated
+    Abandoned(NamedMutexGuard<'a>),
+    /// `lock_timeout` ran out before the mutex was free
+    TimedOut,
+    Io(io::Error),
+}
+
+impl fmt::Debug for LockError<'_> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            LockError::Abandoned(_) => write!(f, "Abandoned(..)"),
+            LockError::TimedOut => write!(f, "TimedOut"),
+            LockError::Io(err) => f.debug_tuple("Io").field(err).finish(),
+        }
+    }
+}
+
+impl fmt::Display for LockError<'_> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            LockError::Abandoned(_) => write!(f, "mutex was abandoned by a thread that exited holding it"),
+            LockError::TimedOut => write!(f, "timed out waiting for the mutex"),
+            LockError::Io(err) => err.fmt(f),
+        }
+    }
+}
+
+impl std::error::Error for LockError<'_> {}
+
+pub struct NamedMutex {
+    handle: HANDLE,
+    name: String,
+}
+
+// A mutex handle can be waited on and closed from any thread, ownership is tracked by the kernel
+unsafe impl Send for NamedMutex {}
+unsafe impl Sync for NamedMutex {}
+
+/// Held lock on a [`NamedMutex`], released on drop by the thread that took it
+pub struct NamedMutexGuard<'a> {
+    mutex: &'a NamedMutex,
+    /// Keeps the guard on the owning thread, `ReleaseMutex` fails anywhere else
+    _not_send: PhantomData<*const ()>,
+}
+
+impl NamedMutex {
+    /// Opens the mutex called `name`, creating it unlocked if it doesn't exist yet
+    pub fn create(name: &str) -> Result<Self, Error> {
+        let c_name = normalize_name(name, NamePolicy::Reject)?.into_c_string();
+        let handle = unsafe { CreateMutexA(ptr::null_mut(), FALSE, c_name.as_ptr()) };
+        NamedMutex::from_handle(handle, name)
+    }
+
+    /// Opens the mutex called `name`, failing if nothing created it
+    pub fn open(name: &str) -> Result<Self, Error> {
+        let c_name = normalize_name(name, NamePolicy::Reject)?.into_c_string();
+        let handle = unsafe { OpenMutexA(SYNCHRONIZE | MUTANT_QUERY_STATE, FALSE, c_name.as_ptr()) };
+        NamedMutex::from_handle(handle, name)
+    }
+
+    /// Creates or opens the mutex for `shm`, named after the segment with [`SEGMENT_SUFFIX`] appended
+    pub fn for_segment(shm: &SharedMemory) -> Result<Self, Error> {
+        NamedMutex::create(&format!("{}{}", shm.name(), SEGMENT_SUFFIX))
+    }
+
+    fn from_handle(handle: HANDLE, name: &str) -> Result<Self, Error> {
+        if handle.is_null() {
+            return Err(io::Error::last_os_error().into());
+        }
+        Ok(NamedMutex { handle, name: name.to_string() })
+    }
+
+    pub fn name(&self) -> &str {
+        &self.name
+    }
+
+    /// Blocks until this thread owns the mutex
+    pub fn lock(&self) -> Result<NamedMutexGuard<'_>, LockError<'_>> {
+        self.wait(INFINITE)
+    }
+
+    /// Like `lock`, giving up with [`LockError::TimedOut`] after `timeout`
+    pub fn lock_timeout(&self, timeout: Duration) -> Result<NamedMutexGuard<'_>, LockError<'_>> {
+        // INFINITE is u32::MAX, so the longest finite wait is one millisecond short of it
+        self.wait(timeout.as_millis().min(INFINITE as u128 - 1) as u32)
+    }
+
+    fn wait(&self, millis: u32) -> Result<NamedMutexGuard<'_>, LockError<'_>> {
+        let guard = || NamedMutexGuard { mutex: self, _not_send: PhantomData };
+        match unsafe { WaitForSingleObject(self.handle, millis) } {
+            WAIT_OBJECT_0 => Ok(guard()),
+            WAIT_ABANDONED => Err(LockError::Abandoned(guard())),
+            WAIT_TIMEOUT => Err(LockError::TimedOut),
+            _ => Err(LockError::Io(io::Error::last_os_error())),
+        }
+    }
+}
+
+impl Drop for NamedMutex {
+    fn drop(&mut self) {
+        unsafe {
+            CloseHandle(self.handle);
+        }
+    }
+}
+
+impl Drop for NamedMutexGuard<'_> {
+    fn drop(&mut self) {
+        unsafe {
+            ReleaseMutex(self.mutex.handle);
+        }
+    }
+}
@@ -0,0 +1,161 @@
+//! POSIX named semaphores made with `sem_open`, for counting alongside code that isn't Rust
+//!
+//! Like a segment, the handle that creates a semaphore owns its name and unlinks it when dropped, while handles that
+//! opened it only close their reference. Waits that a signal interrupts are retried. Apple targets have no
+//! `sem_timedwait`, so timed waits there poll `sem_trywait` every [`MACOS_POLL`], which puts up to that much latency
+//! on a post that lands during the wait.
+
+use std::io;
+use std::time::Duration;
+
+use crate::name::{normalize_name, NamePolicy};
+use crate::shared_memory::Error;
+
+/// How often a timed wait on an Apple target checks the semaphore again
+pub const MACOS_POLL: Duration = Duration::from_millis(1);
+
+pub struct NamedSemaphore {
+    sem: *mut libc::sem_t,
+    name: String,
+    /// Unlinks the name on drop, set for the handle that created it
+    owner: bool,
+}
+
+// Every sem_* call is thread-safe on the same semaphore
+unsafe impl Send for NamedSemaphore {}
+unsafe impl Sync for NamedSemaphore {}
+
+impl NamedSemaphore {
+    /// Creates the semaphore called `name` with a count of `initial`, or opens it if it already exists
+    ///
+    /// An existing semaphore keeps its count, `initial` is ignored, and the handle doesn't own the name
+    pub fn create(name: &str, initial: u32) -> Result<Self, Error> {
+        let name_c = normalize_name(name, NamePolicy::Reject)?.into_c_string();
+        let flags = libc::O_CREAT | libc::O_EXCL;
+        let sem = unsafe { libc::sem_open(name_c.as_ptr(), flags, 0o600 as libc::c_uint, initial as libc::c_uint) };
+        if sem == libc::SEM_FAILED && io::Error::last_os_error().raw_os_error() == Some(libc::EEXIST) {
+            return NamedSemaphore::open(name);
+        }
+        NamedSemaphore::from_sem(sem, name, true)
+    }
+
+    /// Opens the semaphore called `name`, failing if nothing created it
+    pub fn open(name: &str) -> Result<Self, Error> {
+        let name_c = normalize_name(name, NamePolicy::Reject)?.into_c_string();
+        let sem = unsafe { libc::sem_open(name_c.as_ptr(), 0) };
+        NamedSemaphore::from_sem(sem, name, false)
+    }
+
+    /// Removes the name `name` from the system, handles that have it open keep working
+    pub fn unlink(name: &str) -> Result<(), Error> {
+        let name_c = normalize_name(name, NamePolicy::Reject)?.into_c_string();
+        if unsafe { libc::sem_unlink(name_c.as_ptr()) } == -1 {
+            return Err(io::Error::last_os_error().into());
+        }
+        Ok(())
+    }
+
+    fn from_sem(sem: *mut libc::sem_t, name: &str, owner: bool) -> Result<Self, Error> {
+        if sem == libc::SEM_FAILED {
+            return Err(io::Error::last_os_error().into());
+        }
+        Ok(NamedSemaphore { sem, name: name.to_string(), owner })
+    }
+
+    pub fn name(&self) -> &str {
+        &self.name
+    }
+
+    /// Whether dropping this handle unlinks the name
+    pub fn is_owner(&self) -> bool {
+        self.owner
+    }
+
+    /// Adds one to the count, waking a waiter if there is one
+    pub fn post(&self) -> io::Result<()> {
+        if unsafe { libc::sem_post(self.sem) } == -1 {
+            return Err(io::Error::last_os_error());
+        }
+        Ok(())
+    }
+
+    /// Blocks until the count is above zero and takes one from it
+    pub fn wait(&self) -> io::Result<()> {
+        while unsafe { libc::sem_wait(self.sem) } == -1 {
+            let err = io::Error::last_os_error();
+            if err.kind() != io::ErrorKind::Interrupted {
+                return Err(err);
+            }
+        }
+        Ok(())
+    }
+
+    /// Takes one from the count if it's above zero, returning whether it was
+    pub fn try_wait(&self) -> io::Result<bool> {
+        loop {
+            if unsafe { libc::sem_trywait(self.sem) } == 0 {
+                return Ok(true);
+            }
+            let err = io::Error::last_os_error();
+            match err.raw_os_error() {
+                Some(libc::EAGAIN) => return Ok(false),
+                Some(libc::EINTR) => continue,
+                _ => return Err(err),
+            }
+        }
+    }
+
+    /// Like `wait`, returning false if the count stayed at zero for all of `timeout`
+    #[cfg(target_os = "linux")]
+    pub fn wait_timeout(&self, timeout: Duration) -> io::Result<bool> {
+        // sem_timedwait takes a CLOCK_REALTIME deadline, which also keeps retries after EINTR from extending the wait
+        let mut now = libc::timespec { tv_sec: 0, tv_nsec: 0 };
+        unsafe { libc::clock_gettime(libc::CLOCK_REALTIME, &mut now) };
+        let nanos = now.tv_nsec as u64 + timeout.subsec_nanos() as u64;
+        // A timeout too long for time_t waits until the end of time_t
+        let secs = libc::time_t::try_from(timeout.as_secs().saturating_add(nanos / 1_000_000_000)).unwrap_or(libc::time_t::MAX);
+        let deadline = libc::timespec {
+            tv_sec: now.tv_sec.saturating_add(secs),
+            tv_nsec: (nanos % 1_000_000_000) as libc::c_long,
+        };
+        loop {
+            if unsafe { libc::sem_timedwait(self.sem, &deadline) } == 0 {
+                return Ok(true);
+            }
+            let err = io::Error::last_os_error();
+            match err.raw_os_error() {
+                Some(libc::ETIMEDOUT) => return Ok(false),
+                Some(libc::EINTR) => continue,
+                _ => return Err(err),
+            }
+        }
+    }
+
+    /// Like `wait`, returning false if the count stayed at zero for all of `timeout`
+    #[cfg(target_vendor = "apple")]
+    pub fn wait_timeout(&self, timeout: Duration) -> io::Result<bool> {
+        // No deadline when it's too far off for an Instant
+        let deadline = std::time::Instant::now().checked_add(timeout);
+        loop {
+            if self.try_wait()? {
+                return Ok(true);
+            }
+            let left = deadline.map_or(MACOS_POLL, |deadline| deadline.saturating_duration_since(std::time::Instant::now()));
+            if left.is_zero() {
+                return Ok(false);
+            }
+            std::thread::sleep(left.min(MACOS_POLL));
+        }
+    }
+}
+
+impl Drop for NamedSemaphore {
+    fn drop(&mut self) {
+        unsafe {
+            libc::sem_close(self.sem);
+        }
+        if self.owner {
+            let _ = NamedSemaphore::unlink(&self.name);
+        }
+    }
+}
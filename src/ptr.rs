@@ -0,0 +1,108 @@
+//! Offset pointers for linking structures inside a segment
+//!
+//! Every process maps a segment at its own address, so a raw pointer stored in it is meaningless to the others.
+//! [`ShmPtr`] stores the target's offset from the start of the mapping instead, like C++'s `offset_ptr`, and only
+//! becomes a reference again through the mapping it is resolved against.
+
+use std::fmt;
+use std::marker::PhantomData;
+
+use crate::shared_memory::{Access, Error, SharedMemory};
+use crate::shared_safe::SharedSafe;
+
+/// Offset of a `T` from the start of the mapping, stored as a `u64`
+///
+/// The stored value is the offset plus one so that zeroed memory reads as [`ShmPtr::null`]. Bounds and alignment
+/// are checked again every time it is resolved, because the value may have been written by another process.
+#[repr(transparent)]
+pub struct ShmPtr<T> {
+    raw: u64,
+    _target: PhantomData<T>,
+}
+
+impl<T> Clone for ShmPtr<T> {
+    fn clone(&self) -> Self {
+        *self
+    }
+}
+
+impl<T> Copy for ShmPtr<T> {}
+
+impl<T> PartialEq for ShmPtr<T> {
+    fn eq(&self, other: &Self) -> bool {
+        self.raw == other.raw
+    }
+}
+
+impl<T> Eq for ShmPtr<T> {}
+
+impl<T> fmt::Debug for ShmPtr<T> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self.offset() {
+            Some(offset) => write!(f, "ShmPtr({:#x})", offset),
+            None => write!(f, "ShmPtr(null)"),
+        }
+    }
+}
+
+impl<T> Default for ShmPtr<T> {
+    fn default() -> Self {
+        ShmPtr::null()
+    }
+}
+
+// Every u64 is either null or an offset that gets checked before it is used
+unsafe impl<T: SharedSafe> SharedSafe for ShmPtr<T> {}
+
+impl<T> ShmPtr<T> {
+    pub const fn null() -> Self {
+        ShmPtr { raw: 0, _target: PhantomData }
+    }
+
+    /// Points at `offset` without checking anything, that happens on `resolve`. `u64::MAX` can't be represented
+    /// and comes out as null
+    pub const fn from_offset(offset: u64) -> Self {
+        ShmPtr { raw: offset.wrapping_add(1), _target: PhantomData }
+    }
+
+    pub const fn is_null(self) -> bool {
+        self.raw == 0
+    }
+
+    /// The target's offset into the mapping, `None` for a null pointer
+    pub const fn offset(self) -> Option<u64> {
+        match self.raw {
+            0 => None,
+            raw => Some(raw - 1),
+        }
+    }
+}
+
+impl<T: SharedSafe> ShmPtr<T> {
+    /// Points at `target`, which has to lie inside `shm`'s mapping and be aligned for `T`
+    pub fn from_ref(shm: &SharedMemory, target: &T) -> Result<Self, Error> {
+        // An address below the mapping wraps to a huge offset, which the bounds check then rejects
+        let offset = (target as *const T as usize).wrapping_sub(shm.address() as usize);
+        shm.typed_ptr::<T>(offset, Access::Read)?;
+        Ok(ShmPtr::from_offset(offset as u64))
+    }
+
+    /// The target inside `shm`, `None` if the pointer is null, out of bounds or misaligned
+    ///
+    /// Like any shared reference into a segment, the `T` can still change underneath it if another process writes
+    /// to it, see [`SharedMemory::read_data`]
+    pub fn resolve<'a>(&self, shm: &'a SharedMemory) -> Option<&'a T> {
+        let ptr = shm.typed_ptr::<T>(self.checked_offset()?, Access::Read).ok()?;
+        Some(unsafe { &*ptr })
+    }
+
+    /// Like `resolve`, taking the mapping mutably so no other reference into it from this handle can exist
+    pub fn resolve_mut<'a>(&self, shm: &'a mut SharedMemory) -> Option<&'a mut T> {
+        let ptr = shm.typed_ptr::<T>(self.checked_offset()?, Access::Write).ok()?;
+        Some(unsafe { &mut *ptr })
+    }
+
+    fn checked_offset(self) -> Option<usize> {
+        usize::try_from(self.offset()?).ok()
+    }
+}
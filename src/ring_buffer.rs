@@ -0,0 +1,924 @@
+//! Variable-length message ring living inside a segment, safe for many producers and a single consumer
+//!
+//! The segment starts with a small header followed by the data area. Producers claim space by CASing the head
+//! position forward, copy their message in and then mark the record committed, so producers in different processes
+//! never block each other. The consumer copies committed records out in order and zeroes them before moving the tail
+//! on, which is what lets a producer's uncommitted record header always read as zero.
+//!
+//! A producer that dies between claiming space and committing its record leaves the ring stuck at that record. A
+//! header or record header that another process scribbled over does the same: positions that aren't on a record
+//! boundary, or a record that would run past the end of the data area, make the ring look full to producers and
+//! empty to the consumer rather than being followed outside the segment.
+//!
+//! What [`ShmRingBuffer::enqueue`] does with a full ring is the [`FullPolicy`] the creator picked, which sits in the
+//! header so every process pushing agrees on it. Overwriting the oldest messages means producers move the tail too,
+//! so on those rings the consumer and an overwriting producer take turns through a lock word in the header, while
+//! producers with room still never block each other.
+//!
+//! The magic and capacity in the header are stored little-endian, as are the geometry fields of the other
+//! structures in this crate, so a reader on a big-endian host can still recognise a segment made on x86.
+//!
+//! [`ShmRingBuffer::spawn_pump`] and [`ShmRingBuffer::spawn_feeder`] hand the ring to a thread that moves messages
+//! between it and a `std::sync::mpsc` channel, for code that would rather only see the channel.
+//!
+//! [`ShmRingBuffer::recv_async`] pops from async code instead. Futures that find the ring empty leave their waker
+//! with a watcher thread, which futex-waits on the producers' doorbell word while any are left and wakes the ones
+//! that are behind it. Any number of futures from any number of tasks can wait at once, and each message goes to one
+//! of them.
+
+use std::future::Future;
+use std::ops::{Deref, DerefMut};
+use std::pin::Pin;
+use std::sync::atomic::{AtomicBool, AtomicU32, AtomicU64, Ordering};
+use std::sync::mpsc::{Receiver, RecvTimeoutError, SyncSender};
+use std::sync::{Arc, Mutex, MutexGuard};
+use std::task::{Context, Poll, Waker};
+use std::thread::{self, JoinHandle};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+use std::{fmt, io, ptr};
+
+use crate::futex;
+use crate::shared_memory::{Error, SharedMemory};
+
+/// How long a pump or feeder thread waits on the ring or channel before checking whether it was stopped
+pub const PUMP_POLL: Duration = Duration::from_millis(10);
+
+/// "SHRB", written last by the creator so openers never see a half initialized header
+const MAGIC: u32 = 0x5348_5242;
+
+const OFF_MAGIC: usize = 0;
+const OFF_CAPACITY: usize = 8;
+const OFF_POLICY: usize = 16;
+const OFF_POLICY_TIMEOUT: usize = 24;
+// Producer side cache line
+const OFF_HEAD: usize = 64;
+const OFF_DATA_SEQ: usize = 72;
+const OFF_PUSHED: usize = 80;
+const OFF_LAST_PUSH: usize = 88;
+const OFF_HIGH_WATER: usize = 96;
+// Consumer side cache line
+const OFF_TAIL: usize = 128;
+const OFF_SPACE_SEQ: usize = 136;
+const OFF_POPPED: usize = 144;
+const OFF_LAST_POP: usize = 152;
+// Used by the channel built on top of the ring
+const OFF_SENDERS: usize = 192;
+const OFF_SENDERS_SEEN: usize = 196;
+const OFF_RECEIVER_DROPPED: usize = 200;
+// Only used by rings that overwrite the oldest message
+const OFF_TAIL_LOCK: usize = 204;
+const OFF_DROPPED: usize = 208;
+
+/// Bytes in front of the data area
+pub const HEADER_SIZE: usize = 256;
+
+const RECORD_HEADER: usize = 8;
+const COMMITTED: u32 = 1 << 31;
+/// Set on records with no message: a length of zero skips to the end of the lap, anything else skips that many bytes
+/// including the header
+const PADDING: u32 = 1 << 30;
+const LEN_MASK: u32 = PADDING - 1;
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum PushError {
+    /// There isn't room for the message until the consumer catches up
+    Full,
+    /// The message is larger than [`ShmRingBuffer::max_message_len`] and will never fit
+    TooLarge { len: usize, max: usize },
+}
+
+impl fmt::Display for PushError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            PushError::Full => write!(f, "ring buffer is full"),
+            PushError::TooLarge { len, max } => {
+                write!(f, "message of {} bytes is larger than the {} byte ring buffer limit", len, max)
+            }
+        }
+    }
+}
+
+impl std::error::Error for PushError {}
+
+/// What [`ShmRingBuffer::enqueue`] does when a message doesn't fit
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum FullPolicy {
+    /// Fail with `PushError::Full` right away
+    Reject,
+    /// Wait up to `timeout` for the consumer to make room, failing with `PushError::Full` after that
+    Block { timeout: Duration },
+    /// Drop the oldest whole messages until the new one fits, counting them in [`ShmRingBuffer::dropped`]
+    OverwriteOldest,
+}
+
+impl FullPolicy {
+    /// The policy's kind and timeout in nanoseconds as the header stores them
+    fn encode(self) -> (u32, u64) {
+        match self {
+            FullPolicy::Reject => (0, 0),
+            FullPolicy::Block { timeout } => (1, timeout.as_nanos().min(u64::MAX as u128) as u64),
+            FullPolicy::OverwriteOldest => (2, 0),
+        }
+    }
+
+    fn decode(kind: u32, timeout: u64) -> Option<Self> {
+        match kind {
+            0 => Some(FullPolicy::Reject),
+            1 => Some(FullPolicy::Block { timeout: Duration::from_nanos(timeout) }),
+            2 => Some(FullPolicy::OverwriteOldest),
+            _ => None,
+        }
+    }
+}
+
+/// Counters kept in the ring's header, as [`ShmRingBuffer::stats`] read them
+///
+/// Every field is its own relaxed load, so on a busy ring they can be a few operations apart from each other
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct RingStats {
+    /// Bytes of the data area in use, record headers, padding and open reservations included
+    pub occupancy_bytes: u64,
+    /// Messages pushed and not yet popped or dropped
+    pub occupancy_messages: u64,
+    /// Messages pushed since the ring was created
+    pub pushed: u64,
+    /// Messages popped since the ring was created
+    pub popped: u64,
+    /// Messages dropped under [`FullPolicy::OverwriteOldest`]
+    pub dropped: u64,
+    /// Most bytes ever in use at once, counted the same way as `occupancy_bytes`
+    pub high_water_bytes: u64,
+    /// When the last message was pushed, `None` before the first
+    pub last_push: Option<SystemTime>,
+    /// When the last message was popped, `None` before the first
+    pub last_pop: Option<SystemTime>,
+}
+
+/// Nanoseconds since the Unix epoch, what the header keeps timestamps as
+fn now_nanos() -> u64 {
+    SystemTime::now().duration_since(UNIX_EPOCH).map_or(0, |since| since.as_nanos() as u64)
+}
+
+fn from_nanos(nanos: u64) -> Option<SystemTime> {
+    (nanos != 0).then(|| UNIX_EPOCH + Duration::from_nanos(nanos))
+}
+
+fn record_len(len: usize) -> usize {
+    RECORD_HEADER + len.div_ceil(8) * 8
+}
+
+pub struct ShmRingBuffer {
+    shm: SharedMemory,
+    capacity: usize,
+    policy: FullPolicy,
+    waiters: Arc<Waiters>,
+}
+
+// Every shared field is only touched through atomics, and record bytes are only written by the producer that claimed
+// them or read by the single consumer, which `try_pop` enforces by taking `&mut self`
+unsafe impl Send for ShmRingBuffer {}
+unsafe impl Sync for ShmRingBuffer {}
+
+impl ShmRingBuffer {
+    /// Creates the segment and sets up an empty ring with a data area of `capacity` bytes, rounded up to a multiple of 8
+    pub fn create(name: &str, capacity: usize) -> Result<Self, Error> {
+        ShmRingBuffer::create_with_policy(name, capacity, FullPolicy::Reject)
+    }
+
+    /// Like `create`, with `policy` for what `enqueue` does when the ring is full
+    pub fn create_with_policy(name: &str, capacity: usize, policy: FullPolicy) -> Result<Self, Error> {
+        let capacity = ShmRingBuffer::data_capacity(capacity)?;
+        let total = ShmRingBuffer::segment_size(capacity)?;
+        let shm = SharedMemory::create(name, total)?;
+        let ring = ShmRingBuffer { shm, capacity, policy, waiters: Arc::default() };
+        unsafe {
+            ptr::write_bytes(ring.base(), 0, total as usize);
+        }
+        ring.shm.write_u64_le(OFF_CAPACITY, capacity as u64)?;
+        let (kind, timeout) = policy.encode();
+        ring.shm.write_u32_le(OFF_POLICY, kind)?;
+        ring.shm.write_u64_le(OFF_POLICY_TIMEOUT, timeout)?;
+        ring.word(OFF_MAGIC).store(MAGIC.to_le(), Ordering::Release);
+        Ok(ring)
+    }
+
+    /// Opens a ring created with the same `capacity` by another handle, checking the header matches and taking the
+    /// full policy from it
+    pub fn open(name: &str, capacity: usize) -> Result<Self, Error> {
+        let capacity = ShmRingBuffer::data_capacity(capacity)?;
+        let shm = SharedMemory::open(name, ShmRingBuffer::segment_size(capacity)?)?;
+        ShmRingBuffer::attach(shm, Some(capacity))
+    }
+
+    /// Opens the ring called `name` with whatever capacity its header says, for tools that only look at it
+    pub fn open_existing(name: &str) -> Result<Self, Error> {
+        ShmRingBuffer::attach(SharedMemory::open_existing(name)?, None)
+    }
+
+    fn attach(shm: SharedMemory, capacity: Option<usize>) -> Result<Self, Error> {
+        let size = shm.size() as usize;
+        if size < HEADER_SIZE {
+            return Err(Error::OutOfBounds { offset: 0, len: HEADER_SIZE, size });
+        }
+        let mut ring = ShmRingBuffer { shm, capacity: 0, policy: FullPolicy::Reject, waiters: Arc::default() };
+        let magic = u32::from_le(ring.word(OFF_MAGIC).load(Ordering::Acquire));
+        if magic != MAGIC {
+            return Err(Error::LayoutMismatch { what: "ring buffer magic", expected: MAGIC as u64, found: magic as u64 });
+        }
+        let found = ring.shm.read_u64_le(OFF_CAPACITY)?;
+        ring.capacity = match capacity {
+            Some(capacity) if found != capacity as u64 => {
+                return Err(Error::LayoutMismatch { what: "ring buffer capacity", expected: capacity as u64, found });
+            }
+            Some(capacity) => capacity,
+            // The header can't be trusted to fit in the segment without checking
+            None => match usize::try_from(found) {
+                Ok(found) if ShmRingBuffer::data_capacity(found).is_ok_and(|rounded| rounded == found) && found <= size - HEADER_SIZE => found,
+                _ => return Err(io::Error::new(io::ErrorKind::InvalidData, "ring buffer capacity doesn't fit the segment").into()),
+            },
+        };
+        let kind = ring.shm.read_u32_le(OFF_POLICY)?;
+        ring.policy = match FullPolicy::decode(kind, ring.shm.read_u64_le(OFF_POLICY_TIMEOUT)?) {
+            Some(policy) => policy,
+            None => return Err(io::Error::new(io::ErrorKind::InvalidData, "ring buffer header has an unknown full policy").into()),
+        };
+        Ok(ring)
+    }
+
+    fn data_capacity(capacity: usize) -> Result<usize, io::Error> {
+        let capacity = capacity.div_ceil(8) * 8;
+        if capacity < 2 * RECORD_HEADER {
+            return Err(io::Error::new(io::ErrorKind::InvalidInput, "ring buffer capacity is too small to hold a message"));
+        }
+        Ok(capacity)
+    }
+
+    fn segment_size(capacity: usize) -> Result<i32, io::Error> {
+        HEADER_SIZE
+            .checked_add(capacity)
+            .and_then(|total| i32::try_from(total).ok())
+            .ok_or_else(|| io::Error::new(io::ErrorKind::InvalidInput, "ring buffer capacity doesn't fit in a segment"))
+    }
+
+    /// Size of the data area in bytes, including the per-message record headers
+    pub fn capacity(&self) -> usize {
+        self.capacity
+    }
+
+    /// Largest message that can be pushed
+    ///
+    /// Records are capped at half the data area so a message can always be placed once the ring drains,
+    /// no matter where the head has wrapped to
+    pub fn max_message_len(&self) -> usize {
+        ((self.capacity / 2) & !7) - RECORD_HEADER
+    }
+
+    /// What `enqueue` does when the ring is full, the same for every handle
+    pub fn full_policy(&self) -> FullPolicy {
+        self.policy
+    }
+
+    /// How many messages producers dropped to make room under [`FullPolicy::OverwriteOldest`], across every process
+    pub fn dropped(&self) -> u64 {
+        self.counter(OFF_DROPPED)
+    }
+
+    /// The counters in the header, the same from every handle
+    pub fn stats(&self) -> RingStats {
+        let (pushed, popped, dropped) = (self.counter(OFF_PUSHED), self.counter(OFF_POPPED), self.dropped());
+        let head = self.counter(OFF_HEAD);
+        RingStats {
+            occupancy_bytes: head.saturating_sub(self.counter(OFF_TAIL)),
+            occupancy_messages: pushed.saturating_sub(popped).saturating_sub(dropped),
+            pushed,
+            popped,
+            dropped,
+            high_water_bytes: self.counter(OFF_HIGH_WATER),
+            last_push: from_nanos(self.counter(OFF_LAST_PUSH)),
+            last_pop: from_nanos(self.counter(OFF_LAST_POP)),
+        }
+    }
+
+    fn counter(&self, offset: usize) -> u64 {
+        self.position(offset).load(Ordering::Relaxed)
+    }
+
+    /// Counts `count` messages as pushed, after they were committed
+    fn pushed(&self, count: usize) {
+        self.position(OFF_PUSHED).fetch_add(count as u64, Ordering::Relaxed);
+        self.position(OFF_LAST_PUSH).store(now_nanos(), Ordering::Relaxed);
+    }
+
+    /// The segment the ring lives in
+    pub fn shared_memory(&self) -> &SharedMemory {
+        &self.shm
+    }
+
+    fn base(&self) -> *mut u8 {
+        self.shm.address() as *mut u8
+    }
+
+    fn word(&self, offset: usize) -> &AtomicU32 {
+        unsafe { &*(self.base().add(offset) as *const AtomicU32) }
+    }
+
+    fn position(&self, offset: usize) -> &AtomicU64 {
+        unsafe { &*(self.base().add(offset) as *const AtomicU64) }
+    }
+
+    fn data(&self, pos: usize) -> *mut u8 {
+        unsafe { self.base().add(HEADER_SIZE + pos) }
+    }
+
+    fn record_header(&self, pos: usize) -> &AtomicU32 {
+        unsafe { &*(self.data(pos) as *const AtomicU32) }
+    }
+
+    /// Bumped after every committed push, and what a blocked consumer waits on
+    pub(crate) fn data_seq(&self) -> &AtomicU32 {
+        self.word(OFF_DATA_SEQ)
+    }
+
+    /// Bumped after every pop, and what blocked producers wait on
+    pub(crate) fn space_seq(&self) -> &AtomicU32 {
+        self.word(OFF_SPACE_SEQ)
+    }
+
+    pub(crate) fn senders(&self) -> &AtomicU32 {
+        self.word(OFF_SENDERS)
+    }
+
+    pub(crate) fn senders_seen(&self) -> &AtomicU32 {
+        self.word(OFF_SENDERS_SEEN)
+    }
+
+    pub(crate) fn receiver_dropped(&self) -> &AtomicU32 {
+        self.word(OFF_RECEIVER_DROPPED)
+    }
+
+    pub(crate) fn notify(seq: &AtomicU32) {
+        seq.fetch_add(1, Ordering::Release);
+        futex::wake_all(seq);
+    }
+
+    /// Pushes `msg` if there is room for it right now
+    pub fn try_push(&self, msg: &[u8]) -> Result<(), PushError> {
+        self.try_push_batch(&[msg]).map(|_| ())
+    }
+
+    /// Pushes as many of `msgs` as there is room for right now with one move of the head, returning how many
+    ///
+    /// The messages go in order, stopping at the first that doesn't fit or is too large. Errors only come back when
+    /// not even the first message can go in, an empty batch pushes nothing and returns 0
+    pub fn try_push_batch(&self, msgs: &[&[u8]]) -> Result<usize, PushError> {
+        let (mut at, accepted) = self.claim(msgs.len(), |index| msgs[index].len())?;
+        for msg in &msgs[..accepted] {
+            let record = self.start_record(&mut at, msg.len());
+            unsafe {
+                ptr::copy_nonoverlapping(msg.as_ptr(), self.data(record + RECORD_HEADER), msg.len());
+            }
+            // Release so the consumer's Acquire load of the header also sees the payload
+            self.record_header(record).store(COMMITTED | msg.len() as u32, Ordering::Release);
+        }
+        if accepted > 0 {
+            self.pushed(accepted);
+            ShmRingBuffer::notify(self.data_seq());
+        }
+        Ok(accepted)
+    }
+
+    /// Claims room for as many of `count` records as fit with one move of the head, the one at `index` holding
+    /// `len(index)` bytes, returning where the first one starts and how many there are
+    ///
+    /// Errors, and a zero count, are the same as for `try_push_batch`
+    fn claim(&self, count: usize, len: impl Fn(usize) -> usize) -> Result<(u64, usize), PushError> {
+        let max = self.max_message_len();
+        match (0..count).map(&len).next() {
+            None => return Ok((0, 0)),
+            Some(first) if first > max => return Err(PushError::TooLarge { len: first, max }),
+            Some(_) => {}
+        }
+        let head_pos = self.position(OFF_HEAD);
+        let mut head = head_pos.load(Ordering::Acquire);
+        loop {
+            let tail = self.position(OFF_TAIL).load(Ordering::Acquire);
+            if tail > head {
+                // Our head is stale, other producers and the consumer have both moved past it
+                head = head_pos.load(Ordering::Acquire);
+                if tail > head {
+                    // The tail was loaded first, so a head still behind it can only come from a corrupt header
+                    return Err(PushError::Full);
+                }
+                continue;
+            }
+            if head - tail > self.capacity as u64 || !head.is_multiple_of(RECORD_HEADER as u64) {
+                // Nothing this crate writes leaves the positions like this, so the header is corrupt
+                return Err(PushError::Full);
+            }
+            let mut new_head = head;
+            let mut accepted = 0;
+            for len in (0..count).map(&len).take_while(|&len| len <= max) {
+                let (padding, need) = self.place(new_head, len);
+                if (new_head - tail) as usize + padding + need > self.capacity {
+                    break;
+                }
+                new_head = match new_head.checked_add((padding + need) as u64) {
+                    Some(new_head) => new_head,
+                    None => break,
+                };
+                accepted += 1;
+            }
+            if accepted == 0 {
+                return Err(PushError::Full);
+            }
+            match head_pos.compare_exchange_weak(head, new_head, Ordering::AcqRel, Ordering::Acquire) {
+                Ok(_) => {
+                    // fetch_max rather than a load and a store, so a smaller value can't overwrite a larger one
+                    self.position(OFF_HIGH_WATER).fetch_max(new_head - tail, Ordering::Relaxed);
+                    return Ok((head, accepted));
+                }
+                Err(current) => head = current,
+            }
+        }
+    }
+
+    /// Writes the padding in front of the claimed record at `at` if it needs any, returning where the record goes
+    /// and moving `at` past it
+    fn start_record(&self, at: &mut u64, len: usize) -> usize {
+        let (padding, need) = self.place(*at, len);
+        let pos = (*at % self.capacity as u64) as usize;
+        if padding > 0 {
+            self.record_header(pos).store(COMMITTED | PADDING, Ordering::Release);
+        }
+        *at += (padding + need) as u64;
+        (pos + padding) % self.capacity
+    }
+
+    /// Claims room for a message of up to `max_len` bytes to be written in place, see [`WriteReservation`]
+    ///
+    /// Fails the same way `try_push` would for a message of `max_len` bytes. The consumer can't get past the
+    /// reservation, and so to anything pushed after it, until it's committed or dropped
+    pub fn reserve(&self, max_len: usize) -> Result<WriteReservation<'_>, PushError> {
+        let (mut at, _) = self.claim(1, |_| max_len)?;
+        let record = self.start_record(&mut at, max_len);
+        Ok(WriteReservation { ring: self, record, max_len })
+    }
+
+    /// Padding in front of and bytes taken by the record for a message of `len` bytes claimed at position `at`
+    fn place(&self, at: u64, len: usize) -> (usize, usize) {
+        let need = record_len(len);
+        // A record never wraps, so if it doesn't fit before the end the rest of the lap becomes padding
+        let contiguous = self.capacity - (at % self.capacity as u64) as usize;
+        (if contiguous < need { contiguous } else { 0 }, need)
+    }
+
+    /// Pushes `msg`, waiting up to `timeout` (or forever with `None`) for room, `PushError::Full` means it timed out
+    pub fn push(&self, msg: &[u8], timeout: Option<Duration>) -> Result<(), PushError> {
+        let deadline = futex::deadline(timeout);
+        loop {
+            let seq = self.space_seq().load(Ordering::Acquire);
+            match self.try_push(msg) {
+                Err(PushError::Full) => {}
+                result => return result,
+            }
+            match futex::remaining(deadline) {
+                Some(left) if left.is_zero() => return Err(PushError::Full),
+                left => {
+                    futex::wait(self.space_seq(), seq, left);
+                }
+            }
+        }
+    }
+
+    /// Pushes `msg`, doing what the ring's [`FullPolicy`] says if it's full
+    ///
+    /// `PushError::Full` means the policy rejected the message or its timeout ran out. Overwriting can also fail with
+    /// it while the oldest message is still being written by a producer that claimed it, and it drops nothing then
+    pub fn enqueue(&self, msg: &[u8]) -> Result<(), PushError> {
+        match self.policy {
+            FullPolicy::Reject => self.try_push(msg),
+            FullPolicy::Block { timeout } => self.push(msg, Some(timeout)),
+            FullPolicy::OverwriteOldest => loop {
+                let seen = self.position(OFF_TAIL).load(Ordering::Acquire);
+                match self.try_push(msg) {
+                    Err(PushError::Full) if self.drop_oldest(seen) => {}
+                    result => return result,
+                }
+            },
+        }
+    }
+
+    /// Drops the oldest record under the tail lock if the tail is still at `seen`, returning false if there's nothing
+    /// committed to drop
+    ///
+    /// A tail that moved since means the consumer or another producer already made room, so nothing is dropped and
+    /// the push is worth trying again
+    fn drop_oldest(&self, seen: u64) -> bool {
+        let _lock = futex::lock(self.word(OFF_TAIL_LOCK));
+        let tail_pos = self.position(OFF_TAIL);
+        let tail = tail_pos.load(Ordering::Relaxed);
+        if tail != seen {
+            return true;
+        }
+        let head = self.position(OFF_HEAD).load(Ordering::Acquire);
+        let (pos, header, consumed) = match self.committed_record(tail, head) {
+            Some(record) => record,
+            None => return false,
+        };
+        // Zeroed before the tail moves, so no producer that claims the space can find the old header
+        unsafe { self.clear_record(pos, consumed) };
+        tail_pos.store(tail + consumed as u64, Ordering::Release);
+        if header & PADDING == 0 {
+            self.position(OFF_DROPPED).fetch_add(1, Ordering::Relaxed);
+        }
+        ShmRingBuffer::notify(self.space_seq());
+        true
+    }
+
+    /// Position, header and length of the committed record at `tail`, `None` if there isn't one or the
+    /// positions around it are corrupt
+    fn committed_record(&self, tail: u64, head: u64) -> Option<(usize, u32, usize)> {
+        if head <= tail || head - tail > self.capacity as u64 || !tail.is_multiple_of(RECORD_HEADER as u64) {
+            return None;
+        }
+        let pos = (tail % self.capacity as u64) as usize;
+        let header = self.record_header(pos).load(Ordering::Acquire);
+        if header & COMMITTED == 0 {
+            // Claimed by a producer that hasn't finished writing it yet
+            return None;
+        }
+        let consumed = match (header & PADDING != 0, (header & LEN_MASK) as usize) {
+            (true, 0) => self.capacity - pos,
+            // A skip always covers whole records, anything shorter than a header would leave the tail inside one
+            (true, skipped) if skipped < RECORD_HEADER || !skipped.is_multiple_of(RECORD_HEADER) => return None,
+            (true, skipped) => skipped,
+            (false, len) => record_len(len),
+        };
+        if consumed > (self.capacity - pos).min((head - tail) as usize) {
+            // Producers never claim a record running past the end of the data area or the head, so it's corrupt
+            return None;
+        }
+        Some((pos, header, consumed))
+    }
+
+    /// Zeroes the record so whichever producer claims this space next starts from an uncommitted header
+    ///
+    /// # Safety
+    /// Only whoever is about to move the tail past the record may clear it
+    unsafe fn clear_record(&self, pos: usize, consumed: usize) {
+        self.record_header(pos).store(0, Ordering::Relaxed);
+        ptr::write_bytes(self.data(pos + 4), 0, consumed - 4);
+    }
+
+    /// Pops the oldest committed message if there is one
+    pub fn try_pop(&mut self) -> Option<Vec<u8>> {
+        unsafe { self.pop_shared() }
+    }
+
+    /// Pops the oldest message, waiting up to `timeout` (or forever with `None`) for one to be pushed
+    pub fn pop(&mut self, timeout: Option<Duration>) -> Option<Vec<u8>> {
+        let deadline = futex::deadline(timeout);
+        loop {
+            let seq = self.data_seq().load(Ordering::Acquire);
+            if let Some(msg) = self.try_pop() {
+                return Some(msg);
+            }
+            match futex::remaining(deadline) {
+                Some(left) if left.is_zero() => return None,
+                left => {
+                    futex::wait(self.data_seq(), seq, left);
+                }
+            }
+        }
+    }
+
+    /// Pops up to `max` committed messages into `out` with one move of the tail, returning how many
+    pub fn try_pop_batch(&mut self, out: &mut Vec<Vec<u8>>, max: usize) -> usize {
+        unsafe { self.pop_records(max, |msg| out.push(msg)) }
+    }
+
+    /// Pop through a shared reference
+    ///
+    /// # Safety
+    /// The caller has to guarantee no other pop on this ring runs at the same time, from any handle
+    pub(crate) unsafe fn pop_shared(&self) -> Option<Vec<u8>> {
+        let mut popped = None;
+        self.pop_records(1, |msg| popped = Some(msg));
+        popped
+    }
+
+    /// Hands up to `max` committed messages to `sink` in order and then publishes the tail once
+    ///
+    /// # Safety
+    /// Same as `pop_shared`
+    unsafe fn pop_records(&self, max: usize, mut sink: impl FnMut(Vec<u8>)) -> usize {
+        // Overwriting producers move the tail as well, so the records can't be read while one might be dropping them
+        let _lock = (self.policy == FullPolicy::OverwriteOldest).then(|| futex::lock(self.word(OFF_TAIL_LOCK)));
+        let tail_pos = self.position(OFF_TAIL);
+        let start = tail_pos.load(Ordering::Relaxed);
+        let head = self.position(OFF_HEAD).load(Ordering::Acquire);
+        let mut tail = start;
+        let mut popped = 0;
+        while popped < max {
+            let (pos, header, consumed) = match self.committed_record(tail, head) {
+                Some(record) => record,
+                None => break,
+            };
+            if header & PADDING == 0 {
+                let len = (header & LEN_MASK) as usize;
+                let mut msg = vec![0u8; len];
+                ptr::copy_nonoverlapping(self.data(pos + RECORD_HEADER), msg.as_mut_ptr(), len);
+                sink(msg);
+                popped += 1;
+            }
+            self.clear_record(pos, consumed);
+            tail += consumed as u64;
+        }
+        if tail != start {
+            tail_pos.store(tail, Ordering::Release);
+        }
+        if popped > 0 {
+            self.position(OFF_POPPED).fetch_add(popped as u64, Ordering::Relaxed);
+            self.position(OFF_LAST_POP).store(now_nanos(), Ordering::Relaxed);
+            ShmRingBuffer::notify(self.space_seq());
+        }
+        popped
+    }
+}
+
+impl ShmRingBuffer {
+    /// Moves the ring to a thread that pops every message into `sender`, this handle has to be the ring's only
+    /// consumer
+    ///
+    /// A full channel pauses the draining, so messages back up in the ring and producers see `PushError::Full` as
+    /// they would with a slow consumer. The thread ends when the receiver is dropped or [`PumpHandle::stop`] is called
+    pub fn spawn_pump(mut self, sender: SyncSender<Vec<u8>>) -> PumpHandle {
+        PumpHandle::spawn(move |stopped| {
+            while !stopped.load(Ordering::Relaxed) {
+                let msg = match self.pop(Some(PUMP_POLL)) {
+                    Some(msg) => msg,
+                    None => continue,
+                };
+                // A message is off the ring once popped, so it waits for room in the channel even if stop comes
+                // meanwhile
+                if sender.send(msg).is_err() {
+                    return self;
+                }
+            }
+            self
+        })
+    }
+
+    /// Moves the ring to a thread that pushes every message from `receiver` into it
+    ///
+    /// A full ring pauses the thread until the consumer makes room, leaving messages queued in the channel. The
+    /// thread ends when every sender is dropped and the channel is empty, when [`PumpHandle::stop`] is called, or on a
+    /// message longer than [`ShmRingBuffer::max_message_len`], which is dropped
+    pub fn spawn_feeder(self, receiver: Receiver<Vec<u8>>) -> PumpHandle {
+        PumpHandle::spawn(move |stopped| {
+            while !stopped.load(Ordering::Relaxed) {
+                let msg = match receiver.recv_timeout(PUMP_POLL) {
+                    Ok(msg) => msg,
+                    Err(RecvTimeoutError::Timeout) => continue,
+                    Err(RecvTimeoutError::Disconnected) => break,
+                };
+                // Same as the pump, a message taken from the channel goes into the ring even if stop comes meanwhile
+                loop {
+                    match self.push(&msg, Some(PUMP_POLL)) {
+                        Ok(()) => break,
+                        Err(PushError::Full) => {}
+                        Err(PushError::TooLarge { .. }) => return self,
+                    }
+                }
+            }
+            self
+        })
+    }
+}
+
+/// The thread started by [`ShmRingBuffer::spawn_pump`] or [`ShmRingBuffer::spawn_feeder`]
+///
+/// Dropping the handle tells the thread to stop without waiting for it, and the ring is dropped once it has
+pub struct PumpHandle {
+    stopped: Arc<AtomicBool>,
+    thread: Option<JoinHandle<ShmRingBuffer>>,
+}
+
+impl PumpHandle {
+    fn spawn(run: impl FnOnce(&AtomicBool) -> ShmRingBuffer + Send + 'static) -> Self {
+        let stopped = Arc::new(AtomicBool::new(false));
+        let flag = Arc::clone(&stopped);
+        PumpHandle { stopped, thread: Some(thread::spawn(move || run(&flag))) }
+    }
+
+    /// Whether the thread is still moving messages, false once its channel closed or it was handed a message too
+    /// long for the ring
+    pub fn is_running(&self) -> bool {
+        self.thread.as_ref().is_some_and(|thread| !thread.is_finished())
+    }
+
+    /// Stops the thread and hands the ring back
+    ///
+    /// Takes up to [`PUMP_POLL`] to be noticed, plus however long the other side takes to make room for a message the
+    /// thread already picked up, which is never lost. Messages still waiting stay in the ring or channel
+    pub fn stop(mut self) -> ShmRingBuffer {
+        self.stopped.store(true, Ordering::Relaxed);
+        let thread = self.thread.take().expect("the thread is only taken here or on drop");
+        match thread.join() {
+            Ok(ring) => ring,
+            Err(panic) => std::panic::resume_unwind(panic),
+        }
+    }
+}
+
+impl Drop for PumpHandle {
+    fn drop(&mut self) {
+        self.stopped.store(true, Ordering::Relaxed);
+    }
+}
+
+/// The wakers of `recv_async` futures waiting on one handle, and the watcher thread that wakes them
+#[derive(Default)]
+struct Waiters {
+    /// Taken around every async pop, so futures on the same handle never pop at the same time
+    pop: Mutex<()>,
+    state: Mutex<WaiterState>,
+    closed: AtomicBool,
+}
+
+#[derive(Default)]
+struct WaiterState {
+    next_id: u64,
+    /// Each waiting future's id, waker and the doorbell value it saw before finding the ring empty
+    wakers: Vec<(u64, Waker, u32)>,
+    /// Cleared by the watcher under the lock as it decides to exit, so a registration never counts on one that's
+    /// already leaving
+    watching: bool,
+    watcher: Option<JoinHandle<()>>,
+}
+
+impl Waiters {
+    fn state(&self) -> MutexGuard<'_, WaiterState> {
+        // Nothing panics while holding the lock, but a poisoned list is still a usable list
+        self.state.lock().unwrap_or_else(|poisoned| poisoned.into_inner())
+    }
+}
+
+impl ShmRingBuffer {
+    /// A future for the oldest message, for async code
+    ///
+    /// Async pops through this handle take turns, but nothing else may pop from the ring meanwhile, from any handle.
+    /// Dropping the future before it's ready takes nothing off the ring
+    pub fn recv_async(&self) -> RecvFuture<'_> {
+        RecvFuture { ring: self, id: None }
+    }
+
+    fn watch(&self, waiters: &mut WaiterState) {
+        if waiters.watching {
+            return;
+        }
+        waiters.watching = true;
+        let shared = Arc::clone(&self.waiters);
+        // The handle joins the watcher when dropped, so the doorbell outlives it
+        let seq = self.data_seq() as *const AtomicU32 as usize;
+        waiters.watcher = Some(thread::spawn(move || {
+            let seq = unsafe { &*(seq as *const AtomicU32) };
+            loop {
+                let current = seq.load(Ordering::Acquire);
+                {
+                    let mut state = shared.state();
+                    let closed = shared.closed.load(Ordering::Relaxed);
+                    // Wakers that saw an older doorbell may have missed a message, the rest are still up to date
+                    state.wakers.retain(|(_, waker, seen)| {
+                        if closed || *seen != current {
+                            waker.wake_by_ref();
+                            return false;
+                        }
+                        true
+                    });
+                    if state.wakers.is_empty() {
+                        state.watching = false;
+                        return;
+                    }
+                }
+                futex::wait(seq, current, Some(PUMP_POLL));
+            }
+        }));
+    }
+}
+
+impl Drop for ShmRingBuffer {
+    fn drop(&mut self) {
+        self.waiters.closed.store(true, Ordering::Relaxed);
+        let watcher = self.waiters.state().watcher.take();
+        if let Some(watcher) = watcher {
+            futex::wake_all(self.data_seq());
+            let _ = watcher.join();
+        }
+    }
+}
+
+/// Returned by [`ShmRingBuffer::recv_async`]
+pub struct RecvFuture<'a> {
+    ring: &'a ShmRingBuffer,
+    /// Set while the future's waker is registered with the watcher
+    id: Option<u64>,
+}
+
+impl Future for RecvFuture<'_> {
+    type Output = Vec<u8>;
+
+    fn poll(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Vec<u8>> {
+        let ring = self.ring;
+        // Loaded before looking, so a message pushed after the look also moves the doorbell past what's registered
+        let seen = ring.data_seq().load(Ordering::Acquire);
+        let popped = {
+            let _pop = ring.waiters.pop.lock().unwrap_or_else(|poisoned| poisoned.into_inner());
+            unsafe { ring.pop_shared() }
+        };
+        let mut state = ring.waiters.state();
+        if let Some(msg) = popped {
+            if let Some(id) = self.id.take() {
+                state.wakers.retain(|(other, _, _)| *other != id);
+            }
+            return Poll::Ready(msg);
+        }
+        let id = match self.id {
+            Some(id) => id,
+            None => {
+                state.next_id += 1;
+                state.next_id
+            }
+        };
+        self.id = Some(id);
+        match state.wakers.iter_mut().find(|(other, _, _)| *other == id) {
+            Some(entry) => *entry = (id, cx.waker().clone(), seen),
+            None => state.wakers.push((id, cx.waker().clone(), seen)),
+        }
+        ring.watch(&mut state);
+        Poll::Pending
+    }
+}
+
+impl Drop for RecvFuture<'_> {
+    fn drop(&mut self) {
+        if let Some(id) = self.id {
+            self.ring.waiters.state().wakers.retain(|(other, _, _)| *other != id);
+        }
+    }
+}
+
+/// Room claimed in the ring by [`ShmRingBuffer::reserve`], to serialize a message into without copying it
+///
+/// Derefs to the `max_len` reserved bytes. `commit` publishes however many of them were used, and dropping the
+/// reservation without committing leaves a skip record the consumer steps over
+pub struct WriteReservation<'a> {
+    ring: &'a ShmRingBuffer,
+    /// Position of the record header
+    record: usize,
+    max_len: usize,
+}
+
+impl WriteReservation<'_> {
+    /// Publishes the first `len` bytes as a message
+    ///
+    /// A `len` past what was reserved fails with `PushError::TooLarge` and abandons the reservation
+    pub fn commit(self, len: usize) -> Result<(), PushError> {
+        if len > self.max_len {
+            return Err(PushError::TooLarge { len, max: self.max_len });
+        }
+        let rest = record_len(self.max_len) - record_len(len);
+        if rest > 0 {
+            // The skip goes in first so the consumer sees it as soon as it gets past the message
+            self.ring.record_header(self.record + record_len(len)).store(COMMITTED | PADDING | rest as u32, Ordering::Release);
+        }
+        self.ring.record_header(self.record).store(COMMITTED | len as u32, Ordering::Release);
+        self.ring.pushed(1);
+        ShmRingBuffer::notify(self.ring.data_seq());
+        std::mem::forget(self);
+        Ok(())
+    }
+}
+
+impl Deref for WriteReservation<'_> {
+    type Target = [u8];
+
+    fn deref(&self) -> &[u8] {
+        unsafe { std::slice::from_raw_parts(self.ring.data(self.record + RECORD_HEADER), self.max_len) }
+    }
+}
+
+impl DerefMut for WriteReservation<'_> {
+    fn deref_mut(&mut self) -> &mut [u8] {
+        // The claim on the head makes these bytes this reservation's alone until it's committed or dropped
+        unsafe { std::slice::from_raw_parts_mut(self.ring.data(self.record + RECORD_HEADER), self.max_len) }
+    }
+}
+
+impl Drop for WriteReservation<'_> {
+    fn drop(&mut self) {
+        let skipped = record_len(self.max_len) as u32;
+        self.ring.record_header(self.record).store(COMMITTED | PADDING | skipped, Ordering::Release);
+        ShmRingBuffer::notify(self.ring.data_seq());
+    }
+}
@@ -0,0 +1,193 @@
+//! Request/response calls between two processes over a pair of [`ShmRingBuffer`]s
+//!
+//! The server owns a request ring (`<name>.req`) and a response ring (`<name>.resp`). Every frame starts with the
+//! little-endian `u64` request id, which is how responses find their way back to the right call even when the
+//! server answers out of order. All responses go into the single response ring, so a server talks to one client
+//! handle at a time, though that handle can be shared by any number of threads.
+
+use std::collections::HashMap;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Condvar, Mutex};
+use std::time::Duration;
+use std::fmt;
+
+use crate::futex;
+use crate::ring_buffer::{PushError, ShmRingBuffer};
+use crate::shared_memory::Error;
+
+/// Data area size of each ring when no capacity is given
+pub const DEFAULT_CAPACITY: usize = 64 * 1024;
+
+const ID_LEN: usize = 8;
+
+/// Identifies a request so its response can be matched to the waiting call
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub struct RequestId(u64);
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum RpcError {
+    /// No response arrived before the deadline, a late response is dropped when it shows up
+    Timeout,
+    /// The payload is too large to fit in the ring together with its request id
+    TooLarge { len: usize, max: usize },
+}
+
+impl fmt::Display for RpcError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            RpcError::Timeout => write!(f, "timed out waiting for the response"),
+            RpcError::TooLarge { len, max } => write!(f, "payload of {} bytes is larger than the {} byte limit", len, max),
+        }
+    }
+}
+
+impl std::error::Error for RpcError {}
+
+fn ring_names(name: &str) -> (String, String) {
+    (format!("{}.req", name), format!("{}.resp", name))
+}
+
+fn frame(id: u64, data: &[u8]) -> Vec<u8> {
+    let mut msg = Vec::with_capacity(ID_LEN + data.len());
+    msg.extend_from_slice(&id.to_le_bytes());
+    msg.extend_from_slice(data);
+    msg
+}
+
+/// Splits a frame into its id and payload, frames too short to hold an id are garbage and get skipped
+fn unframe(mut msg: Vec<u8>) -> Option<(u64, Vec<u8>)> {
+    if msg.len() < ID_LEN {
+        return None;
+    }
+    let id = u64::from_le_bytes(msg[..ID_LEN].try_into().unwrap());
+    msg.drain(..ID_LEN);
+    Some((id, msg))
+}
+
+fn push_error(err: PushError) -> RpcError {
+    match err {
+        PushError::Full => RpcError::Timeout,
+        PushError::TooLarge { len, max } => RpcError::TooLarge { len: len - ID_LEN, max: max - ID_LEN },
+    }
+}
+
+pub struct ShmRpcServer {
+    requests: ShmRingBuffer,
+    responses: ShmRingBuffer,
+}
+
+impl ShmRpcServer {
+    pub fn bind(name: &str) -> Result<Self, Error> {
+        ShmRpcServer::bind_with_capacity(name, DEFAULT_CAPACITY)
+    }
+
+    /// Creates both rings with `capacity` byte data areas, clients have to connect with the same capacity
+    pub fn bind_with_capacity(name: &str, capacity: usize) -> Result<Self, Error> {
+        let (requests, responses) = ring_names(name);
+        Ok(ShmRpcServer {
+            requests: ShmRingBuffer::create(&requests, capacity)?,
+            responses: ShmRingBuffer::create(&responses, capacity)?,
+        })
+    }
+
+    /// Waits for the next request
+    pub fn next_request(&mut self) -> (RequestId, Vec<u8>) {
+        loop {
+            if let Some((id, data)) = self.requests.pop(None).and_then(unframe) {
+                return (RequestId(id), data);
+            }
+        }
+    }
+
+    /// Waits up to `timeout` for the next request
+    pub fn next_request_timeout(&mut self, timeout: Duration) -> Option<(RequestId, Vec<u8>)> {
+        let deadline = futex::deadline(Some(timeout));
+        loop {
+            let msg = self.requests.pop(futex::remaining(deadline))?;
+            if let Some((id, data)) = unframe(msg) {
+                return Some((RequestId(id), data));
+            }
+        }
+    }
+
+    /// Sends the response for `id`, waiting for room in the response ring if the client is behind
+    pub fn respond(&self, id: RequestId, data: &[u8]) -> Result<(), RpcError> {
+        self.responses.push(&frame(id.0, data), None).map_err(push_error)
+    }
+}
+
+struct ResponseState {
+    /// Taken out by whichever caller is currently popping responses for everyone
+    ring: Option<ShmRingBuffer>,
+    /// Calls still waiting, with their response once it has arrived
+    pending: HashMap<u64, Option<Vec<u8>>>,
+}
+
+pub struct ShmRpcClient {
+    requests: ShmRingBuffer,
+    responses: Mutex<ResponseState>,
+    arrived: Condvar,
+    next_id: AtomicU64,
+}
+
+impl ShmRpcClient {
+    pub fn connect(name: &str) -> Result<Self, Error> {
+        ShmRpcClient::connect_with_capacity(name, DEFAULT_CAPACITY)
+    }
+
+    pub fn connect_with_capacity(name: &str, capacity: usize) -> Result<Self, Error> {
+        let (requests, responses) = ring_names(name);
+        Ok(ShmRpcClient {
+            requests: ShmRingBuffer::open(&requests, capacity)?,
+            responses: Mutex::new(ResponseState {
+                ring: Some(ShmRingBuffer::open(&responses, capacity)?),
+                pending: HashMap::new(),
+            }),
+            arrived: Condvar::new(),
+            next_id: AtomicU64::new(1),
+        })
+    }
+
+    /// Sends `data` as a request and waits up to `timeout` for the server's response to it
+    ///
+    /// Only one calling thread pops the response ring at a time, handing responses for other calls over through
+    /// the pending table, and a call that times out removes its entry so a late response is just dropped
+    pub fn call(&self, data: &[u8], timeout: Duration) -> Result<Vec<u8>, RpcError> {
+        let deadline = futex::deadline(Some(timeout));
+        let id = self.next_id.fetch_add(1, Ordering::Relaxed);
+        self.responses.lock().unwrap().pending.insert(id, None);
+        if let Err(err) = self.requests.push(&frame(id, data), futex::remaining(deadline)) {
+            self.responses.lock().unwrap().pending.remove(&id);
+            return Err(push_error(err));
+        }
+        let mut state = self.responses.lock().unwrap();
+        loop {
+            if let Some(Some(_)) = state.pending.get(&id) {
+                return Ok(state.pending.remove(&id).flatten().unwrap());
+            }
+            let left = futex::remaining(deadline).unwrap();
+            if left.is_zero() {
+                state.pending.remove(&id);
+                return Err(RpcError::Timeout);
+            }
+            match state.ring.take() {
+                Some(mut ring) => {
+                    drop(state);
+                    let msg = ring.pop(Some(left));
+                    state = self.responses.lock().unwrap();
+                    state.ring = Some(ring);
+                    if let Some((response_id, response)) = msg.and_then(unframe) {
+                        if let Some(slot) = state.pending.get_mut(&response_id) {
+                            *slot = Some(response);
+                        }
+                    }
+                    // Either a response landed or the ring is free again, both mean someone else may have work
+                    self.arrived.notify_all();
+                }
+                None => {
+                    state = self.arrived.wait_timeout(state, left).unwrap().0;
+                }
+            }
+        }
+    }
+}
@@ -0,0 +1,174 @@
+//! What is allowed to cross the process boundary through the typed accessors
+//!
+//! Every typed read or write on [`SharedMemory`](crate::shared_memory::SharedMemory) requires [`SharedSafe`], which
+//! makes the impls of it the single place to audit for types whose bytes another process will see.
+//!
+//! With the `derive` feature, `#[derive(SharedSafe)]` checks a struct's repr, fields and padding at compile time
+//! instead of relying on a hand-written `unsafe impl`, and `#[shared_struct]` lays out structs of atomics and
+//! `SharedSafe` fields that are attached in place with [`SharedMemory::struct_at`](crate::shared_memory::SharedMemory::struct_at).
+
+use std::sync::atomic::{AtomicI16, AtomicI32, AtomicI64, AtomicI8, AtomicU16, AtomicU32, AtomicU64, AtomicU8};
+
+/// Implements [`SharedSafe`](trait@SharedSafe) for a `#[repr(C)]` struct after checking its fields and padding
+///
+/// ```compile_fail
+/// use shared_memory::shared_safe::SharedSafe;
+///
+/// #[repr(C)]
+/// #[derive(Clone, SharedSafe)]
+/// struct Samples {
+///     values: Vec<u32>,
+/// }
+/// ```
+///
+/// ```compile_fail,E0080
+/// use shared_memory::shared_safe::SharedSafe;
+///
+/// #[repr(C)]
+/// #[derive(Clone, Copy, SharedSafe)]
+/// struct Padded {
+///     flag: u8,
+///     value: u32,
+/// }
+/// ```
+#[cfg(feature = "derive")]
+pub use shared_memory_derive::SharedSafe;
+
+/// Maps a struct onto a region of a segment, to be used in place through [`SharedMemory::struct_at`](crate::shared_memory::SharedMemory::struct_at)
+///
+/// ```
+/// use std::sync::atomic::{AtomicU64, Ordering};
+///
+/// use shared_memory::shared_memory::SharedMemory;
+/// use shared_memory::shared_safe::shared_struct;
+///
+/// #[shared_struct]
+/// struct Control {
+///     head: AtomicU64,
+///     tail: AtomicU64,
+///     flags: u32,
+///     name: [u8; 32],
+/// }
+///
+/// let (shm, name) = SharedMemory::create_unique("control_doc", 4096).unwrap();
+/// let other = SharedMemory::open(&name, 4096).unwrap();
+/// Control::attach(&shm, 64).unwrap().head.store(7, Ordering::Release);
+/// assert_eq!(Control::attach(&other, 64).unwrap().head.load(Ordering::Acquire), 7);
+/// assert_eq!((Control::FIELD_OFFSET_TAIL, Control::FIELD_OFFSET_FLAGS, Control::SIZE), (8, 16, 56));
+/// assert!(Control::attach(&shm, 4).is_err());
+/// assert!(Control::attach(&shm, 4096 - 48).is_err());
+/// ```
+///
+/// ```compile_fail
+/// use shared_memory::shared_safe::shared_struct;
+///
+/// #[shared_struct]
+/// struct Named {
+///     name: String,
+/// }
+/// ```
+#[cfg(feature = "derive")]
+pub use shared_memory_derive::shared_struct;
+
+/// Types whose bytes mean the same thing in every process mapping the segment
+///
+/// # Safety
+/// Implementors promise that every bit pattern of `size_of::<T>()` bytes is a valid `T`, and that the type holds no
+/// pointers, references or handles (those are only meaningful in the process that made them). The layout also has
+/// to be fixed, so structs need `#[repr(C)]` or `#[repr(transparent)]` with `SharedSafe` fields only, and
+/// [`assert_shared_layout!`](crate::assert_shared_layout) is the way to pin their size and alignment down.
+///
+/// `usize`, `isize`, `u128` and `i128` are deliberately left out since their size or alignment changes between
+/// targets that might share a segment, and `bool` is too because any byte other than 0 or 1 is undefined behaviour,
+/// store flags as a `u8` instead.
+///
+/// ```compile_fail
+/// use shared_memory::shared_memory::SharedMemory;
+///
+/// #[derive(Clone, Copy)]
+/// struct Name(&'static str);
+///
+/// fn read(shm: &SharedMemory) {
+///     let _ = shm.read_value::<Name>(0);
+/// }
+/// ```
+pub unsafe trait SharedSafe: Copy + 'static {}
+
+unsafe impl SharedSafe for u8 {}
+unsafe impl SharedSafe for u16 {}
+unsafe impl SharedSafe for u32 {}
+unsafe impl SharedSafe for u64 {}
+unsafe impl SharedSafe for i8 {}
+unsafe impl SharedSafe for i16 {}
+unsafe impl SharedSafe for i32 {}
+unsafe impl SharedSafe for i64 {}
+unsafe impl SharedSafe for f32 {}
+unsafe impl SharedSafe for f64 {}
+unsafe impl<T: SharedSafe, const N: usize> SharedSafe for [T; N] {}
+
+/// Field types `#[shared_struct]` accepts, every [`SharedSafe`] type and the std integer atomics
+///
+/// # Safety
+/// Same promise as `SharedSafe` without the `Copy`: any bit pattern is a valid value and nothing in it is only
+/// meaningful in one process
+pub unsafe trait SharedField: 'static {}
+
+unsafe impl<T: SharedSafe> SharedField for T {}
+unsafe impl SharedField for AtomicU8 {}
+unsafe impl SharedField for AtomicU16 {}
+unsafe impl SharedField for AtomicU32 {}
+unsafe impl SharedField for AtomicU64 {}
+unsafe impl SharedField for AtomicI8 {}
+unsafe impl SharedField for AtomicI16 {}
+unsafe impl SharedField for AtomicI32 {}
+unsafe impl SharedField for AtomicI64 {}
+
+/// Structs that [`SharedMemory::struct_at`](crate::shared_memory::SharedMemory::struct_at) hands out references
+/// into the segment for, implemented by `#[shared_struct]`
+///
+/// # Safety
+/// The struct has to be `#[repr(C)]` with only [`SharedField`] fields. Every process sees the same bytes through the
+/// reference, so its non-atomic fields must only change while nobody else is reading them
+pub unsafe trait SharedStruct: Sync + 'static {}
+
+/// Fails compilation if a shared type's size or alignment changes, or if it doesn't implement [`SharedSafe`]
+///
+/// ```
+/// use shared_memory::assert_shared_layout;
+/// use shared_memory::shared_safe::SharedSafe;
+///
+/// #[repr(C)]
+/// #[derive(Clone, Copy)]
+/// struct Header {
+///     magic: u32,
+///     flags: u32,
+///     len: u64,
+/// }
+///
+/// unsafe impl SharedSafe for Header {}
+///
+/// assert_shared_layout!(Header, size = 16, align = 8);
+/// ```
+///
+/// ```compile_fail
+/// use shared_memory::assert_shared_layout;
+///
+/// #[repr(C)]
+/// #[derive(Clone)]
+/// struct Named {
+///     name: String,
+/// }
+///
+/// assert_shared_layout!(Named, size = 24, align = 8);
+/// ```
+#[macro_export]
+macro_rules! assert_shared_layout {
+    ($ty:ty, size = $size:expr, align = $align:expr) => {
+        const _: () = {
+            fn shared_safe<T: $crate::shared_safe::SharedSafe>() {}
+            let _ = shared_safe::<$ty>;
+            assert!(::core::mem::size_of::<$ty>() == $size, concat!("size of `", stringify!($ty), "` changed"));
+            assert!(::core::mem::align_of::<$ty>() == $align, concat!("alignment of `", stringify!($ty), "` changed"));
+        };
+    };
+}
@@ -0,0 +1,335 @@
+//! Variable-sized allocations inside a segment, addressed by stable `u32` keys any process can resolve
+//!
+//! The data area is cut into 4 KiB pages that get handed to a size class (64, 256, 1024 or 4096 byte slots) the
+//! first time that class needs room, and go back to the shared page pool once every slot in them is free again.
+//! Each page tracks its free slots in a bitmap and pages with free slots are linked per class, all by index so any
+//! mapping can follow them. Every slot has a generation that changes on insert and remove, and keys carry the low
+//! bits of it so a stale key is caught instead of reading whatever took the slot over. All changes take the lock
+//! word in the header.
+
+use std::sync::atomic::{AtomicU32, Ordering};
+use std::{fmt, io, ptr, slice};
+
+use crate::futex;
+use crate::shared_memory::{Error, SharedMemory};
+
+/// "SHSL"
+const MAGIC: u32 = 0x5348_534c;
+
+const OFF_MAGIC: usize = 0;
+const OFF_PAGES: usize = 8;
+const OFF_LOCK: usize = 16;
+// Pages that have never been used start here, freed pages go on the free page list instead
+const OFF_NEXT_UNUSED: usize = 20;
+const OFF_FREE_PAGES: usize = 24;
+// Head of each class's list of pages with free slots
+const OFF_PARTIAL: usize = 28;
+const HEADER_SIZE: usize = 64;
+
+pub const PAGE_SIZE: usize = 4096;
+const CLASSES: [usize; 4] = [64, 256, 1024, 4096];
+
+// Per page: class + 1 (0 when unassigned), live slots, previous and next page + 1, free slot bitmap
+const PAGE_META: usize = 24;
+// Per slot: generation (odd while live), payload length
+const SLOT_META: usize = 8;
+const SLOTS_PER_PAGE: usize = PAGE_SIZE / 64;
+
+const SLOT_BITS: u32 = 6;
+const PAGE_BITS: u32 = 18;
+const GEN_SHIFT: u32 = SLOT_BITS + PAGE_BITS;
+
+/// Largest number of pages a key can address
+pub const MAX_PAGES: usize = 1 << PAGE_BITS;
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum SlabError {
+    /// No page is free for the size class the allocation needs
+    Full,
+    /// The allocation is larger than the biggest size class
+    TooLarge { len: usize, max: usize },
+    /// The key was never handed out or its allocation has been removed
+    InvalidKey,
+}
+
+impl fmt::Display for SlabError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            SlabError::Full => write!(f, "slab is full"),
+            SlabError::TooLarge { len, max } => write!(f, "allocation of {} bytes is larger than the {} byte limit", len, max),
+            SlabError::InvalidKey => write!(f, "slab key doesn't refer to a live allocation"),
+        }
+    }
+}
+
+impl std::error::Error for SlabError {}
+
+/// Handle to one allocation, plain data so it can be stored in other shared structures
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub struct SlabKey(u32);
+
+impl SlabKey {
+    pub fn from_u32(raw: u32) -> Self {
+        SlabKey(raw)
+    }
+
+    pub fn as_u32(self) -> u32 {
+        self.0
+    }
+
+    fn new(page: usize, slot: usize, generation: u32) -> Self {
+        SlabKey((generation << GEN_SHIFT) | ((page as u32) << SLOT_BITS) | slot as u32)
+    }
+
+    fn page(self) -> usize {
+        ((self.0 >> SLOT_BITS) & ((1 << PAGE_BITS) - 1)) as usize
+    }
+
+    fn slot(self) -> usize {
+        (self.0 & ((1 << SLOT_BITS) - 1)) as usize
+    }
+
+    fn generation(self) -> u32 {
+        self.0 >> GEN_SHIFT
+    }
+}
+
+/// Slot count for a size class
+fn class_slots(class: usize) -> usize {
+    PAGE_SIZE / CLASSES[class]
+}
+
+/// Bitmap with a bit set for every slot of a freshly assigned page
+fn full_mask(class: usize) -> u64 {
+    u64::MAX >> (SLOTS_PER_PAGE - class_slots(class))
+}
+
+pub struct ShmSlab {
+    shm: SharedMemory,
+    pages: usize,
+}
+
+// Metadata is only changed while holding the lock word
+unsafe impl Send for ShmSlab {}
+unsafe impl Sync for ShmSlab {}
+
+impl ShmSlab {
+    /// Creates the segment with `pages` pages of [`PAGE_SIZE`] bytes to allocate from
+    pub fn create(name: &str, pages: usize) -> Result<Self, Error> {
+        let total = ShmSlab::segment_size(pages)?;
+        let slab = ShmSlab { shm: SharedMemory::create(name, total)?, pages };
+        unsafe {
+            ptr::write_bytes(slab.base(), 0, total as usize);
+        }
+        slab.shm.write_u64_le(OFF_PAGES, pages as u64)?;
+        slab.word(OFF_MAGIC).store(MAGIC.to_le(), Ordering::Release);
+        Ok(slab)
+    }
+
+    /// Opens a slab created with the same number of pages by another handle
+    pub fn open(name: &str, pages: usize) -> Result<Self, Error> {
+        let slab = ShmSlab { shm: SharedMemory::open(name, ShmSlab::segment_size(pages)?)?, pages };
+        let magic = u32::from_le(slab.word(OFF_MAGIC).load(Ordering::Acquire));
+        if magic != MAGIC {
+            return Err(Error::LayoutMismatch { what: "slab magic", expected: MAGIC as u64, found: magic as u64 });
+        }
+        let found = slab.shm.read_u64_le(OFF_PAGES)?;
+        if found != pages as u64 {
+            return Err(Error::LayoutMismatch { what: "slab pages", expected: pages as u64, found });
+        }
+        Ok(slab)
+    }
+
+    fn segment_size(pages: usize) -> Result<i32, io::Error> {
+        if pages == 0 || pages > MAX_PAGES {
+            return Err(io::Error::new(io::ErrorKind::InvalidInput, "slab page count out of range"));
+        }
+        ShmSlab::data_offset(pages)
+            .checked_add(pages * PAGE_SIZE)
+            .and_then(|total| i32::try_from(total).ok())
+            .ok_or_else(|| io::Error::new(io::ErrorKind::InvalidInput, "slab doesn't fit in a segment"))
+    }
+
+    fn slot_meta_offset(pages: usize) -> usize {
+        HEADER_SIZE + pages * PAGE_META
+    }
+
+    /// Pages start on a page boundary within the segment
+    fn data_offset(pages: usize) -> usize {
+        (ShmSlab::slot_meta_offset(pages) + pages * SLOTS_PER_PAGE * SLOT_META).div_ceil(PAGE_SIZE) * PAGE_SIZE
+    }
+
+    /// Largest allocation the slab can hold
+    pub fn max_len(&self) -> usize {
+        CLASSES[CLASSES.len() - 1]
+    }
+
+    fn base(&self) -> *mut u8 {
+        self.shm.address() as *mut u8
+    }
+
+    fn word(&self, offset: usize) -> &AtomicU32 {
+        unsafe { &*(self.base().add(offset) as *const AtomicU32) }
+    }
+
+    // The accessors below are plain reads and writes, every caller holds the lock
+
+    fn get_u32(&self, offset: usize) -> u32 {
+        unsafe { ptr::read(self.base().add(offset) as *const u32) }
+    }
+
+    fn set_u32(&self, offset: usize, value: u32) {
+        unsafe { ptr::write(self.base().add(offset) as *mut u32, value) }
+    }
+
+    fn page_meta(&self, page: usize) -> usize {
+        HEADER_SIZE + page * PAGE_META
+    }
+
+    fn page_mask(&self, page: usize) -> u64 {
+        unsafe { ptr::read(self.base().add(self.page_meta(page) + 16) as *const u64) }
+    }
+
+    fn set_page_mask(&self, page: usize, mask: u64) {
+        unsafe { ptr::write(self.base().add(self.page_meta(page) + 16) as *mut u64, mask) }
+    }
+
+    fn slot_meta(&self, page: usize, slot: usize) -> usize {
+        ShmSlab::slot_meta_offset(self.pages) + (page * SLOTS_PER_PAGE + slot) * SLOT_META
+    }
+
+    fn slot_data(&self, page: usize, class: usize, slot: usize) -> *mut u8 {
+        unsafe { self.base().add(ShmSlab::data_offset(self.pages) + page * PAGE_SIZE + slot * CLASSES[class]) }
+    }
+
+    /// Links `page` in front of its class's list of pages with free slots
+    fn push_partial(&self, class: usize, page: usize) {
+        let head = self.get_u32(OFF_PARTIAL + 4 * class);
+        let meta = self.page_meta(page);
+        self.set_u32(meta + 8, 0);
+        self.set_u32(meta + 12, head);
+        if head != 0 {
+            self.set_u32(self.page_meta(head as usize - 1) + 8, page as u32 + 1);
+        }
+        self.set_u32(OFF_PARTIAL + 4 * class, page as u32 + 1);
+    }
+
+    fn unlink_partial(&self, class: usize, page: usize) {
+        let meta = self.page_meta(page);
+        let prev = self.get_u32(meta + 8);
+        let next = self.get_u32(meta + 12);
+        if prev == 0 {
+            self.set_u32(OFF_PARTIAL + 4 * class, next);
+        } else {
+            self.set_u32(self.page_meta(prev as usize - 1) + 12, next);
+        }
+        if next != 0 {
+            self.set_u32(self.page_meta(next as usize - 1) + 8, prev);
+        }
+    }
+
+    /// Takes a free page for `class` and puts it on the class's partial list
+    fn assign_page(&self, class: usize) -> Option<usize> {
+        let free = self.get_u32(OFF_FREE_PAGES);
+        let page = if free != 0 {
+            self.set_u32(OFF_FREE_PAGES, self.get_u32(self.page_meta(free as usize - 1) + 12));
+            free as usize - 1
+        } else {
+            let unused = self.get_u32(OFF_NEXT_UNUSED) as usize;
+            if unused == self.pages {
+                return None;
+            }
+            self.set_u32(OFF_NEXT_UNUSED, unused as u32 + 1);
+            unused
+        };
+        let meta = self.page_meta(page);
+        self.set_u32(meta, class as u32 + 1);
+        self.set_u32(meta + 4, 0);
+        self.set_page_mask(page, full_mask(class));
+        self.push_partial(class, page);
+        Some(page)
+    }
+
+    /// Copies `bytes` into a slot of the smallest size class that fits them
+    pub fn insert(&self, bytes: &[u8]) -> Result<SlabKey, SlabError> {
+        let class = CLASSES
+            .iter()
+            .position(|&size| bytes.len() <= size)
+            .ok_or(SlabError::TooLarge { len: bytes.len(), max: self.max_len() })?;
+        let _lock = futex::lock(self.word(OFF_LOCK));
+        let page = match self.get_u32(OFF_PARTIAL + 4 * class) {
+            0 => self.assign_page(class).ok_or(SlabError::Full)?,
+            head => head as usize - 1,
+        };
+        let mask = self.page_mask(page);
+        let slot = mask.trailing_zeros() as usize;
+        let mask = mask & !(1 << slot);
+        self.set_page_mask(page, mask);
+        let meta = self.page_meta(page);
+        self.set_u32(meta + 4, self.get_u32(meta + 4) + 1);
+        if mask == 0 {
+            self.unlink_partial(class, page);
+        }
+        let slot_meta = self.slot_meta(page, slot);
+        let generation = self.get_u32(slot_meta).wrapping_add(1);
+        self.set_u32(slot_meta, generation);
+        self.set_u32(slot_meta + 4, bytes.len() as u32);
+        unsafe {
+            ptr::copy_nonoverlapping(bytes.as_ptr(), self.slot_data(page, class, slot), bytes.len());
+        }
+        Ok(SlabKey::new(page, slot, generation & ((1 << (32 - GEN_SHIFT)) - 1)))
+    }
+
+    /// Finds the size class of the live allocation `key` refers to, the caller holds the lock
+    fn check(&self, key: SlabKey) -> Result<usize, SlabError> {
+        let (page, slot) = (key.page(), key.slot());
+        if page >= self.pages {
+            return Err(SlabError::InvalidKey);
+        }
+        let class = match self.get_u32(self.page_meta(page)) {
+            0 => return Err(SlabError::InvalidKey),
+            class => class as usize - 1,
+        };
+        let generation = self.get_u32(self.slot_meta(page, slot));
+        if slot >= class_slots(class) || generation & 1 == 0 || generation & ((1 << (32 - GEN_SHIFT)) - 1) != key.generation() {
+            return Err(SlabError::InvalidKey);
+        }
+        Ok(class)
+    }
+
+    /// The bytes stored under `key`
+    ///
+    /// The slice stays valid until the allocation is removed, which callers sharing keys have to coordinate
+    pub fn get(&self, key: SlabKey) -> Result<&[u8], SlabError> {
+        let _lock = futex::lock(self.word(OFF_LOCK));
+        let class = self.check(key)?;
+        let len = self.get_u32(self.slot_meta(key.page(), key.slot()) + 4) as usize;
+        Ok(unsafe { slice::from_raw_parts(self.slot_data(key.page(), class, key.slot()), len) })
+    }
+
+    /// Frees the allocation under `key`, after which the key and any copies of it are invalid
+    pub fn remove(&self, key: SlabKey) -> Result<(), SlabError> {
+        let _lock = futex::lock(self.word(OFF_LOCK));
+        let class = self.check(key)?;
+        let (page, slot) = (key.page(), key.slot());
+        let slot_meta = self.slot_meta(page, slot);
+        self.set_u32(slot_meta, self.get_u32(slot_meta).wrapping_add(1));
+        let meta = self.page_meta(page);
+        let live = self.get_u32(meta + 4) - 1;
+        self.set_u32(meta + 4, live);
+        let was_full = self.page_mask(page) == 0;
+        self.set_page_mask(page, self.page_mask(page) | (1 << slot));
+        if live == 0 {
+            // Hand the whole page back so other size classes can use it
+            if !was_full {
+                self.unlink_partial(class, page);
+            }
+            self.set_u32(meta, 0);
+            self.set_u32(meta + 12, self.get_u32(OFF_FREE_PAGES));
+            self.set_u32(OFF_FREE_PAGES, page as u32 + 1);
+        } else if was_full {
+            self.push_partial(class, page);
+        }
+        Ok(())
+    }
+}
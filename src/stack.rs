@@ -0,0 +1,131 @@
+//! Lock-free stack of node indices, for free lists shared between processes
+//!
+//! The stack lives at a caller-chosen offset inside a segment: an `AtomicU64` head packing the top node index with
+//! an ABA tag, the node count, and then one `next` link per node. Every successful push or pop bumps the tag, so a
+//! pop that raced with a pop and re-push of the same node fails its CAS instead of linking in a stale `next`.
+
+use std::ptr;
+use std::sync::atomic::{fence, AtomicU32, AtomicU64, Ordering};
+
+use crate::shared_memory::{Error, SharedMemory};
+
+const OFF_HEAD: usize = 0;
+const OFF_NODES: usize = 8;
+const OFF_LINKS: usize = 16;
+
+/// Alignment the stack's offset inside the segment has to have
+pub const ALIGNMENT: usize = 8;
+
+/// Node index + 1 in the low half, so 0 means empty
+fn pack(tag: u32, top: u32) -> u64 {
+    ((tag as u64) << 32) | top as u64
+}
+
+fn unpack(head: u64) -> (u32, u32) {
+    ((head >> 32) as u32, head as u32)
+}
+
+pub struct ShmStack<'a> {
+    shm: &'a SharedMemory,
+    offset: usize,
+    nodes: u32,
+}
+
+// Only the segment's address and size are read through the reference, everything in the stack is atomic
+unsafe impl Send for ShmStack<'_> {}
+unsafe impl Sync for ShmStack<'_> {}
+
+impl<'a> ShmStack<'a> {
+    /// Bytes the stack takes up for `nodes` nodes
+    pub fn size_for(nodes: u32) -> usize {
+        OFF_LINKS + nodes as usize * 4
+    }
+
+    /// Sets up an empty stack for `nodes` nodes at `offset` into `shm`
+    ///
+    /// Nothing else may be using the stack while it's being initialized
+    pub fn init(shm: &'a SharedMemory, offset: usize, nodes: u32) -> Result<Self, Error> {
+        let stack = ShmStack::check(shm, offset, nodes)?;
+        stack.head().store(0, Ordering::Relaxed);
+        unsafe {
+            ptr::write_bytes(stack.base().add(OFF_LINKS), 0, nodes as usize * 4);
+        }
+        shm.write_u64_le(offset + OFF_NODES, nodes as u64)?;
+        fence(Ordering::Release);
+        Ok(stack)
+    }
+
+    /// Attaches to a stack another handle initialized at `offset` with the same node count
+    pub fn attach(shm: &'a SharedMemory, offset: usize, nodes: u32) -> Result<Self, Error> {
+        let stack = ShmStack::check(shm, offset, nodes)?;
+        let found = shm.read_u64_le(offset + OFF_NODES)?;
+        if found != nodes as u64 {
+            return Err(Error::LayoutMismatch { what: "stack nodes", expected: nodes as u64, found });
+        }
+        Ok(stack)
+    }
+
+    fn check(shm: &'a SharedMemory, offset: usize, nodes: u32) -> Result<Self, Error> {
+        if !offset.is_multiple_of(ALIGNMENT) {
+            return Err(Error::UnalignedOffset { offset: offset as u64, alignment: ALIGNMENT as u64 });
+        }
+        let len = ShmStack::size_for(nodes);
+        let size = shm.size() as usize;
+        if offset.checked_add(len).is_none_or(|end| end > size) {
+            return Err(Error::OutOfBounds { offset, len, size });
+        }
+        Ok(ShmStack { shm, offset, nodes })
+    }
+
+    fn base(&self) -> *mut u8 {
+        unsafe { (self.shm.address() as *mut u8).add(self.offset) }
+    }
+
+    fn head(&self) -> &AtomicU64 {
+        unsafe { &*(self.base().add(OFF_HEAD) as *const AtomicU64) }
+    }
+
+    fn link(&self, node: u32) -> &AtomicU32 {
+        unsafe { &*(self.base().add(OFF_LINKS + node as usize * 4) as *const AtomicU32) }
+    }
+
+    pub fn nodes(&self) -> u32 {
+        self.nodes
+    }
+
+    /// Pushes `node_index`, which the caller must own, it is handed over to whoever pops it next
+    ///
+    /// # Panics
+    /// If `node_index` is out of range for the stack
+    pub fn push(&self, node_index: u32) {
+        assert!(node_index < self.nodes, "node {} is out of range for a stack of {}", node_index, self.nodes);
+        let head = self.head();
+        let mut current = head.load(Ordering::Relaxed);
+        loop {
+            let (tag, top) = unpack(current);
+            self.link(node_index).store(top, Ordering::Relaxed);
+            match head.compare_exchange_weak(current, pack(tag.wrapping_add(1), node_index + 1), Ordering::Release, Ordering::Relaxed) {
+                Ok(_) => return,
+                Err(actual) => current = actual,
+            }
+        }
+    }
+
+    /// Pops the most recently pushed node and hands its ownership to the caller
+    pub fn pop(&self) -> Option<u32> {
+        let head = self.head();
+        let mut current = head.load(Ordering::Acquire);
+        loop {
+            let (tag, top) = unpack(current);
+            if top == 0 {
+                return None;
+            }
+            // May be stale if another pop won, the CAS then fails on the tag
+            let next = self.link(top - 1).load(Ordering::Relaxed);
+            match head.compare_exchange_weak(current, pack(tag.wrapping_add(1), next), Ordering::AcqRel, Ordering::Acquire) {
+                Ok(_) => return Some(top - 1),
+                Err(actual) => current = actual,
+            }
+        }
+    }
+}
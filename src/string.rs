@@ -0,0 +1,161 @@
+//! Fixed-capacity UTF-8 string slot at an offset inside a segment, for status text and the like
+//!
+//! The slot is a sequence word, the capacity, the current length and then `capacity` bytes. Writers make the
+//! sequence word odd with a CAS, which also keeps two writers out of each other's way, write the length and bytes
+//! and make it even again. Readers copy the length and bytes and retry if the word was odd or changed meanwhile,
+//! so a length from one `set` is never paired with bytes from another.
+
+use std::fmt;
+use std::io;
+use std::sync::atomic::{fence, AtomicU32, AtomicU64, Ordering};
+use std::{hint, ptr, thread};
+
+use crate::shared_memory::{Error, SharedMemory};
+
+const OFF_SEQ: usize = 0;
+const OFF_CAPACITY: usize = 8;
+const OFF_LEN: usize = 12;
+const OFF_BYTES: usize = 16;
+
+/// Alignment the slot's offset inside the segment has to have
+pub const ALIGNMENT: usize = 8;
+
+/// How many torn reads `get` retries past before deciding a writer died mid-`set`
+const READ_ATTEMPTS: u32 = 100_000;
+
+/// The string given to [`ShmString::set`] is `len` bytes, more than the slot's `capacity`
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct TooLong {
+    pub len: usize,
+    pub capacity: usize,
+}
+
+impl fmt::Display for TooLong {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "string of {} bytes doesn't fit in a {} byte slot", self.len, self.capacity)
+    }
+}
+
+impl std::error::Error for TooLong {}
+
+pub struct ShmString<'a> {
+    shm: &'a SharedMemory,
+    offset: usize,
+    capacity: usize,
+}
+
+// Only the segment's address and size are read through the reference, the slot itself is guarded by its sequence word
+unsafe impl Send for ShmString<'_> {}
+unsafe impl Sync for ShmString<'_> {}
+
+impl<'a> ShmString<'a> {
+    /// Bytes the slot takes up for strings of up to `capacity` bytes
+    pub fn size_for(capacity: usize) -> usize {
+        OFF_BYTES + capacity
+    }
+
+    /// Sets up an empty slot for strings of up to `capacity` bytes at `offset` into `shm`
+    ///
+    /// Nothing else may be using the slot while it's being initialized
+    pub fn init(shm: &'a SharedMemory, offset: usize, capacity: usize) -> Result<Self, Error> {
+        let slot = ShmString::check(shm, offset, capacity)?;
+        slot.seq().store(0, Ordering::Relaxed);
+        slot.len().store(0, Ordering::Relaxed);
+        shm.write_u32_le(offset + OFF_CAPACITY, capacity as u32)?;
+        fence(Ordering::Release);
+        Ok(slot)
+    }
+
+    /// Attaches to a slot another handle initialized at `offset` with the same capacity
+    pub fn attach(shm: &'a SharedMemory, offset: usize, capacity: usize) -> Result<Self, Error> {
+        let slot = ShmString::check(shm, offset, capacity)?;
+        let found = shm.read_u32_le(offset + OFF_CAPACITY)? as u64;
+        if found != capacity as u64 {
+            return Err(Error::LayoutMismatch { what: "string capacity", expected: capacity as u64, found });
+        }
+        Ok(slot)
+    }
+
+    fn check(shm: &'a SharedMemory, offset: usize, capacity: usize) -> Result<Self, Error> {
+        if !offset.is_multiple_of(ALIGNMENT) {
+            return Err(Error::UnalignedOffset { offset: offset as u64, alignment: ALIGNMENT as u64 });
+        }
+        if u32::try_from(capacity).is_err() {
+            return Err(io::Error::new(io::ErrorKind::InvalidInput, "string slot capacity doesn't fit in a u32").into());
+        }
+        let len = ShmString::size_for(capacity);
+        let size = shm.size() as usize;
+        if offset.checked_add(len).is_none_or(|end| end > size) {
+            return Err(Error::OutOfBounds { offset, len, size });
+        }
+        Ok(ShmString { shm, offset, capacity })
+    }
+
+    fn base(&self) -> *mut u8 {
+        unsafe { (self.shm.address() as *mut u8).add(self.offset) }
+    }
+
+    fn seq(&self) -> &AtomicU64 {
+        unsafe { &*(self.base().add(OFF_SEQ) as *const AtomicU64) }
+    }
+
+    fn len(&self) -> &AtomicU32 {
+        unsafe { &*(self.base().add(OFF_LEN) as *const AtomicU32) }
+    }
+
+    pub fn capacity(&self) -> usize {
+        self.capacity
+    }
+
+    pub fn set(&self, value: &str) -> Result<(), TooLong> {
+        if value.len() > self.capacity {
+            return Err(TooLong { len: value.len(), capacity: self.capacity });
+        }
+        let seq = self.seq();
+        let mut current = seq.load(Ordering::Relaxed);
+        loop {
+            if current & 1 == 1 {
+                hint::spin_loop();
+                current = seq.load(Ordering::Relaxed);
+                continue;
+            }
+            match seq.compare_exchange_weak(current, current + 1, Ordering::Acquire, Ordering::Relaxed) {
+                Ok(_) => break,
+                Err(actual) => current = actual,
+            }
+        }
+        // Keeps the length and byte stores below the odd sequence number
+        fence(Ordering::Release);
+        self.len().store(value.len() as u32, Ordering::Relaxed);
+        unsafe { ptr::copy_nonoverlapping(value.as_ptr(), self.base().add(OFF_BYTES), value.len()) };
+        seq.store(current + 2, Ordering::Release);
+        Ok(())
+    }
+
+    /// A copy of the string from the last finished `set`, empty before the first
+    ///
+    /// Fails with `WouldBlock` if every attempt overlapped a `set`, which means a writer died in the middle of one,
+    /// or with `InvalidData` if the slot doesn't hold UTF-8
+    pub fn get(&self) -> Result<String, Error> {
+        let seq = self.seq();
+        let mut bytes = vec![0u8; self.capacity];
+        for attempt in 0..READ_ATTEMPTS {
+            let before = seq.load(Ordering::Acquire);
+            if before & 1 == 0 {
+                let len = (self.len().load(Ordering::Relaxed) as usize).min(self.capacity);
+                unsafe { ptr::copy_nonoverlapping(self.base().add(OFF_BYTES), bytes.as_mut_ptr(), len) };
+                fence(Ordering::Acquire);
+                if seq.load(Ordering::Relaxed) == before {
+                    bytes.truncate(len);
+                    return String::from_utf8(bytes).map_err(|err| io::Error::new(io::ErrorKind::InvalidData, err).into());
+                }
+            }
+            if attempt % 64 == 63 {
+                thread::yield_now();
+            } else {
+                hint::spin_loop();
+            }
+        }
+        Err(io::Error::new(io::ErrorKind::WouldBlock, "string slot was being written during every read").into())
+    }
+}
@@ -0,0 +1,131 @@
+//! Two equal halves at an offset inside a segment, one readable and one being rebuilt, swapped in one atomic store
+//!
+//! A single `u64` holds the version in its upper bits and the index of the active half in the lowest one, so a reader
+//! gets both from one Acquire load. The writer builds the new contents in place in the inactive half and publishes it
+//! with a Release store, after which every reader that loads the new state sees all of it.
+//!
+//! Nothing stops a reader that is still inside the half that just went inactive from having it overwritten by the
+//! writer's next `stage`. Readers that can be that slow copy or parse what they need and then check
+//! [`ShmSwap::is_current`] with the version `active` gave them, throwing the result away and starting over if it
+//! changed.
+
+use std::io;
+use std::sync::atomic::{fence, AtomicU64, Ordering};
+
+use crate::shared_memory::{Error, SharedMemory};
+
+const OFF_STATE: usize = 0;
+const OFF_HALF_LEN: usize = 8;
+const HEADER_SIZE: usize = 16;
+
+/// Alignment the swap's offset inside the segment has to have
+pub const ALIGNMENT: usize = 8;
+
+pub struct ShmSwap<'a> {
+    shm: &'a SharedMemory,
+    offset: usize,
+    half_len: usize,
+}
+
+// Only the segment's address and size are read through the reference, the halves are handed out under the
+// single-writer contract of `stage`
+unsafe impl Send for ShmSwap<'_> {}
+unsafe impl Sync for ShmSwap<'_> {}
+
+impl<'a> ShmSwap<'a> {
+    /// Bytes the swap takes up with halves of `half_len` bytes
+    pub fn size_for(half_len: usize) -> usize {
+        HEADER_SIZE + 2 * half_len
+    }
+
+    /// Sets up a swap with zeroed halves of `half_len` bytes at `offset` into `shm`, version 0 with the first half
+    /// active
+    ///
+    /// Nothing else may be using the swap while it's being initialized
+    pub fn init(shm: &'a SharedMemory, offset: usize, half_len: usize) -> Result<Self, Error> {
+        let swap = ShmSwap::check(shm, offset, half_len)?;
+        unsafe { std::ptr::write_bytes(swap.half(0), 0, 2 * half_len) };
+        shm.write_u64_le(offset + OFF_HALF_LEN, half_len as u64)?;
+        swap.state().store(0, Ordering::Release);
+        Ok(swap)
+    }
+
+    /// Attaches to a swap another handle initialized at `offset` with the same half length
+    pub fn attach(shm: &'a SharedMemory, offset: usize, half_len: usize) -> Result<Self, Error> {
+        let swap = ShmSwap::check(shm, offset, half_len)?;
+        let found = shm.read_u64_le(offset + OFF_HALF_LEN)?;
+        if found != half_len as u64 {
+            return Err(Error::LayoutMismatch { what: "swap half length", expected: half_len as u64, found });
+        }
+        Ok(swap)
+    }
+
+    fn check(shm: &'a SharedMemory, offset: usize, half_len: usize) -> Result<Self, Error> {
+        if !offset.is_multiple_of(ALIGNMENT) {
+            return Err(Error::UnalignedOffset { offset: offset as u64, alignment: ALIGNMENT as u64 });
+        }
+        let size = shm.size() as usize;
+        let len = half_len.checked_mul(2).and_then(|halves| halves.checked_add(HEADER_SIZE));
+        if len.and_then(|len| offset.checked_add(len)).is_none_or(|end| end > size) {
+            return Err(Error::OutOfBounds { offset, len: len.unwrap_or(usize::MAX), size });
+        }
+        if half_len == 0 {
+            return Err(io::Error::new(io::ErrorKind::InvalidInput, "swap halves can't be empty").into());
+        }
+        Ok(ShmSwap { shm, offset, half_len })
+    }
+
+    fn base(&self) -> *mut u8 {
+        unsafe { (self.shm.address() as *mut u8).add(self.offset) }
+    }
+
+    fn state(&self) -> &AtomicU64 {
+        unsafe { &*(self.base().add(OFF_STATE) as *const AtomicU64) }
+    }
+
+    fn half(&self, index: u64) -> *mut u8 {
+        unsafe { self.base().add(HEADER_SIZE + index as usize * self.half_len) }
+    }
+
+    pub fn half_len(&self) -> usize {
+        self.half_len
+    }
+
+    /// The inactive half, to build the next contents in before calling `commit`
+    ///
+    /// There may only be one writer across every process using the swap, `&mut self` only rules out a second one
+    /// through this handle. The half still holds whatever was committed two versions ago
+    pub fn stage(&mut self) -> &mut [u8] {
+        let inactive = (self.state().load(Ordering::Relaxed) & 1) ^ 1;
+        // Keeps the writes into the half from moving above the commit that made it inactive
+        fence(Ordering::Release);
+        unsafe { std::slice::from_raw_parts_mut(self.half(inactive), self.half_len) }
+    }
+
+    /// Makes the staged half the active one and bumps the version, returning the new version
+    pub fn commit(&mut self) -> u64 {
+        // Only the writer changes the state, so its own last value can be read without ordering
+        let current = self.state().load(Ordering::Relaxed);
+        let next = ((current >> 1) + 1) << 1 | ((current & 1) ^ 1);
+        self.state().store(next, Ordering::Release);
+        next >> 1
+    }
+
+    /// The active half and its version
+    ///
+    /// The slice only stays intact until the writer commits a newer version and stages again, see the module docs
+    /// for checking that with `is_current`
+    pub fn active(&self) -> (&[u8], u64) {
+        let state = self.state().load(Ordering::Acquire);
+        let half = unsafe { std::slice::from_raw_parts(self.half(state & 1), self.half_len) };
+        (half, state >> 1)
+    }
+
+    /// Whether `version` is still the active one, in which case the writer can't have staged into its half since
+    /// `active` returned it
+    pub fn is_current(&self, version: u64) -> bool {
+        // Keeps the reads of the half from moving below the check
+        fence(Ordering::Acquire);
+        self.state().load(Ordering::Relaxed) >> 1 == version
+    }
+}
@@ -0,0 +1,170 @@
+//! Helpers for tests that need a second process, behind the `testing` feature
+//!
+//! [`run`] starts the current test binary again with a filter that only matches the calling test and an environment
+//! variable naming it, so the same test function runs once in each process and `run` calls the half that belongs
+//! there. The child's output is collected and shown if it fails, and a child that doesn't finish within
+//! [`CHILD_TIMEOUT`] is killed.
+//!
+//! ```no_run
+//! use shared_memory::shared_memory::SharedMemory;
+//! use shared_memory::testing;
+//!
+//! #[test]
+//! fn child_sees_the_parents_write() {
+//!     let name = format!("/handoff.{}", testing::parent_id());
+//!     testing::run(
+//!         "child_sees_the_parents_write",
+//!         &name,
+//!         |child| {
+//!             let shm = SharedMemory::create_exclusive(&name, 4096).unwrap();
+//!             shm.write_u32_le(0, 7).unwrap();
+//!             child.start();
+//!             child.wait();
+//!         },
+//!         |name| assert_eq!(SharedMemory::open(name, 4096).unwrap().read_u32_le(0).unwrap(), 7),
+//!     );
+//! }
+//! ```
+
+use std::io::Read;
+use std::process::{self, Command, Stdio};
+use std::thread::{self, JoinHandle};
+use std::time::{Duration, Instant};
+
+/// Environment variable holding the name of the test a child process was started for
+pub const ROLE_VAR: &str = "SHARED_MEMORY_TEST_ROLE";
+/// Environment variable holding the argument the parent handed its child
+const ARG_VAR: &str = "SHARED_MEMORY_TEST_ARG";
+/// Environment variable holding the parent's process id, see [`parent_id`]
+const PARENT_VAR: &str = "SHARED_MEMORY_TEST_PARENT";
+
+/// Longest a child may run before it's killed and the test fails
+pub const CHILD_TIMEOUT: Duration = Duration::from_secs(30);
+
+/// How often a wait checks whether the child has exited
+const POLL: Duration = Duration::from_millis(5);
+
+/// Printed by a child once its half returned, so a filter that matched no test can't pass for success
+const FINISHED: &str = "shared_memory::testing child finished";
+
+/// The parent's process id in both processes, for names the two halves have to agree on
+pub fn parent_id() -> u32 {
+    match std::env::var(PARENT_VAR) {
+        Ok(id) => id.parse().expect("the parent id is a number"),
+        Err(_) => process::id(),
+    }
+}
+
+/// Whether this process was started by `run` for `test`
+pub fn is_child(test: &str) -> bool {
+    std::env::var(ROLE_VAR).is_ok_and(|role| role == test)
+}
+
+/// Runs `parent` in this process and `child` in a copy of the test binary that only runs `test`
+///
+/// `test` is the test's path as the harness prints it without the crate name, like `tests::my_test`. `child` gets
+/// `arg`, which is usually a segment name. The child is started when `parent` calls [`Child::start`], or before
+/// `parent` runs if it never does, and `run` waits for it after `parent` returns. Panics if the child fails, times
+/// out, or never ran the test
+pub fn run<P, C>(test: &str, arg: &str, parent: P, child: C)
+where
+    P: FnOnce(&mut Child),
+    C: FnOnce(&str),
+{
+    if is_child(test) {
+        let arg = std::env::var(ARG_VAR).unwrap_or_default();
+        child(&arg);
+        println!("{}", FINISHED);
+        return;
+    }
+    let mut process = Child { test: test.to_string(), arg: arg.to_string(), running: None, done: false };
+    parent(&mut process);
+    process.start();
+    process.wait();
+}
+
+/// The child process of a [`run`], as the parent half sees it
+pub struct Child {
+    test: String,
+    arg: String,
+    running: Option<Running>,
+    done: bool,
+}
+
+struct Running {
+    process: process::Child,
+    stdout: JoinHandle<String>,
+    stderr: JoinHandle<String>,
+}
+
+impl Child {
+    /// Starts the child, does nothing if it already was
+    pub fn start(&mut self) {
+        if self.running.is_some() || self.done {
+            return;
+        }
+        let binary = std::env::current_exe().expect("the test binary's path is known");
+        let mut process = Command::new(binary)
+            .args([self.test.as_str(), "--exact", "--nocapture", "--test-threads=1"])
+            .env(ROLE_VAR, &self.test)
+            .env(ARG_VAR, &self.arg)
+            .env(PARENT_VAR, parent_id().to_string())
+            .stdin(Stdio::null())
+            .stdout(Stdio::piped())
+            .stderr(Stdio::piped())
+            .spawn()
+            .expect("the test binary can be started again");
+        // Read on their own threads so a chatty child can't block on a full pipe while the parent waits
+        let drain = |mut pipe: Box<dyn Read + Send>| {
+            thread::spawn(move || {
+                let mut output = String::new();
+                let _ = pipe.read_to_string(&mut output);
+                output
+            })
+        };
+        let stdout = drain(Box::new(process.stdout.take().expect("stdout is piped")));
+        let stderr = drain(Box::new(process.stderr.take().expect("stderr is piped")));
+        self.running = Some(Running { process, stdout, stderr });
+    }
+
+    /// Starts the child if it isn't running yet and waits up to [`CHILD_TIMEOUT`] for it to exit, panicking with
+    /// its output unless it ran the test and passed
+    pub fn wait(&mut self) {
+        self.start();
+        let mut running = match self.running.take() {
+            Some(running) => running,
+            None => return,
+        };
+        self.done = true;
+        let deadline = Instant::now() + CHILD_TIMEOUT;
+        let status = loop {
+            match running.process.try_wait().expect("the child's status can be read") {
+                Some(status) => break Some(status),
+                None if Instant::now() >= deadline => {
+                    let _ = running.process.kill();
+                    let _ = running.process.wait();
+                    break None;
+                }
+                None => thread::sleep(POLL),
+            }
+        };
+        let stdout = running.stdout.join().unwrap_or_default();
+        let stderr = running.stderr.join().unwrap_or_default();
+        match status {
+            None => panic!("child for {} ran past {:?}\n{}{}", self.test, CHILD_TIMEOUT, stdout, stderr),
+            Some(status) if !status.success() => panic!("child for {} failed with {}\n{}{}", self.test, status, stdout, stderr),
+            Some(_) if !stdout.contains(FINISHED) => panic!("child for {} never ran it, is the test path right?\n{}{}", self.test, stdout, stderr),
+            Some(_) => {}
+        }
+    }
+}
+
+impl Drop for Child {
+    fn drop(&mut self) {
+        // A parent half that panicked shouldn't leave its child behind
+        if let Some(running) = &mut self.running {
+            let _ = running.process.kill();
+            let _ = running.process.wait();
+        }
+    }
+}
@@ -0,0 +1,168 @@
+//! Fixed-capacity vector of `SharedSafe` elements at an offset inside a segment
+//!
+//! The header holds a futex lock, the element size and capacity, and the length, followed by room for `capacity`
+//! elements. Writers (`push`, `pop`, `clear`) serialize on the lock and publish the new length with a Release
+//! store, so a reader that loads the length with Acquire sees every element below it fully written. Readers don't
+//! take the lock: an element they are copying can still be replaced if a `pop` and a `push` run at the same time,
+//! which leaves a value that is a valid `T` but may be a mix of the two.
+
+use std::fmt;
+use std::marker::PhantomData;
+use std::mem::{align_of, size_of};
+use std::ptr;
+use std::sync::atomic::{fence, AtomicU32, AtomicU64, Ordering};
+
+use crate::futex;
+use crate::shared_memory::{Error, SharedMemory};
+use crate::shared_safe::SharedSafe;
+
+const OFF_LOCK: usize = 0;
+const OFF_ELEMENT_SIZE: usize = 4;
+const OFF_CAPACITY: usize = 8;
+const OFF_LEN: usize = 16;
+const HEADER_SIZE: usize = 24;
+
+/// `push` found the vector at capacity, the element is handed back
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Full<T>(pub T);
+
+impl<T> fmt::Display for Full<T> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "vector is at capacity")
+    }
+}
+
+impl<T: fmt::Debug> std::error::Error for Full<T> {}
+
+pub struct ShmVec<'a, T: SharedSafe> {
+    shm: &'a SharedMemory,
+    offset: usize,
+    capacity: usize,
+    _elements: PhantomData<T>,
+}
+
+// Only the segment's address and size are read through the reference, the length is atomic and element writes are
+// serialized by the lock in the segment
+unsafe impl<T: SharedSafe> Send for ShmVec<'_, T> {}
+unsafe impl<T: SharedSafe> Sync for ShmVec<'_, T> {}
+
+impl<'a, T: SharedSafe> ShmVec<'a, T> {
+    /// Alignment the vector's offset inside the segment has to have
+    pub const ALIGNMENT: usize = if align_of::<T>() > 8 { align_of::<T>() } else { 8 };
+
+    const DATA: usize = HEADER_SIZE.next_multiple_of(align_of::<T>());
+
+    /// Bytes the vector takes up for `capacity` elements
+    pub fn size_for(capacity: usize) -> usize {
+        Self::DATA + capacity * size_of::<T>()
+    }
+
+    /// Sets up an empty vector for `capacity` elements at `offset` into `shm`
+    ///
+    /// Nothing else may be using the vector while it's being initialized
+    pub fn init(shm: &'a SharedMemory, offset: usize, capacity: usize) -> Result<Self, Error> {
+        let vec = ShmVec::check(shm, offset, capacity)?;
+        vec.word(OFF_LOCK).store(0, Ordering::Relaxed);
+        vec.len_word().store(0, Ordering::Relaxed);
+        shm.write_u32_le(offset + OFF_ELEMENT_SIZE, size_of::<T>() as u32)?;
+        shm.write_u64_le(offset + OFF_CAPACITY, capacity as u64)?;
+        fence(Ordering::Release);
+        Ok(vec)
+    }
+
+    /// Attaches to a vector another handle initialized at `offset` with the same capacity and element type
+    pub fn attach(shm: &'a SharedMemory, offset: usize, capacity: usize) -> Result<Self, Error> {
+        let vec = ShmVec::check(shm, offset, capacity)?;
+        let found = shm.read_u32_le(offset + OFF_ELEMENT_SIZE)? as u64;
+        if found != size_of::<T>() as u64 {
+            return Err(Error::LayoutMismatch { what: "vector element size", expected: size_of::<T>() as u64, found });
+        }
+        let found = shm.read_u64_le(offset + OFF_CAPACITY)?;
+        if found != capacity as u64 {
+            return Err(Error::LayoutMismatch { what: "vector capacity", expected: capacity as u64, found });
+        }
+        Ok(vec)
+    }
+
+    fn check(shm: &'a SharedMemory, offset: usize, capacity: usize) -> Result<Self, Error> {
+        if !offset.is_multiple_of(Self::ALIGNMENT) {
+            return Err(Error::UnalignedOffset { offset: offset as u64, alignment: Self::ALIGNMENT as u64 });
+        }
+        let size = shm.size() as usize;
+        let len = capacity.checked_mul(size_of::<T>()).and_then(|data| data.checked_add(Self::DATA));
+        if len.and_then(|len| offset.checked_add(len)).is_none_or(|end| end > size) {
+            return Err(Error::OutOfBounds { offset, len: len.unwrap_or(usize::MAX), size });
+        }
+        Ok(ShmVec { shm, offset, capacity, _elements: PhantomData })
+    }
+
+    fn base(&self) -> *mut u8 {
+        unsafe { (self.shm.address() as *mut u8).add(self.offset) }
+    }
+
+    fn word(&self, offset: usize) -> &AtomicU32 {
+        unsafe { &*(self.base().add(offset) as *const AtomicU32) }
+    }
+
+    fn len_word(&self) -> &AtomicU64 {
+        unsafe { &*(self.base().add(OFF_LEN) as *const AtomicU64) }
+    }
+
+    fn element(&self, index: usize) -> *mut T {
+        unsafe { self.base().add(Self::DATA + index * size_of::<T>()) as *mut T }
+    }
+
+    pub fn capacity(&self) -> usize {
+        self.capacity
+    }
+
+    pub fn len(&self) -> usize {
+        // A corrupt length from another process must not let reads run past the capacity
+        usize::try_from(self.len_word().load(Ordering::Acquire)).map_or(self.capacity, |len| len.min(self.capacity))
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    pub fn push(&self, value: T) -> Result<(), Full<T>> {
+        let _lock = futex::lock(self.word(OFF_LOCK));
+        let len = self.len();
+        if len == self.capacity {
+            return Err(Full(value));
+        }
+        unsafe { ptr::write(self.element(len), value) };
+        self.len_word().store(len as u64 + 1, Ordering::Release);
+        Ok(())
+    }
+
+    /// Removes and returns the last element
+    pub fn pop(&self) -> Option<T> {
+        let _lock = futex::lock(self.word(OFF_LOCK));
+        let len = self.len().checked_sub(1)?;
+        let value = unsafe { ptr::read(self.element(len)) };
+        self.len_word().store(len as u64, Ordering::Release);
+        Some(value)
+    }
+
+    pub fn get(&self, index: usize) -> Option<T> {
+        if index >= self.len() {
+            return None;
+        }
+        Some(unsafe { ptr::read(self.element(index)) })
+    }
+
+    /// The elements below the current length, borrowed straight from the segment
+    ///
+    /// A `pop` followed by a `push` can change the last elements while the slice is alive, with the same
+    /// aliasing caveat as [`SharedMemory::read_data`]. Copy out with `get` when writers are active
+    pub fn as_slice(&self) -> &[T] {
+        unsafe { std::slice::from_raw_parts(self.element(0), self.len()) }
+    }
+
+    /// Empties the vector, any process can do this so coordinate it with the other writers
+    pub fn clear(&self) {
+        let _lock = futex::lock(self.word(OFF_LOCK));
+        self.len_word().store(0, Ordering::Release);
+    }
+}
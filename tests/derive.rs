@@ -0,0 +1,74 @@
+//! `#[derive(SharedSafe)]` used from outside the crate, the way its generated paths expect
+
+use std::mem::offset_of;
+
+use shared_memory::shared_memory::SharedMemory;
+use shared_memory::shared_safe::SharedSafe;
+
+#[repr(C)]
+#[derive(Clone, Copy, Debug, PartialEq, SharedSafe)]
+struct Point {
+    x: i32,
+    y: i32,
+}
+
+#[repr(C)]
+#[derive(Clone, Copy, Debug, PartialEq, SharedSafe)]
+struct Segment {
+    id: u64,
+    from: Point,
+    to: Point,
+    kind: u32,
+    _pad: [u8; 4],
+}
+
+#[repr(transparent)]
+#[derive(Clone, Copy, Debug, PartialEq, SharedSafe)]
+struct Millis(u64);
+
+#[repr(u32)]
+#[derive(Clone, Copy, Debug, PartialEq, SharedSafe)]
+enum State {
+    Idle = 1,
+    Busy = 5,
+    Done,
+}
+
+#[test]
+fn offset_constants_match_offset_of() {
+    assert_eq!(Point::FIELD_OFFSET_Y, offset_of!(Point, y));
+    assert_eq!(Segment::FIELD_OFFSET_ID, offset_of!(Segment, id));
+    assert_eq!(Segment::FIELD_OFFSET_FROM, offset_of!(Segment, from));
+    assert_eq!(Segment::FIELD_OFFSET_TO, offset_of!(Segment, to));
+    assert_eq!(Segment::FIELD_OFFSET_KIND, offset_of!(Segment, kind));
+    assert_eq!(Segment::FIELD_OFFSET__PAD, offset_of!(Segment, _pad));
+    assert_eq!(Millis::FIELD_OFFSET_0, 0);
+}
+
+#[test]
+fn nested_structs_and_fields_round_trip_through_a_segment() {
+    let name = format!("/derive.{}", std::process::id());
+    let shm = SharedMemory::create(&name, 4096).unwrap();
+    let segment = Segment { id: 9, from: Point { x: -1, y: 2 }, to: Point { x: 30, y: -40 }, kind: 3, _pad: [0; 4] };
+    shm.write_value(64, segment).unwrap();
+    shm.write_value(128, Millis(1500)).unwrap();
+    let other = SharedMemory::open(&name, 4096).unwrap();
+    assert_eq!(other.read_value::<Segment>(64).unwrap(), segment);
+    assert_eq!(other.read_value::<Point>(64 + Segment::FIELD_OFFSET_TO).unwrap(), segment.to);
+    assert_eq!(other.read_value::<i32>(64 + Segment::FIELD_OFFSET_FROM + Point::FIELD_OFFSET_Y).unwrap(), 2);
+    assert_eq!(other.read_value::<Millis>(128).unwrap(), Millis(1500));
+    fn shared_safe<T: SharedSafe>() {}
+    shared_safe::<[Segment; 4]>();
+}
+
+#[test]
+fn enums_are_stored_as_their_repr() {
+    let shm = SharedMemory::create(&format!("/derive_enum.{}", std::process::id()), 4096).unwrap();
+    shm.write_value(0, State::Done.to_repr()).unwrap();
+    assert_eq!(shm.read_value::<u32>(0).unwrap(), 6);
+    assert_eq!(State::from_repr(shm.read_value(0).unwrap()), Some(State::Done));
+    assert_eq!(State::from_repr(1), Some(State::Idle));
+    assert_eq!(State::Busy.to_repr(), 5);
+    // Another process may have written anything
+    assert_eq!(State::from_repr(2), None);
+}
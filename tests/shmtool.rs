@@ -0,0 +1,45 @@
+//! Drives the shmtool binary against a segment of its own
+//!
+//! Only on Linux, where a segment `shmtool create` made outlives the process that made it
+
+#![cfg(target_os = "linux")]
+
+use std::process::{Command, Output};
+
+use shared_memory::shared_memory::SharedMemory;
+
+fn shmtool(args: &[&str]) -> Output {
+    Command::new(env!("CARGO_BIN_EXE_shmtool")).args(args).output().expect("shmtool can be started")
+}
+
+fn stdout(output: &Output) -> String {
+    assert!(output.status.success(), "shmtool failed: {}", String::from_utf8_lossy(&output.stderr));
+    String::from_utf8(output.stdout.clone()).unwrap()
+}
+
+#[test]
+fn commands_create_fill_read_and_remove_a_segment() {
+    let name = format!("/shmtool_cli.{}", std::process::id());
+    stdout(&shmtool(&["create", &name, "4096"]));
+    assert_eq!(stdout(&shmtool(&["ls", &name])), format!("{}\t4096\n", name));
+
+    stdout(&shmtool(&["write", &name, "--data", "from the tool"]));
+    assert_eq!(SharedMemory::open(&name, 4096).unwrap().read_data(), b"from the tool");
+    assert_eq!(stdout(&shmtool(&["read", &name])), "from the tool");
+    assert_eq!(stdout(&shmtool(&["read", &name, "--offset", "5", "--len", "3"])), "the");
+    assert_eq!(
+        stdout(&shmtool(&["read", &name, "--hex", "--len", "4"])),
+        "00000000 | 66 72 6f 6d                                     | from\n"
+    );
+    let info = stdout(&shmtool(&["info", &name]));
+    assert!(info.contains("size: 4096 bytes\n") && info.contains("used: 13 bytes\n"), "{}", info);
+
+    // Library errors and usage mistakes get their own exit codes
+    assert_eq!(shmtool(&["read", &name, "--offset", "4090", "--len", "10"]).status.code(), Some(5));
+    assert_eq!(shmtool(&["write", &name]).status.code(), Some(2));
+    assert_eq!(shmtool(&["frobnicate"]).status.code(), Some(2));
+
+    stdout(&shmtool(&["rm", &name]));
+    assert_eq!(shmtool(&["read", &name]).status.code(), Some(3));
+    assert_eq!(stdout(&shmtool(&["ls", &name])), "");
+}